@@ -0,0 +1,116 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use std::future::{ready, Ready};
+
+use crate::config::AppConfig;
+
+// Extractor guarding moderator routes. Accepts the configured admin password
+// via HTTP Basic auth (any user name) or as a bearer token. With no password
+// configured every admin route is refused, and Basic auth only counts for
+// POSTs sent from the site's own pages.
+pub struct Admin {
+    // Who the moderation log credits: the Basic auth user name, or "admin"
+    // for a bearer token. The password is shared, so this is only as
+    // honest as the moderators are.
+    pub name: String,
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+enum Credentials {
+    Bearer(String),
+    Basic { user: Option<String>, password: String },
+}
+
+fn presented_credentials(req: &HttpRequest) -> Option<Credentials> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(Credentials::Bearer(token.trim().to_string()));
+    }
+
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some(Credentials::Basic {
+        user: Some(user.trim().to_string()).filter(|user| !user.is_empty()),
+        password: password.to_string(),
+    })
+}
+
+// Whether a request that changes something was made from this site's own
+// pages. Browsers send remembered Basic auth with any request to the site,
+// so without this a form on another site could post as a moderator. They
+// say where a request came from in Sec-Fetch-Site, or in Origin when
+// older; a client sending neither isn't a browser another site can steer.
+pub fn same_site(req: &HttpRequest) -> bool {
+    if req.method().is_safe() {
+        return true;
+    }
+    if let Some(site) = req.headers().get("sec-fetch-site") {
+        return matches!(site.to_str(), Ok("same-origin" | "none"));
+    }
+    match req.headers().get(header::ORIGIN).map(|origin| origin.to_str()) {
+        None => true,
+        Some(Ok(origin)) => origin.split_once("://").is_some_and(|(_, host)| host.eq_ignore_ascii_case(req.connection_info().host())),
+        Some(Err(_)) => false,
+    }
+}
+
+enum Refusal {
+    // No credentials, or the wrong password
+    Unauthorized,
+    // The right password, on a request another site made the browser send
+    CrossSite,
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Admin, Refusal> {
+    let expected = req
+        .app_data::<Data<AppConfig>>()
+        .and_then(|config| config.admin_password.clone())
+        .ok_or(Refusal::Unauthorized)?;
+    let (user, presented, from_browser) = match presented_credentials(req).ok_or(Refusal::Unauthorized)? {
+        Credentials::Bearer(token) => (None, token, false),
+        Credentials::Basic { user, password } => (user, password, true),
+    };
+    if !constant_time_eq(expected.as_bytes(), presented.as_bytes()) {
+        return Err(Refusal::Unauthorized);
+    }
+    // Browsers never add a bearer token by themselves
+    if from_browser && !same_site(req) {
+        return Err(Refusal::CrossSite);
+    }
+    Ok(Admin { name: user.unwrap_or_else(|| "admin".to_string()) })
+}
+
+// The moderator making the request, if it is one
+pub fn admin(req: &HttpRequest) -> Option<Admin> {
+    authenticate(req).ok()
+}
+
+pub fn is_admin(req: &HttpRequest) -> bool {
+    admin(req).is_some()
+}
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let response = match authenticate(req) {
+            Ok(admin) => return ready(Ok(admin)),
+            Err(Refusal::Unauthorized) => HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"admin\""))
+                .body("Unauthorized"),
+            Err(Refusal::CrossSite) => HttpResponse::Forbidden().body("Moderator actions must be sent from this site's own pages."),
+        };
+        ready(Err(InternalError::from_response("Unauthorized", response).into()))
+    }
+}
@@ -0,0 +1,397 @@
+use actix_multipart::Multipart;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use futures_util::stream::StreamExt as _;
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::AppConfig;
+use crate::timefmt::parse_timestamp;
+use crate::blocklist::is_blocked;
+use crate::upload::{save_pending, store_upload, UploadError};
+use crate::filetypes::is_image;
+use crate::site::Site;
+use crate::storage::Storage;
+use crate::upload_url;
+
+#[derive(Serialize)]
+pub struct ApiPost {
+    pub id: i32,
+    pub post_id: String,
+    pub parent_id: i32,
+    pub title: String,
+    pub message: String,
+    pub file_url: Option<String>,
+    pub file_name: Option<String>,
+    pub file_alt: Option<String>,
+    pub created_at: String,
+    pub last_reply_at: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiThread {
+    pub op: ApiPost,
+    pub replies: Vec<ApiPost>,
+}
+
+// Most posts returned by one /api/changes call
+const MAX_CHANGES: usize = 500;
+
+pub const POST_COLUMNS: &str = "id, post_id, parent_id, title, message, file_path, created_at, last_reply_at, original_name, alt_text";
+
+// Row mapper for POST_COLUMNS
+pub fn post_from_row(config: &AppConfig) -> impl Fn(&Row) -> rusqlite::Result<ApiPost> + '_ {
+    move |row| Ok(ApiPost {
+        id: row.get(0)?,
+        post_id: row.get(1)?,
+        parent_id: row.get(2)?,
+        title: row.get(3)?,
+        message: row.get(4)?,
+        file_url: row.get::<_, Option<String>>(5)?.map(|path| upload_url(config, &path)),
+        created_at: row.get(6)?,
+        last_reply_at: row.get(7)?,
+        file_name: row.get(8)?,
+        file_alt: row.get(9)?,
+    })
+}
+
+pub fn load_thread(conn: &Connection, config: &AppConfig, thread_id: i32) -> Option<ApiThread> {
+    let op = conn.query_row(
+        &format!("SELECT {} FROM files WHERE id = ?1 AND parent_id = 0 AND hidden = 0", POST_COLUMNS),
+        params![thread_id],
+        post_from_row(config),
+    ).ok()?;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM files WHERE parent_id = ?1 AND hidden = 0 ORDER BY created_at ASC, id ASC", POST_COLUMNS)).unwrap();
+    let replies = stmt.query_map(params![thread_id], post_from_row(config)).unwrap()
+        .filter_map(|reply| reply.ok())
+        .collect();
+
+    Some(ApiThread { op, replies })
+}
+
+pub async fn api_posts(conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+    let offset = (page - 1) * config.posts_per_page;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM files WHERE parent_id = 0 AND archived = 0 AND hidden = 0 ORDER BY last_reply_at DESC LIMIT ?1 OFFSET ?2", POST_COLUMNS)).unwrap();
+    let posts: Vec<ApiPost> = stmt.query_map(params![config.posts_per_page as i64, offset as i64], post_from_row(&config)).unwrap()
+        .filter_map(|post| post.ok())
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "page": page,
+        "posts": posts,
+    })))
+}
+
+// Posts and replies created after `since` ("YYYY-MM-DD HH:MM:SS", UTC),
+// oldest first, for clients that sync incrementally. Pass `next_since` back
+// as `since` to continue; `more` says whether to call again right away.
+pub async fn api_changes(conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let Some(since) = query.get("since").filter(|since| parse_timestamp(since).is_some()) else {
+        return Ok(HttpResponse::BadRequest().json(json!({ "error": "since must be a \"YYYY-MM-DD HH:MM:SS\" timestamp" })));
+    };
+
+    let conn = conn.lock().unwrap();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM files WHERE hidden = 0 AND created_at > ?1 ORDER BY created_at ASC, id ASC LIMIT ?2", POST_COLUMNS)).unwrap();
+    let mut posts: Vec<ApiPost> = stmt.query_map(params![since, MAX_CHANGES as i64 + 1], post_from_row(&config)).unwrap()
+        .filter_map(|post| post.ok())
+        .collect();
+
+    let more = posts.len() > MAX_CHANGES;
+    if more {
+        posts.truncate(MAX_CHANGES);
+        // Timestamps only have second precision, so a cut in the middle of a
+        // second would lose the rest of it on the next call. Stop before
+        // that second instead, unless it is all we have.
+        let last = posts[posts.len() - 1].created_at.clone();
+        if posts[0].created_at != last {
+            posts.retain(|post| post.created_at != last);
+        }
+    }
+    let next_since = posts.last().map_or(since.clone(), |post| post.created_at.clone());
+
+    Ok(HttpResponse::Ok().json(json!({
+        "posts": posts,
+        "next_since": next_since,
+        "more": more,
+    })))
+}
+
+// Thread containing a post; an OP is its own thread
+pub async fn api_which_thread(conn: web::Data<Mutex<Connection>>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+
+    let thread_id = conn.query_row(
+        "SELECT CASE WHEN parent_id = 0 THEN id ELSE parent_id END FROM files WHERE id = ?1",
+        params![path.into_inner()],
+        |row| row.get::<_, i32>(0),
+    );
+
+    match thread_id {
+        Ok(thread_id) => Ok(HttpResponse::Ok().json(json!({ "parent_id": thread_id }))),
+        Err(_) => Ok(HttpResponse::NotFound().json(json!({ "error": "Post not found" }))),
+    }
+}
+
+// Stores a single file ahead of posting (drag and drop). The returned token
+// goes in the post form's `attachment_token` field and works once.
+pub async fn api_upload(mut payload: Multipart, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, storage: web::Data<dyn Storage>, site: web::Data<Site>) -> Result<HttpResponse> {
+    if site.read_only() {
+        return Ok(HttpResponse::ServiceUnavailable().json(json!({ "error": "Board is in maintenance, posting temporarily disabled" })));
+    }
+    if !config.uploads_enabled {
+        return Ok(HttpResponse::Forbidden().json(json!({ "error": "Uploads are disabled" })));
+    }
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let content_disposition = field.content_disposition().clone();
+        if content_disposition.get_name() != Some("file") {
+            continue;
+        }
+        let Some(filename) = content_disposition.get_filename().filter(|name| !name.is_empty()) else {
+            break;
+        };
+
+        let stored = match store_upload(&mut field, filename, &config, &storage, &conn).await {
+            Ok(stored) => stored,
+            Err(e) => return e.json_response(),
+        };
+        if stored.file_hash.as_deref().is_some_and(|hash| is_blocked(&conn.lock().unwrap(), hash)) {
+            stored.discard(storage.get_ref());
+            return UploadError::Blocked.json_response();
+        }
+        let size = storage.size(&stored.file_path).unwrap_or(0);
+        let thumbnail_url = if is_image(&config.file_types, &stored.file_path) {
+            Some(upload_url(&config, &stored.file_path))
+        } else {
+            stored.thumbnail_path.as_deref().map(|poster| upload_url(&config, poster))
+        };
+
+        let Some(token) = save_pending(&mut conn.lock().unwrap(), storage.get_ref(), &config, &stored).map_err(ErrorInternalServerError)? else {
+            stored.discard(storage.get_ref());
+            return UploadError::OverQuota.json_response();
+        };
+        return Ok(HttpResponse::Ok().json(json!({
+            "token": token,
+            "file_url": upload_url(&config, &stored.file_path),
+            "thumbnail_url": thumbnail_url,
+            "size": size,
+        })));
+    }
+
+    Ok(HttpResponse::BadRequest().json(json!({ "error": "No file was uploaded" })))
+}
+
+pub async fn api_thread(conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+
+    match load_thread(&conn, &config, path.into_inner()) {
+        Some(thread) => Ok(HttpResponse::Ok().json(thread)),
+        None => Ok(HttpResponse::NotFound().json(json!({ "error": "Thread not found" }))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    use crate::config::AppConfig;
+    use crate::jobs::{AppState, Job};
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+    use crate::upload::OrphanCleanupJob;
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    fn last_id(shared: &crate::Shared) -> i64 {
+        shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn which_thread_finds_a_replys_parent() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(crate::app(&shared)).await;
+
+        call_service(&app, form_post("/upload", multipart(&[("title", "op"), ("message", "thread"), ("parent_id", "0")], None), PEER).to_request()).await;
+        let thread_id = last_id(&shared);
+        call_service(&app, form_post("/upload", multipart(&[("title", "re"), ("message", "reply"), ("parent_id", &thread_id.to_string())], None), PEER).to_request()).await;
+        let reply_id = last_id(&shared);
+        assert_ne!(reply_id, thread_id);
+
+        for (post, expected) in [(reply_id, thread_id), (thread_id, thread_id)] {
+            let response = call_service(&app, TestRequest::get().uri(&format!("/api/which-thread/{}", post)).to_request()).await;
+            assert_eq!(response.status(), 200);
+            let body: Value = read_body_json(response).await;
+            assert_eq!(body["parent_id"], expected);
+        }
+
+        let response = call_service(&app, TestRequest::get().uri("/api/which-thread/999").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn a_sent_ahead_file_is_attached_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, form_post("/api/upload", multipart(&[], Some(("drop.png", "image/png", &png(10)))), PEER).to_request()).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = read_body_json(response).await;
+        let token = body["token"].as_str().unwrap().to_string();
+        let file_url = body["file_url"].as_str().unwrap().to_string();
+        assert!(body["thumbnail_url"].is_string());
+        assert!(body["size"].as_u64().unwrap() > 0);
+
+        let form = || multipart(&[("title", "dropped"), ("message", "with a file"), ("parent_id", "0"), ("attachment_token", &token)], None);
+        let response = call_service(&app, form_post("/upload", form(), PEER).to_request()).await;
+        assert_eq!(response.status(), 303);
+        let post_id = last_id(&shared);
+        let file_path: String = shared.conn.lock().unwrap()
+            .query_row("SELECT file_path FROM files WHERE id = ?1", [post_id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(crate::upload_url(&shared.config, &file_path), file_url);
+
+        // The token was used up by the first post
+        let response = call_service(&app, form_post("/upload", form(), PEER).to_request()).await;
+        assert_eq!(response.status(), 400);
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 1);
+    }
+
+    #[actix_web::test]
+    async fn unclaimed_uploads_are_cleaned_up_after_an_hour() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        for shade in [20, 30] {
+            let response = call_service(&app, form_post("/api/upload", multipart(&[], Some(("drop.png", "image/png", &png(shade)))), PEER).to_request()).await;
+            assert_eq!(response.status(), 200);
+        }
+        let conn = shared.conn.lock().unwrap();
+        let old: String = conn.query_row("SELECT file_path FROM pending_uploads ORDER BY rowid LIMIT 1", [], |row| row.get(0)).unwrap();
+        conn.execute("UPDATE pending_uploads SET created_at = datetime('now', '-61 minutes') WHERE file_path = ?1", [&old]).unwrap();
+        drop(conn);
+
+        let state = AppState { conn: shared.conn.clone(), config: shared.config.clone(), storage: shared.storage.clone() };
+        OrphanCleanupJob.run(&state).unwrap();
+
+        let conn = shared.conn.lock().unwrap();
+        let left: Vec<String> = conn.prepare("SELECT file_path FROM pending_uploads").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(left.len(), 1);
+        assert_ne!(left[0], old);
+        assert!(!std::path::Path::new(&old).exists());
+        assert!(std::path::Path::new(&left[0]).exists());
+    }
+
+    fn insert_at(conn: &rusqlite::Connection, parent_id: i64, title: &str, created_at: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, created_at) VALUES (?1, ?2, ?1, 'body', ?3)",
+            rusqlite::params![title, parent_id, created_at],
+        ).unwrap();
+        conn.last_insert_rowid()
+    }
+
+    #[actix_web::test]
+    async fn changes_returns_only_newer_posts_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        {
+            let conn = shared.conn.lock().unwrap();
+            let thread = insert_at(&conn, 0, "old-thread", "2024-01-01 10:00:00");
+            insert_at(&conn, thread, "new-reply", "2024-01-01 12:00:00");
+            insert_at(&conn, 0, "new-thread", "2024-01-01 11:00:00");
+        }
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/api/changes?since=2024-01-01%2010:30:00").to_request()).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = read_body_json(response).await;
+        let titles: Vec<&str> = body["posts"].as_array().unwrap().iter().map(|post| post["title"].as_str().unwrap()).collect();
+        assert_eq!(titles, ["new-thread", "new-reply"]);
+        assert_eq!(body["next_since"], "2024-01-01 12:00:00");
+        assert_eq!(body["more"], false);
+
+        let response = call_service(&app, TestRequest::get().uri("/api/changes?since=yesterday").to_request()).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn changes_are_capped_without_splitting_a_second() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        {
+            let conn = shared.conn.lock().unwrap();
+            for second in ["2024-01-01 13:00:00", "2024-01-01 13:00:01"] {
+                for _ in 0..300 {
+                    insert_at(&conn, 0, "bulk", second);
+                }
+            }
+        }
+        let app = init_service(crate::app(&shared)).await;
+
+        let body: Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/changes?since=2024-01-01%2000:00:00").to_request()).await).await;
+        assert_eq!(body["posts"].as_array().unwrap().len(), 300);
+        assert_eq!(body["next_since"], "2024-01-01 13:00:00");
+        assert_eq!(body["more"], true);
+
+        let body: Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/changes?since=2024-01-01%2013:00:00").to_request()).await).await;
+        assert_eq!(body["posts"].as_array().unwrap().len(), 300);
+        assert_eq!(body["next_since"], "2024-01-01 13:00:01");
+        assert_eq!(body["more"], false);
+    }
+
+    #[actix_web::test]
+    async fn dropped_files_come_back_as_urls_after_the_usual_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { max_upload_bytes: 4096, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let drop = |file: Option<(&str, &str, &[u8])>| form_post("/api/upload", multipart(&[("alt", "a cat")], file), PEER).to_request();
+
+        let image = png(40);
+        let response = call_service(&app, drop(Some(("cat.png", "image/png", &image)))).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = read_body_json(response).await;
+        let file_url = body["file_url"].as_str().unwrap().to_string();
+        assert!(file_url.starts_with("/file/") && file_url.ends_with(".png"), "{}", file_url);
+        assert_eq!(body["thumbnail_url"], file_url.as_str());
+        assert_eq!(body["size"], image.len());
+        let served = call_and_read_body(&app, TestRequest::get().uri(&file_url).to_request()).await;
+        assert_eq!(served.to_vec(), image);
+
+        let mut truncated = png(50);
+        truncated.truncate(truncated.len() / 2);
+        for (file, status, error) in [
+            (Some(("notes.png", "image/png", &b"just some text"[..])), 400, "File contents do not match its type"),
+            (Some(("tool.exe", "application/octet-stream", &b"MZ\x90\x00"[..])), 400, "Unsupported file type"),
+            (Some(("big.png", "image/png", &[png(60), vec![0; 5000]].concat()[..])), 400, "File is too large"),
+            (Some(("cut.png", "image/png", &truncated[..])), 422, "Image is corrupt or truncated"),
+            (None, 400, "No file was uploaded"),
+        ] {
+            let response = call_service(&app, drop(file)).await;
+            assert_eq!(response.status(), status, "{}", error);
+            let body: Value = read_body_json(response).await;
+            assert_eq!(body["error"], error);
+        }
+        // Only the accepted file was kept
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 1);
+        let pending: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM pending_uploads", [], |row| row.get(0)).unwrap();
+        assert_eq!(pending, 1);
+
+        // The post forms send files here from their drop zone
+        let board = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(board.contains(r#"<script src="/static/upload.js" defer></script>"#));
+        assert!(board.contains(r#"<div class="drop-zone" data-hint="or drop a file here""#));
+        assert!(board.contains(r#"<input type="hidden" name="attachment_token">"#));
+    }
+}
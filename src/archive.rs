@@ -0,0 +1,186 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::escape_html;
+use crate::jobs::{AppState, Job};
+use crate::locale::translator;
+use crate::render_page;
+use crate::timefmt::absolute_timestamp;
+use crate::timezone::format_ctx;
+use crate::site::Site;
+
+// Threads archived per UPDATE, so a large backlog never holds the write lock for long
+const ARCHIVE_BATCH_SIZE: i64 = 200;
+
+// Archives threads whose last bump is older than `archive_after_days`.
+// Already archived threads are left alone, so reruns are harmless.
+pub struct ArchiveJob;
+
+impl Job for ArchiveJob {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn first_delay(&self, _state: &AppState) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        let days = state.config.archive_after_days;
+        if days == 0 {
+            return Ok(());
+        }
+
+        let archived = archive_inactive(&state.conn, days).map_err(|e| e.to_string())?;
+        println!("Archived {} inactive threads", archived);
+        Ok(())
+    }
+}
+
+// Archives threads last bumped more than `days` days ago, in batches, and
+// returns how many it archived
+pub fn archive_inactive(conn: &Mutex<Connection>, days: u32) -> rusqlite::Result<usize> {
+    let mut archived = 0;
+    loop {
+        let changed = conn.lock().unwrap().execute(
+            "UPDATE files SET archived = 1 WHERE id IN (
+                SELECT id FROM files
+                WHERE parent_id = 0 AND archived = 0 AND last_reply_at < datetime('now', ?1)
+                LIMIT ?2
+            )",
+            params![format!("-{} days", days), ARCHIVE_BATCH_SIZE],
+        )?;
+
+        archived += changed;
+        if (changed as i64) < ARCHIVE_BATCH_SIZE {
+            return Ok(archived);
+        }
+    }
+}
+
+// None when there is no thread with this id
+pub fn thread_archived(conn: &Connection, thread_id: i32) -> Option<bool> {
+    conn.query_row(
+        "SELECT archived FROM files WHERE id = ?1 AND parent_id = 0",
+        params![thread_id],
+        |row| row.get(0),
+    ).ok()
+}
+
+pub async fn archive(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, site: web::Data<Site>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+    let offset = (page - 1) * config.posts_per_page;
+
+    let mut stmt = conn.prepare("SELECT id, title, last_reply_at FROM files WHERE parent_id = 0 AND archived = 1 AND hidden = 0 ORDER BY last_reply_at DESC LIMIT ?1 OFFSET ?2").unwrap();
+    let threads = stmt.query_map(params![config.posts_per_page as i64, offset as i64], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    }).unwrap();
+
+    let ctx = format_ctx(&req);
+    let mut threads_html = String::new();
+    let mut thread_count = 0;
+
+    for thread in threads {
+        let (id, title, last_reply_at) = thread.unwrap();
+        threads_html.push_str(&format!(
+            r#"<tr><td><a href="/post/{}">{}</a></td><td>{}</td></tr>"#,
+            id, escape_html(&title), absolute_timestamp(&ctx, &last_reply_at)
+        ));
+        thread_count += 1;
+    }
+
+    let tr = translator(&req);
+    let mut pagination_html = String::new();
+    if page > 1 {
+        pagination_html.push_str(&format!(r#"<a href="/archive?page={}">{}</a>"#, page - 1, tr.t("page.previous")));
+    }
+    if thread_count == config.posts_per_page {
+        pagination_html.push_str(&format!(r#"<a href="/archive?page={}">{}</a>"#, page + 1, tr.t("page.next")));
+    }
+
+    let mut context = HashMap::from([
+        ("THREADS", threads_html),
+        ("PAGINATION", pagination_html),
+    ]);
+    context.extend(site.chrome());
+
+    let body = render_page("templates/archive.html", &context, &tr);
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::web::Data;
+
+    use crate::config::AppConfig;
+    use crate::storage::open_storage;
+    use crate::testing::{form_post, multipart, shared, test_db};
+
+    fn seed_thread(conn: &Connection, title: &str, bumped: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at) VALUES (?1, 0, ?1, 'body', datetime('now', ?2), datetime('now', ?2))",
+            params![title, bumped],
+        ).unwrap();
+        conn.last_insert_rowid()
+    }
+
+    fn archived_count(conn: &Mutex<Connection>) -> i64 {
+        conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files WHERE archived = 1", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn archives_old_threads_in_batches_once() {
+        let conn = test_db();
+        let backlog = 2 * ARCHIVE_BATCH_SIZE + 50;
+        for n in 0..backlog {
+            seed_thread(&conn, &format!("old {}", n), "-40 days");
+        }
+        let recent = seed_thread(&conn, "recent", "-2 days");
+        let config = AppConfig { archive_after_days: 30, ..AppConfig::default() };
+        let state = AppState {
+            conn: Data::new(Mutex::new(conn)),
+            storage: Data::from(open_storage(&config)),
+            config: Data::new(config),
+        };
+
+        ArchiveJob.run(&state).unwrap();
+        assert_eq!(archived_count(&state.conn), backlog);
+        ArchiveJob.run(&state).unwrap();
+        assert_eq!(archived_count(&state.conn), backlog);
+        assert_eq!(thread_archived(&state.conn.lock().unwrap(), recent as i32), Some(false));
+    }
+
+    #[actix_web::test]
+    async fn archived_threads_are_listed_and_read_only() {
+        let shared = shared(AppConfig { archive_after_days: 30, ..AppConfig::default() });
+        let thread_id = seed_thread(&shared.conn.lock().unwrap(), "gone quiet", "-40 days");
+        let state = AppState { conn: shared.conn.clone(), config: shared.config.clone(), storage: shared.storage.clone() };
+        ArchiveJob.run(&state).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/archive").to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains(&format!(r#"<a href="/post/{}">gone quiet</a>"#, thread_id)));
+
+        let reply = multipart(&[("title", "late"), ("message", "anyone?"), ("parent_id", &thread_id.to_string())], None);
+        let response = call_service(&app, form_post("/upload", reply, "127.0.0.1:40000").to_request()).await;
+        assert_eq!(response.status(), 403);
+        let replies: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files WHERE parent_id = ?1", [thread_id], |row| row.get(0)).unwrap();
+        assert_eq!(replies, 0);
+    }
+}
@@ -0,0 +1,180 @@
+use rusqlite::Row;
+
+use crate::config::AppConfig;
+use crate::locale::Tr;
+use crate::filetypes::{extension_of, file_kind, FileKind, FileType};
+use crate::reencode::format_size;
+use crate::{escape_html, reencode, upload_name, upload_url};
+
+// Shown for videos we couldn't grab a frame from
+const VIDEO_PLACEHOLDER: &str = "/static/video-placeholder.svg";
+// Shown in place of documents
+const DOCUMENT_ICON: &str = "/static/pdf-icon.svg";
+
+// Columns read by `attachment_from_row`, in order
+pub const ATTACHMENT_COLUMNS: &str = "file_path, original_name, original_format, original_size, thumbnail_path, width, height, alt_text, page_count";
+
+// Longest alt text accepted with an upload, in characters
+pub const MAX_ALT_LENGTH: usize = 250;
+
+pub struct Attachment {
+    pub file_path: String,
+    pub original_name: Option<String>,
+    // Format and size of the upload when we re-encoded it
+    pub original_format: Option<String>,
+    pub original_size: Option<i64>,
+    // Poster frame for videos
+    pub thumbnail_path: Option<String>,
+    // Of the displayed image; None or 0 when unknown
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // Poster-supplied description for screen readers
+    pub alt_text: Option<String>,
+    // Of a PDF, when known
+    pub page_count: Option<i64>,
+}
+
+// Reads ATTACHMENT_COLUMNS starting at column `start`; None when the post
+// has no file.
+pub fn attachment_from_row(row: &Row, start: usize) -> rusqlite::Result<Option<Attachment>> {
+    let Some(file_path) = row.get::<_, Option<String>>(start)? else {
+        return Ok(None);
+    };
+    Ok(Some(Attachment {
+        file_path,
+        original_name: row.get(start + 1)?,
+        original_format: row.get(start + 2)?,
+        original_size: row.get(start + 3)?,
+        thumbnail_path: row.get(start + 4)?,
+        width: row.get(start + 5)?,
+        height: row.get(start + 6)?,
+        alt_text: row.get(start + 7)?,
+        page_count: row.get(start + 8)?,
+    }))
+}
+
+// Escaped value for an image's alt attribute
+pub fn alt_attr(alt_text: Option<&str>, tr: &Tr) -> String {
+    match alt_text {
+        Some(alt) => escape_html(alt),
+        None => tr.t("attachment.alt_fallback"),
+    }
+}
+
+// width/height attributes so the page doesn't shift as images load
+fn size_attrs(attachment: &Attachment) -> String {
+    match (attachment.width, attachment.height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => format!(r#" width="{}" height="{}""#, width, height),
+        _ => String::new(),
+    }
+}
+
+// "(1.2 MB, 14 pages)" after a document's name; the page count is left
+// out when it couldn't be read from the file
+fn document_details(attachment: &Attachment, types: &[FileType], tr: &Tr) -> String {
+    if file_kind(types, &attachment.file_path) != FileKind::Document {
+        return String::new();
+    }
+    let mut details: Vec<String> = std::fs::metadata(&attachment.file_path)
+        .map(|meta| format_size(meta.len() as i64))
+        .into_iter()
+        .collect();
+    details.extend(attachment.page_count.map(|pages| tr.tn("attachment.pages", pages)));
+    if details.is_empty() {
+        return String::new();
+    }
+    format!(r#" <span class="file-details">({})</span>"#, details.join(", "))
+}
+
+// Renders an attachment with its caption, shown according to its entry in
+// `types`. With `thread_link` set (the board index), videos show as a
+// poster frame linking to the thread instead of an inline player.
+pub fn render_attachment(attachment: &Attachment, config: &AppConfig, thread_link: Option<i32>, tr: &Tr) -> String {
+    let types = &config.file_types;
+    let file_path = attachment.file_path.as_str();
+    let url = upload_url(config, file_path);
+    let name = upload_name(file_path);
+    let size = size_attrs(attachment);
+    let alt = alt_attr(attachment.alt_text.as_deref(), tr);
+    let media = match file_kind(types, file_path) {
+        FileKind::Image => format!(r#"<img src="{}" alt="{}" loading="lazy"{}>"#, url, alt, size),
+        FileKind::Video => {
+            let poster = attachment.thumbnail_path.as_deref().map(|poster| upload_url(config, poster));
+            match thread_link {
+                Some(thread_id) => format!(
+                    r#"<a class="video-thumb" href="/post/{}"><img src="{}" alt="{}" loading="lazy"{}><span class="play-icon">&#9654;</span></a>"#,
+                    thread_id, poster.as_deref().unwrap_or(VIDEO_PLACEHOLDER), alt, size
+                ),
+                None => {
+                    let poster = poster.map(|poster| format!(r#" poster="{}""#, poster)).unwrap_or_default();
+                    format!(r#"<video controls aria-label="{}"{}{}><source src="{}"></video>"#, alt, poster, size, url)
+                },
+            }
+        },
+        FileKind::Audio => format!(r#"<audio controls preload="none" aria-label="{}" src="{}"></audio>"#, alt, url),
+        // Never embedded; the link opens the file in the browser's viewer
+        FileKind::Document => format!(
+            r#"<a class="document-icon" href="{}"><img src="{}" alt="{}" width="40" height="48"></a>"#,
+            url, DOCUMENT_ICON, extension_of(file_path).to_ascii_uppercase()
+        ),
+        // Just the caption and its download link
+        FileKind::Download => String::new(),
+    };
+    let note = attachment.original_format.as_deref().zip(attachment.original_size)
+        .map(|(format, size)| {
+            let original = escape_html(&reencode::conversion_note(format, size));
+            format!(r#" <span class="conversion-note">({})</span>"#, tr.tf("attachment.converted_from", &[("original", &original)]))
+        })
+        .unwrap_or_default();
+    format!(
+        r#"{}<div class="attachment-caption"><a href="{}">{}</a>{}{} <a class="download-link" href="{}?download=1">{}</a></div>"#,
+        media, url, escape_html(attachment.original_name.as_deref().unwrap_or(name)), note, document_details(attachment, types, tr), url, tr.t("attachment.download")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    #[actix_web::test]
+    async fn alt_text_is_stored_escaped_and_exported() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        let alt = r#"A "cat" <on> a mat"#;
+
+        let op = multipart(&[("title", "described"), ("message", "pic"), ("parent_id", "0"), ("alt", alt)], Some(("cat.png", "image/png", &png(1))));
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        let parent = thread_id.to_string();
+        let undescribed = multipart(&[("title", "plain"), ("message", "pic"), ("parent_id", &parent)], Some(("dog.png", "image/png", &png(2))));
+        assert_eq!(call_service(&app, form_post("/upload", undescribed, PEER).to_request()).await.status(), 303);
+        // Without a file there's nothing to describe
+        let no_file = multipart(&[("title", "words"), ("message", "only text"), ("parent_id", &parent), ("alt", "ignored")], None);
+        assert_eq!(call_service(&app, form_post("/upload", no_file, PEER).to_request()).await.status(), 303);
+        let too_long = "x".repeat(MAX_ALT_LENGTH + 1);
+        let long = multipart(&[("title", "long"), ("message", "pic"), ("parent_id", &parent), ("alt", &too_long)], Some(("pig.png", "image/png", &png(3))));
+        assert_eq!(call_service(&app, form_post("/upload", long, PEER).to_request()).await.status(), 422);
+
+        let escaped = r#"alt="A &quot;cat&quot; &lt;on&gt; a mat""#;
+        let thread = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", thread_id)).to_request()).await.to_vec()).unwrap();
+        assert!(thread.contains(escaped));
+        assert_eq!(thread.matches(r#"alt="attachment""#).count(), 1);
+        let index = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(index.contains(escaped));
+        let stored: Option<String> = shared.conn.lock().unwrap().query_row("SELECT alt_text FROM files WHERE title = 'words'", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, None);
+
+        let api: Value = read_body_json(call_service(&app, TestRequest::get().uri(&format!("/api/thread/{}", thread_id)).to_request()).await).await;
+        assert_eq!(api["op"]["file_alt"], alt);
+        assert_eq!(api["replies"][0]["file_alt"], Value::Null);
+        let text = call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}/export?format=txt", thread_id)).to_request()).await;
+        assert!(String::from_utf8(text.to_vec()).unwrap().contains(&format!("Alt text: {}\n", alt)));
+    }
+}
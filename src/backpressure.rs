@@ -0,0 +1,145 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::locale::translator;
+use crate::render_notice;
+use crate::site::site;
+
+// Seconds a client is asked to wait when every slot is taken
+const BUSY_RETRY_AFTER: u64 = 2;
+
+// Caps how many requests may be working against the database at once.
+// Everything shares one SQLite connection, so past a point extra handlers
+// only queue on the lock; turning them away early keeps latency bounded.
+pub struct DbLimiter {
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl DbLimiter {
+    // A limit of 0 disables the cap
+    pub fn new(limit: usize) -> Self {
+        DbLimiter {
+            slots: (limit > 0).then(|| Arc::new(Semaphore::new(limit))),
+        }
+    }
+}
+
+// Static assets never touch the database, and long-polls spend nearly all
+// their time waiting rather than querying
+fn is_exempt(req: &ServiceRequest) -> bool {
+    req.path().starts_with("/static/") || req.path().ends_with("/wait")
+}
+
+fn busy_response(req: &ServiceRequest) -> HttpResponse {
+    if req.path().starts_with("/api/") {
+        HttpResponse::ServiceUnavailable()
+            .append_header((header::RETRY_AFTER, BUSY_RETRY_AFTER.to_string()))
+            .json(json!({
+                "error": "Server busy",
+                "retry_after": BUSY_RETRY_AFTER,
+            }))
+    } else {
+        busy_page(req.request())
+    }
+}
+
+// Also shown when a post can't get the database's write lock in time
+pub fn busy_page(req: &HttpRequest) -> HttpResponse {
+    let tr = translator(req);
+    let body = render_notice(&site(req), &tr, &tr.t("error.busy_title"), &tr.t("error.busy"));
+    HttpResponse::ServiceUnavailable()
+        .append_header((header::RETRY_AFTER, BUSY_RETRY_AFTER.to_string()))
+        .content_type("text/html")
+        .body(body)
+}
+
+pub async fn db_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let slots = req
+        .app_data::<Data<DbLimiter>>()
+        .and_then(|limiter| limiter.slots.clone())
+        .filter(|_| !is_exempt(&req));
+
+    // Held until the handler has finished with the request
+    let _permit = match slots {
+        Some(slots) => match slots.try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                let response = busy_response(&req);
+                return Ok(req.into_response(response).map_into_right_body());
+            },
+        },
+        None => None,
+    };
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use futures_util::future::join;
+    use tokio::sync::Notify;
+
+    #[actix_web::test]
+    async fn requests_past_the_limit_get_503() {
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(DbLimiter::new(1)))
+                .wrap(from_fn(db_limit))
+                .route("/api/slow", web::get().to(move || {
+                    let held = held.clone();
+                    async move {
+                        held.notified().await;
+                        HttpResponse::Ok().finish()
+                    }
+                }))
+                .route("/api/fast", web::get().to(HttpResponse::Ok))
+                .route("/static/styles.css", web::get().to(HttpResponse::Ok))
+                .route("/api/reply/1/wait", web::get().to(HttpResponse::Ok)),
+        ).await;
+
+        // The slow request takes the only slot first and holds it
+        let slow = call_service(&app, TestRequest::get().uri("/api/slow").to_request());
+        let others = async {
+            actix_web::rt::task::yield_now().await;
+            let busy = call_service(&app, TestRequest::get().uri("/api/fast").to_request()).await;
+            let exempt = call_service(&app, TestRequest::get().uri("/static/styles.css").to_request()).await;
+            let poll = call_service(&app, TestRequest::get().uri("/api/reply/1/wait").to_request()).await;
+            release.notify_one();
+            (busy, exempt, poll)
+        };
+        let (slow, (busy, exempt, poll)) = join(slow, others).await;
+
+        assert_eq!(slow.status(), 200);
+        assert_eq!(exempt.status(), 200);
+        // A long-poll holds its request open, so it mustn't take a slot
+        assert_eq!(poll.status(), 200);
+        assert_eq!(busy.status(), 503);
+        assert_eq!(busy.headers().get(header::RETRY_AFTER).unwrap(), "2");
+        let body: serde_json::Value = read_body_json(busy).await;
+        assert_eq!(body["retry_after"], BUSY_RETRY_AFTER);
+
+        // The slot is free again once the slow request is done
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/fast").to_request()).await.status(), 200);
+    }
+
+    #[test]
+    fn zero_disables_the_cap() {
+        assert!(DbLimiter::new(0).slots.is_none());
+    }
+}
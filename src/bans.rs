@@ -0,0 +1,165 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+use crate::admin::Admin;
+use crate::client_ip::ClientIp;
+use crate::moderation::log_mod_action;
+
+pub enum BanStatus {
+    None,
+    Banned,
+    // Posts are accepted but only the poster sees them
+    Shadow,
+}
+
+// Per-install salt so stored IP hashes can't be reversed by hashing every
+// IPv4 address. Created on first use.
+fn ip_salt(conn: &Connection) -> rusqlite::Result<String> {
+    let read = || conn.query_row("SELECT value FROM settings WHERE key = 'ip_salt'", [], |row| row.get(0));
+    if let Some(salt) = read().optional()? {
+        return Ok(salt);
+    }
+    let salt = uuid::Uuid::new_v4().simple().to_string();
+    conn.execute("INSERT OR IGNORE INTO settings (key, value) VALUES ('ip_salt', ?1)", params![salt])?;
+    read()
+}
+
+// Salted hash of the client's IP; raw addresses are never stored
+pub fn ip_hash(conn: &Connection, req: &HttpRequest) -> rusqlite::Result<String> {
+    let ip = ClientIp::of(req).text();
+    Ok(Sha256::digest(format!("{}{}", ip_salt(conn)?, ip).as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+pub fn ban_status(conn: &Connection, ip_hash: &str) -> rusqlite::Result<BanStatus> {
+    let shadow: Option<bool> = conn.query_row("SELECT shadow FROM bans WHERE ip_hash = ?1", params![ip_hash], |row| row.get(0))
+        .optional()?;
+    Ok(match shadow {
+        None => BanStatus::None,
+        Some(false) => BanStatus::Banned,
+        Some(true) => BanStatus::Shadow,
+    })
+}
+
+// SQL condition for posts the viewer whose ip hash is bound to `?N` may
+// see: everything not hidden, plus their own hidden posts.
+pub fn visible_sql(param: usize) -> String {
+    format!("(hidden = 0 OR ip_hash = ?{})", param)
+}
+
+#[derive(Deserialize)]
+pub struct BanForm {
+    #[serde(default)]
+    shadow: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+// Bans whoever made post `id`. A shadow ban lets them keep posting, but
+// nobody else sees those posts and they never bump threads.
+pub async fn ban_poster(admin: Admin, conn: web::Data<Mutex<Connection>>, path: web::Path<i32>, form: web::Form<BanForm>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let post_id = path.into_inner();
+
+    let ip_hash: Option<String> = conn.query_row("SELECT ip_hash FROM files WHERE id = ?1", params![post_id], |row| row.get(0))
+        .optional()
+        .map_err(ErrorInternalServerError)?
+        .flatten();
+    let Some(ip_hash) = ip_hash else {
+        return Ok(HttpResponse::NotFound().body("No poster is recorded for that post."));
+    };
+
+    conn.execute(
+        "INSERT INTO bans (ip_hash, shadow, reason, created_at) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP)
+         ON CONFLICT (ip_hash) DO UPDATE SET shadow = excluded.shadow, reason = excluded.reason",
+        params![ip_hash, form.shadow, form.reason],
+    ).map_err(ErrorInternalServerError)?;
+    let kind = if form.shadow { "shadow-ban" } else { "ban" };
+    log_mod_action(&conn, Some(&admin.name), kind, Some(post_id), &format!("poster of post {}: {}", post_id, form.reason)).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().body(format!("Poster of post {} is now under a {}.", post_id, kind)))
+}
+
+pub async fn unban_poster(admin: Admin, conn: web::Data<Mutex<Connection>>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let post_id = path.into_inner();
+
+    let removed = conn.execute(
+        "DELETE FROM bans WHERE ip_hash = (SELECT ip_hash FROM files WHERE id = ?1)",
+        params![post_id],
+    ).map_err(ErrorInternalServerError)?;
+    if removed == 0 {
+        return Ok(HttpResponse::NotFound().body("That poster is not banned."));
+    }
+    log_mod_action(&conn, Some(&admin.name), "unban", Some(post_id), &format!("poster of post {}", post_id)).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().body(format!("Poster of post {} is no longer banned.", post_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const REGULAR: &str = "10.0.0.1:40000";
+    const SPAMMER: &str = "10.0.0.2:40000";
+
+    fn page_for(uri: &str, peer: &str) -> TestRequest {
+        TestRequest::get().uri(uri).peer_addr(peer.parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn shadow_banned_posts_only_show_to_their_poster() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let last_id = || -> i32 { shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap() };
+
+        let op = multipart(&[("title", "regular"), ("message", "hello"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, REGULAR).to_request()).await.status(), 303);
+        let thread_id = last_id();
+        let reply = |message: &str| multipart(&[("title", "re"), ("message", message), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply("buy pills"), SPAMMER).to_request()).await.status(), 303);
+        let first_spam = last_id();
+
+        let ban = TestRequest::post()
+            .uri(&format!("/admin/ban/{}", first_spam))
+            .insert_header((header::AUTHORIZATION, "Bearer letmein"))
+            .set_form([("shadow", "true"), ("reason", "spam")]);
+        assert_eq!(call_service(&app, ban.to_request()).await.status(), 200);
+
+        shared.conn.lock().unwrap().execute("UPDATE files SET last_reply_at = datetime('now', '-1 days') WHERE id = ?1", [thread_id]).unwrap();
+        let bumped = || -> String { shared.conn.lock().unwrap().query_row("SELECT last_reply_at FROM files WHERE id = ?1", [thread_id], |row| row.get(0)).unwrap() };
+        let before = bumped();
+        // Shadow-banned posts go through as if nothing happened
+        assert_eq!(call_service(&app, form_post("/upload", reply("more pills"), SPAMMER).to_request()).await.status(), 303);
+        let spam_thread = multipart(&[("title", "cheap pills"), ("message", "buy now"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", spam_thread, SPAMMER).to_request()).await.status(), 303);
+        assert_eq!(bumped(), before);
+
+        let thread = format!("/post/{}", thread_id);
+        let regular = String::from_utf8(call_and_read_body(&app, page_for(&thread, REGULAR).to_request()).await.to_vec()).unwrap();
+        assert!(regular.contains("buy pills"));
+        assert!(!regular.contains("more pills"));
+        let own = String::from_utf8(call_and_read_body(&app, page_for(&thread, SPAMMER).to_request()).await.to_vec()).unwrap();
+        assert!(own.contains("more pills"));
+
+        let index = String::from_utf8(call_and_read_body(&app, page_for("/", REGULAR).to_request()).await.to_vec()).unwrap();
+        assert!(!index.contains("cheap pills"));
+        let index = String::from_utf8(call_and_read_body(&app, page_for("/", SPAMMER).to_request()).await.to_vec()).unwrap();
+        assert!(index.contains("cheap pills"));
+
+        // The hidden reply isn't counted either
+        let replies: i32 = shared.conn.lock().unwrap().query_row("SELECT reply_count FROM files WHERE id = ?1", [thread_id], |row| row.get(0)).unwrap();
+        assert_eq!(replies, 1);
+    }
+}
@@ -0,0 +1,298 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::admin::Admin;
+use crate::config::AppConfig;
+use crate::feed::FeedCache;
+use crate::jobs::{AppState, Job};
+use crate::moderation::{log_mod_action, recount_replies};
+use crate::quota::{delete_files, files_of};
+use crate::storage::Storage;
+
+// SHA-256 (hex) of an upload as received, before any re-encoding, so a
+// block still catches the file after it has been converted to WebP
+pub fn file_hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn is_blocked(conn: &Connection, hash: &str) -> bool {
+    conn.query_row("SELECT 1 FROM blocked_hashes WHERE sha256 = ?1", params![hash], |_| Ok(()))
+        .optional()
+        .unwrap()
+        .is_some()
+}
+
+// Posts hashed per run of the backfill job
+const BACKFILL_BATCH: i64 = 200;
+
+// Hash of a stored file, or an empty one for a file that can't be read
+// (gone from storage, or a remote image) so it isn't tried again. Posts
+// from before hashes were recorded get theirs this way; for a re-encoded
+// upload that is the hash of the converted file, so those only match
+// blocks made from the same post.
+fn stored_hash(storage: &dyn Storage, file_path: &str) -> String {
+    storage.get(file_path).map(|data| file_hash(&data)).unwrap_or_default()
+}
+
+// Gives older posts the hash of their file, a batch at a time, reading
+// the files without holding the connection. Copies of a file that was
+// blocked before they were hashed are taken down as they turn up.
+pub struct HashBackfillJob;
+
+impl Job for HashBackfillJob {
+    fn name(&self) -> &'static str {
+        "hash-backfill"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn first_delay(&self, _state: &AppState) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        let rows: Vec<(i32, String)> = {
+            let conn = state.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT id, file_path FROM files WHERE file_path IS NOT NULL AND file_hash IS NULL LIMIT ?1")
+                .map_err(|e| e.to_string())?;
+            let rows = stmt.query_map(params![BACKFILL_BATCH], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(|e| e.to_string())?
+                .filter_map(|row| row.ok())
+                .collect();
+            rows
+        };
+        let hashed: Vec<(i32, String)> = rows.iter()
+            .map(|(id, file_path)| (*id, stored_hash(&**state.storage, file_path)))
+            .collect();
+
+        let conn = state.conn.lock().unwrap();
+        let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        let mut paths = Vec::new();
+        for (id, hash) in &hashed {
+            // Still unhashed: the file may have changed since it was read
+            tx.execute("UPDATE files SET file_hash = ?2 WHERE id = ?1 AND file_hash IS NULL", params![id, hash])
+                .map_err(|e| e.to_string())?;
+            if !hash.is_empty() && is_blocked(&tx, hash) {
+                let (removed, removed_paths) = take_down(&tx, &state.config, hash).map_err(|e| e.to_string())?;
+                log_mod_action(&tx, None, "block-file", Some(*id), &format!("{} found on post {} by the hash backfill; {} posts", hash, id, removed))
+                    .map_err(|e| e.to_string())?;
+                paths.extend(removed_paths);
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        delete_files(&conn, &**state.storage, &paths);
+
+        println!("Recorded file hashes for {} posts", hashed.len());
+        Ok(())
+    }
+}
+
+// Takes the file off every post that carries it; the posts stay
+fn strip_posts(conn: &Connection, hash: &str) -> rusqlite::Result<(usize, Vec<String>)> {
+    let paths = files_of(conn, "SELECT file_path, thumbnail_path FROM files WHERE file_hash = ?1", params![hash])?;
+    let stripped = conn.execute(
+        "UPDATE files SET file_path = NULL, original_name = NULL, original_format = NULL, original_size = NULL,
+            thumbnail_path = NULL, width = NULL, height = NULL, alt_text = NULL, file_hash = NULL
+         WHERE file_hash = ?1",
+        params![hash],
+    )?;
+    Ok((stripped, paths))
+}
+
+// Deletes every post that carries the file. An OP takes its whole thread
+// with it.
+fn delete_posts(conn: &Connection, hash: &str) -> rusqlite::Result<(usize, Vec<String>)> {
+    let mut stmt = conn.prepare("SELECT id, parent_id FROM files WHERE file_hash = ?1")?;
+    let posts: Vec<(i32, i32)> = stmt.query_map(params![hash], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut paths = Vec::new();
+    let mut deleted = 0;
+    for (id, parent_id) in posts {
+        let rows = if parent_id == 0 { "id = ?1 OR parent_id = ?1" } else { "id = ?1" };
+        paths.extend(files_of(conn, &format!("SELECT file_path, thumbnail_path FROM files WHERE {}", rows), params![id])?);
+        deleted += conn.execute(&format!("DELETE FROM files WHERE {}", rows), params![id])?;
+        if parent_id != 0 {
+            recount_replies(conn, parent_id)?;
+        }
+    }
+    Ok((deleted, paths))
+}
+
+#[derive(Deserialize)]
+pub struct BlockForm {
+    #[serde(default)]
+    reason: String,
+}
+
+// Takes down every copy of a blocked file: the file alone, or the posts
+// carrying it with `blocked_file_deletes_posts`, and uploads sent ahead
+// with it. Returns the posts affected and the files to delete once the
+// transaction has committed.
+fn take_down(conn: &Connection, config: &AppConfig, hash: &str) -> rusqlite::Result<(usize, Vec<String>)> {
+    let (removed, mut paths) = if config.blocked_file_deletes_posts { delete_posts(conn, hash)? } else { strip_posts(conn, hash)? };
+    paths.extend(files_of(conn, "SELECT file_path, thumbnail_path FROM pending_uploads WHERE file_hash = ?1", params![hash])?);
+    conn.execute("DELETE FROM pending_uploads WHERE file_hash = ?1", params![hash])?;
+    Ok((removed, paths))
+}
+
+// Blocks the file attached to post `id` from being posted again, and takes
+// every existing copy down. Copies on posts the backfill job hasn't hashed
+// yet are taken down when it gets to them.
+pub async fn block_file(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, config: web::Data<AppConfig>, storage: web::Data<dyn Storage>, path: web::Path<i32>, form: web::Form<BlockForm>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let post_id = path.into_inner();
+
+    let file: Option<(String, Option<String>)> = conn.query_row(
+        "SELECT file_path, file_hash FROM files WHERE id = ?1 AND file_path IS NOT NULL",
+        params![post_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some((file_path, hash)) = file else {
+        return Ok(HttpResponse::NotFound().body("That post has no file."));
+    };
+    // Only this post's file is hashed here if the backfill hasn't yet
+    let hash = match hash {
+        Some(hash) => hash,
+        None => {
+            let hash = stored_hash(storage.get_ref(), &file_path);
+            conn.execute("UPDATE files SET file_hash = ?2 WHERE id = ?1", params![post_id, hash]).map_err(ErrorInternalServerError)?;
+            hash
+        },
+    };
+    // The file has gone missing from storage
+    if hash.is_empty() {
+        return Ok(HttpResponse::NotFound().body("That post has no file."));
+    }
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    tx.execute(
+        "INSERT INTO blocked_hashes (sha256, reason, added_by, created_at) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP)
+         ON CONFLICT (sha256) DO UPDATE SET reason = excluded.reason",
+        params![hash, form.reason.trim(), admin.name],
+    ).map_err(ErrorInternalServerError)?;
+    let (removed, paths) = take_down(&tx, &config, &hash).map_err(ErrorInternalServerError)?;
+    log_mod_action(&tx, Some(&admin.name), "block-file", Some(post_id), &format!("{} from post {}: {}", hash, post_id, form.reason.trim())).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    delete_files(&conn, storage.get_ref(), &paths);
+    feed_cache.clear();
+
+    let action = if config.blocked_file_deletes_posts { "deleted" } else { "removed from" };
+    Ok(HttpResponse::Ok().body(format!("File blocked; {} {} posts.", action, removed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "10.0.0.1:4000";
+
+    fn config(dir: &std::path::Path) -> AppConfig {
+        AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir) }
+    }
+
+    fn post(title: &str, parent_id: i32, image: &[u8]) -> Vec<u8> {
+        multipart(&[("title", title), ("message", "look"), ("parent_id", &parent_id.to_string())], Some(("pic.png", "image/png", image)))
+    }
+
+    fn block(id: i32) -> TestRequest {
+        TestRequest::post()
+            .uri(&format!("/admin/block-file/{}", id))
+            .insert_header((header::AUTHORIZATION, "Bearer letmein"))
+            .set_form([("reason", "not again")])
+    }
+
+    #[actix_web::test]
+    async fn a_blocked_file_is_taken_off_every_post_and_refused_after() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        for (title, parent, shade) in [("op", 0, 9), ("copy", 1, 9), ("other", 1, 10)] {
+            assert_eq!(call_service(&app, form_post("/upload", post(title, parent, &png(shade)), PEER).to_request()).await.status(), 303);
+        }
+
+        let unauthorized = TestRequest::post().uri("/admin/block-file/1").set_form([("reason", "x")]).to_request();
+        assert_eq!(call_service(&app, unauthorized).await.status(), 401);
+
+        let response = call_service(&app, block(1).to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(read_body(response).await, "File blocked; removed from 2 posts.");
+        {
+            let conn = shared.conn.lock().unwrap();
+            let with_files: Vec<String> = conn.prepare("SELECT title FROM files WHERE file_path IS NOT NULL").unwrap()
+                .query_map([], |row| row.get(0)).unwrap().map(|title| title.unwrap()).collect();
+            assert_eq!(with_files, ["other"]);
+            let posts: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+            assert_eq!(posts, 3);
+            let (reason, added_by): (String, String) = conn.query_row("SELECT reason, added_by FROM blocked_hashes WHERE sha256 = ?1", [file_hash(&png(9))], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+            assert_eq!((reason.as_str(), added_by.as_str()), ("not again", "admin"));
+        }
+
+        // The refusal doesn't say why
+        let response = call_service(&app, form_post("/upload", post("again", 0, &png(9)), PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("This file can't be posted."));
+        assert!(!page.contains("block"));
+        let ahead = form_post("/api/upload", multipart(&[], Some(("pic.png", "image/png", &png(9)))), PEER);
+        assert_eq!(call_service(&app, ahead.to_request()).await.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn blocks_match_the_upload_as_sent_and_can_delete_the_posts() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { blocked_file_deletes_posts: true, convert_to_webp: true, ..config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        assert_eq!(call_service(&app, form_post("/upload", post("op", 0, &png(1)), PEER).to_request()).await.status(), 303);
+        assert_eq!(call_service(&app, form_post("/upload", post("copy", 1, &png(9)), PEER).to_request()).await.status(), 303);
+        let stored: String = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files WHERE id = 2", [], |row| row.get(0)).unwrap();
+        assert!(stored.ends_with(".webp"));
+
+        assert_eq!(call_service(&app, block(2).to_request()).await.status(), 200);
+        let (posts, replies): (i64, i32) = shared.conn.lock().unwrap()
+            .query_row("SELECT COUNT(*), (SELECT reply_count FROM files WHERE id = 1) FROM files", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((posts, replies), (1, 0));
+
+        // The original PNG is refused though only a WebP was ever stored
+        let response = call_service(&app, form_post("/upload", post("again", 1, &png(9)), PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn the_backfill_job_hashes_older_posts_and_takes_down_blocked_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        for (title, parent, shade) in [("op", 0, 9), ("old copy", 1, 9), ("gone", 1, 10)] {
+            assert_eq!(call_service(&app, form_post("/upload", post(title, parent, &png(shade)), PEER).to_request()).await.status(), 303);
+        }
+        let gone: String = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files WHERE title = 'gone'", [], |row| row.get(0)).unwrap();
+        std::fs::remove_file(gone).unwrap();
+        // Rows from before hashes were recorded
+        shared.conn.lock().unwrap().execute("UPDATE files SET file_hash = NULL WHERE title IN ('old copy', 'gone')", []).unwrap();
+
+        // Blocking hashes only the post it is given
+        assert_eq!(call_service(&app, block(1).to_request()).await.status(), 200);
+        let has_file = |title: &str| -> bool { shared.conn.lock().unwrap().query_row("SELECT file_path IS NOT NULL FROM files WHERE title = ?1", [title], |row| row.get(0)).unwrap() };
+        assert!(has_file("old copy"));
+
+        let state = AppState { conn: shared.conn.clone(), config: shared.config.clone(), storage: shared.storage.clone() };
+        HashBackfillJob.run(&state).unwrap();
+        assert!(!has_file("old copy"));
+        let hashes: Vec<String> = shared.conn.lock().unwrap().prepare("SELECT file_hash FROM files WHERE title = 'gone'").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().map(|hash| hash.unwrap()).collect();
+        assert_eq!(hashes, [""]);
+    }
+}
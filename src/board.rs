@@ -0,0 +1,172 @@
+use actix_web::{HttpRequest, HttpResponse};
+use rusqlite::{params, Connection, OptionalExtension, Result as SqlResult};
+use std::collections::HashMap;
+
+use crate::config::AppConfig;
+use crate::locale::Tr;
+use crate::site::Site;
+use crate::{escape_html, render_page};
+
+// Board that posts from before boards existed belong to, and that /upload
+// and / fall back to
+pub const DEFAULT_BOARD: &str = "main";
+
+// First path segments taken by other routes, so they can't name a board
+pub const RESERVED_SLUGS: [&str; 15] = [
+    "admin", "api", "archive", "emoji", "feed.xml", "file", "fragment", "gallery",
+    "img-proxy", "lang", "post", "static", "tz", "upload", "boards",
+];
+
+pub struct Board {
+    pub slug: String,
+    pub name: String,
+}
+
+impl Board {
+    pub fn url(&self) -> String {
+        board_url(&self.slug)
+    }
+}
+
+pub fn board_url(slug: &str) -> String {
+    format!("/{}/", slug)
+}
+
+// Whether `slug` can name a board: short, lowercase and URL-safe
+pub fn valid_slug(slug: &str) -> bool {
+    (1..=20).contains(&slug.len())
+        && slug.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        && !RESERVED_SLUGS.contains(&slug)
+}
+
+// Adds the configured boards to the boards table, and renames existing
+// ones. Boards dropped from the config keep their threads but are no
+// longer listed.
+pub fn sync_boards(conn: &Connection, config: &AppConfig) -> SqlResult<()> {
+    conn.execute("UPDATE boards SET listed = 0", [])?;
+    for board in &config.boards {
+        conn.execute(
+            "INSERT INTO boards (slug, name, listed) VALUES (?1, ?2, 1) ON CONFLICT (slug) DO UPDATE SET name = excluded.name, listed = 1",
+            params![board.slug, board.name],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn find_board(conn: &Connection, slug: &str) -> Option<Board> {
+    conn.query_row(
+        "SELECT slug, name FROM boards WHERE slug = ?1 AND listed = 1",
+        params![slug],
+        |row| Ok(Board { slug: row.get(0)?, name: row.get(1)? }),
+    ).optional().unwrap()
+}
+
+// The board named in the request path, or the default board for routes
+// without one. None when the path names a board that doesn't exist.
+pub fn request_board(conn: &Connection, req: &HttpRequest) -> Option<Board> {
+    find_board(conn, req.match_info().get("board").unwrap_or(DEFAULT_BOARD))
+}
+
+// Board a thread was posted to
+pub fn thread_board(conn: &Connection, thread_id: i32) -> String {
+    conn.query_row("SELECT board_slug FROM files WHERE id = ?1", params![thread_id], |row| row.get(0))
+        .optional()
+        .unwrap()
+        .unwrap_or_else(|| DEFAULT_BOARD.to_string())
+}
+
+pub fn listed_boards(conn: &Connection) -> Vec<Board> {
+    let mut stmt = conn.prepare("SELECT slug, name FROM boards WHERE listed = 1 ORDER BY slug = ?1 DESC, slug").unwrap();
+    stmt.query_map(params![DEFAULT_BOARD], |row| Ok(Board { slug: row.get(0)?, name: row.get(1)? }))
+        .unwrap()
+        .map(|board| board.unwrap())
+        .collect()
+}
+
+// Front page when there is more than one board: each board with its
+// number of active threads
+pub fn landing_page(conn: &Connection, site: &Site, tr: &Tr) -> HttpResponse {
+    let mut boards_html = String::new();
+    for board in listed_boards(conn) {
+        let threads: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM files WHERE parent_id = 0 AND archived = 0 AND hidden = 0 AND board_slug = ?1",
+            params![board.slug],
+            |row| row.get(0),
+        ).unwrap();
+        boards_html.push_str(&format!(
+            r#"<tr><td><a href="{}">/{}/ - {}</a></td><td>{}</td></tr>"#,
+            board.url(), board.slug, escape_html(&board.name), tr.tn("boards.threads", threads)
+        ));
+    }
+
+    let mut context = HashMap::from([
+        ("BOARDS", boards_html),
+        ("ANNOUNCEMENT", site.announcement_banner()),
+    ]);
+    context.extend(site.chrome());
+    let body = render_page("templates/boards.html", &context, tr);
+    HttpResponse::Ok().content_type("text/html").body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::config::BoardConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[test]
+    fn slugs_are_short_lowercase_and_not_routes() {
+        for slug in ["main", "tech", "b", "retro-games", "v2_0"] {
+            assert!(valid_slug(slug), "{}", slug);
+        }
+        for slug in ["", "Tech", "-x", "a/b", "post", "admin", "twenty-one-characters"] {
+            assert!(!valid_slug(slug), "{}", slug);
+        }
+    }
+
+    #[actix_web::test]
+    async fn boards_keep_their_threads_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            boards: vec![
+                BoardConfig { slug: "main".to_string(), name: "Main".to_string() },
+                BoardConfig { slug: "tech".to_string(), name: "Tech & Gadgets".to_string() },
+            ],
+            ..test_config(dir.path())
+        });
+        // A thread from before there were boards
+        shared.conn.lock().unwrap().execute("INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at) VALUES ('old', 0, 'from before', 'x', datetime('now', '-1 hours'), datetime('now', '-1 hours'))", []).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+        let page = |uri: &str| {
+            let request = TestRequest::get().uri(uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        let post = |uri: &str, title: &str, parent: &str| form_post(uri, multipart(&[("title", title), ("message", "shared words"), ("parent_id", parent)], None), "10.0.0.1:4000").to_request();
+
+        assert_eq!(call_service(&app, post("/main/upload", "cats", "0")).await.status(), 303);
+        assert_eq!(call_service(&app, post("/tech/upload", "laptops", "0")).await.status(), 303);
+        let laptops: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'laptops'", [], |row| row.get(0)).unwrap();
+        // Replies follow their thread whichever board path they're sent to
+        assert_eq!(call_service(&app, post("/main/upload", "re", &laptops.to_string())).await.status(), 303);
+        assert_eq!(thread_board(&shared.conn.lock().unwrap(), laptops + 1), "tech");
+
+        let landing = page("/").await;
+        assert!(landing.contains(r#"<a href="/main/">/main/ - Main</a>"#));
+        assert!(landing.contains(r#"<a href="/tech/">/tech/ - Tech &amp; Gadgets</a></td><td>1 thread</td>"#));
+
+        let main = page("/main/").await;
+        assert!(main.contains(">cats<") && main.contains(">from before<"));
+        assert!(!main.contains(">laptops<"));
+        let tech = page("/tech/").await;
+        assert!(tech.contains(">laptops<"));
+        assert!(!tech.contains(">cats<") && !tech.contains(">from before<"));
+        let search = page("/tech/?q=shared").await;
+        assert!(search.contains(">laptops<") && !search.contains(">cats<"));
+
+        let response = call_service(&app, TestRequest::get().uri("/nope/").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+}
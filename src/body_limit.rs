@@ -0,0 +1,153 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::stream::StreamExt as _;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::config::AppConfig;
+use crate::locale::translator;
+use crate::render_notice;
+use crate::site::site;
+
+// Room for the text fields and multipart framing around a full-size file
+const FORM_OVERHEAD: usize = 64 * 1024;
+
+// Largest request body each route takes, and how often each route has
+// turned one away
+pub struct BodyLimits {
+    default: usize,
+    routes: HashMap<String, usize>,
+    rejected: Mutex<BTreeMap<String, u64>>,
+}
+
+impl BodyLimits {
+    pub fn new(config: &AppConfig) -> Self {
+        BodyLimits {
+            default: config.max_upload_bytes + FORM_OVERHEAD,
+            routes: config.body_limits.clone(),
+            rejected: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn limit_for(&self, route: &str) -> usize {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+
+    fn record_rejection(&self, route: &str) {
+        *self.rejected.lock().unwrap().entry(route.to_string()).or_default() += 1;
+    }
+
+    // Routes that have rejected a body since startup, with their counts
+    pub fn rejections(&self) -> Vec<(String, u64)> {
+        self.rejected.lock().unwrap().iter().map(|(route, count)| (route.clone(), *count)).collect()
+    }
+}
+
+fn too_large_response(req: &ServiceRequest, limit: usize) -> HttpResponse {
+    let mut response = HttpResponse::PayloadTooLarge();
+    if req.path().starts_with("/api/") {
+        response.json(json!({
+            "error": "Request body too large",
+            "limit": limit,
+        }))
+    } else {
+        let tr = translator(req.request());
+        let body = render_notice(&site(req.request()), &tr, &tr.t("error.too_large_title"), &tr.t("error.too_large"));
+        response.content_type("text/html").body(body)
+    }
+}
+
+// Refuses bodies over the route's limit. A declared Content-Length over it
+// gets a 413 straight away; a body without one is cut off once it passes
+// the limit, which the handler sees as a broken upload.
+pub async fn body_limit(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(limits) = req.app_data::<Data<BodyLimits>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let route = req.request().match_pattern().unwrap_or_else(|| req.path().to_string());
+    let limit = limits.limit_for(&route);
+
+    let declared = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        limits.record_rejection(&route);
+        let response = too_large_response(&req, limit);
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    if declared.is_none() {
+        let mut received = 0;
+        let counted = limits.clone();
+        let capped = req.take_payload().map(move |chunk| {
+            let chunk = chunk?;
+            received += chunk.len();
+            if received > limit {
+                // Counted once, on the chunk that crossed the limit
+                if received - chunk.len() <= limit {
+                    counted.record_rejection(&route);
+                }
+                return Err(PayloadError::Overflow);
+            }
+            Ok(chunk)
+        });
+        req.set_payload(Payload::from(capped.boxed_local()));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json};
+    use serde_json::Value;
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "10.0.0.1:4000";
+
+    #[actix_web::test]
+    async fn each_route_has_its_own_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            body_limits: HashMap::from([("/upload".to_string(), 1024), ("/api/upload".to_string(), 512)]),
+            ..test_config(dir.path())
+        });
+        let app = init_service(crate::app(&shared)).await;
+        let op = multipart(&[("title", "op"), ("message", "thread"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/main/upload", op, PEER).to_request()).await.status(), 303);
+
+        // Replies post to /upload, which takes little
+        let long_reply = multipart(&[("title", "re"), ("message", &"words ".repeat(400)), ("parent_id", "1")], None);
+        let response = call_service(&app, form_post("/upload", long_reply, PEER).to_request()).await;
+        assert_eq!(response.status(), 413);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("What you sent is larger than this page accepts."));
+        let short_reply = multipart(&[("title", "re"), ("message", "fine"), ("parent_id", "1")], None);
+        assert_eq!(call_service(&app, form_post("/upload", short_reply, PEER).to_request()).await.status(), 303);
+
+        // The same body with an image is well within the thread route's limit
+        let image_thread = multipart(&[("title", "pic"), ("message", &"words ".repeat(400)), ("parent_id", "0")], Some(("a.png", "image/png", &png(4))));
+        assert_eq!(call_service(&app, form_post("/main/upload", image_thread, PEER).to_request()).await.status(), 303);
+
+        let ahead = multipart(&[], Some(("a.png", "image/png", &[0u8; 600])));
+        let response = call_service(&app, form_post("/api/upload", ahead, PEER).to_request()).await;
+        assert_eq!(response.status(), 413);
+        let body: Value = read_body_json(response).await;
+        assert_eq!(body["limit"], 512);
+
+        assert_eq!(shared.body_limits.rejections(), [("/api/upload".to_string(), 1), ("/upload".to_string(), 1)]);
+        assert_eq!(shared.body_limits.limit_for("/{board}/upload"), shared.config.max_upload_bytes + FORM_OVERHEAD);
+    }
+}
@@ -0,0 +1,217 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use rusqlite::Connection;
+use std::sync::Mutex;
+
+use crate::api::load_thread;
+use crate::archive::archive_inactive;
+use crate::config::AppConfig;
+use crate::export::{thread_to_json, thread_to_text};
+use crate::{import, setup_db, DATABASE_PATH};
+
+// Exit codes of the one-off commands. Usage errors exit with 2, as clap
+// does for them.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_NOTHING_TO_DO: i32 = 3;
+
+#[derive(Parser)]
+#[command(
+    about = "Image board server",
+    after_help = "Commands other than serve exit with 0 on success, 1 on failure and 3 when there was nothing to do."
+)]
+pub struct Cli {
+    // Serves the board when left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server (the default)
+    Serve,
+    /// Apply pending schema migrations and exit
+    Migrate,
+    /// Archive threads that haven't been bumped for a while
+    Prune {
+        /// Age of the last bump, in days ("90d" or "90") or weeks ("12w")
+        #[arg(long, value_parser = parse_age)]
+        older_than: u32,
+    },
+    /// Write a thread to standard output
+    Export {
+        #[arg(long)]
+        thread: i32,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    /// Add the threads in a JSON file, as written by export or used as a seed
+    Import {
+        file: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Txt,
+}
+
+// Days in an age such as "90d", "12w" or a plain "90"
+fn parse_age(age: &str) -> Result<u32, String> {
+    let (number, days_per_unit) = match age.strip_suffix('w') {
+        Some(weeks) => (weeks, 7),
+        None => (age.strip_suffix('d').unwrap_or(age), 1),
+    };
+    match number.parse::<u32>() {
+        Ok(number) if number > 0 => number.checked_mul(days_per_unit).ok_or_else(|| format!("{} is too long", age)),
+        _ => Err(format!("expected a number of days like 90d or weeks like 12w, not {:?}", age)),
+    }
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Runs one of the commands besides serve and returns its exit code. They
+// use the same config and database as the server, which may be running.
+pub fn run(command: Command, config: &AppConfig) -> i32 {
+    run_on(command, config, DATABASE_PATH)
+}
+
+fn run_on(command: Command, config: &AppConfig, database: &str) -> i32 {
+    let result = match command {
+        Command::Serve => unreachable!("serve starts the web server instead"),
+        Command::Migrate => migrate(database),
+        Command::Prune { older_than } => prune(database, older_than),
+        Command::Export { thread, format } => export(database, config, thread, format),
+        Command::Import { file } => import(database, config, &file),
+    };
+    match result {
+        Ok(true) => EXIT_SUCCESS,
+        Ok(false) => EXIT_NOTHING_TO_DO,
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_FAILURE
+        },
+    }
+}
+
+// The database at `path`, created or migrated as the server would
+fn open(path: &str) -> rusqlite::Result<Connection> {
+    setup_db(Connection::open(path)?)
+}
+
+// Each command returns whether it did anything
+
+fn migrate(database: &str) -> Result<bool, String> {
+    let before = Connection::open(database).and_then(|conn| schema_version(&conn)).map_err(|e| e.to_string())?;
+    let conn = open(database).map_err(|e| format!("Migration failed: {}", e))?;
+    let after = schema_version(&conn).map_err(|e| e.to_string())?;
+    if after == before {
+        println!("The database is up to date (schema version {})", after);
+        return Ok(false);
+    }
+    println!("Migrated the database from schema version {} to {}", before, after);
+    Ok(true)
+}
+
+fn prune(database: &str, days: u32) -> Result<bool, String> {
+    let conn = Mutex::new(open(database).map_err(|e| e.to_string())?);
+    let archived = archive_inactive(&conn, days).map_err(|e| e.to_string())?;
+    println!("Archived {} threads not bumped in {} days", archived, days);
+    Ok(archived > 0)
+}
+
+fn export(database: &str, config: &AppConfig, thread_id: i32, format: ExportFormat) -> Result<bool, String> {
+    let conn = open(database).map_err(|e| e.to_string())?;
+    let thread = load_thread(&conn, config, thread_id).ok_or_else(|| format!("Thread {} not found", thread_id))?;
+    match format {
+        ExportFormat::Json => println!("{}", thread_to_json(&thread)),
+        ExportFormat::Txt => print!("{}", thread_to_text(&thread)),
+    }
+    Ok(true)
+}
+
+fn import(database: &str, config: &AppConfig, path: &str) -> Result<bool, String> {
+    let mut conn = open(database).map_err(|e| e.to_string())?;
+    let imported = import::import_file(&mut conn, path, &config.upload_dir).map_err(|e| format!("Import failed: {}", e))?;
+    println!("Imported {} threads from {}", imported, path);
+    Ok(imported > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testing::test_config;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, clap::Error> {
+        Cli::try_parse_from(std::iter::once("dream").chain(args.iter().copied())).map(|cli| cli.command)
+    }
+
+    #[test]
+    fn arguments_pick_the_command() {
+        assert!(matches!(parse(&[]), Ok(None)));
+        assert!(matches!(parse(&["serve"]), Ok(Some(Command::Serve))));
+        assert!(matches!(parse(&["migrate"]), Ok(Some(Command::Migrate))));
+        assert!(matches!(parse(&["prune", "--older-than", "90d"]), Ok(Some(Command::Prune { older_than: 90 }))));
+        assert!(matches!(parse(&["prune", "--older-than", "12w"]), Ok(Some(Command::Prune { older_than: 84 }))));
+        assert!(matches!(parse(&["prune", "--older-than", "30"]), Ok(Some(Command::Prune { older_than: 30 }))));
+        assert!(matches!(parse(&["export", "--thread", "42"]), Ok(Some(Command::Export { thread: 42, format: ExportFormat::Json }))));
+        assert!(matches!(parse(&["export", "--thread", "42", "--format", "txt"]), Ok(Some(Command::Export { thread: 42, format: ExportFormat::Txt }))));
+        assert!(matches!(parse(&["import", "threads.json"]), Ok(Some(Command::Import { file })) if file == "threads.json"));
+
+        for bad in [&["prune"][..], &["prune", "--older-than", "0d"], &["prune", "--older-than", "soon"], &["export", "--thread", "42", "--format", "xml"], &["launch"]] {
+            assert_eq!(parse(bad).err().map(|e| e.exit_code()), Some(2), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn commands_report_what_they_did_in_the_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let database = dir.path().join("board.db");
+        let database = database.to_str().unwrap();
+
+        assert_eq!(run_on(Command::Migrate, &config, database), EXIT_SUCCESS);
+        assert_eq!(run_on(Command::Migrate, &config, database), EXIT_NOTHING_TO_DO);
+
+        let (stale, fresh): (i32, i32) = {
+            let conn = Connection::open(database).unwrap();
+            let seed = |title: &str, bumped: &str| conn.query_row(
+                "INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at) VALUES (?1, 0, ?1, 'body', datetime('now', ?2), datetime('now', ?2)) RETURNING id",
+                rusqlite::params![title, bumped],
+                |row| row.get(0),
+            ).unwrap();
+            (seed("stale", "-100 days"), seed("fresh", "-1 days"))
+        };
+        let prune = || Command::Prune { older_than: 90 };
+        assert_eq!(run_on(prune(), &config, database), EXIT_SUCCESS);
+        assert_eq!(run_on(prune(), &config, database), EXIT_NOTHING_TO_DO);
+        let archived = |id: i32| Connection::open(database).unwrap()
+            .query_row("SELECT archived FROM files WHERE id = ?1", [id], |row| row.get::<_, bool>(0))
+            .unwrap();
+        assert!(archived(stale));
+        assert!(!archived(fresh));
+
+        assert_eq!(run_on(Command::Export { thread: fresh, format: ExportFormat::Txt }, &config, database), EXIT_SUCCESS);
+        assert_eq!(run_on(Command::Export { thread: 9999, format: ExportFormat::Json }, &config, database), EXIT_FAILURE);
+
+        // An export reads back in as a new thread
+        let export = dir.path().join("export.json");
+        let thread = load_thread(&open(database).unwrap(), &config, fresh).unwrap();
+        fs::write(&export, thread_to_json(&thread)).unwrap();
+        let import = |path: &std::path::Path| Command::Import { file: path.to_str().unwrap().to_string() };
+        assert_eq!(run_on(import(&export), &config, database), EXIT_SUCCESS);
+        let copies: i64 = Connection::open(database).unwrap()
+            .query_row("SELECT COUNT(*) FROM files WHERE title = 'fresh'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(copies, 2);
+
+        let empty = dir.path().join("empty.json");
+        fs::write(&empty, "[]").unwrap();
+        assert_eq!(run_on(import(&empty), &config, database), EXIT_NOTHING_TO_DO);
+        assert_eq!(run_on(import(&dir.path().join("missing.json")), &config, database), EXIT_FAILURE);
+    }
+}
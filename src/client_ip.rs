@@ -0,0 +1,205 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+use crate::config::AppConfig;
+
+// Extractor for the address a request comes from. Behind a proxy listed in
+// `trusted_proxies` that is the address the proxy says it forwarded for;
+// from anywhere else the forwarding headers are ignored, so clients can't
+// pick their own address.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(req: &HttpRequest) -> Self {
+        let Some(peer) = req.peer_addr().map(|addr| addr.ip().to_canonical()) else {
+            return ClientIp(None);
+        };
+        let trusted = req.app_data::<Data<AppConfig>>().map_or(&[][..], |config| &config.trusted_proxies[..]);
+        ClientIp(Some(resolve(peer, req.headers(), trusted)))
+    }
+
+    // The address as text; empty when it isn't known
+    pub fn text(&self) -> String {
+        self.0.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(ClientIp::of(req)))
+    }
+}
+
+// Whether `entry` is a valid `trusted_proxies` entry: an address, or a
+// range such as "10.0.0.0/8"
+pub fn valid_proxy(entry: &str) -> bool {
+    parse_range(entry).is_some()
+}
+
+fn parse_range(entry: &str) -> Option<(IpAddr, u32)> {
+    let (address, bits) = match entry.split_once('/') {
+        Some((address, bits)) => (address, Some(bits.parse::<u32>().ok()?)),
+        None => (entry, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let bits = bits.unwrap_or(max);
+    (bits <= max).then_some((address, bits))
+}
+
+fn in_range(ip: IpAddr, entry: &str) -> bool {
+    let Some((network, bits)) = parse_range(entry) else {
+        return false;
+    };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[String]) -> bool {
+    trusted.iter().any(|entry| in_range(ip, entry))
+}
+
+// The client's address for a request from `peer`. Only a trusted peer's
+// headers count. X-Forwarded-For is read from the right, each proxy
+// having appended the address it got the request from, and the first
+// address not belonging to a trusted proxy is the client. Failing that,
+// X-Real-IP, and failing that the peer itself.
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[String]) -> IpAddr {
+    if !is_trusted(peer, trusted) {
+        return peer;
+    }
+
+    let forwarded: Vec<&str> = headers.get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    if !forwarded.is_empty() {
+        let mut client = peer;
+        for hop in forwarded.iter().rev() {
+            // Anything unreadable ends the chain at the last proxy we trust
+            let Ok(ip) = hop.parse::<IpAddr>().map(|ip| ip.to_canonical()) else {
+                return client;
+            };
+            client = ip;
+            if !is_trusted(ip, trusted) {
+                return ip;
+            }
+        }
+        // Every hop was one of ours; the leftmost is as far back as it goes
+        return client;
+    }
+
+    headers.get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .map_or(peer, |ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use actix_web::test::TestRequest;
+
+    const PROXIES: &[&str] = &["10.0.0.0/8", "2001:db8::1"];
+
+    fn client(peer: &str, headers: &[(&str, &str)]) -> String {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        let trusted: Vec<String> = PROXIES.iter().map(|entry| entry.to_string()).collect();
+        resolve(peer.parse::<IpAddr>().unwrap().to_canonical(), &map, &trusted).to_string()
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        assert_eq!(client("203.0.113.9", &[("x-forwarded-for", "1.2.3.4")]), "203.0.113.9");
+        assert_eq!(client("203.0.113.9", &[("x-real-ip", "1.2.3.4")]), "203.0.113.9");
+        // Just outside the trusted range
+        assert_eq!(client("11.0.0.1", &[("x-forwarded-for", "1.2.3.4")]), "11.0.0.1");
+        assert_eq!(client("2001:db8::2", &[("x-forwarded-for", "1.2.3.4")]), "2001:db8::2");
+    }
+
+    #[test]
+    fn a_trusted_proxy_names_the_client() {
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "198.51.100.7")]), "198.51.100.7");
+        assert_eq!(client("2001:db8::1", &[("x-forwarded-for", "198.51.100.7")]), "198.51.100.7");
+        assert_eq!(client("::ffff:10.0.0.2", &[("x-forwarded-for", "::ffff:198.51.100.7")]), "198.51.100.7");
+        assert_eq!(client("10.0.0.2", &[("x-real-ip", " 198.51.100.7 ")]), "198.51.100.7");
+        // X-Forwarded-For wins over X-Real-IP
+        assert_eq!(client("10.0.0.2", &[("x-real-ip", "198.51.100.8"), ("x-forwarded-for", "198.51.100.7")]), "198.51.100.7");
+        // A proxy that forwards nothing is the client
+        assert_eq!(client("10.0.0.2", &[]), "10.0.0.2");
+        assert_eq!(client("10.0.0.2", &[("x-real-ip", "unknown")]), "10.0.0.2");
+    }
+
+    #[test]
+    fn spoofed_entries_left_of_the_client_are_ignored() {
+        // The client sent its own X-Forwarded-For; our proxy appended the
+        // address it really came from
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "6.6.6.6, 198.51.100.7")]), "198.51.100.7");
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "10.9.9.9, 198.51.100.7")]), "198.51.100.7");
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "6.6.6.6"), ("x-forwarded-for", "198.51.100.7")]), "198.51.100.7");
+    }
+
+    #[test]
+    fn chained_proxies_are_skipped_from_the_right() {
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "198.51.100.7, 10.0.0.3, 2001:db8::1")]), "198.51.100.7");
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.0.0.3")]), "198.51.100.7");
+        // Every hop is ours: the leftmost is as far back as it goes
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "10.0.0.4, 10.0.0.3")]), "10.0.0.4");
+        // An unreadable hop stops at the last proxy that could be trusted
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "198.51.100.7, garbage, 10.0.0.3")]), "10.0.0.3");
+        assert_eq!(client("10.0.0.2", &[("x-forwarded-for", "garbage")]), "10.0.0.2");
+    }
+
+    #[test]
+    fn proxy_entries_are_addresses_or_ranges() {
+        for entry in ["10.0.0.1", "10.0.0.0/8", "0.0.0.0/0", "::1", "2001:db8::/32"] {
+            assert!(valid_proxy(entry), "{}", entry);
+        }
+        for entry in ["", "proxy.local", "10.0.0.0/33", "::/129", "10.0.0.0/x"] {
+            assert!(!valid_proxy(entry), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn the_extractor_uses_the_configured_proxies() {
+        let forwarded = |peer: &str, config: Option<AppConfig>| {
+            let mut request = TestRequest::get()
+                .peer_addr(format!("{}:40000", peer).parse().unwrap())
+                .insert_header(("X-Forwarded-For", "6.6.6.6, 198.51.100.7"));
+            if let Some(config) = config {
+                request = request.app_data(Data::new(config));
+            }
+            ClientIp::of(&request.to_http_request()).text()
+        };
+        let config = || AppConfig { trusted_proxies: vec!["127.0.0.1".to_string()], ..AppConfig::default() };
+
+        assert_eq!(forwarded("127.0.0.1", Some(config())), "198.51.100.7");
+        assert_eq!(forwarded("192.0.2.1", Some(config())), "192.0.2.1");
+        // Nothing is trusted by default
+        assert_eq!(forwarded("127.0.0.1", Some(AppConfig::default())), "127.0.0.1");
+        assert_eq!(forwarded("127.0.0.1", None), "127.0.0.1");
+        assert_eq!(ClientIp::of(&TestRequest::get().to_http_request()).text(), "");
+    }
+}
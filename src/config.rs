@@ -0,0 +1,523 @@
+use actix_web::http::header::HeaderValue;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::board::{valid_slug, DEFAULT_BOARD};
+use crate::client_ip::valid_proxy;
+use crate::filetypes::{default_file_types, valid_extension, valid_signature, FileType};
+use crate::spam::LinkLimit;
+use crate::storage::{S3Config, StorageKind};
+use crate::upload::{DuplicateImages, UploadNames};
+
+const CONFIG_PATH: &str = "Rocket.toml";
+// Stylesheets, scripts and icons, served as they are at /static
+pub const STATIC_DIR: &str = "./static";
+
+const POST_HTML_TAGS: [&str; 13] = ["a", "b", "br", "code", "em", "i", "p", "pre", "s", "strong", "sub", "sup", "u"];
+const ADMIN_EXTRA_HTML_TAGS: [&str; 13] = ["blockquote", "h2", "h3", "hr", "li", "ol", "table", "tbody", "td", "th", "thead", "tr", "ul"];
+const EMBED_HOSTS: [&str; 3] = ["youtube.com", "youtu.be", "vimeo.com"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BoardConfig {
+    pub slug: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    // Threads per page on the board, gallery, archive and API
+    pub posts_per_page: usize,
+    // Largest accepted upload in bytes
+    pub max_upload_bytes: usize,
+    // Largest request body in bytes for individual routes, keyed by route
+    // pattern such as "/{board}/upload" or "/api/upload". Other routes take
+    // max_upload_bytes plus room for the form's text fields.
+    pub body_limits: HashMap<String, usize>,
+    // Requests per minute allowed on /api/* for each IP or API token (0 disables the limit)
+    pub api_requests_per_minute: u32,
+    // Posting slow-down: each IP may make post_burst posts straight away,
+    // earning one more every post_refill_secs. Posts beyond that are held
+    // back, post_slowdown_ms for the first and twice as long for each one
+    // after, up to post_max_delay_secs. 0 posts turns it off.
+    pub post_burst: u32,
+    pub post_refill_secs: u64,
+    pub post_slowdown_ms: u64,
+    pub post_max_delay_secs: u64,
+    // JSON file of threads imported at startup when the board is empty
+    pub import_path: Option<String>,
+    // Seconds a new visitor must wait before their first post (0 disables the delay)
+    pub first_post_delay_secs: u64,
+    // Password for the moderator routes (admin routes are disabled when unset)
+    pub admin_password: Option<String>,
+    // SHA-256 (hex) of the shared password needed to post; browsing stays
+    // open. Posting is anonymous when unset.
+    pub post_password: Option<String>,
+    // Hour (UTC) of the nightly database optimize; VACUUM runs weekly at the same hour
+    pub maintenance_hour: u32,
+    // Threads with no replies for this many days are archived (0 disables)
+    pub archive_after_days: u32,
+    // Replies a thread can take before it is closed as full (0 disables the cap)
+    pub max_replies_per_thread: u32,
+    // Posting rules shown at the top of the board (plain text)
+    pub rules_banner: String,
+    // Placeholder text for the message boxes; the built-in hint is used when empty
+    pub content_placeholder: String,
+    // Words longer than this get line-break opportunities when rendered (0 disables)
+    pub max_word_length: usize,
+    // Thread openers on the board are cut to this many characters, with a
+    // link to the full thread (0 shows them whole)
+    pub preview_chars: usize,
+    // Where uploaded files are stored; created at startup if missing. Must
+    // be outside ./static: uploads are only served through /file/<name>.
+    pub upload_dir: String,
+    // Remote hosts /img-proxy may fetch images from (the proxy is off when empty)
+    pub image_proxy_hosts: Vec<String>,
+    // Where /img-proxy keeps its copies of remote images. Kept apart from
+    // upload_dir: the copies don't count against storage_quota_bytes and
+    // are only ever served through /img-proxy.
+    pub image_proxy_cache_dir: String,
+    // A cached copy is fetched again once it is this many hours old
+    pub image_proxy_cache_hours: u64,
+    // Most bytes the cache may hold; past it the oldest copies are removed
+    pub image_proxy_cache_bytes: u64,
+    // Reverse proxies (addresses or ranges like "10.0.0.0/8") whose
+    // X-Forwarded-For and X-Real-IP headers are believed. Everyone else is
+    // known by the address they connect from.
+    pub trusted_proxies: Vec<String>,
+    // PNG uploads larger than this many bytes are re-encoded to WebP (0 disables)
+    pub webp_min_png_bytes: u64,
+    // Quality (0-100) for lossy WebP re-encoding
+    pub webp_quality: f32,
+    // Convert every JPEG and PNG upload to WebP (GIFs are left alone)
+    pub convert_to_webp: bool,
+    // Requests allowed to work against the database at once; beyond this
+    // the server answers 503 instead of queueing (0 disables the cap)
+    pub max_db_requests: usize,
+    // ffmpeg binary used to grab poster frames from video uploads; videos
+    // get a generic placeholder on the board when unset
+    pub ffmpeg_path: Option<String>,
+    // Mark replies posted since the visitor last opened the thread
+    pub highlight_new_replies: bool,
+    // Show runs of consecutive replies by the same poster as one block
+    pub group_same_poster_replies: bool,
+    // Next to each reply, show how many posts its poster has in the thread
+    pub show_poster_post_counts: bool,
+    // Drop zero-width, bidi-override and other invisible characters from
+    // titles and messages
+    pub strip_invisible_chars: bool,
+    // Seconds /feed.xml is served from memory before being rebuilt
+    pub feed_cache_secs: u64,
+    // Threads listed in /feed.xml
+    pub feed_items: usize,
+    // Shown in every page title and in the header
+    pub site_name: String,
+    // Shown under the site name in the header
+    pub tagline: String,
+    // Plain text shown at the bottom of every page
+    pub footer_text: String,
+    // HTML tags and attributes (allowed on any of those tags) kept in
+    // ordinary posts; everything else is stripped when rendering
+    pub post_html_tags: Vec<String>,
+    pub post_html_attributes: Vec<String>,
+    // The same for posts made by a moderator
+    pub admin_html_tags: Vec<String>,
+    pub admin_html_attributes: Vec<String>,
+    // Host this site is reached on, such as "board.example.com". Links in
+    // posts to it, like relative ones, open in the same tab; links anywhere
+    // else get `external_link_rel` and, with `external_links_new_tab`, a
+    // new tab.
+    pub site_host: Option<String>,
+    pub external_link_rel: String,
+    pub external_links_new_tab: bool,
+    // New threads must come with a file; replies may still be text-only
+    pub require_op_image: bool,
+    // Accept file uploads at all; text-only boards turn this off
+    pub uploads_enabled: bool,
+    // Show YouTube and Vimeo links in posts as embedded players
+    pub video_embeds: bool,
+    // Sites whose links get embedded; only youtube.com, youtu.be and
+    // vimeo.com are understood
+    pub embed_hosts: Vec<String>,
+    // Boards, each served under /<slug>/. The "main" board holds the posts
+    // from before there were boards and must stay.
+    pub boards: Vec<BoardConfig>,
+    // Blocking a file deletes the posts that carry it (a thread's OP takes
+    // the thread with it); otherwise only the file is taken off them
+    pub blocked_file_deletes_posts: bool,
+    // Upload formats accepted, as [[default.file_types]] entries. Listing
+    // any replaces the built-in set (JPEG, PNG, GIF, WebP, MP4, WebM, MP3, PDF).
+    pub file_types: Vec<FileType>,
+    // How stored uploads are named: "uuid", "hash" (of the file's contents)
+    // or "original" (a random prefix and the uploader's file name)
+    pub upload_names: UploadNames,
+    // Replies whose file (matched by hash) is already in the thread:
+    // "allow", "warn" (posted, and noted in the moderation log) or "block"
+    pub duplicate_images: DuplicateImages,
+    // Virus scanner run on every upload before it is stored: a command
+    // given the file's path as its last argument (exit 0 clean, 1
+    // infected), or a clamd TCP address such as "127.0.0.1:3310"
+    pub scan_command: Option<String>,
+    pub clamd_address: Option<String>,
+    // Seconds a scan may take before it counts as failed
+    pub scan_timeout_secs: u64,
+    // Refuse uploads whose scan failed or timed out; otherwise they're
+    // stored unscanned
+    pub reject_on_scan_error: bool,
+    // Where uploads are kept: "filesystem" (upload_dir) or "s3", which
+    // needs a [default.s3] table
+    pub storage: StorageKind,
+    pub s3: Option<S3Config>,
+    // Content-Security-Policy sent with every response. Unset, it allows
+    // only this site's own scripts and styles, plus the video players and
+    // the hosts uploads are served from when those are in use.
+    pub content_security_policy: Option<String>,
+    // Host uploads are linked on, such as a CDN or cookieless domain in
+    // front of this server: "https://media.example.com" makes links
+    // https://media.example.com/file/<name>
+    pub media_base_url: Option<String>,
+    pub referrer_policy: String,
+    // Most bytes of uploads kept; unset for no limit. Past it, new files
+    // are refused, or with `evict_archived_files` the files of the oldest
+    // archived threads are removed to make room.
+    pub storage_quota_bytes: Option<u64>,
+    pub evict_archived_files: bool,
+    // MaxMind country database (GeoLite2-Country.mmdb). Set, posts record
+    // their poster's country code and show it as a flag; the address
+    // itself is never kept.
+    pub geoip_database: Option<String>,
+    // Statements taking at least this many milliseconds are logged with
+    // the route that ran them; 0 turns the log off
+    pub slow_query_ms: u64,
+    // Run the full PRAGMA integrity_check on the database at startup
+    // instead of the faster quick_check; slow on large boards
+    pub integrity_check: bool,
+    // Start with posting turned off, the board still readable; moderators
+    // can switch it at /admin/read-only
+    pub read_only: bool,
+    // New posts scoring this much or more are shadow-hidden as spam (0
+    // turns scoring off). Each link scores 1, each long run of one
+    // repeated character 2 and each of the spam_words found 3.
+    pub spam_score_threshold: u32,
+    pub spam_words: Vec<String>,
+    // Most links a post may have, in its title and message together (0
+    // for no limit); `link_limit` says what happens to posts with more
+    pub max_links: usize,
+    pub link_limit: LinkLimit,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            posts_per_page: 30,
+            max_upload_bytes: 20 * 1024 * 1024,
+            body_limits: HashMap::new(),
+            api_requests_per_minute: 60,
+            post_burst: 0,
+            post_refill_secs: 60,
+            post_slowdown_ms: 2000,
+            post_max_delay_secs: 30,
+            import_path: None,
+            first_post_delay_secs: 0,
+            admin_password: None,
+            post_password: None,
+            maintenance_hour: 4,
+            archive_after_days: 30,
+            max_replies_per_thread: 1000,
+            rules_banner: String::new(),
+            content_placeholder: String::new(),
+            max_word_length: 80,
+            preview_chars: 2700,
+            upload_dir: "./uploads".to_string(),
+            image_proxy_hosts: Vec::new(),
+            image_proxy_cache_dir: "./proxy-cache".to_string(),
+            image_proxy_cache_hours: 24,
+            image_proxy_cache_bytes: 256 * 1024 * 1024,
+            trusted_proxies: Vec::new(),
+            webp_min_png_bytes: 0,
+            webp_quality: 80.0,
+            convert_to_webp: false,
+            max_db_requests: 64,
+            ffmpeg_path: None,
+            highlight_new_replies: true,
+            group_same_poster_replies: false,
+            show_poster_post_counts: false,
+            strip_invisible_chars: true,
+            feed_cache_secs: 60,
+            feed_items: 20,
+            site_name: String::new(),
+            tagline: String::new(),
+            footer_text: String::new(),
+            post_html_tags: POST_HTML_TAGS.iter().map(|tag| tag.to_string()).collect(),
+            post_html_attributes: Vec::new(),
+            site_host: None,
+            external_link_rel: "noopener nofollow".to_string(),
+            external_links_new_tab: true,
+            admin_html_tags: POST_HTML_TAGS.iter().chain(&ADMIN_EXTRA_HTML_TAGS).map(|tag| tag.to_string()).collect(),
+            admin_html_attributes: vec!["title".to_string()],
+            require_op_image: false,
+            uploads_enabled: true,
+            video_embeds: true,
+            embed_hosts: EMBED_HOSTS.iter().map(|host| host.to_string()).collect(),
+            boards: vec![BoardConfig { slug: DEFAULT_BOARD.to_string(), name: "Main".to_string() }],
+            blocked_file_deletes_posts: false,
+            file_types: default_file_types(),
+            upload_names: UploadNames::Uuid,
+            duplicate_images: DuplicateImages::Allow,
+            scan_command: None,
+            clamd_address: None,
+            scan_timeout_secs: 30,
+            reject_on_scan_error: true,
+            storage: StorageKind::Filesystem,
+            s3: None,
+            content_security_policy: None,
+            media_base_url: None,
+            referrer_policy: "same-origin".to_string(),
+            storage_quota_bytes: None,
+            evict_archived_files: false,
+            geoip_database: None,
+            slow_query_ms: 50,
+            integrity_check: false,
+            read_only: false,
+            spam_score_threshold: 0,
+            spam_words: Vec::new(),
+            max_links: 3,
+            link_limit: LinkLimit::Reject,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    default: AppConfig,
+}
+
+// Creates a directory the server writes to if needed and checks we can
+fn check_writable_dir(dir: &str) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let probe = Path::new(dir).join(".write-test");
+    std::fs::write(&probe, b"").map_err(|e| e.to_string())?;
+    std::fs::remove_file(&probe).map_err(|e| e.to_string())
+}
+
+// Whether `dir` is ./static or inside it, where the static mount would
+// serve its files without the checks and headers of the board's own routes
+fn inside_static(dir: &str) -> bool {
+    match (Path::new(dir).canonicalize(), Path::new(STATIC_DIR).canonicalize()) {
+        (Ok(dir), Ok(public)) => dir.starts_with(public),
+        _ => false,
+    }
+}
+
+impl AppConfig {
+    // Every problem with the config, so they can all be fixed in one go
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.posts_per_page == 0 {
+            problems.push("posts_per_page must be greater than 0".to_string());
+        }
+        if self.max_upload_bytes == 0 {
+            problems.push("max_upload_bytes must be greater than 0".to_string());
+        }
+        for (route, limit) in &self.body_limits {
+            if !route.starts_with('/') {
+                problems.push(format!("body_limits: {:?} is not a route pattern (they start with /)", route));
+            }
+            if *limit == 0 {
+                problems.push(format!("body_limits: the limit for {} must be greater than 0", route));
+            }
+        }
+        if self.maintenance_hour > 23 {
+            problems.push(format!("maintenance_hour must be between 0 and 23 (got {})", self.maintenance_hour));
+        }
+        if !(0.0..=100.0).contains(&self.webp_quality) {
+            problems.push(format!("webp_quality must be between 0 and 100 (got {})", self.webp_quality));
+        }
+        if self.admin_password.as_deref().is_some_and(str::is_empty) {
+            problems.push("admin_password must not be empty; leave it unset to disable the admin routes".to_string());
+        }
+        if self.post_password.as_deref().is_some_and(|hash| hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit())) {
+            problems.push("post_password must be the SHA-256 of the password as 64 hex characters".to_string());
+        }
+        if self.ffmpeg_path.as_deref().is_some_and(str::is_empty) {
+            problems.push("ffmpeg_path must not be empty; leave it unset to skip poster frames".to_string());
+        }
+        if self.site_host.as_deref().is_some_and(|host| host.is_empty() || host.contains(['/', ':', ' '])) {
+            problems.push("site_host must be a bare host name such as board.example.com".to_string());
+        }
+        if self.external_link_rel.contains('"') {
+            problems.push("external_link_rel must not contain quotes".to_string());
+        }
+        if self.post_burst > 0 && self.post_refill_secs == 0 {
+            problems.push("post_refill_secs must be at least 1 while post_burst is set".to_string());
+        }
+        for proxy in self.trusted_proxies.iter().filter(|proxy| !valid_proxy(proxy)) {
+            problems.push(format!("trusted_proxies: {} is not an IP address or range such as 10.0.0.0/8", proxy));
+        }
+        for host in self.embed_hosts.iter().filter(|host| !EMBED_HOSTS.contains(&host.to_ascii_lowercase().as_str())) {
+            problems.push(format!("embed_hosts: {} is not a supported video site", host));
+        }
+        for (index, board) in self.boards.iter().enumerate() {
+            if !valid_slug(&board.slug) {
+                problems.push(format!("boards: {:?} is not a usable slug (lowercase letters, digits, - and _, not a route name)", board.slug));
+            } else if self.boards[..index].iter().any(|other| other.slug == board.slug) {
+                problems.push(format!("boards: {} is listed twice", board.slug));
+            }
+        }
+        if !self.boards.iter().any(|board| board.slug == DEFAULT_BOARD) {
+            problems.push(format!("boards must include the {} board", DEFAULT_BOARD));
+        }
+        for (index, file_type) in self.file_types.iter().enumerate() {
+            let extension = &file_type.extension;
+            if !valid_extension(extension) {
+                problems.push(format!("file_types: {:?} is not a usable extension (up to 10 letters and digits, no dot)", extension));
+            } else if self.file_types[..index].iter().any(|other| other.extension.eq_ignore_ascii_case(extension)) {
+                problems.push(format!("file_types: {} is listed twice", extension));
+            }
+            if !file_type.mime.contains('/') || file_type.mime.contains(',') {
+                problems.push(format!("file_types: {} has an invalid mime type {:?}", extension, file_type.mime));
+            }
+            for signature in file_type.magic.iter().filter(|signature| !valid_signature(signature)) {
+                problems.push(format!("file_types: {} has an invalid signature {:?} (hex bytes, ?? for any byte)", extension, signature));
+            }
+            if file_type.max_bytes == Some(0) {
+                problems.push(format!("file_types: {} max_bytes must be greater than 0", extension));
+            }
+        }
+        if self.uploads_enabled && self.file_types.is_empty() {
+            problems.push("file_types must not be empty while uploads_enabled is on".to_string());
+        }
+        if self.scan_command.as_deref().is_some_and(|command| command.trim().is_empty()) {
+            problems.push("scan_command must not be empty; leave it unset to skip virus scanning".to_string());
+        }
+        if self.clamd_address.as_deref().is_some_and(|address| address.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err())) {
+            problems.push("clamd_address must be host:port".to_string());
+        }
+        if self.scan_command.is_some() && self.clamd_address.is_some() {
+            problems.push("set only one of scan_command and clamd_address".to_string());
+        }
+        if self.scan_timeout_secs == 0 {
+            problems.push("scan_timeout_secs must be greater than 0".to_string());
+        }
+        if self.storage == StorageKind::S3 {
+            match &self.s3 {
+                None => problems.push("storage = \"s3\" needs a [default.s3] table".to_string()),
+                Some(s3) => {
+                    if !s3.endpoint.starts_with("http://") && !s3.endpoint.starts_with("https://") {
+                        problems.push("s3.endpoint must be an http(s) URL".to_string());
+                    }
+                    if s3.bucket.is_empty() {
+                        problems.push("s3.bucket must not be empty".to_string());
+                    }
+                    if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+                        problems.push("s3.access_key and s3.secret_key must be set".to_string());
+                    }
+                },
+            }
+        }
+        if self.geoip_database.as_deref().is_some_and(|path| !Path::new(path).is_file()) {
+            problems.push("geoip_database must be the path of a MaxMind .mmdb file".to_string());
+        }
+        if self.storage_quota_bytes == Some(0) {
+            problems.push("storage_quota_bytes must be greater than 0; leave it unset for no limit".to_string());
+        }
+        if self.media_base_url.as_deref().is_some_and(|base| !base.starts_with("http://") && !base.starts_with("https://")) {
+            problems.push("media_base_url must be an http(s) URL".to_string());
+        }
+        if self.content_security_policy.as_deref().is_some_and(|policy| policy.trim().is_empty() || HeaderValue::from_str(policy).is_err()) {
+            problems.push("content_security_policy must be a non-empty, single-line header value".to_string());
+        }
+        if self.referrer_policy.trim().is_empty() || HeaderValue::from_str(&self.referrer_policy).is_err() {
+            problems.push("referrer_policy must be a non-empty, single-line header value".to_string());
+        }
+        if self.require_op_image && !self.uploads_enabled {
+            problems.push("require_op_image needs uploads_enabled".to_string());
+        }
+        if self.uploads_enabled {
+            if let Err(e) = check_writable_dir(&self.upload_dir) {
+                problems.push(format!("upload_dir {} is not usable: {}", self.upload_dir, e));
+            } else if inside_static(&self.upload_dir) {
+                problems.push(format!("upload_dir {} is inside {}, which is served publicly; move the files to a separate directory such as ./uploads", self.upload_dir, STATIC_DIR));
+            }
+        }
+        if !self.image_proxy_hosts.is_empty() {
+            if let Err(e) = check_writable_dir(&self.image_proxy_cache_dir) {
+                problems.push(format!("image_proxy_cache_dir {} is not usable: {}", self.image_proxy_cache_dir, e));
+            } else if inside_static(&self.image_proxy_cache_dir) {
+                problems.push(format!("image_proxy_cache_dir {} is inside {}, which is served publicly", self.image_proxy_cache_dir, STATIC_DIR));
+            }
+            if self.image_proxy_cache_hours == 0 {
+                problems.push("image_proxy_cache_hours must be greater than 0".to_string());
+            }
+        }
+        problems
+    }
+}
+
+// Reads and validates the config. Startup stops with every problem listed
+// rather than failing later on the first request that trips over one.
+pub fn load_config() -> Result<AppConfig, String> {
+    match read_to_string(CONFIG_PATH) {
+        Ok(contents) => parse_config(&contents),
+        Err(_) => checked(AppConfig::default()),
+    }
+}
+
+fn parse_config(contents: &str) -> Result<AppConfig, String> {
+    let config = toml::from_str::<ConfigFile>(contents)
+        .map_err(|e| format!("Unable to parse {}: {}", CONFIG_PATH, e))?
+        .default;
+    checked(config)
+}
+
+fn checked(config: AppConfig) -> Result<AppConfig, String> {
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(format!("Invalid configuration in {}:\n  - {}", CONFIG_PATH, problems.join("\n  - ")));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unusable_upload_dir_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocker = dir.path().join("not-a-dir");
+        std::fs::write(&blocker, "").unwrap();
+        let config = AppConfig { upload_dir: blocker.join("uploads").to_string_lossy().into_owned(), ..AppConfig::default() };
+
+        let problems = config.validate();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with(&format!("upload_dir {} is not usable: ", config.upload_dir)), "{}", problems[0]);
+    }
+
+    #[test]
+    fn missing_upload_dir_is_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let upload_dir = dir.path().join("nested").join("uploads");
+        let config = AppConfig { upload_dir: upload_dir.to_string_lossy().into_owned(), ..AppConfig::default() };
+
+        assert_eq!(config.validate(), Vec::<String>::new());
+        assert!(upload_dir.is_dir());
+    }
+
+    #[test]
+    fn invalid_config_stops_startup_with_every_problem() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = format!("[default]\nposts_per_page = 0\nmax_upload_bytes = 0\nupload_dir = {:?}\n", dir.path().join("uploads"));
+
+        let error = parse_config(&contents).err().unwrap();
+        assert!(error.starts_with(&format!("Invalid configuration in {}:", CONFIG_PATH)), "{}", error);
+        assert!(error.contains("\n  - posts_per_page must be greater than 0"), "{}", error);
+        assert!(error.contains("\n  - max_upload_bytes must be greater than 0"), "{}", error);
+
+        let error = parse_config("[default]\nposts_per_page = \"ten\"\n").err().unwrap();
+        assert!(error.starts_with(&format!("Unable to parse {}: ", CONFIG_PATH)), "{}", error);
+    }
+}
@@ -0,0 +1,259 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::Data;
+use actix_web::{web, HttpResponse, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::admin::Admin;
+use crate::body_limit::BodyLimits;
+use crate::config::AppConfig;
+use crate::query_stats::{busiest_queries, BUCKETS_MS};
+use crate::quota::stored_bytes;
+use crate::reencode::format_size;
+use crate::scan::scan_counts;
+use crate::site::Site;
+use crate::{escape_html, render_template};
+
+// Posts and moderator actions listed on the dashboard
+const RECENT_LIMIT: i64 = 30;
+// Queries listed under query timings
+const QUERY_LIMIT: usize = 20;
+
+fn count(conn: &Connection, sql: &str) -> rusqlite::Result<i64> {
+    conn.query_row(sql, [], |row| row.get(0))
+}
+
+fn stats_html(conn: &Connection) -> rusqlite::Result<String> {
+    let stats = [
+        ("Active threads", "SELECT COUNT(*) FROM files WHERE parent_id = 0 AND archived = 0"),
+        ("Archived threads", "SELECT COUNT(*) FROM files WHERE parent_id = 0 AND archived = 1"),
+        ("Replies", "SELECT COUNT(*) FROM files WHERE parent_id != 0"),
+        ("Posts in the last 24 hours", "SELECT COUNT(*) FROM files WHERE created_at > datetime('now', '-1 day')"),
+        ("Posts with files", "SELECT COUNT(*) FROM files WHERE file_path IS NOT NULL"),
+        ("Hidden (shadow-banned or spam) posts", "SELECT COUNT(*) FROM files WHERE hidden = 1"),
+        ("Banned posters", "SELECT COUNT(*) FROM bans"),
+    ];
+    let mut counts = Vec::new();
+    for (label, sql) in stats {
+        counts.push((label, count(conn, sql)?));
+    }
+    Ok(counts.into_iter()
+        .chain(scan_counts().into_iter().map(|(label, count)| (label, count as i64)))
+        .map(|(label, count)| format!("<tr><th>{}</th><td>{}</td></tr>", label, count))
+        .collect())
+}
+
+// Upload storage in use, against the quota when there is one
+fn storage_html(conn: &Connection, config: &AppConfig) -> String {
+    let used = match stored_bytes(conn) {
+        Some(used) => format_size(used),
+        None => "Not counted yet".to_string(),
+    };
+    let quota = config.storage_quota_bytes.map(|quota| format!(" of {}", format_size(quota as i64))).unwrap_or_default();
+    format!("<tr><th>Upload storage used</th><td>{}{}</td></tr>", used, quota)
+}
+
+fn recent_posts_html(conn: &Connection) -> rusqlite::Result<String> {
+    let mut stmt = conn.prepare(
+        "SELECT files.id, files.parent_id, files.title, files.created_at, files.ip_hash, files.hidden, bans.shadow IS NOT NULL, files.file_path IS NOT NULL
+         FROM files LEFT JOIN bans ON bans.ip_hash = files.ip_hash
+         ORDER BY files.id DESC LIMIT ?1"
+    )?;
+    let posts = stmt.query_map(params![RECENT_LIMIT], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, bool>(5)?,
+            row.get::<_, bool>(6)?,
+            row.get::<_, bool>(7)?,
+        ))
+    })?;
+
+    let mut html = String::new();
+    for post in posts {
+        let (id, parent_id, title, created_at, ip_hash, hidden, banned, has_file) = post?;
+        let thread_id = if parent_id == 0 { id } else { parent_id };
+        let poster = ip_hash.as_deref().map(|hash| &hash[..hash.len().min(8)]).unwrap_or("-");
+        let mut actions = if banned {
+            format!(r#"<form action="/admin/unban/{}" method="post"><button type="submit">Unban</button></form>"#, id)
+        } else {
+            format!(
+                r#"<form action="/admin/ban/{id}" method="post"><button type="submit">Ban</button></form><form action="/admin/ban/{id}" method="post"><input type="hidden" name="shadow" value="true"><button type="submit">Shadow-ban</button></form>"#,
+                id = id
+            )
+        };
+        if parent_id == 0 {
+            actions.push_str(&format!(r#"<form action="/post/{}/autosage" method="post"><button type="submit">Toggle autosage</button></form>"#, id));
+        }
+        if hidden {
+            actions.push_str(&format!(r#"<form action="/admin/unhide/{}" method="post"><button type="submit">Unhide</button></form>"#, id));
+        }
+        if has_file {
+            actions.push_str(&format!(r#"<form action="/admin/block-file/{}" method="post"><button type="submit">Block file</button></form>"#, id));
+        }
+        actions.push_str(&format!(r#"<form action="/admin/delete/{}" method="post"><button type="submit">Delete</button></form>"#, id));
+        html.push_str(&format!(
+            r#"<tr><td><a href="/post/{}#p{}">{}</a></td><td>{}</td><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td></tr>"#,
+            thread_id, id, id, escape_html(&title), created_at, poster, if hidden { "hidden" } else { "" }, actions
+        ));
+    }
+    Ok(html)
+}
+
+fn mod_actions_html(conn: &Connection) -> rusqlite::Result<String> {
+    let mut stmt = conn.prepare("SELECT created_at, action, moderator, details FROM mod_actions ORDER BY id DESC LIMIT ?1")?;
+    let actions = stmt.query_map(params![RECENT_LIMIT], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, String>(3)?))
+    })?;
+
+    actions
+        .map(|action| {
+            let (created_at, action, moderator, details) = action?;
+            Ok(format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                created_at, escape_html(&action), escape_html(moderator.as_deref().unwrap_or("-")), escape_html(&details)
+            ))
+        })
+        .collect()
+}
+
+// Requests turned away for their size since startup, by route
+fn rejected_bodies_html(limits: &BodyLimits) -> String {
+    let rejections = limits.rejections();
+    if rejections.is_empty() {
+        return r#"<tr><td colspan="3">None since the server started</td></tr>"#.to_string();
+    }
+    rejections.iter()
+        .map(|(route, count)| format!("<tr><td><code>{}</code></td><td>{}</td><td>{}</td></tr>", escape_html(route), limits.limit_for(route), count))
+        .collect()
+}
+
+fn millis(duration: std::time::Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
+
+// Queries that have taken the most time since startup, with how their
+// timings are spread over the histogram buckets
+fn query_timings_html() -> String {
+    let queries = busiest_queries(QUERY_LIMIT);
+    if queries.is_empty() {
+        return format!(r#"<tr><td colspan="{}">None since the server started</td></tr>"#, BUCKETS_MS.len() + 5);
+    }
+    queries.iter()
+        .map(|(sql, stats)| {
+            let average = stats.total / stats.calls.max(1) as u32;
+            let buckets: String = stats.buckets.iter().map(|count| format!("<td>{}</td>", count)).collect();
+            format!(
+                "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>{}</tr>",
+                escape_html(sql), stats.calls, millis(stats.total), millis(average), millis(stats.max), buckets
+            )
+        })
+        .collect()
+}
+
+// Header row of the query timings table, one column per bucket
+fn query_buckets_html() -> String {
+    let mut html: String = BUCKETS_MS.iter().map(|bound| format!("<th>&lt; {} ms</th>", bound)).collect();
+    html.push_str(&format!("<th>&ge; {} ms</th>", BUCKETS_MS[BUCKETS_MS.len() - 1]));
+    html
+}
+
+// State of posting, with the button that flips it
+fn read_only_html(site: &Site) -> String {
+    let (state, button, enabled) = if site.read_only() {
+        ("Posting is disabled; the board is read-only.", "Enable posting", "false")
+    } else {
+        ("Posting is enabled.", "Disable posting", "true")
+    };
+    format!(
+        r#"<p>{}</p><form class="admin-read-only" action="/admin/read-only" method="post"><input type="hidden" name="enabled" value="{}"><button type="submit">{}</button></form>"#,
+        state, enabled, button
+    )
+}
+
+// Moderator overview: board statistics, the newest posts with ban,
+// autosage and delete controls, and the moderation log
+pub async fn dashboard(_admin: Admin, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, site: Data<Site>, limits: Data<BodyLimits>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+
+    let mut context = HashMap::from([
+        ("STATS", stats_html(&conn).map_err(ErrorInternalServerError)? + &storage_html(&conn, &config)),
+        ("REJECTED_BODIES", rejected_bodies_html(&limits)),
+        ("QUERY_BUCKETS", query_buckets_html()),
+        ("QUERIES", query_timings_html()),
+        ("POSTS", recent_posts_html(&conn).map_err(ErrorInternalServerError)?),
+        ("ACTIONS", mod_actions_html(&conn).map_err(ErrorInternalServerError)?),
+        ("READ_ONLY", read_only_html(&site)),
+    ]);
+    context.extend(site.chrome());
+    let body = render_template("templates/admin.html", &context);
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[actix_web::test]
+    async fn only_moderators_get_the_dashboard() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let post = multipart(&[("title", "needs a look"), ("message", "body"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", post, "10.0.0.1:4000").to_request()).await.status(), 303);
+        let dashboard = |authorization: Option<String>| {
+            let request = TestRequest::get().uri("/admin");
+            match authorization {
+                Some(value) => request.insert_header((header::AUTHORIZATION, value)),
+                None => request,
+            }.to_request()
+        };
+
+        let response = call_service(&app, dashboard(None)).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(response.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Basic realm=\"admin\"");
+        assert_eq!(call_service(&app, dashboard(Some("Bearer wrong".to_string()))).await.status(), 401);
+        let wrong_basic = format!("Basic {}", STANDARD.encode("mod:wrong"));
+        assert_eq!(call_service(&app, dashboard(Some(wrong_basic))).await.status(), 401);
+
+        for authorization in ["Bearer letmein".to_string(), format!("Basic {}", STANDARD.encode("mod:letmein"))] {
+            let response = call_service(&app, dashboard(Some(authorization))).await;
+            assert_eq!(response.status(), 200);
+            let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert!(page.contains("<title>Moderation</title>"));
+            assert!(page.contains("<tr><th>Active threads</th><td>1</td></tr>"));
+            assert!(page.contains("<td>needs a look</td>"));
+            assert!(page.contains(r#"<form action="/admin/ban/1" method="post">"#));
+        }
+    }
+
+    #[actix_web::test]
+    async fn remembered_basic_auth_cannot_be_used_from_other_sites() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let basic = format!("Basic {}", STANDARD.encode("mod:letmein"));
+        let set_read_only = |fetch_site: &str| TestRequest::post()
+            .uri("/admin/read-only")
+            .insert_header((header::AUTHORIZATION, basic.clone()))
+            .insert_header(("sec-fetch-site", fetch_site.to_string()))
+            .set_form([("enabled", "true")])
+            .to_request();
+
+        assert_eq!(call_service(&app, set_read_only("cross-site")).await.status(), 403);
+        assert!(!shared.site.read_only());
+        assert_eq!(call_service(&app, set_read_only("same-origin")).await.status(), 200);
+        assert!(shared.site.read_only());
+    }
+}
@@ -0,0 +1,383 @@
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, ToSql};
+
+use crate::attachment::{attachment_from_row, Attachment, ATTACHMENT_COLUMNS};
+use crate::bans::visible_sql;
+use crate::upload::StoredUpload;
+use crate::ListingQuery;
+
+// Queries for threads and posts, shared by the pages, fragments and the
+// posting handler. Every function sees posts the way `viewer` (an IP hash)
+// does where one is taken: everything not hidden, plus their own posts.
+
+// A post as shown on a thread page
+pub struct ThreadPost {
+    pub id: i32,
+    pub title: String,
+    pub message: String,
+    pub created_at: String,
+    // Posted by a moderator, so rendered with the wider HTML allowlist
+    pub by_admin: bool,
+    // ISO code from the GeoIP lookup
+    pub country: Option<String>,
+    // IP hash of whoever posted it, for telling posters apart; never shown
+    pub poster: Option<String>,
+    pub attachment: Option<Attachment>,
+}
+
+// Followed by ATTACHMENT_COLUMNS in queries
+const THREAD_POST_COLUMNS: &str = "id, title, message, created_at, by_admin, country, ip_hash";
+
+fn thread_post_from_row(row: &Row, start: usize) -> Result<ThreadPost> {
+    Ok(ThreadPost {
+        id: row.get(start)?,
+        title: row.get(start + 1)?,
+        message: row.get(start + 2)?,
+        created_at: row.get(start + 3)?,
+        by_admin: row.get(start + 4)?,
+        country: row.get(start + 5)?,
+        poster: row.get(start + 6)?,
+        attachment: attachment_from_row(row, start + 7)?,
+    })
+}
+
+// A thread with the replies its viewer can see, oldest first
+pub struct Thread {
+    pub op: ThreadPost,
+    pub replies: Vec<ThreadPost>,
+    pub autosage: bool,
+}
+
+// A thread as listed on its board
+pub struct ThreadSummary {
+    pub id: i32,
+    // Random display id shown in the coloured box
+    pub post_id: String,
+    pub title: String,
+    pub message: String,
+    pub created_at: String,
+    pub last_reply_at: String,
+    pub autosage: bool,
+    pub reply_count: i32,
+    pub by_admin: bool,
+    pub country: Option<String>,
+    pub attachment: Option<Attachment>,
+}
+
+// A post about to be stored; `parent_id` is 0 for a new thread
+pub struct NewPost<'a> {
+    pub post_id: &'a str,
+    pub parent_id: i32,
+    pub title: &'a str,
+    pub message: &'a str,
+    pub upload: Option<&'a StoredUpload>,
+    pub alt_text: Option<&'a str>,
+    pub ip_hash: &'a str,
+    pub hidden: bool,
+    pub autosage: bool,
+    pub by_admin: bool,
+    pub board: &'a str,
+    pub country: Option<&'a str>,
+}
+
+// Full-text query for a search box entry: every word must appear, as a
+// word or the start of one. Words are quoted so FTS syntax typed into the
+// box is taken literally.
+fn fts_query(search: &str) -> String {
+    search.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Search hits with their rank, joined onto `files` when a search is given.
+// A word in the title weighs ten times one in the message; lower ranks
+// are better.
+const SEARCH_JOIN: &str = "JOIN (SELECT rowid AS hit, bm25(posts_fts, 10.0, 1.0) AS rank FROM posts_fts WHERE posts_fts MATCH ?5) ON hit = files.id";
+
+// One page of `board`'s threads in the listing's order
+pub fn list_threads(conn: &Connection, board: &str, viewer: &str, listing: &ListingQuery, per_page: usize) -> Result<Vec<ThreadSummary>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, post_id, title, message, created_at, last_reply_at, autosage, reply_count, by_admin, country, {} FROM files {}
+         WHERE parent_id = 0 AND archived = 0 AND board_slug = ?4 AND {}
+         ORDER BY {} LIMIT ?1 OFFSET ?2",
+        ATTACHMENT_COLUMNS, if listing.search.is_some() { SEARCH_JOIN } else { "" }, visible_sql(3), listing.order_by
+    ))?;
+    let (limit, offset) = (per_page as i64, ((listing.page - 1) * per_page) as i64);
+    let search = listing.search.map(fts_query);
+    let mut args: Vec<&dyn ToSql> = vec![&limit, &offset, &viewer, &board];
+    if let Some(search) = &search {
+        args.push(search);
+    }
+    let threads = stmt.query_map(&*args, |row| {
+        Ok(ThreadSummary {
+            id: row.get(0)?,
+            post_id: row.get(1)?,
+            title: row.get(2)?,
+            message: row.get(3)?,
+            created_at: row.get(4)?,
+            last_reply_at: row.get(5)?,
+            autosage: row.get(6)?,
+            reply_count: row.get(7)?,
+            by_admin: row.get(8)?,
+            country: row.get(9)?,
+            attachment: attachment_from_row(row, 10)?,
+        })
+    })?;
+    threads.collect()
+}
+
+// Number of threads on `board` matching a search
+pub fn count_matches(conn: &Connection, board: &str, viewer: &str, search: &str) -> Result<usize> {
+    conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM files
+             JOIN (SELECT rowid AS hit FROM posts_fts WHERE posts_fts MATCH ?2) ON hit = files.id
+             WHERE parent_id = 0 AND archived = 0 AND board_slug = ?3 AND {}",
+            visible_sql(1)
+        ),
+        params![viewer, fts_query(search), board],
+        |row| row.get::<_, i64>(0),
+    ).map(|count| count as usize)
+}
+
+// Thread `id`; None when there is no such thread or its opening post is
+// hidden from the viewer
+pub fn get_thread(conn: &Connection, id: i32, viewer: &str) -> Result<Option<Thread>> {
+    let op = conn.query_row(
+        &format!("SELECT autosage, {}, {} FROM files WHERE id = ?1 AND parent_id = 0 AND {}", THREAD_POST_COLUMNS, ATTACHMENT_COLUMNS, visible_sql(2)),
+        params![id, viewer],
+        |row| Ok((row.get::<_, bool>(0)?, thread_post_from_row(row, 1)?)),
+    ).optional()?;
+    let Some((autosage, op)) = op else {
+        return Ok(None);
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, {} FROM files WHERE parent_id = ?1 AND {} ORDER BY created_at ASC, id ASC",
+        THREAD_POST_COLUMNS, ATTACHMENT_COLUMNS, visible_sql(2)
+    ))?;
+    let replies = stmt.query_map(params![id, viewer], |row| thread_post_from_row(row, 0))?.collect::<Result<_>>()?;
+    Ok(Some(Thread { op, replies, autosage }))
+}
+
+// A single post with its thread id (0 for an opening post) and, for an
+// opening post, whether the thread is on autosage
+pub fn get_post(conn: &Connection, id: i32, viewer: &str) -> Result<Option<(i32, bool, ThreadPost)>> {
+    conn.query_row(
+        &format!("SELECT parent_id, autosage, {}, {} FROM files WHERE id = ?1 AND {}", THREAD_POST_COLUMNS, ATTACHMENT_COLUMNS, visible_sql(2)),
+        params![id, viewer],
+        |row| Ok((row.get(0)?, row.get(1)?, thread_post_from_row(row, 2)?)),
+    ).optional()
+}
+
+// Position of a reply among the replies of `thread_id` the viewer can see,
+// counting from 1, the way the thread page numbers them
+pub fn reply_number(conn: &Connection, thread_id: i32, reply: &ThreadPost, viewer: &str) -> Result<usize> {
+    conn.query_row(
+        &format!("SELECT COUNT(*) FROM files WHERE parent_id = ?1 AND (created_at, id) <= (?2, ?3) AND {}", visible_sql(4)),
+        params![thread_id, reply.created_at, reply.id, viewer],
+        |row| row.get(0),
+    )
+}
+
+// Cached number of visible replies to a thread; 0 for a thread that
+// doesn't exist
+pub fn count_replies(conn: &Connection, thread_id: i32) -> Result<i32> {
+    conn.query_row("SELECT reply_count FROM files WHERE id = ?1", params![thread_id], |row| row.get(0))
+        .optional()
+        .map(Option::unwrap_or_default)
+}
+
+// A post in thread `thread_id`, its opening post included, carrying the
+// file with this hash; hidden posts count too
+pub fn post_with_file_in_thread(conn: &Connection, thread_id: i32, hash: &str) -> Result<Option<i32>> {
+    conn.query_row(
+        "SELECT id FROM files WHERE file_hash = ?1 AND (id = ?2 OR parent_id = ?2) ORDER BY id LIMIT 1",
+        params![hash, thread_id],
+        |row| row.get(0),
+    ).optional()
+}
+
+// Whether `e` is a reply being stored for a thread that doesn't exist
+pub fn is_foreign_key_violation(e: &rusqlite::Error) -> bool {
+    e.sqlite_error().is_some_and(|e| e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY)
+}
+
+// Stores a new post and returns its number. A visible reply counts
+// towards its thread and bumps it, unless the thread is on autosage.
+pub fn insert_post(conn: &Connection, post: &NewPost) -> Result<i32> {
+    let upload = post.upload;
+    conn.execute(
+        "INSERT INTO files (post_id, parent_id, title, message, file_path, original_name, original_format, original_size, thumbnail_path, width, height, alt_text, ip_hash, hidden, autosage, by_admin, board_slug, file_hash, page_count, country, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, CURRENT_TIMESTAMP)",
+        params![
+            post.post_id, post.parent_id, post.title, post.message,
+            upload.map(|u| &u.file_path),
+            upload.and_then(|u| u.original_name.as_ref()),
+            upload.and_then(|u| u.original_format.as_ref()),
+            upload.and_then(|u| u.original_size),
+            upload.and_then(|u| u.thumbnail_path.as_ref()),
+            upload.and_then(|u| u.width),
+            upload.and_then(|u| u.height),
+            post.alt_text,
+            post.ip_hash,
+            post.hidden,
+            post.autosage,
+            post.by_admin,
+            post.board,
+            upload.and_then(|u| u.file_hash.as_ref()),
+            upload.and_then(|u| u.page_count),
+            post.country,
+        ],
+    )?;
+    let id = conn.last_insert_rowid() as i32;
+
+    // Hidden posts never bump or count
+    if post.parent_id != 0 && !post.hidden {
+        conn.execute(
+            "UPDATE files SET reply_count = reply_count + 1,
+                last_reply_at = CASE WHEN autosage = 0 THEN CURRENT_TIMESTAMP ELSE last_reply_at END
+             WHERE id = ?1",
+            params![post.parent_id],
+        )?;
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::testing::test_db;
+
+    fn post(parent_id: i32, title: &str) -> NewPost<'_> {
+        NewPost {
+            post_id: "test",
+            parent_id,
+            title,
+            message: "body",
+            upload: None,
+            alt_text: None,
+            ip_hash: "poster",
+            hidden: false,
+            autosage: false,
+            by_admin: false,
+            board: "main",
+            country: None,
+        }
+    }
+
+    #[test]
+    fn cached_reply_counts_match_the_replies() {
+        let conn = test_db();
+        let threads = [insert_post(&conn, &post(0, "one")).unwrap(), insert_post(&conn, &post(0, "two")).unwrap()];
+        for n in 0..60 {
+            let reply = NewPost { hidden: n % 7 == 0, ..post(threads[n % 2], "reply") };
+            insert_post(&conn, &reply).unwrap();
+        }
+
+        for thread in threads {
+            let actual: i32 = conn.query_row("SELECT COUNT(*) FROM files WHERE parent_id = ?1 AND hidden = 0", params![thread], |row| row.get(0)).unwrap();
+            assert_eq!(count_replies(&conn, thread).unwrap(), actual);
+        }
+        assert_eq!(count_replies(&conn, threads[0]).unwrap() + count_replies(&conn, threads[1]).unwrap(), 60 - 9);
+    }
+
+    fn listing(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn titles(threads: &[ThreadSummary]) -> Vec<&str> {
+        threads.iter().map(|thread| thread.title.as_str()).collect()
+    }
+
+    #[test]
+    fn threads_are_listed_by_board_page_and_order() {
+        let conn = test_db();
+        for (n, title) in ["one", "two", "three", "four", "five"].iter().enumerate() {
+            let id = insert_post(&conn, &post(0, title)).unwrap();
+            conn.execute("UPDATE files SET last_reply_at = datetime('now', ?2) WHERE id = ?1", params![id, format!("-{} hours", 10 - n)]).unwrap();
+        }
+        conn.execute("INSERT INTO boards (slug, name) VALUES ('tech', 'Tech') ON CONFLICT DO NOTHING", []).unwrap();
+        insert_post(&conn, &NewPost { board: "tech", ..post(0, "elsewhere") }).unwrap();
+        let hidden = insert_post(&conn, &NewPost { ip_hash: "spammer", hidden: true, ..post(0, "hidden") }).unwrap();
+        conn.execute("UPDATE files SET last_reply_at = datetime('now', '-30 minutes') WHERE id = ?1", params![hidden]).unwrap();
+        let two = conn.query_row("SELECT id FROM files WHERE title = 'two'", [], |row| row.get(0)).unwrap();
+        insert_post(&conn, &post(two, "reply")).unwrap();
+
+        let page = |params: &[(&str, &str)], viewer: &str| -> Vec<String> {
+            let query = listing(params);
+            let threads = list_threads(&conn, "main", viewer, &ListingQuery::from_query(&query, None), 2).unwrap();
+            titles(&threads).iter().map(|title| title.to_string()).collect()
+        };
+        assert_eq!(page(&[], "poster"), ["two", "five"]);
+        assert_eq!(page(&[("page", "2")], "poster"), ["four", "three"]);
+        assert_eq!(page(&[("page", "3")], "poster"), ["one"]);
+        assert_eq!(page(&[("sort", "new")], "poster"), ["five", "four"]);
+        // Hidden threads are listed for their poster only
+        assert_eq!(page(&[], "spammer"), ["two", "hidden"]);
+
+        let tech = list_threads(&conn, "tech", "poster", &ListingQuery::from_query(&listing(&[]), None), 10).unwrap();
+        assert_eq!(titles(&tech), ["elsewhere"]);
+        assert_eq!(tech[0].reply_count, 0);
+    }
+
+    #[test]
+    fn searches_match_words_literally() {
+        let conn = test_db();
+        insert_post(&conn, &NewPost { message: "the quick brown fox", ..post(0, "animals") }).unwrap();
+        insert_post(&conn, &NewPost { message: "quickly now", ..post(0, "hurry") }).unwrap();
+        insert_post(&conn, &NewPost { message: "slow", ..post(0, "snails") }).unwrap();
+
+        let search = |q: &str| -> Vec<String> {
+            let query = listing(&[("q", q)]);
+            let threads = list_threads(&conn, "main", "poster", &ListingQuery::from_query(&query, None), 10).unwrap();
+            assert_eq!(count_matches(&conn, "main", "poster", q).unwrap(), threads.len(), "{}", q);
+            titles(&threads).iter().map(|title| title.to_string()).collect()
+        };
+        assert_eq!(search("quick").len(), 2);
+        assert_eq!(search("quick fox"), ["animals"]);
+        // FTS syntax is taken as text rather than failing
+        assert!(search("fox OR \"slow").is_empty());
+        assert!(search("NEAR(").is_empty());
+    }
+
+    #[test]
+    fn threads_and_posts_are_seen_as_their_viewer_sees_them() {
+        let conn = test_db();
+        let thread = insert_post(&conn, &post(0, "thread")).unwrap();
+        let first = insert_post(&conn, &post(thread, "first")).unwrap();
+        let hidden = insert_post(&conn, &NewPost { ip_hash: "spammer", hidden: true, ..post(thread, "hidden") }).unwrap();
+        let last = insert_post(&conn, &post(thread, "last")).unwrap();
+
+        let replies = |viewer: &str| -> Vec<String> {
+            get_thread(&conn, thread, viewer).unwrap().unwrap().replies.iter().map(|reply| reply.title.clone()).collect()
+        };
+        assert_eq!(replies("poster"), ["first", "last"]);
+        assert_eq!(replies("spammer"), ["first", "hidden", "last"]);
+        assert!(get_thread(&conn, first, "poster").unwrap().is_none());
+        assert!(get_thread(&conn, 999, "poster").unwrap().is_none());
+
+        let (parent, _, reply) = get_post(&conn, last, "poster").unwrap().unwrap();
+        assert_eq!(parent, thread);
+        assert_eq!(reply_number(&conn, thread, &reply, "poster").unwrap(), 2);
+        assert_eq!(reply_number(&conn, thread, &reply, "spammer").unwrap(), 3);
+        assert!(get_post(&conn, hidden, "poster").unwrap().is_none());
+        assert_eq!(get_post(&conn, thread, "poster").unwrap().unwrap().0, 0);
+        assert_eq!(count_replies(&conn, thread).unwrap(), 2);
+        assert_eq!(count_replies(&conn, 999).unwrap(), 0);
+    }
+
+    #[test]
+    fn files_are_found_anywhere_in_their_thread() {
+        let conn = test_db();
+        let thread = insert_post(&conn, &post(0, "thread")).unwrap();
+        let other = insert_post(&conn, &post(0, "other")).unwrap();
+        let reply = insert_post(&conn, &post(thread, "reply")).unwrap();
+        conn.execute("UPDATE files SET file_hash = 'abc' WHERE id IN (?1, ?2)", params![reply, other]).unwrap();
+
+        assert_eq!(post_with_file_in_thread(&conn, thread, "abc").unwrap(), Some(reply));
+        assert_eq!(post_with_file_in_thread(&conn, other, "abc").unwrap(), Some(other));
+        assert_eq!(post_with_file_in_thread(&conn, thread, "def").unwrap(), None);
+    }
+}
@@ -0,0 +1,119 @@
+use rusqlite::params;
+use std::time::Duration;
+
+use crate::filetypes::{is_image, FileType};
+use crate::jobs::{AppState, Job};
+
+// Rows probed per run of the backfill job
+const BACKFILL_BATCH: i64 = 500;
+
+// Width and height of an image from its header, without decoding it
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let size = imagesize::blob_size(data).ok()?;
+    Some((size.width.try_into().ok()?, size.height.try_into().ok()?))
+}
+
+// The image shown for an attachment: the file itself, or a video's poster frame
+fn displayed_image<'a>(types: &[FileType], file_path: &'a str, thumbnail_path: Option<&'a str>) -> Option<&'a str> {
+    if is_image(types, file_path) {
+        Some(file_path)
+    } else {
+        thumbnail_path
+    }
+}
+
+// Fills in width and height for attachments stored before dimensions were
+// recorded. Files that can't be read (remote, missing, not an image) get
+// 0x0 so they aren't probed again; 0 renders the same as unknown.
+pub struct DimensionsBackfillJob;
+
+impl Job for DimensionsBackfillJob {
+    fn name(&self) -> &'static str {
+        "dimensions-backfill"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn first_delay(&self, _state: &AppState) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        // Read the files without holding the connection
+        let rows: Vec<(i32, String, Option<String>)> = {
+            let conn = state.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT id, file_path, thumbnail_path FROM files WHERE file_path IS NOT NULL AND width IS NULL LIMIT ?1")
+                .map_err(|e| e.to_string())?;
+            let rows = stmt.query_map(params![BACKFILL_BATCH], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .map_err(|e| e.to_string())?
+                .filter_map(|row| row.ok())
+                .collect();
+            rows
+        };
+
+        let probed: Vec<(i32, (u32, u32))> = rows.iter()
+            .map(|(id, file_path, thumbnail_path)| {
+                let dimensions = displayed_image(&state.config.file_types, file_path, thumbnail_path.as_deref())
+                    .filter(|path| !path.starts_with("http://") && !path.starts_with("https://"))
+                    .and_then(|path| state.storage.get(path).ok())
+                    .and_then(|data| image_dimensions(&data));
+                (*id, dimensions.unwrap_or((0, 0)))
+            })
+            .collect();
+
+        let conn = state.conn.lock().unwrap();
+        for (id, (width, height)) in &probed {
+            conn.execute("UPDATE files SET width = ?2, height = ?3 WHERE id = ?1", params![id, width, height])
+                .map_err(|e| e.to_string())?;
+        }
+
+        println!("Recorded dimensions for {} attachments", probed.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    #[actix_web::test]
+    async fn images_carry_their_size_and_old_rows_get_it_backfilled() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        for (title, shade) in [("sized", 1), ("old", 2), ("lost", 3)] {
+            let body = multipart(&[("title", title), ("message", "pic"), ("parent_id", "0")], Some(("pic.png", "image/png", &png(shade))));
+            assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        }
+        let path_of = |title: &str| -> String { shared.conn.lock().unwrap().query_row("SELECT file_path FROM files WHERE title = ?1", [title], |row| row.get(0)).unwrap() };
+        let img = |title: &str, size: &str| format!(r#"<img src="{}" alt="attachment" loading="lazy"{}>"#, crate::upload_url(&shared.config, &path_of(title)), size);
+
+        // Rows from before dimensions were stored, one whose file is gone
+        shared.conn.lock().unwrap().execute("UPDATE files SET width = NULL, height = NULL WHERE title IN ('old', 'lost')", []).unwrap();
+        std::fs::remove_file(path_of("lost")).unwrap();
+        let index = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(index.contains(&img("sized", r#" width="8" height="8""#)));
+        assert!(index.contains(&img("old", "")));
+
+        let state = AppState { conn: shared.conn.clone(), config: shared.config.clone(), storage: shared.storage.clone() };
+        DimensionsBackfillJob.run(&state).unwrap();
+
+        let dimensions = |title: &str| -> (Option<u32>, Option<u32>) {
+            shared.conn.lock().unwrap().query_row("SELECT width, height FROM files WHERE title = ?1", [title], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        assert_eq!(dimensions("old"), (Some(8), Some(8)));
+        assert_eq!(dimensions("lost"), (Some(0), Some(0)));
+
+        let id: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'old'", [], |row| row.get(0)).unwrap();
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", id)).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(&img("old", r#" width="8" height="8""#)));
+        let index = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(index.contains(&img("lost", "")));
+    }
+}
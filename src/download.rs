@@ -0,0 +1,173 @@
+use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType, HeaderValue, LOCATION, X_CONTENT_TYPE_OPTIONS};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::AppConfig;
+use crate::filetypes::{extension_of, file_kind, find_type, FileKind};
+use crate::storage::storage;
+
+// Resolves `name` inside the upload directory, refusing anything that
+// escapes it once symlinks and `..` components are resolved.
+fn resolve_upload(upload_dir: &str, name: &str) -> Option<PathBuf> {
+    let upload_dir = Path::new(upload_dir).canonicalize().ok()?;
+    let path = upload_dir.join(name).canonicalize().ok()?;
+    (path.starts_with(&upload_dir) && path.is_file()).then_some(path)
+}
+
+// The name the file had on the uploader's machine, if we recorded one
+fn original_name(conn: &Connection, name: &str) -> Option<String> {
+    conn.query_row(
+        "SELECT original_name FROM files WHERE substr(file_path, -length(?1) - 1) = '/' || ?1 AND original_name IS NOT NULL",
+        params![name],
+        |row| row.get(0),
+    ).ok()
+}
+
+// Whether `name` is a file or thumbnail some post or pending upload refers
+// to, so only stored files get a link into remote storage
+fn is_recorded(conn: &Connection, name: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM files WHERE substr(file_path, -length(?1) - 1) = '/' || ?1 OR substr(thumbnail_path, -length(?1) - 1) = '/' || ?1
+         UNION ALL SELECT 1 FROM pending_uploads WHERE substr(file_path, -length(?1) - 1) = '/' || ?1 OR substr(thumbnail_path, -length(?1) - 1) = '/' || ?1
+         LIMIT 1",
+        params![name],
+        |_| Ok(()),
+    ).is_ok()
+}
+
+// Re-encoded uploads keep the uploader's name but take the extension of
+// the file we actually serve, so "shot.png" downloads as "shot.webp".
+fn download_name(original: &str, served: &str) -> String {
+    match (Path::new(original).extension(), Path::new(served).extension()) {
+        (Some(from), Some(to)) if from != to => Path::new(original).with_extension(to).to_string_lossy().into_owned(),
+        _ => original.to_string(),
+    }
+}
+
+// Serves an upload inline, or as an attachment with `?download=1` and for
+// download-only types. The Content-Type comes from `file_types`, falling
+// back to NamedFile's guess; NamedFile also handles Range requests.
+// Files kept in remote storage are a redirect to wherever it serves them.
+pub async fn serve_file(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, path: web::Path<String>, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+    let name = path.into_inner();
+    let Some(file_path) = resolve_upload(&config.upload_dir, &name) else {
+        if !is_recorded(&conn.lock().unwrap(), &name) {
+            return HttpResponse::NotFound().body("File not found.");
+        }
+        return match storage(&req).get_url(&name) {
+            Some(url) => HttpResponse::Found().insert_header((LOCATION, url)).finish(),
+            None => HttpResponse::NotFound().body("File not found."),
+        };
+    };
+    let Ok(mut file) = NamedFile::open(&file_path) else {
+        return HttpResponse::NotFound().body("File not found.");
+    };
+    if let Some(mime) = find_type(&config.file_types, extension_of(&name)).and_then(|file_type| file_type.mime.parse().ok()) {
+        file = file.set_content_type(mime);
+    }
+
+    let download = query.get("download").is_some_and(|value| value == "1")
+        || file_kind(&config.file_types, &name) == FileKind::Download;
+    let disposition = if download {
+        let filename = original_name(&conn.lock().unwrap(), &name)
+            .map(|original| download_name(&original, &name))
+            .unwrap_or(name);
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        }
+    } else {
+        ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: vec![],
+        }
+    };
+
+    let mut response = file.set_content_disposition(disposition).respond_to(&req).map_into_boxed_body();
+    // Browsers must take the Content-Type as given, not sniff their own
+    response.headers_mut().insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::CONTENT_DISPOSITION;
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    use crate::storage::{S3Config, StorageKind};
+    use crate::testing::{shared, test_config};
+
+    #[actix_web::test]
+    async fn uploads_are_inline_unless_downloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let stored = Path::new(&shared.config.upload_dir).join("abc123.png");
+        std::fs::write(&stored, b"\x89PNG\r\n\x1a\n").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "not an upload").unwrap();
+        shared.conn.lock().unwrap().execute(
+            "INSERT INTO files (post_id, parent_id, title, message, file_path, original_name) VALUES ('p', 0, 't', 'm', ?1, 'holiday.png')",
+            params![stored.to_string_lossy()],
+        ).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/file/abc123.png").to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+        assert_eq!(response.headers().get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(response.headers().get(CONTENT_DISPOSITION).unwrap(), "inline");
+
+        let response = call_service(&app, TestRequest::get().uri("/file/abc123.png?download=1").to_request()).await;
+        assert_eq!(response.headers().get(CONTENT_DISPOSITION).unwrap(), "attachment; filename=\"holiday.png\"");
+
+        let response = call_service(&app, TestRequest::get().uri("/file/..%2Fsecret.txt").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn only_recorded_files_are_linked_into_the_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            storage: StorageKind::S3,
+            s3: Some(S3Config {
+                endpoint: "http://minio.local:9000".to_string(),
+                bucket: "uploads".to_string(),
+                region: "us-east-1".to_string(),
+                access_key: "key".to_string(),
+                secret_key: "secret".to_string(),
+                public_url: None,
+            }),
+            ..test_config(dir.path())
+        });
+        {
+            let conn = shared.conn.lock().unwrap();
+            conn.execute("INSERT INTO files (post_id, parent_id, title, message, file_path, thumbnail_path) VALUES ('p', 0, 't', 'm', 's3://uploads/posted.png', 's3://uploads/thumb_posted.png')", []).unwrap();
+            conn.execute("INSERT INTO pending_uploads (token, file_path, original_name, created_at) VALUES ('t', 's3://uploads/pending.png', 'p.png', CURRENT_TIMESTAMP)", []).unwrap();
+        }
+        let app = init_service(crate::app(&shared)).await;
+
+        for name in ["posted.png", "thumb_posted.png", "pending.png"] {
+            let response = call_service(&app, TestRequest::get().uri(&format!("/file/{}", name)).to_request()).await;
+            assert_eq!(response.status(), 302, "{}", name);
+            let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+            assert!(location.starts_with(&format!("http://minio.local:9000/uploads/{}?", name)), "{}", location);
+        }
+
+        // Any other key would otherwise get a signed link of its own
+        for name in ["backup.sql", "unknown.png", "posted.png.bak"] {
+            let response = call_service(&app, TestRequest::get().uri(&format!("/file/{}", name)).to_request()).await;
+            assert_eq!(response.status(), 404, "{}", name);
+        }
+    }
+
+    #[test]
+    fn download_names_follow_the_served_format() {
+        assert_eq!(download_name("shot.png", "abc.webp"), "shot.webp");
+        assert_eq!(download_name("shot.png", "abc.png"), "shot.png");
+        assert_eq!(download_name("README", "abc.txt"), "README");
+    }
+}
@@ -0,0 +1,183 @@
+use url::Url;
+
+use crate::config::AppConfig;
+use crate::sanitize::{external_link_attributes, rewrite_text};
+
+// Video links past this many in one post stay links
+const MAX_EMBEDS: usize = 2;
+
+// A video on one of the supported sites, identified only by its id. Embed
+// markup is built from the id, never from the URL the poster wrote.
+enum Video {
+    YouTube(String),
+    Vimeo(String),
+}
+
+impl Video {
+    fn embed_html(&self) -> String {
+        let (src, title) = match self {
+            Video::YouTube(id) => (format!("https://www.youtube-nocookie.com/embed/{}", id), "YouTube video"),
+            Video::Vimeo(id) => (format!("https://player.vimeo.com/video/{}?dnt=1", id), "Vimeo video"),
+        };
+        format!(
+            r#"<div class="video-embed"><iframe src="{}" title="{}" loading="lazy" referrerpolicy="strict-origin-when-cross-origin" allow="fullscreen; picture-in-picture" allowfullscreen></iframe></div>"#,
+            src, title
+        )
+    }
+
+    fn link(&self) -> String {
+        match self {
+            Video::YouTube(id) => format!("https://www.youtube.com/watch?v={}", id),
+            Video::Vimeo(id) => format!("https://vimeo.com/{}", id),
+        }
+    }
+}
+
+// Origins the embedded players load from, for the content security policy
+pub fn player_origins(config: &AppConfig) -> Vec<&'static str> {
+    let mut origins = Vec::new();
+    if !config.video_embeds {
+        return origins;
+    }
+    if ["youtube.com", "youtu.be"].iter().any(|host| host_allowed(config, host)) {
+        origins.push("https://www.youtube-nocookie.com");
+    }
+    if host_allowed(config, "vimeo.com") {
+        origins.push("https://player.vimeo.com");
+    }
+    origins
+}
+
+fn youtube_id(id: &str) -> Option<String> {
+    let valid = id.len() == 11 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+fn vimeo_id(id: &str) -> Option<String> {
+    let valid = !id.is_empty() && id.len() <= 12 && id.chars().all(|c| c.is_ascii_digit());
+    valid.then(|| id.to_string())
+}
+
+// Whether `host` is one of `embed_hosts` or its www./m. form
+fn host_allowed(config: &AppConfig, host: &str) -> bool {
+    let bare = host.strip_prefix("www.").or_else(|| host.strip_prefix("m.")).unwrap_or(host);
+    config.embed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(bare))
+}
+
+// The video a URL points at, if it is a plain http(s) link to an allowed
+// host. Hosts must match exactly, so lookalikes such as
+// youtube.com.example.net or youtube.com@example.net don't qualify.
+fn parse_video(config: &AppConfig, text: &str) -> Option<Video> {
+    // The text comes from sanitized HTML
+    let url = Url::parse(&text.replace("&amp;", "&")).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || !url.username().is_empty() || url.port().is_some() {
+        return None;
+    }
+    let host = url.host_str()?.to_ascii_lowercase();
+    if !host_allowed(config, &host) {
+        return None;
+    }
+    let mut segments = url.path_segments()?.filter(|segment| !segment.is_empty());
+    let first = segments.next();
+
+    match host.as_str() {
+        "youtube.com" | "www.youtube.com" | "m.youtube.com" => match first? {
+            "watch" => url.query_pairs().find(|(key, _)| key == "v").and_then(|(_, id)| youtube_id(&id)).map(Video::YouTube),
+            "shorts" | "embed" | "live" => youtube_id(segments.next()?).map(Video::YouTube),
+            _ => None,
+        },
+        "youtu.be" => youtube_id(first?).map(Video::YouTube),
+        "vimeo.com" | "www.vimeo.com" => vimeo_id(first?).map(Video::Vimeo),
+        _ => None,
+    }
+}
+
+fn embed_text(config: &AppConfig, text: &str, out: &mut String, budget: &mut usize) {
+    for word in text.split_inclusive(char::is_whitespace) {
+        let url_text = word.trim_end().trim_end_matches(['.', ',', '!', '?', ')']);
+        let video = if url_text.starts_with("http://") || url_text.starts_with("https://") { parse_video(config, url_text) } else { None };
+        match video {
+            Some(video) if *budget > 0 => {
+                out.push_str(&video.embed_html());
+                *budget -= 1;
+            },
+            Some(video) => out.push_str(&format!(r#"<a href="{}"{}>{}</a>"#, video.link(), external_link_attributes(config), url_text)),
+            None => {
+                out.push_str(word);
+                continue;
+            },
+        }
+        out.push_str(&word[url_text.len()..]);
+    }
+}
+
+// Turns bare video links in a rendered message into embeds, up to
+// MAX_EMBEDS per post. Links already in the markup and code are left alone.
+pub fn embed_videos(config: &AppConfig, html: &str) -> String {
+    if !config.video_embeds || config.embed_hosts.is_empty() {
+        return html.to_string();
+    }
+    let mut budget = MAX_EMBEDS;
+    rewrite_text(html, |text, out| embed_text(config, text, out, &mut budget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIP: &str = r#"<div class="video-embed"><iframe src="https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ""#;
+
+    #[test]
+    fn video_links_become_embeds_built_from_the_id() {
+        let config = AppConfig::default();
+        for link in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&amp;t=10",
+            "https://youtu.be/dQw4w9WgXcQ",
+            "http://m.youtube.com/shorts/dQw4w9WgXcQ",
+        ] {
+            let html = embed_videos(&config, &format!("watch {}!", link));
+            assert!(html.starts_with(&format!("watch {}", CLIP)), "{}", html);
+            assert!(html.ends_with("</iframe></div>!"));
+        }
+        assert!(embed_videos(&config, "https://vimeo.com/76979871").contains(r#"src="https://player.vimeo.com/video/76979871?dnt=1""#));
+    }
+
+    #[test]
+    fn lookalikes_and_odd_links_stay_text() {
+        let config = AppConfig::default();
+        for text in [
+            "https://youtube.com.example.net/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com@example.net/watch?v=dQw4w9WgXcQ",
+            "https://notyoutube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com:8080/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ\"onload=\"x",
+            "https://youtu.be/short",
+            "javascript://youtube.com/%0aalert(1)",
+            "https://vimeo.com/channels/staffpicks",
+        ] {
+            assert_eq!(embed_videos(&config, text), text);
+        }
+        // Already a link or in code
+        let linked = r#"<a href="https://youtu.be/dQw4w9WgXcQ">https://youtu.be/dQw4w9WgXcQ</a> <code>https://youtu.be/dQw4w9WgXcQ</code>"#;
+        assert_eq!(embed_videos(&config, linked), linked);
+    }
+
+    #[test]
+    fn only_two_embeds_per_post() {
+        let config = AppConfig::default();
+        let html = embed_videos(&config, &["https://youtu.be/dQw4w9WgXcQ"; 3].join(" "));
+        assert_eq!(html.matches("<iframe").count(), MAX_EMBEDS);
+        assert!(html.ends_with(r#"<a href="https://www.youtube.com/watch?v=dQw4w9WgXcQ" target="_blank" rel="noopener nofollow">https://youtu.be/dQw4w9WgXcQ</a>"#), "{}", html);
+    }
+
+    #[test]
+    fn embedding_can_be_turned_off_or_narrowed() {
+        let off = AppConfig { video_embeds: false, ..AppConfig::default() };
+        assert_eq!(embed_videos(&off, "https://youtu.be/dQw4w9WgXcQ"), "https://youtu.be/dQw4w9WgXcQ");
+        assert!(player_origins(&off).is_empty());
+
+        let vimeo_only = AppConfig { embed_hosts: vec!["vimeo.com".to_string()], ..AppConfig::default() };
+        assert_eq!(embed_videos(&vimeo_only, "https://youtu.be/dQw4w9WgXcQ"), "https://youtu.be/dQw4w9WgXcQ");
+        assert_eq!(player_origins(&vimeo_only), ["https://player.vimeo.com"]);
+    }
+}
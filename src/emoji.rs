@@ -0,0 +1,159 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use std::collections::HashMap;
+
+use crate::locale::translator;
+use crate::render_page;
+use crate::sanitize::rewrite_text;
+use crate::site::Site;
+
+// Shortcodes understood in post messages. The /emoji page lists this same
+// table, so it is the only place a new shortcode needs adding.
+const EMOJI: [(&str, &str); 48] = [
+    ("+1", "\u{1F44D}"),
+    ("-1", "\u{1F44E}"),
+    ("angry", "\u{1F620}"),
+    ("blush", "\u{1F60A}"),
+    ("broken_heart", "\u{1F494}"),
+    ("check", "\u{2705}"),
+    ("clap", "\u{1F44F}"),
+    ("cold_sweat", "\u{1F630}"),
+    ("cool", "\u{1F60E}"),
+    ("cry", "\u{1F622}"),
+    ("eyes", "\u{1F440}"),
+    ("fire", "\u{1F525}"),
+    ("frowning", "\u{1F626}"),
+    ("ghost", "\u{1F47B}"),
+    ("grin", "\u{1F601}"),
+    ("heart", "\u{2764}\u{FE0F}"),
+    ("heart_eyes", "\u{1F60D}"),
+    ("hundred", "\u{1F4AF}"),
+    ("joy", "\u{1F602}"),
+    ("kiss", "\u{1F618}"),
+    ("laughing", "\u{1F606}"),
+    ("neutral_face", "\u{1F610}"),
+    ("ok_hand", "\u{1F44C}"),
+    ("party", "\u{1F973}"),
+    ("pensive", "\u{1F614}"),
+    ("point_up", "\u{261D}\u{FE0F}"),
+    ("poop", "\u{1F4A9}"),
+    ("pray", "\u{1F64F}"),
+    ("question", "\u{2753}"),
+    ("rage", "\u{1F621}"),
+    ("relieved", "\u{1F60C}"),
+    ("rocket", "\u{1F680}"),
+    ("rofl", "\u{1F923}"),
+    ("scream", "\u{1F631}"),
+    ("shrug", "\u{1F937}"),
+    ("skull", "\u{1F480}"),
+    ("sleeping", "\u{1F634}"),
+    ("smile", "\u{1F604}"),
+    ("smirk", "\u{1F60F}"),
+    ("sob", "\u{1F62D}"),
+    ("sparkles", "\u{2728}"),
+    ("star", "\u{2B50}"),
+    ("stuck_out_tongue", "\u{1F61B}"),
+    ("sweat_smile", "\u{1F605}"),
+    ("thinking", "\u{1F914}"),
+    ("warning", "\u{26A0}\u{FE0F}"),
+    ("wave", "\u{1F44B}"),
+    ("wink", "\u{1F609}"),
+];
+
+// Shortcodes past this many in one message are left as text
+const MAX_EXPANSIONS: usize = 100;
+
+fn lookup(name: &str) -> Option<&'static str> {
+    EMOJI.iter().find(|(shortcode, _)| *shortcode == name).map(|(_, emoji)| *emoji)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '+' || c == '-'
+}
+
+// Expands the shortcodes in one run of text, a word at a time. Words that
+// look like URLs are copied as they are.
+fn expand_text(text: &str, out: &mut String, budget: &mut usize) {
+    for word in text.split_inclusive(char::is_whitespace) {
+        if *budget == 0 || word.contains("://") {
+            out.push_str(word);
+            continue;
+        }
+        let mut rest = word;
+        while let Some(start) = rest.find(':') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let name_len = after.find(|c: char| !is_name_char(c)).unwrap_or(after.len());
+            let emoji = if *budget > 0 && after[name_len..].starts_with(':') { lookup(&after[..name_len]) } else { None };
+            match emoji {
+                Some(emoji) => {
+                    out.push_str(emoji);
+                    *budget -= 1;
+                    rest = &after[name_len + 1..];
+                },
+                None => {
+                    out.push(':');
+                    rest = after;
+                },
+            }
+        }
+        out.push_str(rest);
+    }
+}
+
+// Replaces :name: shortcodes in a sanitized message with their emoji.
+// Markup is copied untouched, as is anything inside code, pre and links.
+pub fn expand_shortcodes(html: &str) -> String {
+    let mut budget = MAX_EXPANSIONS;
+    rewrite_text(html, |text, out| expand_text(text, out, &mut budget))
+}
+
+// Reference page listing every supported shortcode
+pub async fn emoji_list(req: HttpRequest, site: web::Data<Site>) -> Result<HttpResponse> {
+    let tr = translator(&req);
+    let rows: String = EMOJI.iter()
+        .map(|(shortcode, emoji)| format!("<tr><td><code>:{}:</code></td><td class=\"emoji\">{}</td></tr>", shortcode, emoji))
+        .collect();
+
+    let mut context = HashMap::from([("EMOJI", rows)]);
+    context.extend(site.chrome());
+    let body = render_page("templates/emoji.html", &context, &tr);
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+
+    use crate::testing::{shared, test_config};
+
+    #[test]
+    fn known_shortcodes_expand_outside_code_and_links() {
+        assert_eq!(expand_shortcodes("hi :wave: :+1:"), "hi \u{1F44B} \u{1F44D}");
+        assert_eq!(expand_shortcodes(":not_an_emoji: and :smile"), ":not_an_emoji: and :smile");
+        assert_eq!(expand_shortcodes("a::fire:"), "a:\u{1F525}");
+        assert_eq!(expand_shortcodes("<code>:fire:</code> :fire:"), "<code>:fire:</code> \u{1F525}");
+        assert_eq!(expand_shortcodes(r#"<a href="/x">:fire:</a>"#), r#"<a href="/x">:fire:</a>"#);
+        assert_eq!(expand_shortcodes("see https://example.com/:fire:/page"), "see https://example.com/:fire:/page");
+    }
+
+    #[test]
+    fn expansion_stops_at_the_cap() {
+        let message = ":star:".repeat(10_000);
+        let expanded = expand_shortcodes(&message);
+        assert_eq!(expanded.matches('\u{2B50}').count(), MAX_EXPANSIONS);
+        assert_eq!(expanded.matches(":star:").count(), 10_000 - MAX_EXPANSIONS);
+    }
+
+    #[actix_web::test]
+    async fn the_reference_page_lists_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/emoji").to_request()).await.to_vec()).unwrap();
+        assert_eq!(page.matches("<tr><td><code>:").count(), EMOJI.len());
+        assert!(page.contains("<tr><td><code>:rocket:</code></td><td class=\"emoji\">\u{1F680}</td></tr>"));
+    }
+}
@@ -0,0 +1,108 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use crate::config::AppConfig;
+use crate::api::{load_thread, ApiPost, ApiThread};
+
+// Same schema as /api/thread so tooling can share one parser
+pub fn thread_to_json(thread: &ApiThread) -> String {
+    serde_json::to_string_pretty(thread).unwrap()
+}
+
+pub fn thread_to_text(thread: &ApiThread) -> String {
+    let mut text = String::new();
+    for post in std::iter::once(&thread.op).chain(&thread.replies) {
+        write_post_text(&mut text, post);
+    }
+    text
+}
+
+fn write_post_text(text: &mut String, post: &ApiPost) {
+    let _ = writeln!(text, "[{}] No.{}: {}", post.created_at, post.id, post.title);
+    let _ = writeln!(text, "{}", post.message);
+    if let Some(file_url) = &post.file_url {
+        let _ = writeln!(text, "Attachment: {}", file_url);
+        if let Some(alt) = &post.file_alt {
+            let _ = writeln!(text, "Alt text: {}", alt);
+        }
+    }
+    text.push('\n');
+}
+
+pub async fn export_thread(conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, path: web::Path<i32>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let thread_id = path.into_inner();
+
+    let Some(thread) = load_thread(&conn, &config, thread_id) else {
+        return Ok(HttpResponse::NotFound().body("Thread not found."));
+    };
+
+    let (body, content_type, extension) = match query.get("format").map(String::as_str) {
+        None | Some("json") => (thread_to_json(&thread), "application/json", "json"),
+        Some("txt") => (thread_to_text(&thread), "text/plain; charset=utf-8", "txt"),
+        Some(_) => return Ok(HttpResponse::BadRequest().body("Unknown export format.")),
+    };
+
+    let date: String = conn.query_row("SELECT date('now')", [], |row| row.get(0)).unwrap();
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("thread-{}-{}.{}", thread_id, date, extension))],
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(disposition)
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    #[actix_web::test]
+    async fn threads_export_as_json_or_text() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "exported"), ("message", "first words"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files", [], |row| row.get(0)).unwrap();
+        let reply = multipart(&[("title", "re"), ("message", "a reply"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+
+        let export = |format: &str| TestRequest::get().uri(&format!("/post/{}/export{}", thread_id, format)).to_request();
+        let response = call_service(&app, export("")).await;
+        assert_eq!(response.status(), 200);
+        let disposition = response.headers().get(header::CONTENT_DISPOSITION).unwrap().to_str().unwrap().to_string();
+        assert!(disposition.starts_with(&format!("attachment; filename=\"thread-{}-", thread_id)));
+        assert!(disposition.ends_with(".json\""));
+        let json: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(json["op"]["title"], "exported");
+        assert_eq!(json["replies"][0]["message"], "a reply");
+
+        let text = String::from_utf8(read_body(call_service(&app, export("?format=txt")).await).await.to_vec()).unwrap();
+        let (op_at, reply_at) = (text.find("first words").unwrap(), text.find("a reply").unwrap());
+        assert!(op_at < reply_at);
+        assert!(text.contains(&format!("No.{}: exported", thread_id)));
+
+        assert_eq!(call_service(&app, export("?format=pdf")).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn a_missing_thread_is_not_found() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(crate::app(&shared)).await;
+        let response = call_service(&app, TestRequest::get().uri("/post/42/export").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+}
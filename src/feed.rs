@@ -0,0 +1,160 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rusqlite::{params, Connection};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::escape_html;
+use crate::timefmt::parse_timestamp;
+
+struct CachedFeed {
+    // Newest visible post and the site address the feed was built for
+    key: String,
+    built: Instant,
+    body: String,
+}
+
+// The last rendered feed. A new post changes the key, so it's rebuilt
+// straight away, and moderator actions that take threads away or bring
+// them back clear it; the TTL only bounds how long edits take to show up.
+pub struct FeedCache {
+    entry: Mutex<Option<CachedFeed>>,
+}
+
+impl FeedCache {
+    pub fn new() -> Self {
+        FeedCache { entry: Mutex::new(None) }
+    }
+
+    // Drops the cached feed so the next request rebuilds it
+    pub fn clear(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
+
+fn cache_key(conn: &Connection, base: &str) -> String {
+    let (latest, max_id): (Option<String>, Option<i32>) = conn.query_row(
+        "SELECT MAX(created_at), MAX(id) FROM files WHERE parent_id = 0 AND hidden = 0",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).unwrap();
+    format!("{}|{}|{}", latest.unwrap_or_default(), max_id.unwrap_or(0), base)
+}
+
+fn build_feed(conn: &Connection, config: &AppConfig, base: &str) -> String {
+    let mut stmt = conn.prepare(
+        "SELECT id, title, message, created_at FROM files WHERE parent_id = 0 AND hidden = 0 ORDER BY created_at DESC, id DESC LIMIT ?1"
+    ).unwrap();
+    let threads = stmt.query_map(params![config.feed_items as i64], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    }).unwrap();
+
+    let mut items = String::new();
+    for thread in threads {
+        let (id, title, message, created_at) = thread.unwrap();
+        let link = format!("{}/post/{}", base, id);
+        let published = parse_timestamp(&created_at).map(|time| time.to_rfc2822()).unwrap_or_default();
+        let description = if message.trim().is_empty() { "(no text)".to_string() } else { escape_html(&message) };
+        items.push_str(&format!(
+            "<item><title>{}</title><link>{}</link><guid>{}</guid><pubDate>{}</pubDate><description>{}</description></item>",
+            escape_html(&title), link, link, published, description
+        ));
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0"><channel><title>Latest threads</title><link>{}/</link><description>Newest threads on the board</description>{}</channel></rss>"#,
+        base, items
+    )
+}
+
+// RSS feed of the newest threads
+pub async fn feed(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, cache: web::Data<FeedCache>) -> Result<HttpResponse> {
+    let info = req.connection_info();
+    let base = format!("{}://{}", info.scheme(), info.host());
+    let conn = conn.lock().unwrap();
+    let key = cache_key(&conn, &base);
+    let ttl = Duration::from_secs(config.feed_cache_secs);
+
+    let mut entry = cache.entry.lock().unwrap();
+    let body = match entry.as_ref() {
+        Some(cached) if cached.key == key && cached.built.elapsed() < ttl => cached.body.clone(),
+        _ => {
+            let body = build_feed(&conn, &config, &base);
+            *entry = Some(CachedFeed { key, built: Instant::now(), body: body.clone() });
+            body
+        },
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[actix_web::test]
+    async fn the_feed_is_cached_until_a_new_thread_arrives() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str| form_post("/upload", multipart(&[("title", title), ("message", "body"), ("parent_id", "0")], None), "10.0.0.1:4000").to_request();
+        let feed = || TestRequest::get().uri("/feed.xml").to_request();
+
+        assert_eq!(call_service(&app, post("first")).await.status(), 303);
+        let first = call_and_read_body(&app, feed()).await;
+        assert!(String::from_utf8_lossy(&first).contains("<title>first</title>"));
+
+        // An edit doesn't change the key, so a second request is served
+        // the feed built for the first
+        shared.conn.lock().unwrap().execute("UPDATE files SET title = 'edited'", []).unwrap();
+        assert_eq!(call_and_read_body(&app, feed()).await, first);
+
+        // A new thread is in the feed straight away
+        assert_eq!(call_service(&app, post("second")).await.status(), 303);
+        let rebuilt = String::from_utf8(call_and_read_body(&app, feed()).await.to_vec()).unwrap();
+        assert!(rebuilt.contains("<title>second</title>"));
+        assert!(rebuilt.contains("<title>edited</title>"));
+    }
+
+    #[actix_web::test]
+    async fn moderator_changes_show_in_the_feed_straight_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        for title in ["alpha", "bravo", "charlie", "delta"] {
+            let post = form_post("/upload", multipart(&[("title", title), ("message", "body"), ("parent_id", "0")], None), "10.0.0.1:4000");
+            assert_eq!(call_service(&app, post.to_request()).await.status(), 303);
+        }
+        let id = |title: &str| shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = ?1", [title], |row| row.get::<_, i32>(0)).unwrap();
+        let (alpha, bravo, charlie, delta) = (id("alpha"), id("bravo"), id("charlie"), id("delta"));
+        let moderate = |uri: String| TestRequest::post().uri(&uri).insert_header((header::AUTHORIZATION, "Bearer letmein")).to_request();
+        let feed = || async { String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/feed.xml").to_request()).await.to_vec()).unwrap() };
+
+        // Hidden as spam, so only the others are in the cached feed
+        shared.conn.lock().unwrap().execute("UPDATE files SET hidden = 1 WHERE id = ?1", [bravo]).unwrap();
+        assert!(!feed().await.contains("<title>bravo</title>"));
+
+        // None of these touch the newest thread, so the key stays the same
+        assert_eq!(call_service(&app, moderate(format!("/admin/unhide/{}", bravo))).await.status(), 303);
+        assert!(feed().await.contains("<title>bravo</title>"));
+
+        assert_eq!(call_service(&app, moderate(format!("/admin/delete/{}", charlie))).await.status(), 303);
+        assert!(!feed().await.contains("<title>charlie</title>"));
+
+        assert_eq!(call_service(&app, moderate(format!("/admin/merge/{}/{}", alpha, delta))).await.status(), 303);
+        let page = feed().await;
+        assert!(!page.contains("<title>alpha</title>"));
+        assert!(page.contains("<title>delta</title>") && page.contains("<title>bravo</title>"));
+    }
+}
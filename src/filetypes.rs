@@ -0,0 +1,223 @@
+use serde::Deserialize;
+
+// How an upload is shown in a post
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Image,
+    Video,
+    Audio,
+    // Shown as an icon and link, opened in the browser's own viewer
+    Document,
+    // Offered as a link only, and always served as a download
+    Download,
+}
+
+// One accepted upload format
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileType {
+    // Without the dot; matched case-insensitively
+    pub extension: String,
+    pub mime: String,
+    // Signatures the file must start with, as hex with ?? for any byte. A
+    // file matching none of them is refused; an empty list skips the check.
+    #[serde(default)]
+    pub magic: Vec<String>,
+    // Largest upload of this type; max_upload_bytes still applies on top
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+    pub kind: FileKind,
+}
+
+// (extension, mime, signatures, size limit, kind)
+type BuiltInType = (&'static str, &'static str, &'static [&'static str], Option<usize>, FileKind);
+
+// Formats accepted out of the box
+const DEFAULT_TYPES: [BuiltInType; 9] = [
+    ("jpg", "image/jpeg", &["ffd8ff"], None, FileKind::Image),
+    ("jpeg", "image/jpeg", &["ffd8ff"], None, FileKind::Image),
+    ("png", "image/png", &["89504e470d0a1a0a"], None, FileKind::Image),
+    ("gif", "image/gif", &["474946383761", "474946383961"], None, FileKind::Image),
+    ("webp", "image/webp", &["52494646????????57454250"], None, FileKind::Image),
+    ("mp4", "video/mp4", &["????????66747970"], None, FileKind::Video),
+    ("webm", "video/webm", &["1a45dfa3"], None, FileKind::Video),
+    ("mp3", "audio/mpeg", &["494433", "fffb", "fff3", "fff2"], None, FileKind::Audio),
+    ("pdf", "application/pdf", &["25504446"], Some(10 * 1024 * 1024), FileKind::Document),
+];
+
+pub fn default_file_types() -> Vec<FileType> {
+    DEFAULT_TYPES.iter()
+        .map(|(extension, mime, magic, max_bytes, kind)| FileType {
+            extension: extension.to_string(),
+            mime: mime.to_string(),
+            magic: magic.iter().map(|signature| signature.to_string()).collect(),
+            max_bytes: *max_bytes,
+            kind: *kind,
+        })
+        .collect()
+}
+
+// Bytes of a signature, None standing for ??; None overall when it isn't
+// valid hex
+fn parse_signature(signature: &str) -> Option<Vec<Option<u8>>> {
+    if signature.is_empty() || !signature.len().is_multiple_of(2) || !signature.is_ascii() {
+        return None;
+    }
+    (0..signature.len()).step_by(2)
+        .map(|i| match &signature[i..i + 2] {
+            "??" => Some(None),
+            byte => u8::from_str_radix(byte, 16).ok().map(Some),
+        })
+        .collect()
+}
+
+pub fn valid_signature(signature: &str) -> bool {
+    parse_signature(signature).is_some()
+}
+
+impl FileType {
+    // Whether `data` starts with one of the type's signatures
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.magic.is_empty() || self.magic.iter().filter_map(|signature| parse_signature(signature)).any(|bytes| {
+            data.len() >= bytes.len() && bytes.iter().zip(data).all(|(expected, actual)| expected.is_none_or(|byte| byte == *actual))
+        })
+    }
+}
+
+pub fn extension_of(file_name: &str) -> &str {
+    file_name.rsplit_once('.').map(|(_, extension)| extension).unwrap_or("")
+}
+
+pub fn valid_extension(extension: &str) -> bool {
+    (1..=10).contains(&extension.len()) && extension.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn find_type<'a>(types: &'a [FileType], extension: &str) -> Option<&'a FileType> {
+    types.iter().find(|file_type| file_type.extension.eq_ignore_ascii_case(extension))
+}
+
+// How a stored file is shown. Files of a type since dropped from the
+// config still render the way they did when they were posted, and
+// anything unknown is offered as a download.
+pub fn file_kind(types: &[FileType], file_path: &str) -> FileKind {
+    let extension = extension_of(file_path);
+    find_type(types, extension)
+        .map(|file_type| file_type.kind)
+        .or_else(|| DEFAULT_TYPES.iter().find(|(known, ..)| known.eq_ignore_ascii_case(extension)).map(|(.., kind)| *kind))
+        .unwrap_or(FileKind::Download)
+}
+
+pub fn is_image(types: &[FileType], file_path: &str) -> bool {
+    file_kind(types, file_path) == FileKind::Image
+}
+
+// Extensions rendered as images, configured or built in
+pub fn image_extensions(types: &[FileType]) -> Vec<String> {
+    let mut extensions: Vec<String> = types.iter()
+        .filter(|file_type| file_type.kind == FileKind::Image)
+        .map(|file_type| file_type.extension.to_ascii_lowercase())
+        .collect();
+    for (extension, _, _, _, kind) in DEFAULT_TYPES {
+        if kind == FileKind::Image && find_type(types, extension).is_none() {
+            extensions.push(extension.to_string());
+        }
+    }
+    extensions
+}
+
+// Value for the file input's accept attribute
+pub fn accept_attr(types: &[FileType]) -> String {
+    let mut accept: Vec<String> = Vec::new();
+    for file_type in types {
+        for entry in [format!(".{}", file_type.extension.to_ascii_lowercase()), file_type.mime.to_ascii_lowercase()] {
+            if !accept.contains(&entry) {
+                accept.push(entry);
+            }
+        }
+    }
+    accept.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "10.0.0.1:4000";
+
+    fn text_type() -> FileType {
+        FileType { extension: "txt".to_string(), mime: "text/plain".to_string(), magic: Vec::new(), max_bytes: Some(16), kind: FileKind::Download }
+    }
+
+    #[test]
+    fn signatures_allow_wildcards() {
+        let types = default_file_types();
+        let webp = find_type(&types, "WEBP").unwrap();
+        assert!(webp.matches(b"RIFF\x10\x20\x30\x40WEBPVP8 "));
+        assert!(!webp.matches(b"RIFF\x10\x20\x30\x40WAVEfmt "));
+        assert!(!webp.matches(b"RIFF"));
+        assert!(text_type().matches(b"anything at all"));
+
+        assert!(valid_signature("ff??00"));
+        for invalid in ["", "f", "zz", "ff?", "ééé"] {
+            assert!(!valid_signature(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn dropped_types_keep_rendering_as_before() {
+        let types = vec![text_type()];
+        assert_eq!(file_kind(&types, "uploads/a.txt"), FileKind::Download);
+        assert_eq!(file_kind(&types, "uploads/old.JPG"), FileKind::Image);
+        assert_eq!(file_kind(&types, "uploads/old.mp4"), FileKind::Video);
+        assert_eq!(file_kind(&types, "uploads/mystery.bin"), FileKind::Download);
+        assert!(image_extensions(&types).contains(&"png".to_string()));
+        assert_eq!(accept_attr(&types), ".txt,text/plain");
+        assert_eq!(accept_attr(&default_file_types()[..2]), ".jpg,image/jpeg,.jpeg");
+    }
+
+    #[actix_web::test]
+    async fn the_table_decides_what_is_accepted_and_how_it_is_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let png_type = default_file_types().into_iter().find(|file_type| file_type.extension == "png").unwrap();
+        let shared = shared(AppConfig { file_types: vec![png_type, text_type()], ..test_config(dir.path()) });
+        // A JPEG from before the type was dropped
+        shared.conn.lock().unwrap().execute(
+            "INSERT INTO files (post_id, parent_id, title, message, file_path, created_at, last_reply_at) VALUES ('old', 0, 'old photo', 'x', ?1, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)",
+            [format!("{}/old.jpg", shared.config.upload_dir)],
+        ).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str, file: (&str, &str, &[u8])| form_post("/upload", multipart(&[("title", title), ("message", "m"), ("parent_id", "0")], Some(file)), PEER).to_request();
+
+        let board = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(board.contains(r#"accept=".png,image/png,.txt,text/plain""#));
+        assert!(board.contains(&format!(r#"<img src="{}""#, crate::upload_url(&shared.config, &format!("{}/old.jpg", shared.config.upload_dir)))));
+
+        let response = call_service(&app, post("too long", ("notes.txt", "text/plain", b"seventeen bytes!!"))).await;
+        assert_eq!(response.status(), 400);
+        assert!(String::from_utf8(read_body(response).await.to_vec()).unwrap().contains("File is too large."));
+
+        assert_eq!(call_service(&app, post("notes", ("notes.txt", "text/plain", b"short notes"))).await.status(), 303);
+        assert_eq!(call_service(&app, post("png", ("a.png", "image/png", &png(2)))).await.status(), 303);
+        // No longer accepted: the post goes ahead without it
+        assert_eq!(call_service(&app, post("jpeg", ("a.jpg", "image/jpeg", b"\xff\xd8\xff\xe0 jpeg"))).await.status(), 303);
+
+        let files: Vec<(String, Option<String>)> = shared.conn.lock().unwrap()
+            .prepare("SELECT title, file_path FROM files WHERE post_id != 'old' ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert!(files[0].1.as_deref().unwrap().ends_with(".txt"));
+        assert!(files[1].1.as_deref().unwrap().ends_with(".png"));
+        assert_eq!(files[2], ("jpeg".to_string(), None));
+
+        let url = crate::upload_url(&shared.config, files[0].1.as_deref().unwrap());
+        let response = call_service(&app, TestRequest::get().uri(&url).to_request()).await;
+        assert!(response.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/plain"));
+        assert!(response.headers().get(header::CONTENT_DISPOSITION).unwrap().to_str().unwrap().starts_with("attachment"));
+    }
+}
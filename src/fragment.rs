@@ -0,0 +1,158 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rusqlite::Connection;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::bans::ip_hash;
+use crate::board::DEFAULT_BOARD;
+use crate::config::AppConfig;
+use crate::db::{get_post, reply_number};
+use crate::highlight::Highlighter;
+use crate::locale::translator;
+use crate::sanitize::HtmlSanitizer;
+use crate::site::site;
+use crate::session::Prefs;
+use crate::timezone::format_ctx;
+use crate::{render_thread_list, render_thread_post, ListingQuery, PostRenderer, PostRole};
+
+// Rendered HTML for a single post, as it appears on its thread page, for
+// hover previews of >>N links. Moderator controls are never included.
+pub async fn post_fragment(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, sanitizer: web::Data<HtmlSanitizer>, highlighter: web::Data<Highlighter>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let id = path.into_inner();
+    let viewer = ip_hash(&conn, &req).map_err(ErrorInternalServerError)?;
+
+    let found = get_post(&conn, id, &viewer).map_err(ErrorInternalServerError)?;
+    let Some((parent_id, autosage, post)) = found else {
+        return Ok(HttpResponse::NotFound().json(json!({ "error": "Post not found" })));
+    };
+
+    let (ctx, tr, prefs) = (format_ctx(&req), translator(&req), Prefs::of(&req));
+    let renderer = PostRenderer { config: &config, sanitizer: &sanitizer, highlighter: &highlighter, ctx: &ctx, tr: &tr, prefs: &prefs };
+    let html = if parent_id == 0 {
+        render_thread_post(id, &post, PostRole::Op { autosage }, "", &renderer)
+    } else {
+        // Same numbering as the thread page: position among replies the viewer can see
+        let number = reply_number(&conn, parent_id, &post, &viewer).map_err(ErrorInternalServerError)?;
+        render_thread_post(parent_id, &post, PostRole::Reply { number, new: false, poster: None }, "", &renderer)
+    };
+
+    // Private: shadow-hidden posts make the result depend on the viewer
+    Ok(HttpResponse::Ok()
+        .content_type("text/html")
+        .insert_header(("Cache-Control", "private, max-age=30"))
+        .body(html))
+}
+
+// Just the thread entries for one page of the board, for infinite scroll.
+// Takes the same `page`, `sort` and `q` parameters as the index, plus
+// `board` (the default board when missing); 204 once the page is past the end.
+pub async fn board_fragment(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, sanitizer: web::Data<HtmlSanitizer>, highlighter: web::Data<Highlighter>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let viewer = ip_hash(&conn, &req).map_err(ErrorInternalServerError)?;
+    let (ctx, tr, prefs) = (format_ctx(&req), translator(&req), Prefs::of(&req));
+    let renderer = PostRenderer { config: &config, sanitizer: &sanitizer, highlighter: &highlighter, ctx: &ctx, tr: &tr, prefs: &prefs };
+    let board = query.get("board").map(String::as_str).unwrap_or(DEFAULT_BOARD);
+    let html = render_thread_list(&conn, board, &viewer, &ListingQuery::from_query(&query, prefs.sort.as_deref()), site(&req).read_only(), &renderer);
+    if html.is_empty() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    #[actix_web::test]
+    async fn a_post_renders_alone_without_moderator_controls() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let last_id = || -> i32 { shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap() };
+
+        let op = multipart(&[("title", "previews"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id = last_id();
+        let fields = [("title", "loud"), ("message", "<script>alert(1)</script>quoting >>1"), ("parent_id", &thread_id.to_string())];
+        let reply = multipart(&fields, Some(("cat.png", "image/png", &png(70))));
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        let reply_id = last_id();
+        let file_path: String = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files WHERE id = ?1", [reply_id], |row| row.get(0)).unwrap();
+
+        // Asked for by a moderator, to show nothing leaks into the preview
+        let request = TestRequest::get()
+            .uri(&format!("/api/fragment/post/{}", reply_id))
+            .insert_header((header::AUTHORIZATION, "Bearer letmein"));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "private, max-age=30");
+        let html = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(html.starts_with(&format!(r#"<div class="post" id="p{}">"#, reply_id)));
+        assert!(html.contains(r#"id="r1""#));
+        assert!(html.contains(r#"<div class="post-title">loud</div>"#));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains(r#"<a class="quote-ref" href="/post/1" data-post="1">&gt;&gt;1</a>"#));
+        assert!(html.contains(&format!(r#"<img src="{}""#, crate::upload_url(&shared.config, &file_path))));
+        assert!(!html.contains("/admin/"));
+        assert!(!html.contains("<html"));
+    }
+
+    #[actix_web::test]
+    async fn missing_and_deleted_posts_are_404() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "gone"), ("message", "soon"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        let fragment = format!("/api/fragment/post/{}", id);
+        assert_eq!(call_service(&app, TestRequest::get().uri(&fragment).to_request()).await.status(), 200);
+
+        shared.conn.lock().unwrap().execute("DELETE FROM files WHERE id = ?1", [id]).unwrap();
+        assert_eq!(call_service(&app, TestRequest::get().uri(&fragment).to_request()).await.status(), 404);
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/fragment/post/999").to_request()).await.status(), 404);
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/fragment/post/abc").to_request()).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn board_pages_come_as_bare_thread_lists_until_they_run_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { posts_per_page: 2, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        for (hours, title) in [(3, "first"), (2, "second"), (1, "third")] {
+            let op = multipart(&[("title", title), ("message", "op"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+            shared.conn.lock().unwrap()
+                .execute("UPDATE files SET last_reply_at = datetime('now', ?1) WHERE title = ?2", [format!("-{} hours", hours), title.to_string()])
+                .unwrap();
+        }
+
+        let get = |uri: &str| TestRequest::get().uri(uri).peer_addr(PEER.parse().unwrap()).to_request();
+        let first = String::from_utf8(call_and_read_body(&app, get("/fragment/board?page=1")).await.to_vec()).unwrap();
+        assert_eq!(first.matches(r#"<div class="post">"#).count(), 2);
+        assert!(first.starts_with(r#"<div class="post">"#));
+        assert!(!first.contains("<html") && !first.contains("?page=2"));
+        // The same cards the board page shows
+        let index = String::from_utf8(call_and_read_body(&app, get("/")).await.to_vec()).unwrap();
+        assert!(index.contains(&first));
+
+        let second = String::from_utf8(call_and_read_body(&app, get("/fragment/board?page=2")).await.to_vec()).unwrap();
+        assert_eq!(second.matches(r#"<div class="post">"#).count(), 1);
+        assert!(second.contains("first"));
+
+        let response = call_service(&app, get("/fragment/board?page=3")).await;
+        assert_eq!(response.status(), 204);
+        assert!(read_body(response).await.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use maxminddb::{geoip2, Reader};
+
+use crate::client_ip::ClientIp;
+use crate::config::AppConfig;
+
+// Country lookups against a MaxMind database (GeoLite2-Country or
+// compatible). Only the resulting country code is ever stored with a post.
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    // Reads the database named by `geoip_database`; without one, every
+    // lookup comes back empty
+    pub fn open(config: &AppConfig) -> Result<Self, String> {
+        let reader = match &config.geoip_database {
+            Some(path) => Some(Reader::open_readfile(path).map_err(|e| format!("geoip_database {} can't be read: {}", path, e))?),
+            None => None,
+        };
+        Ok(GeoIp { reader })
+    }
+
+    // Two-letter ISO code of the country the request comes from
+    pub fn country(&self, req: &HttpRequest) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let ip = ClientIp::of(req).0?;
+        let record: geoip2::Country = reader.lookup(ip).ok()?;
+        let code = record.country?.iso_code?;
+        (code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic())).then(|| code.to_ascii_uppercase())
+    }
+}
+
+pub fn geoip(req: &HttpRequest) -> Data<GeoIp> {
+    req.app_data::<Data<GeoIp>>().cloned().expect("geoip is registered with the app")
+}
+
+// The country's flag, made of regional indicator symbols, with the code as
+// its tooltip. Nothing when flags are off or the country is unknown.
+pub fn country_flag(config: &AppConfig, country: Option<&str>) -> String {
+    let Some(code) = country.filter(|_| config.geoip_database.is_some()) else {
+        return String::new();
+    };
+    let flag: String = code.chars()
+        .filter_map(|c| char::from_u32(0x1F1E6 + (c.to_ascii_uppercase() as u32).checked_sub('A' as u32)?))
+        .collect();
+    format!(r#" <span class="country-flag" title="{}">{}</span>"#, code, flag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use std::path::Path;
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    // MaxMind DB encoding of a string, unsigned integer or map header
+    fn text(value: &str) -> Vec<u8> {
+        [vec![0x40 | value.len() as u8], value.as_bytes().to_vec()].concat()
+    }
+
+    fn uint16(value: u8) -> Vec<u8> {
+        vec![0xa1, value]
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(text(key));
+            bytes.extend(value);
+        }
+        bytes
+    }
+
+    // A stand-in for GeoLite2-Country: IPv4 addresses in 0.0.0.0/1 are in
+    // Germany, the rest aren't found
+    fn stub_database(path: &Path) {
+        const NODES: u32 = 32;
+        let record = |value: u32| value.to_be_bytes()[1..].to_vec();
+        let mut db = Vec::new();
+        // Node 0 splits on the first bit; the rest lead to the one record,
+        // just past the tree and its 16-byte separator
+        for node in 0..NODES {
+            let (left, right) = match node {
+                0 => (1, NODES),
+                n if n == NODES - 1 => (NODES + 16, NODES + 16),
+                n => (n + 1, n + 1),
+            };
+            db.extend(record(left));
+            db.extend(record(right));
+        }
+        db.extend([0; 16]);
+        db.extend(map(&[("country", map(&[("iso_code", text("DE"))]))]));
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        db.extend(map(&[
+            ("binary_format_major_version", uint16(2)),
+            ("binary_format_minor_version", vec![0xa0]),
+            ("build_epoch", vec![0x00, 0x02]),
+            ("database_type", text("Stub-Country")),
+            ("description", vec![0xe0]),
+            ("ip_version", uint16(4)),
+            ("languages", vec![0x00, 0x04]),
+            ("node_count", vec![0xc1, NODES as u8]),
+            ("record_size", uint16(24)),
+        ]));
+        std::fs::write(path, db).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn countries_are_stored_and_flagged() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("country.mmdb");
+        stub_database(&database);
+        let shared = shared(AppConfig { geoip_database: Some(database.to_string_lossy().into_owned()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        for (title, peer) in [("from-germany", "10.1.2.3:40000"), ("from-nowhere", "203.0.113.9:40000")] {
+            let form = multipart(&[("title", title), ("message", "hallo"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", form, peer).to_request()).await.status(), 303);
+        }
+        let country = |title: &str| -> (i32, Option<String>) {
+            shared.conn.lock().unwrap().query_row("SELECT id, country FROM files WHERE title = ?1", [title], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        let (german, code) = country("from-germany");
+        assert_eq!(code.as_deref(), Some("DE"));
+        assert_eq!(country("from-nowhere").1, None);
+
+        let flag = r#" <span class="country-flag" title="DE">🇩🇪</span>"#;
+        let board = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert_eq!(board.matches(flag).count(), 1);
+        let thread = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", german)).to_request()).await.to_vec()).unwrap();
+        assert!(thread.contains(flag));
+        // The address itself is never kept
+        let conn = shared.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM files WHERE id = ?1").unwrap();
+        let columns = stmt.column_count();
+        let values: Vec<String> = stmt.query_row([german], |row| {
+            Ok((0..columns).map(|column| match row.get_ref(column).unwrap() {
+                rusqlite::types::ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                value => format!("{:?}", value),
+            }).collect())
+        }).unwrap();
+        assert!(values.iter().all(|value| !value.contains("10.1.2.3")), "{:?}", values);
+    }
+
+    #[actix_web::test]
+    async fn without_a_database_nothing_is_looked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let form = multipart(&[("title", "anywhere"), ("message", "hi"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", form, "10.1.2.3:40000").to_request()).await.status(), 303);
+        let country: Option<String> = shared.conn.lock().unwrap().query_row("SELECT country FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(country, None);
+        assert_eq!(country_flag(&shared.config, Some("DE")), "");
+        let board = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(!board.contains("country-flag"));
+    }
+}
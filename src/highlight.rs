@@ -0,0 +1,160 @@
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::escape_html;
+
+// Code blocks carry classes rather than inline styles, which the CSP
+// blocks. The colours, from syntect's light InspiredGitHub theme to match
+// the board, are in static/highlight.css.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+// Bigger code blocks are shown without highlighting to bound the CPU spent
+// on a single post
+const MAX_HIGHLIGHT_LINES: usize = 200;
+const MAX_HIGHLIGHT_BYTES: usize = 50 * 1024;
+
+// A piece of a post message: ordinary text, or the inside of a ``` fence
+pub enum Block<'a> {
+    Text(&'a str),
+    Code { language: &'a str, code: &'a str },
+}
+
+// Splits a message on ``` fences. The opening fence may name a language
+// (```rust); a fence that is never closed runs to the end of the message.
+pub fn split_fences(message: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut text_start = 0;
+    let mut offset = 0;
+    // Language and start of the code while inside a fence
+    let mut open: Option<(&str, usize)> = None;
+
+    for line in message.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        match open {
+            None => {
+                if let Some(info) = trimmed.strip_prefix("```").filter(|info| !info.contains('`')) {
+                    if line_start > text_start {
+                        blocks.push(Block::Text(&message[text_start..line_start]));
+                    }
+                    open = Some((info.split_whitespace().next().unwrap_or(""), offset));
+                }
+            },
+            Some((language, code_start)) => {
+                if trimmed == "```" {
+                    blocks.push(Block::Code { language, code: &message[code_start..line_start] });
+                    open = None;
+                    text_start = offset;
+                }
+            },
+        }
+    }
+
+    match open {
+        Some((language, code_start)) => blocks.push(Block::Code { language, code: &message[code_start..] }),
+        None if text_start < message.len() => blocks.push(Block::Text(&message[text_start..])),
+        None => {},
+    }
+    blocks
+}
+
+// Syntax definitions for code blocks, loaded once at startup
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+}
+
+impl Highlighter {
+    pub fn load() -> Self {
+        Highlighter { syntaxes: SyntaxSet::load_defaults_newlines() }
+    }
+
+    // `<pre><code>` markup for a fenced block, highlighted as `language`
+    // when that names a known syntax and as plain text otherwise
+    pub fn code_block(&self, language: &str, code: &str) -> String {
+        let too_big = code.len() > MAX_HIGHLIGHT_BYTES || code.lines().count() > MAX_HIGHLIGHT_LINES;
+        let body = if too_big {
+            escape_html(code)
+        } else {
+            self.highlight(language, code).unwrap_or_else(|| escape_html(code))
+        };
+        format!("<pre class=\"code-block\"><code>{}</code></pre>", body)
+    }
+
+    fn highlight(&self, language: &str, code: &str) -> Option<String> {
+        let syntax = Some(language)
+            .filter(|language| !language.is_empty())
+            .and_then(|language| self.syntaxes.find_syntax_by_token(language))
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+        let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &self.syntaxes, CLASS_STYLE);
+        for line in LinesWithEndings::from(code) {
+            generator.parse_html_for_line_which_includes_newline(line).ok()?;
+        }
+        Some(generator.finalize())
+    }
+}
+
+pub fn highlighter(req: &HttpRequest) -> Data<Highlighter> {
+    req.app_data::<Data<Highlighter>>().cloned().expect("highlighter is registered with the app")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[test]
+    fn fences_split_the_message() {
+        let blocks = split_fences("before\n```rust extra\nfn main() {}\n```\nafter\n```\nnever closed");
+        let shapes: Vec<(&str, &str)> = blocks.iter()
+            .map(|block| match block {
+                Block::Text(text) => ("text", *text),
+                Block::Code { language, code } => (*language, *code),
+            })
+            .collect();
+        assert_eq!(shapes, [("text", "before\n"), ("rust", "fn main() {}\n"), ("text", "after\n"), ("", "never closed")]);
+    }
+
+    #[test]
+    fn known_languages_are_highlighted_and_the_rest_escaped() {
+        let highlighter = Highlighter::load();
+        let rust = highlighter.code_block("rust", "fn main() { let x = 1 < 2; }\n");
+        assert!(rust.starts_with("<pre class=\"code-block\"><code>"));
+        assert!(rust.contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"), "{}", rust);
+        assert!(rust.contains("&lt;"));
+        assert!(!rust.contains("style="));
+
+        for language in ["", "no-such-language"] {
+            let plain = highlighter.code_block(language, "fn <b>\n");
+            assert!(!plain.contains("hl-rust"));
+            assert!(plain.contains("fn &lt;b&gt;"), "{}", plain);
+        }
+    }
+
+    #[test]
+    fn oversized_blocks_are_not_highlighted() {
+        let highlighter = Highlighter::load();
+        let long = "let x = 1;\n".repeat(MAX_HIGHLIGHT_LINES + 1);
+        assert_eq!(highlighter.code_block("rust", &long), format!("<pre class=\"code-block\"><code>{}</code></pre>", long));
+        let wide = format!("let x = \"{}\";\n", "a".repeat(MAX_HIGHLIGHT_BYTES));
+        assert!(!highlighter.code_block("rust", &wide).contains("<span"));
+    }
+
+    #[actix_web::test]
+    async fn posted_code_blocks_render_highlighted() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let body = multipart(&[("title", "code"), ("message", "look:\n```rust\nfn main() {}\n```\ndone"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/post/1").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("<pre class=\"code-block\"><code>"));
+        assert!(page.contains("hl-rust"));
+    }
+}
@@ -0,0 +1,249 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+use crate::config::AppConfig;
+use crate::filetypes::{find_type, FileKind, FileType};
+use crate::jobs::{AppState, Job};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub struct ProxyQuery {
+    url: String,
+}
+
+enum ProxyError {
+    BadUrl,
+    HostNotAllowed,
+    Upstream(String),
+    NotAnImage,
+    TooLarge,
+}
+
+// Local URL for an image hosted elsewhere, so visitors' browsers never
+// talk to the remote host
+pub fn proxied_url(remote: &str) -> String {
+    format!("/img-proxy?url={}", url::form_urlencoded::byte_serialize(remote.as_bytes()).collect::<String>())
+}
+
+fn cache_path(cache_dir: &str, url: &str) -> PathBuf {
+    let key: String = Sha256::digest(url.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect();
+    Path::new(cache_dir).join(key)
+}
+
+fn cache_ttl(config: &AppConfig) -> Duration {
+    Duration::from_secs(config.image_proxy_cache_hours * 60 * 60)
+}
+
+fn is_fresh(modified: SystemTime, ttl: Duration) -> bool {
+    modified.elapsed().is_ok_and(|age| age <= ttl)
+}
+
+// The cached copy of `path`, unless it is missing or has expired
+fn read_cached(path: &Path, ttl: Duration) -> Option<Vec<u8>> {
+    let modified = std::fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    if !is_fresh(modified, ttl) {
+        return None;
+    }
+    std::fs::read(path).ok()
+}
+
+// Sniffed content type, if the bytes are an image type we accept
+fn image_mime(types: &[FileType], data: &[u8]) -> Option<&'static str> {
+    let kind = infer::get(data)?;
+    find_type(types, kind.extension())
+        .is_some_and(|file_type| file_type.kind == FileKind::Image)
+        .then(|| kind.mime_type())
+}
+
+fn check_url(raw: &str, allowed_hosts: &[String]) -> Result<Url, ProxyError> {
+    let url = Url::parse(raw).map_err(|_| ProxyError::BadUrl)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ProxyError::BadUrl);
+    }
+    let host = url.host_str().ok_or(ProxyError::BadUrl)?;
+    if !allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return Err(ProxyError::HostNotAllowed);
+    }
+    Ok(url)
+}
+
+// Downloads the image, refusing redirects (they could lead off the
+// allowlist), non-image responses and anything over `max_bytes`.
+fn fetch(url: &Url, max_bytes: usize, types: &[FileType]) -> Result<Vec<u8>, ProxyError> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(FETCH_TIMEOUT)
+        .redirects(0)
+        .build();
+    let response = agent.request_url("GET", url).call().map_err(|e| ProxyError::Upstream(e.to_string()))?;
+    if !response.content_type().starts_with("image/") {
+        return Err(ProxyError::NotAnImage);
+    }
+
+    let mut data = Vec::new();
+    response.into_reader()
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| ProxyError::Upstream(e.to_string()))?;
+    if data.len() > max_bytes {
+        return Err(ProxyError::TooLarge);
+    }
+    if image_mime(types, &data).is_none() {
+        return Err(ProxyError::NotAnImage);
+    }
+    Ok(data)
+}
+
+fn load_or_fetch(raw: &str, config: &AppConfig) -> Result<Vec<u8>, ProxyError> {
+    let url = check_url(raw, &config.image_proxy_hosts)?;
+    let cached = cache_path(&config.image_proxy_cache_dir, url.as_str());
+    if let Some(data) = read_cached(&cached, cache_ttl(config)) {
+        return Ok(data);
+    }
+
+    let data = fetch(&url, config.max_upload_bytes, &config.file_types)?;
+    // A failed cache write only costs a refetch next time
+    let written = cached.parent()
+        .map(std::fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|_| std::fs::write(&cached, &data));
+    if let Err(e) = written {
+        eprintln!("Failed to cache proxied image {}: {}", url, e);
+    }
+    Ok(data)
+}
+
+// Serves an image from an allowlisted remote host through the board.
+// Disabled (404) when no hosts are configured.
+pub async fn image_proxy(config: web::Data<AppConfig>, query: web::Query<ProxyQuery>) -> Result<HttpResponse> {
+    if config.image_proxy_hosts.is_empty() {
+        return Ok(HttpResponse::NotFound().body("Image proxy is disabled."));
+    }
+
+    let url = query.into_inner().url;
+    let config = config.into_inner();
+    let fetch_config = config.clone();
+    match web::block(move || load_or_fetch(&url, &fetch_config)).await? {
+        Ok(data) => {
+            let mime = image_mime(&config.file_types, &data).unwrap_or("application/octet-stream");
+            Ok(HttpResponse::Ok()
+                .content_type(mime)
+                .insert_header(("Cache-Control", "public, max-age=86400"))
+                .body(data))
+        },
+        Err(ProxyError::BadUrl) => Ok(HttpResponse::BadRequest().body("Invalid image URL.")),
+        Err(ProxyError::HostNotAllowed) => Ok(HttpResponse::Forbidden().body("That host is not allowed.")),
+        Err(ProxyError::NotAnImage) => Ok(HttpResponse::BadGateway().body("Remote file is not a supported image.")),
+        Err(ProxyError::TooLarge) => Ok(HttpResponse::BadGateway().body("Remote image is too large.")),
+        Err(ProxyError::Upstream(e)) => {
+            eprintln!("Image proxy fetch failed: {}", e);
+            Ok(HttpResponse::BadGateway().body("Could not fetch the remote image."))
+        },
+    }
+}
+
+// Keeps the proxy's cache bounded: copies past image_proxy_cache_hours are
+// removed, then the oldest of the rest until they fit in
+// image_proxy_cache_bytes.
+pub struct ProxyCacheJob;
+
+impl Job for ProxyCacheJob {
+    fn name(&self) -> &'static str {
+        "proxy-cache"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        let config = &state.config;
+        let entries = match std::fs::read_dir(&config.image_proxy_cache_dir) {
+            Ok(entries) => entries,
+            // Nothing has been proxied yet
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("{}: {}", config.image_proxy_cache_dir, e)),
+        };
+        let mut copies: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+                Some((meta.modified().ok()?, meta.len(), entry.path()))
+            })
+            .collect();
+        // Oldest first
+        copies.sort();
+
+        let ttl = cache_ttl(config);
+        let mut total: u64 = copies.iter().map(|(_, size, _)| size).sum();
+        let mut removed = 0;
+        for (modified, size, path) in &copies {
+            if is_fresh(*modified, ttl) && total <= config.image_proxy_cache_bytes {
+                break;
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => {
+                    total -= size;
+                    removed += 1;
+                },
+                Err(e) => eprintln!("Failed to remove cached image {}: {}", path.display(), e),
+            }
+        }
+
+        println!("Removed {} cached proxy images; {} bytes in {} remain", removed, total, copies.len() - removed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    use crate::testing::{png, shared, test_config};
+
+    #[actix_web::test]
+    async fn allowlisted_images_are_proxied_and_others_refused() {
+        let mut remote = mockito::Server::new_async().await;
+        let image = remote.mock("GET", "/cat.png")
+            .with_header("content-type", "image/png")
+            .with_body(png(60))
+            .expect(1)
+            .create_async()
+            .await;
+        let page = remote.mock("GET", "/page.html")
+            .with_header("content-type", "text/html")
+            .with_body("<html></html>")
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { image_proxy_hosts: vec!["127.0.0.1".to_string()], ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        // The second request is answered from the cache
+        for _ in 0..2 {
+            let response = call_service(&app, TestRequest::get().uri(&proxied_url(&format!("{}/cat.png", remote.url()))).to_request()).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+            assert_eq!(read_body(response).await.to_vec(), png(60));
+        }
+        image.assert_async().await;
+
+        let other_host = remote.url().replace("127.0.0.1", "localhost");
+        for (url, status) in [
+            (format!("{}/cat.png", other_host), 403),
+            (format!("{}/page.html", remote.url()), 502),
+            ("file:///etc/passwd".to_string(), 400),
+        ] {
+            let response = call_service(&app, TestRequest::get().uri(&proxied_url(&url)).to_request()).await;
+            assert_eq!(response.status(), status, "{}", url);
+        }
+        page.assert_async().await;
+    }
+}
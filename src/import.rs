@@ -0,0 +1,194 @@
+use rusqlite::{params, Connection, Transaction};
+use serde::Deserialize;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::{generate_post_id, generate_upload_name};
+
+// Accepts both hand-written seed files and the output of the thread export,
+// so an exported thread can be imported into a fresh board.
+#[derive(Deserialize)]
+pub struct ImportThread {
+    pub op: ImportPost,
+    #[serde(default)]
+    pub replies: Vec<ImportPost>,
+}
+
+// A list of threads, or one thread on its own as the export writes it
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImportFile {
+    Threads(Vec<ImportThread>),
+    Thread(ImportThread),
+}
+
+#[derive(Deserialize)]
+pub struct ImportPost {
+    pub post_id: Option<String>,
+    pub title: String,
+    pub message: String,
+    // A local file to copy into the upload directory
+    #[serde(alias = "file_url")]
+    pub image_path: Option<String>,
+    // Original name of the image, shown as its caption
+    pub file_name: Option<String>,
+    pub file_alt: Option<String>,
+    pub created_at: Option<String>,
+    pub last_reply_at: Option<String>,
+}
+
+// Imports the threads in `path` when the board has no posts yet. Returns the
+// number of threads imported; nothing is written if any record is invalid.
+pub fn import_if_empty(conn: &mut Connection, path: &str, upload_dir: &str) -> Result<usize, String> {
+    let existing: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if existing > 0 {
+        return Ok(0);
+    }
+    import_file(conn, path, upload_dir)
+}
+
+// Imports every thread in `path` alongside whatever the board already
+// holds. Returns the number of threads imported; nothing is written if any
+// record is invalid.
+pub fn import_file(conn: &mut Connection, path: &str, upload_dir: &str) -> Result<usize, String> {
+    let contents = read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let threads = match serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))? {
+        ImportFile::Threads(threads) => threads,
+        ImportFile::Thread(thread) => vec![thread],
+    };
+
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    for (index, thread) in threads.iter().enumerate() {
+        import_thread(&tx, thread, upload_dir).map_err(|e| format!("{}: record {}: {}", path, index + 1, e))?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    Ok(threads.len())
+}
+
+pub fn import_thread(tx: &Transaction, thread: &ImportThread, upload_dir: &str) -> Result<i64, String> {
+    let thread_id = insert_post(tx, &thread.op, 0, upload_dir)?;
+    for (index, reply) in thread.replies.iter().enumerate() {
+        insert_post(tx, reply, thread_id, upload_dir).map_err(|e| format!("reply {}: {}", index + 1, e))?;
+    }
+    Ok(thread_id)
+}
+
+fn insert_post(tx: &Transaction, post: &ImportPost, parent_id: i64, upload_dir: &str) -> Result<i64, String> {
+    if post.title.trim().is_empty() || post.message.trim().is_empty() {
+        return Err("title and message are mandatory".to_string());
+    }
+
+    let file_path = match &post.image_path {
+        Some(image_path) => Some(copy_image(image_path, upload_dir)?),
+        None => None,
+    };
+    let post_id = post.post_id.clone().unwrap_or_else(generate_post_id);
+
+    tx.execute(
+        "INSERT INTO files (post_id, parent_id, title, message, file_path, last_reply_at, created_at, original_name, alt_text)
+         VALUES (?1, ?2, ?3, ?4, ?5, COALESCE(?6, ?7, CURRENT_TIMESTAMP), COALESCE(?7, ?6, CURRENT_TIMESTAMP), ?8, ?9)",
+        params![post_id, parent_id, post.title, post.message, file_path, post.last_reply_at, post.created_at, post.file_name, file_path.as_ref().and(post.file_alt.as_ref())],
+    ).map_err(|e| e.to_string())?;
+
+    Ok(tx.last_insert_rowid())
+}
+
+// Copies an image into the upload directory, leaving files that already
+// live there (e.g. from an export of this board) where they are.
+fn copy_image(image_path: &str, upload_dir: &str) -> Result<String, String> {
+    let uploaded_name = image_path.strip_prefix("/file/").or_else(|| image_path.strip_prefix("/static/"));
+    let source = match uploaded_name {
+        Some(name) => Path::new(upload_dir).join(name).to_string_lossy().into_owned(),
+        None => image_path.to_string(),
+    };
+
+    let source_path = Path::new(&source);
+    if !source_path.is_file() {
+        return Err(format!("image {} not found", image_path));
+    }
+    if uploaded_name.is_some() {
+        return Ok(source);
+    }
+
+    let filename = source_path.file_name().and_then(|name| name.to_str()).unwrap_or("image");
+    let destination = Path::new(upload_dir).join(generate_upload_name(filename)).to_string_lossy().into_owned();
+    std::fs::copy(source_path, &destination).map_err(|e| format!("image {}: {}", image_path, e))?;
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::load_thread;
+    use crate::export::thread_to_json;
+    use crate::testing::{test_config, test_db};
+
+    fn post(title: &str, message: &str, created_at: &str) -> ImportPost {
+        ImportPost {
+            post_id: None,
+            title: title.to_string(),
+            message: message.to_string(),
+            image_path: None,
+            file_name: None,
+            file_alt: None,
+            created_at: Some(created_at.to_string()),
+            last_reply_at: None,
+        }
+    }
+
+    #[test]
+    fn exported_thread_imports_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        std::fs::create_dir_all(&config.upload_dir).unwrap();
+        let image = dir.path().join("seed.png");
+        std::fs::write(&image, b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let mut original = test_db();
+        let thread = ImportThread {
+            op: ImportPost {
+                image_path: Some(image.to_string_lossy().into_owned()),
+                file_name: Some("seed.png".to_string()),
+                file_alt: Some("a blue square".to_string()),
+                ..post("Round trip", "the opening post", "2024-01-01 10:00:00")
+            },
+            replies: vec![
+                post("Re", "first reply\nwith two lines", "2024-01-01 11:00:00"),
+                post("Re", ">>1 quoting the op", "2024-01-02 09:30:00"),
+            ],
+        };
+        let tx = original.transaction().unwrap();
+        let thread_id = import_thread(&tx, &thread, &config.upload_dir).unwrap() as i32;
+        tx.commit().unwrap();
+
+        let exported = format!("[{}]", thread_to_json(&load_thread(&original, &config, thread_id).unwrap()));
+        assert!(exported.contains("quoting the op"));
+        assert!(exported.contains(r#""file_name": "seed.png""#));
+        assert!(exported.contains(r#""file_alt": "a blue square""#));
+        let export_path = dir.path().join("thread.json");
+        std::fs::write(&export_path, &exported).unwrap();
+
+        let mut fresh = test_db();
+        assert_eq!(import_if_empty(&mut fresh, export_path.to_str().unwrap(), &config.upload_dir), Ok(1));
+        let reimported = format!("[{}]", thread_to_json(&load_thread(&fresh, &config, thread_id).unwrap()));
+        assert_eq!(reimported, exported);
+
+        // A board with posts is left alone
+        assert_eq!(import_if_empty(&mut fresh, export_path.to_str().unwrap(), &config.upload_dir), Ok(0));
+    }
+
+    #[test]
+    fn invalid_record_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seed.json");
+        std::fs::write(&path, r#"[{"op": {"title": "fine", "message": "ok"}}, {"op": {"title": "", "message": "no title"}}]"#).unwrap();
+
+        let mut conn = test_db();
+        let error = import_if_empty(&mut conn, path.to_str().unwrap(), dir.path().to_str().unwrap()).unwrap_err();
+        assert!(error.contains("record 2"), "{}", error);
+        let posts: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+    }
+}
@@ -0,0 +1,114 @@
+use rusqlite::{Connection, OpenFlags};
+use std::fs::File;
+use std::path::Path;
+
+use crate::config::AppConfig;
+
+// Problems listed when the check fails; SQLite can report thousands
+const MAX_PROBLEMS: usize = 10;
+
+fn recovery_advice(path: &str) -> String {
+    format!(
+        "Stop anything else using it, keep a copy of {path} (and any {path}-wal file), then either restore a backup \
+         or salvage what can be read with: sqlite3 {path} .recover | sqlite3 recovered.db"
+    )
+}
+
+// Checks the database before anything opens it for writing, so damage is
+// reported at startup rather than as a panic in the first request. A
+// missing file is fine (a new board starts); an empty or unreadable one
+// is reported apart from corruption. `integrity_check` runs the full
+// PRAGMA integrity_check instead of the quicker quick_check.
+pub fn check_database(path: &str, config: &AppConfig) -> Result<(), String> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let length = File::open(path)
+        .and_then(|file| file.metadata())
+        .map_err(|e| format!("The database {} can't be read: {}. Check its permissions and the disk.", path, e))?
+        .len();
+    if length == 0 {
+        return Err(format!(
+            "The database {} is empty (0 bytes), which usually means it was cut short by a full disk or a crash. \
+             Restore a backup, or delete the file to start a new, empty board.",
+            path
+        ));
+    }
+
+    let pragma = if config.integrity_check { "integrity_check" } else { "quick_check" };
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("The database {} can't be opened: {}.", path, e))?;
+    let mut stmt = conn.prepare(&format!("PRAGMA {}({})", pragma, MAX_PROBLEMS))
+        .map_err(|e| format!("The database {} is damaged or not a SQLite database: {}. {}", path, e, recovery_advice(path)))?;
+    let problems = stmt.query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("The database {} is damaged: {}. {}", path, e, recovery_advice(path)))?;
+    if problems.len() == 1 && problems[0] == "ok" {
+        return Ok(());
+    }
+    Err(format!(
+        "The database {} failed PRAGMA {}:\n  {}\n{}",
+        path, pragma, problems.join("\n  "), recovery_advice(path)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A healthy database of several pages at `path`
+    fn write_database(path: &Path) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch("CREATE TABLE posts (id INTEGER PRIMARY KEY, message TEXT); CREATE INDEX posts_message ON posts (message);").unwrap();
+        for n in 0..500 {
+            conn.execute("INSERT INTO posts (message) VALUES (?1)", [format!("message number {} {}", n, "x".repeat(100))]).unwrap();
+        }
+    }
+
+    #[test]
+    fn healthy_and_missing_databases_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("posts.db");
+        let path = path.to_str().unwrap();
+        check_database(path, &AppConfig::default()).unwrap();
+
+        write_database(Path::new(path));
+        check_database(path, &AppConfig::default()).unwrap();
+        check_database(path, &AppConfig { integrity_check: true, ..AppConfig::default() }).unwrap();
+    }
+
+    #[test]
+    fn empty_files_are_reported_apart_from_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("posts.db");
+        std::fs::write(&path, b"").unwrap();
+        let error = check_database(path.to_str().unwrap(), &AppConfig::default()).unwrap_err();
+        assert!(error.contains("is empty (0 bytes)"), "{}", error);
+        assert!(error.contains(path.to_str().unwrap()));
+
+        std::fs::write(&path, "these are not the bytes of a database".repeat(200)).unwrap();
+        let error = check_database(path.to_str().unwrap(), &AppConfig::default()).unwrap_err();
+        assert!(error.contains("is damaged or not a SQLite database"), "{}", error);
+        assert!(error.contains(".recover"));
+    }
+
+    #[test]
+    fn damaged_pages_fail_both_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("posts.db");
+        write_database(&path);
+        // Scribble over a page in the middle of the file
+        let mut bytes = std::fs::read(&path).unwrap();
+        let page = 4096;
+        let middle = bytes.len() / page / 2 * page;
+        bytes[middle..middle + page].fill(0x5a);
+        std::fs::write(&path, bytes).unwrap();
+
+        for integrity_check in [false, true] {
+            let error = check_database(path.to_str().unwrap(), &AppConfig { integrity_check, ..AppConfig::default() }).unwrap_err();
+            assert!(error.contains("damaged") || error.contains("failed PRAGMA"), "{}", error);
+            assert!(error.contains(path.to_str().unwrap()));
+            assert!(error.contains("restore a backup"));
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use actix_web::rt::task::spawn_blocking;
+use actix_web::web::Data;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::admin::Admin;
+use crate::config::AppConfig;
+use crate::render_template;
+use crate::site::Site;
+use crate::storage::Storage;
+
+// What a job gets to work with: the shared connection and the config
+#[derive(Clone)]
+pub struct AppState {
+    pub conn: Data<Mutex<Connection>>,
+    pub config: Data<AppConfig>,
+    pub storage: Data<dyn Storage>,
+}
+
+pub trait Job: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn interval(&self) -> Duration;
+    // Delay before the first scheduled run
+    fn first_delay(&self, _state: &AppState) -> Duration {
+        self.interval()
+    }
+    fn run(&self, state: &AppState) -> Result<(), String>;
+}
+
+#[derive(Clone, Default)]
+pub struct JobStatus {
+    pub running: bool,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_duration_ms: u128,
+    pub last_error: Option<String>,
+}
+
+pub struct Scheduler {
+    state: AppState,
+    jobs: Vec<Arc<dyn Job>>,
+    statuses: Mutex<HashMap<&'static str, JobStatus>>,
+}
+
+impl Scheduler {
+    pub fn new(state: AppState, jobs: Vec<Arc<dyn Job>>) -> Self {
+        let statuses = jobs.iter().map(|job| (job.name(), JobStatus::default())).collect();
+        Scheduler {
+            state,
+            jobs,
+            statuses: Mutex::new(statuses),
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<(&'static str, Duration, JobStatus)> {
+        let statuses = self.statuses.lock().unwrap();
+        self.jobs.iter()
+            .map(|job| (job.name(), job.interval(), statuses[job.name()].clone()))
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<dyn Job>> {
+        self.jobs.iter().find(|job| job.name() == name).cloned()
+    }
+
+    // Runs a job on the blocking pool, recording the outcome. A job that is
+    // already running is not started a second time.
+    pub async fn run_job(&self, job: Arc<dyn Job>) {
+        let name = job.name();
+        {
+            let mut statuses = self.statuses.lock().unwrap();
+            let status = statuses.get_mut(name).unwrap();
+            if status.running {
+                return;
+            }
+            status.running = true;
+        }
+
+        let state = self.state.clone();
+        let started = Instant::now();
+        let result = spawn_blocking(move || job.run(&state)).await
+            .unwrap_or_else(|_| Err("job panicked".to_string()));
+
+        if let Err(e) = &result {
+            eprintln!("Job {} failed: {}", name, e);
+        }
+
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.get_mut(name).unwrap();
+        status.running = false;
+        status.last_run = Some(Utc::now());
+        status.last_duration_ms = started.elapsed().as_millis();
+        status.last_error = result.err();
+    }
+}
+
+// Spawns one timer loop per job. The loops exit once `shutdown` flips to true.
+pub fn start(scheduler: Data<Scheduler>, shutdown: watch::Receiver<bool>) {
+    for job in scheduler.jobs.clone() {
+        let scheduler = scheduler.clone();
+        let mut shutdown = shutdown.clone();
+        actix_web::rt::spawn(async move {
+            let mut delay = job.first_delay(&scheduler.state);
+            loop {
+                tokio::select! {
+                    _ = actix_web::rt::time::sleep(delay) => {},
+                    _ = shutdown.changed() => break,
+                }
+                scheduler.run_job(job.clone()).await;
+                delay = job.interval();
+            }
+        });
+    }
+}
+
+fn format_interval(interval: Duration) -> String {
+    let secs = interval.as_secs();
+    let (count, unit) = match secs {
+        s if s % 86400 == 0 => (s / 86400, "day"),
+        s if s % 3600 == 0 => (s / 3600, "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    if count == 1 {
+        format!("every {}", unit)
+    } else {
+        format!("every {} {}s", count, unit)
+    }
+}
+
+pub async fn list_jobs(_admin: Admin, scheduler: Data<Scheduler>, site: Data<Site>) -> Result<HttpResponse> {
+    let mut jobs_html = String::new();
+
+    for (name, interval, status) in scheduler.statuses() {
+        let last_run = status.last_run
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let outcome = match (&status.last_error, status.running) {
+            (_, true) => "running".to_string(),
+            (Some(e), _) => format!("failed: {}", e),
+            (None, _) if status.last_run.is_some() => format!("ok ({} ms)", status.last_duration_ms),
+            (None, _) => String::new(),
+        };
+        jobs_html.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td><form action="/admin/jobs/{}/run" method="post"><button type="submit">Run now</button></form></td></tr>"#,
+            name, format_interval(interval), last_run, outcome, name
+        ));
+    }
+
+    let mut context = HashMap::from([("JOBS", jobs_html)]);
+    context.extend(site.chrome());
+    let body = render_template("templates/admin_jobs.html", &context);
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn trigger_job(_admin: Admin, scheduler: Data<Scheduler>, path: web::Path<String>) -> Result<HttpResponse> {
+    let Some(job) = scheduler.find(&path.into_inner()) else {
+        return Ok(HttpResponse::NotFound().body("Unknown job."));
+    };
+
+    scheduler.run_job(job).await;
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/admin/jobs")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::storage::open_storage;
+    use crate::testing::{test_config, test_db};
+
+    struct CountingJob {
+        runs: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl Job for CountingJob {
+        fn name(&self) -> &'static str {
+            "count"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_millis(20)
+        }
+
+        fn run(&self, _state: &AppState) -> Result<(), String> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                Err("dummy failure".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn scheduler(job: CountingJob) -> Data<Scheduler> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let state = AppState {
+            conn: Data::new(Mutex::new(test_db())),
+            storage: Data::from(open_storage(&config)),
+            config: Data::new(config),
+        };
+        Data::new(Scheduler::new(state, vec![Arc::new(job)]))
+    }
+
+    #[actix_web::test]
+    async fn jobs_run_on_their_interval_until_shutdown() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let scheduler = scheduler(CountingJob { runs: runs.clone(), fail: false });
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        start(scheduler.clone(), shutdown_rx);
+
+        actix_web::rt::time::sleep(Duration::from_millis(150)).await;
+        assert!(runs.load(Ordering::SeqCst) >= 3, "ran {} times", runs.load(Ordering::SeqCst));
+        let (name, _, status) = scheduler.statuses().remove(0);
+        assert_eq!(name, "count");
+        assert!(status.last_run.is_some());
+        assert_eq!(status.last_error, None);
+
+        shutdown_tx.send(true).unwrap();
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        let stopped_at = runs.load(Ordering::SeqCst);
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped_at);
+    }
+
+    #[actix_web::test]
+    async fn failures_are_recorded() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let scheduler = scheduler(CountingJob { runs: runs.clone(), fail: true });
+        let job = scheduler.find("count").unwrap();
+        scheduler.run_job(job).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let (_, _, status) = scheduler.statuses().remove(0);
+        assert!(!status.running);
+        assert_eq!(status.last_error.as_deref(), Some("dummy failure"));
+        assert!(scheduler.find("missing").is_none());
+    }
+
+    #[test]
+    fn intervals_read_naturally() {
+        assert_eq!(format_interval(Duration::from_secs(24 * 60 * 60)), "every day");
+        assert_eq!(format_interval(Duration::from_secs(7 * 24 * 60 * 60)), "every 7 days");
+        assert_eq!(format_interval(Duration::from_secs(90)), "every 90 seconds");
+    }
+}
@@ -0,0 +1,94 @@
+use actix_web::cookie::Cookie;
+use actix_web::{HttpRequest, HttpResponseBuilder};
+use chrono::{DateTime, Utc};
+
+use crate::session::Prefs;
+use crate::timefmt::parse_timestamp;
+
+// The visitor's previous look at each thread is kept in their prefs. It
+// used to be a cookie of its own, scoped to the thread's path; that one is
+// still read until the thread is next viewed.
+const OLD_LAST_SEEN_COOKIE: &str = "last_seen";
+
+pub fn last_seen(req: &HttpRequest, prefs: &Prefs, thread_id: i32) -> Option<DateTime<Utc>> {
+    let secs = match prefs.seen.get(&thread_id) {
+        Some(&secs) => secs,
+        None => req.cookie(OLD_LAST_SEEN_COOKIE)?.value().parse().ok()?,
+    };
+    DateTime::from_timestamp(secs, 0)
+}
+
+// Removes the old per-thread cookie once its value has moved into the prefs
+pub fn drop_old_cookie(req: &HttpRequest, response: &mut HttpResponseBuilder, thread_id: i32) {
+    if req.cookie(OLD_LAST_SEEN_COOKIE).is_some() {
+        let mut removal = Cookie::build(OLD_LAST_SEEN_COOKIE, "").path(format!("/post/{}", thread_id)).finish();
+        removal.make_removal();
+        response.cookie(removal);
+    }
+}
+
+// Whether a post created at `created_at` is newer than the previous visit.
+// Nothing is new on a first visit.
+pub fn is_new(seen: Option<DateTime<Utc>>, created_at: &str) -> bool {
+    match (seen, parse_timestamp(created_at)) {
+        (Some(seen), Some(created)) => created > seen,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use rusqlite::params;
+
+    use crate::session::prefs_cookies;
+    use crate::testing::{shared, test_config};
+
+    #[actix_web::test]
+    async fn replies_since_the_last_visit_are_highlighted() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let thread_id = {
+            let conn = shared.conn.lock().unwrap();
+            let post = |parent_id: i64, title: &str, ago: &str| {
+                conn.execute(
+                    "INSERT INTO files (post_id, parent_id, title, message, created_at) VALUES (?1, ?2, ?1, 'body', datetime('now', ?3))",
+                    params![title, parent_id, ago],
+                ).unwrap();
+                conn.last_insert_rowid()
+            };
+            let thread_id = post(0, "op", "-3 hours");
+            post(thread_id, "read-before", "-2 hours");
+            post(thread_id, "arrived-since", "-10 minutes");
+            thread_id as i32
+        };
+        let app = init_service(crate::app(&shared)).await;
+        let thread = format!("/post/{}", thread_id);
+        let highlighted = |page: &str, title: &str| {
+            let at = page.find(&format!(">{}<", title)).unwrap();
+            // The post's own div is the last one opened with an id="p<n>" anchor
+            let start = page[..at].rfind(" id=\"p").unwrap();
+            page[..start].ends_with("<div class=\"post new-reply\"")
+        };
+
+        // First visit: nothing to compare with
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&thread).to_request()).await.to_vec()).unwrap();
+        assert!(!page.contains("new-reply"));
+
+        // A visit an hour ago, as remembered in the signed prefs cookie
+        let an_hour_ago = Utc::now().timestamp() - 60 * 60;
+        let req = TestRequest::default().app_data(shared.cookie_key.clone()).to_http_request();
+        let cookies = prefs_cookies(&req, |prefs| prefs.mark_seen(thread_id, an_hour_ago));
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&thread).cookie(cookies[0].clone()).to_request()).await.to_vec()).unwrap();
+        assert!(highlighted(&page, "arrived-since"));
+        assert!(!highlighted(&page, "read-before"));
+
+        // Still read from the cookie it used to have of its own
+        let old = Cookie::new(OLD_LAST_SEEN_COOKIE, an_hour_ago.to_string());
+        let response = call_service(&app, TestRequest::get().uri(&thread).cookie(old).to_request()).await;
+        assert!(response.response().cookies().any(|cookie| cookie.name() == OLD_LAST_SEEN_COOKIE && cookie.value().is_empty()));
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(highlighted(&page, "arrived-since"));
+    }
+}
@@ -0,0 +1,308 @@
+use actix_web::web::Data;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::render_notice;
+use crate::session::{update_prefs, Prefs};
+use crate::site::Site;
+
+// One table per language, named <code>.toml. A new language only needs a
+// new file here.
+const LOCALES_DIR: &str = "locales";
+// Used for any key missing from the visitor's language
+const FALLBACK_LANG: &str = "en";
+
+const PLURAL_CATEGORIES: [&str; 6] = ["zero", "one", "two", "few", "many", "other"];
+
+// How a language picks the plural form for a count, after the CLDR rules.
+// Tables name theirs with `plural_rule`.
+#[derive(Clone, Copy)]
+enum PluralRule {
+    // English, German, Spanish, ...: "one" for 1
+    OneOther,
+    // French, Brazilian Portuguese: "one" for 0 and 1
+    ZeroOneOther,
+    // Russian, Ukrainian: "one" for 1, 21, 31...; "few" for 2-4, 22-24...
+    EastSlavic,
+    // Polish: "one" only for 1; "few" as East Slavic
+    Polish,
+    // Japanese, Chinese, Korean, ...: no plural forms
+    Invariant,
+}
+
+impl PluralRule {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "one-other" => Some(PluralRule::OneOther),
+            "zero-one-other" => Some(PluralRule::ZeroOneOther),
+            "east-slavic" => Some(PluralRule::EastSlavic),
+            "polish" => Some(PluralRule::Polish),
+            "invariant" => Some(PluralRule::Invariant),
+            _ => None,
+        }
+    }
+
+    fn category(self, n: i64) -> &'static str {
+        let n = n.abs();
+        let few = (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100));
+        match self {
+            PluralRule::OneOther => if n == 1 { "one" } else { "other" },
+            PluralRule::ZeroOneOther => if n <= 1 { "one" } else { "other" },
+            PluralRule::EastSlavic if n % 10 == 1 && n % 100 != 11 => "one",
+            PluralRule::Polish if n == 1 => "one",
+            PluralRule::EastSlavic | PluralRule::Polish => if few { "few" } else { "many" },
+            PluralRule::Invariant => "other",
+        }
+    }
+}
+
+enum Entry {
+    Text(String),
+    // Keyed by plural category
+    Plural(HashMap<String, String>),
+}
+
+struct Locale {
+    // The language's own name for itself, shown in the language links
+    name: String,
+    plural_rule: PluralRule,
+    entries: HashMap<String, Entry>,
+}
+
+// Flattens nested tables into dotted keys. A table whose keys are all
+// plural categories is one plural entry.
+fn collect_entries(prefix: &str, table: &toml::Table, entries: &mut HashMap<String, Entry>) -> Result<(), String> {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::String(text) => {
+                entries.insert(key, Entry::Text(text.clone()));
+            },
+            toml::Value::Table(forms) if !forms.is_empty() && forms.keys().all(|form| PLURAL_CATEGORIES.contains(&form.as_str())) => {
+                let forms = forms.iter()
+                    .map(|(form, text)| text.as_str().map(|text| (form.clone(), text.to_string())).ok_or(format!("{}.{} must be a string", key, form)))
+                    .collect::<Result<_, _>>()?;
+                entries.insert(key, Entry::Plural(forms));
+            },
+            toml::Value::Table(nested) => collect_entries(&key, nested, entries)?,
+            _ => return Err(format!("{} must be a string or a table", key)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_locale(source: &str) -> Result<Locale, String> {
+    let mut table: toml::Table = source.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let name = match table.remove("name") {
+        Some(toml::Value::String(name)) => name,
+        _ => return Err("missing `name`".to_string()),
+    };
+    let plural_rule = match table.remove("plural_rule") {
+        Some(toml::Value::String(rule)) => PluralRule::parse(&rule).ok_or(format!("unknown plural_rule \"{}\"", rule))?,
+        _ => return Err("missing `plural_rule`".to_string()),
+    };
+    let mut entries = HashMap::new();
+    collect_entries("", &table, &mut entries)?;
+    Ok(Locale { name, plural_rule, entries })
+}
+
+pub struct Locales {
+    tables: HashMap<String, Locale>,
+}
+
+impl Locales {
+    // Reads every table in LOCALES_DIR. The fallback language must exist.
+    pub fn load() -> Result<Self, String> {
+        let dir = std::fs::read_dir(LOCALES_DIR).map_err(|e| format!("Cannot read {}: {}", LOCALES_DIR, e))?;
+        let mut tables = HashMap::new();
+        for entry in dir {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                continue;
+            }
+            let code = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+            let source = std::fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            let locale = parse_locale(&source).map_err(|e| format!("Invalid locale {}: {}", path.display(), e))?;
+            tables.insert(code, locale);
+        }
+        if !tables.contains_key(FALLBACK_LANG) {
+            return Err(format!("Missing {}", Path::new(LOCALES_DIR).join(format!("{}.toml", FALLBACK_LANG)).display()));
+        }
+        Ok(Locales { tables })
+    }
+
+    // (code, name) of every language, sorted by code
+    fn languages(&self) -> Vec<(&str, &str)> {
+        let mut languages: Vec<(&str, &str)> = self.tables.iter().map(|(code, locale)| (code.as_str(), locale.name.as_str())).collect();
+        languages.sort();
+        languages
+    }
+}
+
+// Looks up UI strings in the visitor's language, falling back to English
+// and finally to the key itself.
+#[derive(Clone)]
+pub struct Tr {
+    locales: Data<Locales>,
+    lang: String,
+}
+
+impl Tr {
+    fn lookup(&self, key: &str) -> Option<(&Locale, &Entry)> {
+        [self.lang.as_str(), FALLBACK_LANG].into_iter()
+            .filter_map(|lang| self.locales.tables.get(lang))
+            .find_map(|locale| locale.entries.get(key).map(|entry| (locale, entry)))
+    }
+
+    pub fn t(&self, key: &str) -> String {
+        match self.lookup(key) {
+            Some((_, Entry::Text(text))) => text.clone(),
+            _ => key.to_string(),
+        }
+    }
+
+    // `t` with {name} placeholders filled in
+    pub fn tf(&self, key: &str, args: &[(&str, &str)]) -> String {
+        args.iter().fold(self.t(key), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+    }
+
+    // The form of a plural entry for `n`, with {n} filled in
+    pub fn tn(&self, key: &str, n: i64) -> String {
+        let form = match self.lookup(key) {
+            Some((locale, Entry::Plural(forms))) => forms.get(locale.plural_rule.category(n)).or_else(|| forms.get("other")),
+            _ => None,
+        };
+        match form {
+            Some(form) => form.replace("{n}", &n.to_string()),
+            None => key.to_string(),
+        }
+    }
+
+    // Replaces {{t:key}} placeholders in a template
+    pub fn localize(&self, template: &str) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{t:") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            out.push_str(&self.t(&rest[start + 4..start + end]));
+            rest = &rest[start + end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    // Links for switching language, the current one unlinked
+    pub fn language_links(&self) -> String {
+        let mut html = format!(r#"<div class="language-links">{}"#, self.t("language.label"));
+        for (code, name) in self.locales.languages() {
+            if code == self.lang {
+                html.push_str(&format!(r#" <span class="active-language">{}</span>"#, name));
+            } else {
+                html.push_str(&format!(r#" <a href="/lang/{}">{}</a>"#, code, name));
+            }
+        }
+        html.push_str("</div>");
+        html
+    }
+}
+
+// Translator for the visitor's chosen language
+pub fn translator(req: &HttpRequest) -> Tr {
+    let locales = req.app_data::<Data<Locales>>().cloned().expect("locales are registered with the app");
+    let lang = Prefs::of(req).lang
+        .filter(|lang| locales.tables.contains_key(lang))
+        .unwrap_or_else(|| FALLBACK_LANG.to_string());
+    Tr { locales, lang }
+}
+
+pub async fn set_language(req: HttpRequest, locales: Data<Locales>, site: Data<Site>, path: web::Path<String>) -> Result<HttpResponse> {
+    let code = path.into_inner();
+    if !locales.tables.contains_key(&code) {
+        let tr = translator(&req);
+        let body = render_notice(&site, &tr, &tr.t("error.unknown_language_title"), &tr.tf("error.unknown_language", &[("code", &crate::escape_html(&code))]));
+        return Ok(HttpResponse::NotFound().content_type("text/html").body(body));
+    }
+
+    let mut response = HttpResponse::SeeOther();
+    update_prefs(&req, &mut response, |prefs| prefs.lang = Some(code));
+    Ok(response.append_header(("Location", "/")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{shared, test_config};
+
+    fn tr(lang: &str, tables: &[(&str, &str)]) -> Tr {
+        let tables = tables.iter().map(|(code, source)| (code.to_string(), parse_locale(source).unwrap())).collect();
+        Tr { locales: Data::new(Locales { tables }), lang: lang.to_string() }
+    }
+
+    const EN: &str = r#"
+        name = "English"
+        plural_rule = "one-other"
+        [thread]
+        reply = "Reply"
+        posted = "Posted by {name}"
+        replies = { one = "1 reply", other = "{n} replies" }
+    "#;
+    const RU: &str = r#"
+        name = "Русский"
+        plural_rule = "east-slavic"
+        [thread]
+        reply = "Ответить"
+        replies = { one = "{n} ответ", few = "{n} ответа", many = "{n} ответов" }
+    "#;
+
+    #[test]
+    fn plural_rules_pick_the_cldr_category() {
+        let categories = |rule: &str, counts: &[i64]| counts.iter().map(|&n| PluralRule::parse(rule).unwrap().category(n)).collect::<Vec<_>>();
+        assert_eq!(categories("one-other", &[0, 1, 2]), ["other", "one", "other"]);
+        assert_eq!(categories("zero-one-other", &[0, 1, 2]), ["one", "one", "other"]);
+        assert_eq!(categories("east-slavic", &[1, 2, 5, 11, 12, 21, 22, 111]), ["one", "few", "many", "many", "many", "one", "few", "many"]);
+        assert_eq!(categories("polish", &[1, 2, 5, 21, 22]), ["one", "few", "many", "many", "few"]);
+        assert_eq!(categories("invariant", &[1, 2]), ["other", "other"]);
+        assert!(PluralRule::parse("klingon").is_none());
+    }
+
+    #[test]
+    fn missing_keys_fall_back_to_english_then_the_key() {
+        let tr = tr("ru", &[("en", EN), ("ru", RU)]);
+        assert_eq!(tr.t("thread.reply"), "Ответить");
+        assert_eq!(tr.tf("thread.posted", &[("name", "Anna")]), "Posted by Anna");
+        assert_eq!(tr.t("thread.nowhere"), "thread.nowhere");
+        assert_eq!([1, 3, 5, 21].map(|n| tr.tn("thread.replies", n)), ["1 ответ", "3 ответа", "5 ответов", "21 ответ"]);
+        assert_eq!(tr.localize("<button>{{t:thread.reply}}</button>"), "<button>Ответить</button>");
+    }
+
+    #[test]
+    fn broken_tables_are_refused() {
+        assert!(parse_locale("plural_rule = \"one-other\"").is_err());
+        assert!(parse_locale("name = \"X\"\nplural_rule = \"klingon\"").is_err());
+        assert!(parse_locale("name = \"X\"\nplural_rule = \"one-other\"\ncount = 3").is_err());
+    }
+
+    #[actix_web::test]
+    async fn the_chosen_language_is_used_for_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(r#"<a href="/lang/de">Deutsch</a>"#));
+
+        assert_eq!(call_service(&app, TestRequest::get().uri("/lang/xx").to_request()).await.status(), 404);
+        let response = call_service(&app, TestRequest::get().uri("/lang/de").to_request()).await;
+        assert_eq!(response.status(), 303);
+        let cookie = response.response().cookies().find(|cookie| cookie.name() == "prefs").unwrap().into_owned();
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").cookie(cookie).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("Neuen Thread erstellen"));
+        assert!(page.contains(r#"<span class="active-language">Deutsch</span>"#));
+    }
+}
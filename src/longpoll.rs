@@ -0,0 +1,136 @@
+use actix_web::{web, HttpResponse, Result};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{timeout_at, Instant};
+
+use crate::config::AppConfig;
+use crate::api::{post_from_row, ApiPost, POST_COLUMNS};
+
+// How long a waiting request is held before returning empty
+const WAIT_TIMEOUT: Duration = Duration::from_secs(25);
+
+// Tells long-polling clients which thread just got a reply
+pub struct ReplyNotifier {
+    sender: broadcast::Sender<i32>,
+}
+
+impl ReplyNotifier {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(256);
+        ReplyNotifier { sender }
+    }
+
+    pub fn notify(&self, thread_id: i32) {
+        // No receivers just means nobody is waiting
+        let _ = self.sender.send(thread_id);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WaitQuery {
+    // created_at of the newest reply the client has, "YYYY-MM-DD HH:MM:SS"
+    since: String,
+}
+
+fn replies_since(conn: &Connection, config: &AppConfig, thread_id: i32, since: &str) -> Vec<ApiPost> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE parent_id = ?1 AND hidden = 0 AND created_at > ?2 ORDER BY created_at ASC, id ASC",
+        POST_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![thread_id, since], post_from_row(config)).unwrap()
+        .filter_map(|reply| reply.ok())
+        .collect()
+}
+
+// Long-poll for new replies in a thread: answers as soon as there are
+// replies newer than `since`, or with an empty list after WAIT_TIMEOUT.
+pub async fn wait_for_replies(conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, notifier: web::Data<ReplyNotifier>, path: web::Path<i32>, query: web::Query<WaitQuery>) -> Result<HttpResponse> {
+    let thread_id = path.into_inner();
+    // Subscribe before the first check so a reply landing in between isn't missed
+    let mut receiver = notifier.sender.subscribe();
+
+    {
+        let conn = conn.lock().unwrap();
+        let exists = conn.query_row("SELECT 1 FROM files WHERE id = ?1 AND parent_id = 0", params![thread_id], |_| Ok(())).is_ok();
+        if !exists {
+            return Ok(HttpResponse::NotFound().json(json!({ "error": "Thread not found" })));
+        }
+        let replies = replies_since(&conn, &config, thread_id, &query.since);
+        if !replies.is_empty() {
+            return Ok(HttpResponse::Ok().json(json!({ "replies": replies })));
+        }
+    }
+
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    loop {
+        match timeout_at(deadline, receiver.recv()).await {
+            Err(_) | Ok(Err(broadcast::error::RecvError::Closed)) => break,
+            Ok(Ok(id)) if id != thread_id => continue,
+            // Our thread, or we fell behind and can't tell: look again
+            Ok(_) => {
+                let replies = replies_since(&conn.lock().unwrap(), &config, thread_id, &query.since);
+                if !replies.is_empty() {
+                    return Ok(HttpResponse::Ok().json(json!({ "replies": replies })));
+                }
+            },
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "replies": [] })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use futures_util::future::join;
+    use serde_json::Value;
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    #[actix_web::test]
+    async fn a_waiting_request_is_released_by_a_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "live"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+
+        let started = std::time::Instant::now();
+        let wait = call_service(&app, TestRequest::get().uri(&format!("/api/reply/{}/wait?since=2000-01-01%2000:00:00", thread_id)).to_request());
+        let reply = async {
+            // Post once the request is waiting on the notifier
+            while shared.reply_notifier.sender.receiver_count() == 0 {
+                actix_web::rt::task::yield_now().await;
+            }
+            let reply = multipart(&[("title", "re"), ("message", "you rang?"), ("parent_id", &thread_id.to_string())], None);
+            call_service(&app, form_post("/upload", reply, PEER).to_request()).await
+        };
+        let (waited, posted) = join(wait, reply).await;
+
+        assert_eq!(posted.status(), 303);
+        assert_eq!(waited.status(), 200);
+        assert!(started.elapsed() < super::WAIT_TIMEOUT);
+        let body: Value = read_body_json(waited).await;
+        let replies = body["replies"].as_array().unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["message"], "you rang?");
+    }
+
+    #[actix_web::test]
+    async fn waiting_on_a_missing_thread_is_404() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/api/reply/999/wait?since=2000-01-01%2000:00:00").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+}
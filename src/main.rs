@@ -1,23 +1,256 @@
 use actix_files as fs;
-use actix_multipart::Multipart;
-use actix_web::{web, App, HttpResponse, HttpServer, Result};
+use actix_multipart::{Field, Multipart, MultipartError};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Result};
 use futures_util::stream::StreamExt as _;
 use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::hash::{Hash, Hasher};
-use std::io::Write;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use actix_web::middleware::from_fn;
 use actix_web::web::Data;
-use rusqlite::{params, Connection, Result as SqlResult};
+use rusqlite::{params, Connection, Result as SqlResult, TransactionBehavior};
 use rand::{distributions::Alphanumeric, Rng};
+use clap::Parser;
 use std::collections::hash_map::DefaultHasher;
 
-// Maximum file size (20 MB)
-const MAX_SIZE: usize = 20 * 1024 * 1024;
-const POSTS_PER_PAGE: usize = 30;
+mod admin;
+mod api;
+mod archive;
+mod attachment;
+mod bans;
+mod blocklist;
+mod board;
+mod client_ip;
+mod cli;
+mod body_limit;
+mod backpressure;
+mod config;
+mod dashboard;
+mod db;
+mod dimensions;
+mod embed;
+mod emoji;
+mod download;
+mod export;
+mod filetypes;
+mod feed;
+mod fragment;
+mod geoip;
+mod highlight;
+mod img_proxy;
+mod import;
+mod integrity;
+mod jobs;
+mod last_seen;
+mod locale;
+mod longpoll;
+mod maintenance;
+mod moderation;
+mod pdf;
+mod query_stats;
+mod quota;
+mod post_password;
+mod poster;
+mod rate_limit;
+mod sanitize;
+mod scan;
+mod security_headers;
+mod reencode;
+mod session;
+mod signed_cookie;
+mod site;
+mod spam;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod textclean;
+mod timefmt;
+mod timezone;
+mod upload;
+mod video;
+mod wordbreak;
+
+use archive::{thread_archived, ArchiveJob};
+use cli::{Cli, Command};
+use attachment::{alt_attr, render_attachment, MAX_ALT_LENGTH};
+use bans::{ban_status, ip_hash, BanStatus};
+use blocklist::{is_blocked, HashBackfillJob};
+use body_limit::{body_limit, BodyLimits};
+use board::{board_url, listed_boards, request_board, sync_boards, thread_board, DEFAULT_BOARD};
+use backpressure::{busy_page, db_limit, DbLimiter};
+use config::{load_config, AppConfig};
+use db::{NewPost, ThreadPost, ThreadSummary};
+use dimensions::DimensionsBackfillJob;
+use query_stats::{install_query_stats, track_route};
+use quota::{reserve, DiskUsageJob};
+use embed::embed_videos;
+use emoji::expand_shortcodes;
+use filetypes::{accept_attr, image_extensions, FileType};
+use jobs::{AppState, Scheduler};
+use last_seen::{drop_old_cookie, is_new, last_seen};
+use locale::{translator, Locales, Tr};
+use feed::FeedCache;
+use geoip::{country_flag, geoip, GeoIp};
+use highlight::{highlighter, split_fences, Block, Highlighter};
+use img_proxy::ProxyCacheJob;
+use longpoll::ReplyNotifier;
+use maintenance::{OptimizeJob, VacuumJob};
+use moderation::log_mod_action;
+use post_password::{password_field, post_password_ok};
+use poster::{ensure_poster, poster_age};
+use timefmt::{absolute_timestamp, relative_timestamp, FormatCtx};
+use sanitize::HtmlSanitizer;
+use session::{prefs_cookies, update_prefs, Prefs};
+use signed_cookie::CookieKey;
+use site::Site;
+use spam::{is_spam, link_count, spam_score, too_many_links, LinkLimit};
+use security_headers::{security_headers, SecurityHeaders};
+use storage::{open_storage, storage, Storage};
+use timezone::format_ctx;
+use wordbreak::{break_long_words, truncate_words};
+use upload::{claim_pending, find_pending, store_upload, DuplicateImages, OrphanCleanupJob, StoredUpload, UploadError};
+use rate_limit::{api_rate_limit, post_throttle, ApiRateLimiter, PostThrottle};
+
+const DATABASE_PATH: &str = "my_database.db";
+// How long a write waits on another connection's lock before failing.
+// Longer than a VACUUM of the maintenance job takes, so posts made while
+// it runs wait for it rather than erroring.
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MAX_ORIGINAL_NAME_CHARS: usize = 100;
+const DEFAULT_SORT: &str = "bump";
+// Default order for search results, best match first
+const SEARCH_SORT: &str = "relevance";
+
+// Schema changes applied in order on startup; PRAGMA user_version records
+// how many have run.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE files ADD COLUMN created_at TIMESTAMP;
+     UPDATE files SET created_at = last_reply_at;",
+    "ALTER TABLE files ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE files ADD COLUMN original_name TEXT;",
+    "ALTER TABLE files ADD COLUMN original_format TEXT;
+     ALTER TABLE files ADD COLUMN original_size INTEGER;",
+    "ALTER TABLE files ADD COLUMN thumbnail_path TEXT;",
+    "CREATE TABLE pending_uploads (
+        token TEXT PRIMARY KEY,
+        file_path TEXT NOT NULL,
+        original_name TEXT,
+        original_format TEXT,
+        original_size INTEGER,
+        thumbnail_path TEXT,
+        created_at TIMESTAMP NOT NULL
+     );",
+    "CREATE TABLE mod_actions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        action TEXT NOT NULL,
+        details TEXT NOT NULL,
+        created_at TIMESTAMP NOT NULL
+     );",
+    "ALTER TABLE files ADD COLUMN ip_hash TEXT;
+     ALTER TABLE files ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;
+     CREATE TABLE settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+     );
+     CREATE TABLE bans (
+        ip_hash TEXT PRIMARY KEY,
+        shadow INTEGER NOT NULL DEFAULT 0,
+        reason TEXT NOT NULL DEFAULT '',
+        created_at TIMESTAMP NOT NULL
+     );",
+    "ALTER TABLE files ADD COLUMN autosage INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE files ADD COLUMN reply_count INTEGER NOT NULL DEFAULT 0;
+     UPDATE files SET reply_count = (SELECT COUNT(*) FROM files AS replies WHERE replies.parent_id = files.id AND replies.hidden = 0)
+     WHERE parent_id = 0;",
+    "ALTER TABLE files ADD COLUMN width INTEGER;
+     ALTER TABLE files ADD COLUMN height INTEGER;
+     ALTER TABLE pending_uploads ADD COLUMN width INTEGER;
+     ALTER TABLE pending_uploads ADD COLUMN height INTEGER;",
+    "ALTER TABLE files ADD COLUMN alt_text TEXT;",
+    "ALTER TABLE files ADD COLUMN by_admin INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE boards (
+        slug TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        listed INTEGER NOT NULL DEFAULT 1
+     );
+     INSERT INTO boards (slug, name) VALUES ('main', 'Main');
+     ALTER TABLE files ADD COLUMN board_slug TEXT NOT NULL DEFAULT 'main';
+     CREATE INDEX files_board ON files (board_slug, parent_id);",
+    "CREATE TABLE blocked_hashes (
+        sha256 TEXT PRIMARY KEY,
+        reason TEXT NOT NULL DEFAULT '',
+        added_by TEXT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+     );
+     ALTER TABLE files ADD COLUMN file_hash TEXT;
+     ALTER TABLE pending_uploads ADD COLUMN file_hash TEXT;
+     CREATE INDEX files_file_hash ON files (file_hash);",
+    "ALTER TABLE files ADD COLUMN page_count INTEGER;
+     ALTER TABLE pending_uploads ADD COLUMN page_count INTEGER;",
+    "CREATE VIRTUAL TABLE posts_fts USING fts5(
+        title, message,
+        content = 'files', content_rowid = 'id',
+        tokenize = 'unicode61 remove_diacritics 2'
+     );
+     CREATE TRIGGER files_fts_insert AFTER INSERT ON files BEGIN
+        INSERT INTO posts_fts (rowid, title, message) VALUES (new.id, new.title, new.message);
+     END;
+     CREATE TRIGGER files_fts_delete AFTER DELETE ON files BEGIN
+        INSERT INTO posts_fts (posts_fts, rowid, title, message) VALUES ('delete', old.id, old.title, old.message);
+     END;
+     CREATE TRIGGER files_fts_update AFTER UPDATE OF title, message ON files BEGIN
+        INSERT INTO posts_fts (posts_fts, rowid, title, message) VALUES ('delete', old.id, old.title, old.message);
+        INSERT INTO posts_fts (rowid, title, message) VALUES (new.id, new.title, new.message);
+     END;
+     INSERT INTO posts_fts (posts_fts) VALUES ('rebuild');",
+    "ALTER TABLE files ADD COLUMN country TEXT;",
+    // Replies whose thread is gone are kept, each group under its oldest
+    // post made into a hidden, archived thread for moderators to look at.
+    // thread_id mirrors parent_id with NULL for an OP, so it can reference
+    // the thread: replies to a missing thread are refused and deleting a
+    // thread takes its replies with it.
+    "CREATE TEMP TABLE orphan_threads AS
+        SELECT parent_id AS missing, MIN(id) AS new_op FROM files
+        WHERE parent_id != 0 AND parent_id NOT IN (SELECT id FROM files)
+        GROUP BY parent_id;
+     UPDATE files SET parent_id = (SELECT new_op FROM orphan_threads WHERE missing = files.parent_id)
+     WHERE parent_id IN (SELECT missing FROM orphan_threads);
+     UPDATE files SET parent_id = 0, archived = 1, hidden = 1 WHERE id IN (SELECT new_op FROM orphan_threads);
+     UPDATE files SET reply_count = (SELECT COUNT(*) FROM files AS replies WHERE replies.parent_id = files.id AND replies.hidden = 0)
+     WHERE id IN (SELECT new_op FROM orphan_threads);
+     INSERT INTO mod_actions (action, details, created_at)
+        SELECT 'repair', 'replies to missing thread ' || missing || ' kept as hidden archived thread ' || new_op, CURRENT_TIMESTAMP
+        FROM orphan_threads;
+     DROP TABLE orphan_threads;
+     ALTER TABLE files ADD COLUMN thread_id INTEGER GENERATED ALWAYS AS (NULLIF(parent_id, 0)) VIRTUAL
+        REFERENCES files (id) ON DELETE CASCADE;
+     CREATE INDEX files_thread ON files (thread_id);",
+    // Who took a moderation action and on which post; NULL for the board's
+    // own actions and for entries from before this
+    "ALTER TABLE mod_actions ADD COLUMN target_id INTEGER;
+     ALTER TABLE mod_actions ADD COLUMN moderator TEXT;",
+];
 
 fn render_template(path: &str, context: &HashMap<&str, String>) -> String {
     let template = read_to_string(path).expect("Unable to read template file");
+    fill_template(template, context)
+}
+
+// Like `render_template`, with {{t:key}} placeholders translated first so
+// nothing in the context is ever taken for one
+fn render_page(path: &str, context: &HashMap<&str, String>, tr: &Tr) -> String {
+    let template = read_to_string(path).expect("Unable to read template file");
+    fill_template(tr.localize(&template), context)
+}
+
+fn fill_template(template: String, context: &HashMap<&str, String>) -> String {
     let mut rendered = template;
     for (key, value) in context {
         let placeholder = format!("{{{{{}}}}}", key);
@@ -26,6 +259,131 @@ fn render_template(path: &str, context: &HashMap<&str, String>) -> String {
     rendered
 }
 
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn message_placeholder(config: &AppConfig, tr: &Tr) -> String {
+    if config.content_placeholder.is_empty() {
+        tr.t("form.message_placeholder")
+    } else {
+        escape_html(&config.content_placeholder)
+    }
+}
+
+// File and alt text inputs for the post forms; nothing when uploads are off
+fn file_fields(config: &AppConfig, tr: &Tr, required: bool) -> String {
+    if !config.uploads_enabled {
+        return String::new();
+    }
+    let note = if required {
+        format!(r#" <span class="field-note">{}</span>"#, tr.t("form.image_required"))
+    } else {
+        String::new()
+    };
+    // With JS the file is sent ahead through /api/upload as soon as it is
+    // chosen or dropped, and the post carries the token it gets back
+    let drop_zone = format!(
+        r#"<div class="drop-zone" data-hint="{}" data-uploading="{}" data-attached="{}" data-failed="{}">"#,
+        tr.t("form.drop_hint"), tr.t("form.uploading"), tr.t("form.attached"), tr.t("form.upload_failed")
+    );
+    format!(
+        r#"{}<input type="file" name="file" accept="{}"{}>{}<input type="hidden" name="attachment_token"><progress class="upload-progress" max="100" value="0" hidden></progress><span class="upload-status"></span></div><input type="text" name="alt" maxlength="{}" placeholder="{}"><br>"#,
+        drop_zone, accept_attr(&config.file_types), if required { " required" } else { "" }, note, MAX_ALT_LENGTH, tr.t("form.alt_placeholder")
+    )
+}
+
+fn rules_banner(config: &AppConfig) -> String {
+    if config.rules_banner.is_empty() {
+        String::new()
+    } else {
+        format!("<div class=\"rules-banner\">{}</div>", escape_html(&config.rules_banner))
+    }
+}
+
+fn render_notice(site: &Site, tr: &Tr, title: &str, message: &str) -> String {
+    let mut context = HashMap::from([
+        ("TITLE", title.to_string()),
+        ("MESSAGE", message.to_string()),
+    ]);
+    context.extend(site.chrome());
+    render_page("templates/notice.html", &context, tr)
+}
+
+// Starts an HTML page response with `change` made to the visitor's prefs.
+// A poster token is handed out too when a first-post delay is configured,
+// so the waiting period starts on first visit.
+fn board_response(req: &HttpRequest, conn: &Connection, config: &AppConfig, change: impl FnOnce(&mut Prefs)) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html");
+    update_prefs(req, &mut response, |prefs| {
+        if config.first_post_delay_secs > 0 {
+            ensure_poster(conn, prefs);
+        }
+        change(prefs);
+    });
+    response
+}
+
+// Name of an upload inside the upload directory
+fn upload_name(file_path: &str) -> &str {
+    file_path.rsplit('/').next().unwrap_or(file_path)
+}
+
+// Where browsers fetch an upload: /file/<name>, on media_base_url when one
+// is set. The database keeps only the stored path, so moving to another
+// host is a config change. Remote images are routed through the image proxy.
+fn upload_url(config: &AppConfig, file_path: &str) -> String {
+    if file_path.starts_with("http://") || file_path.starts_with("https://") {
+        return img_proxy::proxied_url(file_path);
+    }
+    let base = config.media_base_url.as_deref().unwrap_or("").trim_end_matches('/');
+    format!("{}/file/{}", base, upload_name(file_path))
+}
+
+fn generate_post_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(6)
+        .map(char::from)
+        .collect()
+}
+
+fn generate_upload_name(filename: &str) -> String {
+    format!("{}-{}", generate_post_id(), sanitize_filename::sanitize(filename))
+}
+
+// Cleans up a client-supplied filename for display: drops any directory
+// part and control characters and caps the length. None if nothing is left.
+fn sanitize_original_name(raw: &str) -> Option<String> {
+    let base = raw.rsplit(['/', '\\']).next().unwrap_or(raw);
+    let cleaned: String = base.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_ORIGINAL_NAME_CHARS)
+        .collect();
+    let cleaned = cleaned.trim();
+    (!cleaned.is_empty()).then(|| cleaned.to_string())
+}
+
+// SQL condition matching rows whose attachment is an image
+fn image_filter_sql(types: &[FileType]) -> String {
+    let conditions: Vec<String> = image_extensions(types).iter()
+        .map(|ext| format!("file_path LIKE '%.{}'", ext))
+        .collect();
+    format!("({})", conditions.join(" OR "))
+}
+
 fn generate_color_from_id(id: &str) -> String {
     let mut hasher = DefaultHasher::new();
     id.hash(&mut hasher);
@@ -36,224 +394,999 @@ fn generate_color_from_id(id: &str) -> String {
     format!("#{:02X}{:02X}{:02X}", r, g, b)
 }
 
-async fn save_file(mut payload: Multipart, conn: web::Data<Mutex<Connection>>) -> Result<HttpResponse> {
-    let mut title = String::new();
-    let mut message = String::new();
-    let mut file_path = None;
-    let mut parent_id: i32 = 0;
+// Fields of the posting forms
+#[derive(Default)]
+struct PostForm {
+    title: String,
+    message: String,
+    upload: Option<StoredUpload>,
+    attachment_token: String,
+    password: String,
+    autosage: bool,
+    parent_id: i32,
+    alt_text: String,
+    // Set by the board's quick-reply forms so the poster lands back on the board
+    return_to_board: bool,
+}
+
+// What a posting form is filled with when its page is shown. A refused
+// post comes back with what was typed and the reason it was refused.
+#[derive(Default)]
+struct FormState {
+    title: String,
+    message: String,
+    autosage: bool,
+    error: String,
+}
+
+impl FormState {
+    fn error_html(&self) -> String {
+        if self.error.is_empty() {
+            String::new()
+        } else {
+            format!("<div class=\"form-error\">{}</div>", self.error)
+        }
+    }
+
+    // Puts the cursor back in the message box of a refused post, which also
+    // scrolls it into view
+    fn autofocus(&self) -> String {
+        if self.error.is_empty() { String::new() } else { " autofocus".to_string() }
+    }
+
+    // Typed text for the form's fields, with braces escaped too so nothing
+    // typed is taken for a template placeholder
+    fn title_html(&self) -> String {
+        escape_html(&self.title).replace('{', "&#123;")
+    }
+
+    fn message_html(&self) -> String {
+        escape_html(&self.message).replace('{', "&#123;")
+    }
+
+    fn autosage_checked(&self) -> &'static str {
+        if self.autosage { " checked" } else { "" }
+    }
+}
+
+impl PostForm {
+    // Removes the stored file of a post that was refused
+    fn discard_upload(&self, storage: &Data<dyn Storage>) {
+        if let Some(upload) = &self.upload {
+            upload.discard(storage.get_ref());
+        }
+    }
+}
+
+async fn read_text(field: &mut Field) -> Result<String, MultipartError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
 
+// Reads the multipart body into `form`. On error, whatever was read so far
+// (including a stored file) is left in `form` for the caller to clean up.
+async fn read_post_form(payload: &mut Multipart, config: &AppConfig, storage: &Data<dyn Storage>, conn: &Data<Mutex<Connection>>, form: &mut PostForm) -> Result<(), UploadError> {
     while let Some(item) = payload.next().await {
         let mut field = item?;
         let content_disposition = field.content_disposition().clone();
         let name = content_disposition.get_name().unwrap_or("").to_string();
 
         match name.as_str() {
-            "title" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    title.push_str(&String::from_utf8_lossy(&data));
-                }
-            },
-            "message" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    message.push_str(&String::from_utf8_lossy(&data));
-                }
-            },
-            "file" => {
+            "title" => form.title = read_text(&mut field).await?,
+            "message" => form.message = read_text(&mut field).await?,
+            // With uploads off, files are ignored and the post goes ahead as text
+            "file" if config.uploads_enabled => {
                 if let Some(filename) = content_disposition.get_filename() {
-                    let file_extension = filename.split('.').last().unwrap_or("");
-                    let sanitized_filename = sanitize_filename::sanitize(&filename);
-                    let unique_id: String = rand::thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(6)
-                        .map(char::from)
-                        .collect();
-                    let unique_filename = format!("{}-{}", unique_id, sanitized_filename);
-
-                    let valid_image_extensions = ["jpg", "jpeg", "png", "gif", "webp"];
-                    let valid_video_extensions = ["mp4", "mp3", "webm"];
-
-                    if valid_image_extensions.contains(&file_extension) || valid_video_extensions.contains(&file_extension) {
-                        let file_path_string = format!("./static/{}", unique_filename);
-                        let file_path_clone = file_path_string.clone();
-                        let mut f = web::block(move || std::fs::File::create(file_path_clone)).await??;
-
-                        while let Some(chunk) = field.next().await {
-                            let data = chunk?;
-                            f = web::block(move || f.write_all(&data).map(|_| f)).await??;
-                        }
-
-                        file_path = Some(file_path_string);
+                    match store_upload(&mut field, filename, config, storage, conn).await {
+                        Ok(stored) => form.upload = Some(stored),
+                        // Unknown file types and empty file parts (a file
+                        // chosen then cleared) are dropped and the post goes
+                        // ahead without them
+                        Err(UploadError::Unsupported | UploadError::Empty) => {},
+                        Err(e) => return Err(e),
                     }
                 }
             },
-            "parent_id" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    parent_id = String::from_utf8_lossy(&data).trim().parse().unwrap_or(0);
-                }
+            "password" => form.password = read_text(&mut field).await?,
+            "autosage" => {
+                read_text(&mut field).await?;
+                form.autosage = true;
             },
+            "attachment_token" if config.uploads_enabled => form.attachment_token = read_text(&mut field).await?,
+            "parent_id" => form.parent_id = read_text(&mut field).await?.trim().parse().unwrap_or(0),
+            "alt" => form.alt_text = read_text(&mut field).await?,
+            "return_to" => form.return_to_board = read_text(&mut field).await? == "board",
             _ => {},
         }
     }
+    Ok(())
+}
+
+// Shown in place of the post forms while posting is off
+fn read_only_notice(tr: &Tr) -> String {
+    format!("<div class=\"thread-notice\">{}</div>", tr.t("form.read_only"))
+}
+
+fn read_only_response(site: &Site, tr: &Tr) -> HttpResponse {
+    let body = render_notice(site, tr, &tr.t("error.read_only_title"), &tr.t("error.read_only"));
+    HttpResponse::ServiceUnavailable().content_type("text/html").body(body)
+}
+
+fn form_error(site: &Site, tr: &Tr, status: StatusCode, message: &str) -> HttpResponse {
+    let body = render_notice(site, tr, &tr.t("error.form_title"), message);
+    HttpResponse::build(status).content_type("text/html").body(body)
+}
 
-    if title.trim().is_empty() || message.trim().is_empty() {
-        return Ok(HttpResponse::BadRequest().body("Title and message are mandatory."));
+// Shows the page a refused post came from (its thread, or the board for a
+// new thread) again, with what was typed kept and `error` above the form.
+// A chosen file can't be put back into a form, so the error says so.
+fn refill_form(req: &HttpRequest, conn: &Connection, config: &AppConfig, site: &Site, form: &PostForm, status: StatusCode, error: &str) -> Result<HttpResponse> {
+    let tr = translator(req);
+    let mut error = error.to_string();
+    if form.upload.is_some() || !form.attachment_token.trim().is_empty() {
+        error.push(' ');
+        error.push_str(&tr.t("error.file_not_kept"));
     }
+    let state = FormState { title: form.title.clone(), message: form.message.clone(), autosage: form.autosage, error };
+    let sanitizer = req.app_data::<web::Data<HtmlSanitizer>>().expect("the sanitizer is registered with the app");
+    let mut response = if form.parent_id == 0 {
+        board_page(req, conn, config, sanitizer, site, &HashMap::new(), &state)?
+    } else {
+        thread_page(req, conn, config, sanitizer, site, form.parent_id, &state)?
+    };
+    // A page that couldn't be shown keeps its own status
+    if response.status().is_success() {
+        *response.status_mut() = status;
+    }
+    Ok(response)
+}
 
-    if title.len() > 30 || message.len() > 50000 {
-        return Ok(HttpResponse::BadRequest().body("Title or message is too long."));
+async fn save_file(req: HttpRequest, mut payload: Multipart, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, site: web::Data<Site>, notifier: web::Data<ReplyNotifier>) -> Result<HttpResponse> {
+    // Refused before the upload is read, so nothing gets stored
+    if site.read_only() {
+        return Ok(read_only_response(&site, &translator(&req)));
+    }
+    let storage = storage(&req);
+    let mut form = PostForm::default();
+    if let Err(e) = read_post_form(&mut payload, &config, &storage, &conn, &mut form).await {
+        form.discard_upload(&storage);
+        let tr = translator(&req);
+        // Whatever was typed before the file is kept
+        if let Some((status, message)) = e.form_message(&tr) {
+            return refill_form(&req, &conn.lock().unwrap(), &config, &site, &form, status, &message);
+        }
+        return e.form_response(&site, &tr);
     }
 
-    let post_id: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(6)
-        .map(char::from)
-        .collect();
+    let tr = translator(&req);
+    if !post_password_ok(&config, &form.password) {
+        form.discard_upload(&storage);
+        return refill_form(&req, &conn.lock().unwrap(), &config, &site, &form, StatusCode::FORBIDDEN, &tr.t("error.password_required"));
+    }
 
-    let conn = conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO files (post_id, parent_id, title, message, file_path) VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![post_id, parent_id, title, message, file_path],
-    ).unwrap();
+    if config.strip_invisible_chars {
+        form.title = textclean::strip_invisible(&form.title);
+        form.message = textclean::strip_invisible(&form.message);
+        form.alt_text = textclean::strip_invisible(&form.alt_text);
+    }
+    // Length limits below apply to the normalized text
+    form.message = textclean::normalize_message(&form.message);
 
-    if parent_id != 0 {
-        conn.execute(
-            "UPDATE files SET last_reply_at = CURRENT_TIMESTAMP WHERE id = ?1 OR parent_id = ?1",
-            params![parent_id],
-        ).unwrap();
+    let links = link_count(&form.title) + link_count(&form.message);
+    let over_link_limit = too_many_links(&config, links);
+
+    // The message may be left out when the post carries a file
+    let has_file = form.upload.is_some() || !form.attachment_token.trim().is_empty();
+    let invalid = if form.title.trim().is_empty() {
+        Some(tr.t("error.missing_title"))
+    } else if form.parent_id == 0 && config.require_op_image && !has_file {
+        Some(tr.t("error.op_image_required"))
+    } else if form.message.trim().is_empty() && !has_file {
+        Some(tr.t("error.missing_content"))
+    } else if form.title.chars().count() > 30 {
+        Some(tr.t("error.title_too_long"))
+    } else if form.message.chars().count() > 50000 {
+        Some(tr.t("error.message_too_long"))
+    } else if form.alt_text.chars().count() > MAX_ALT_LENGTH {
+        Some(tr.t("error.alt_too_long"))
+    } else if over_link_limit && config.link_limit == LinkLimit::Reject {
+        Some(tr.tn("error.too_many_links", config.max_links as i64))
+    } else {
+        None
+    };
+    if let Some(message) = invalid {
+        form.discard_upload(&storage);
+        return refill_form(&req, &conn.lock().unwrap(), &config, &site, &form, StatusCode::UNPROCESSABLE_ENTITY, &message);
     }
 
-    if parent_id == 0 {
-        Ok(HttpResponse::SeeOther().append_header(("Location", "/")).finish())
+    let post_id = generate_post_id();
+    let parent_id = form.parent_id;
+
+    let mut conn = conn.lock().unwrap();
+
+    // New threads go to the board in the path; replies stay on their thread's board
+    let board = if parent_id == 0 {
+        match request_board(&conn, &req) {
+            Some(board) => board.slug,
+            None => {
+                form.discard_upload(&storage);
+                return Ok(form_error(&site, &tr, StatusCode::NOT_FOUND, &tr.t("error.board_not_found")));
+            },
+        }
     } else {
-        Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}", parent_id))).finish())
+        thread_board(&conn, parent_id)
+    };
+
+    let poster_hash = ip_hash(&conn, &req).map_err(ErrorInternalServerError)?;
+    let country = geoip(&req).country(&req);
+    let spam_score = spam_score(&config, &form.title, &form.message);
+    let flagged_as_spam = is_spam(&config, spam_score);
+    let hidden_for_links = over_link_limit && config.link_limit == LinkLimit::Hide;
+    let hidden = match ban_status(&conn, &poster_hash).map_err(ErrorInternalServerError)? {
+        BanStatus::None => flagged_as_spam || hidden_for_links,
+        BanStatus::Shadow => true,
+        BanStatus::Banned => {
+            form.discard_upload(&storage);
+            let body = render_notice(&site, &tr, &tr.t("error.banned_title"), &tr.t("error.banned"));
+            return Ok(HttpResponse::Forbidden().content_type("text/html").body(body));
+        },
+    };
+
+    if config.first_post_delay_secs > 0 {
+        let mut token = String::new();
+        let cookies = prefs_cookies(&req, |prefs| token = ensure_poster(&conn, prefs));
+        let age = poster_age(&conn, &token).unwrap_or(0).max(0) as u64;
+        if age < config.first_post_delay_secs {
+            form.discard_upload(&storage);
+            let wait = config.first_post_delay_secs - age;
+            let mut response = refill_form(&req, &conn, &config, &site, &form, StatusCode::TOO_MANY_REQUESTS, &tr.tn("error.wait", wait as i64))?;
+            response.headers_mut().insert(header::RETRY_AFTER, wait.into());
+            // The refilled page may hand out a token of its own; this one
+            // is the token the wait was measured from
+            for cookie in cookies {
+                response.del_cookie(cookie.name());
+                response.add_cookie(&cookie).unwrap();
+            }
+            return Ok(response);
+        }
+    }
+
+    // The thread's state and reply cap are checked inside the same
+    // transaction as the insert and bump. IMMEDIATE takes the write lock
+    // up front, so another process writing the database can't slip in
+    // between the checks and the insert either.
+    let Ok(tx) = conn.transaction_with_behavior(TransactionBehavior::Immediate) else {
+        // Another connection kept the lock past DB_BUSY_TIMEOUT
+        form.discard_upload(&storage);
+        return Ok(busy_page(&req));
+    };
+
+    if parent_id != 0 {
+        match thread_archived(&tx, parent_id) {
+            None => {
+                form.discard_upload(&storage);
+                return Ok(form_error(&site, &tr, StatusCode::NOT_FOUND, &tr.t("error.thread_not_found")));
+            },
+            Some(true) => {
+                form.discard_upload(&storage);
+                let body = render_notice(&site, &tr, &tr.t("error.archived_title"), &tr.t("thread.archived"));
+                return Ok(HttpResponse::Forbidden().content_type("text/html").body(body));
+            },
+            Some(false) => {},
+        }
+    }
+
+    if parent_id != 0 && thread_full(&tx, &config, parent_id).map_err(ErrorInternalServerError)? {
+        form.discard_upload(&storage);
+        let body = render_notice(&site, &tr, &tr.t("error.full_title"), &tr.t("error.full"));
+        return Ok(HttpResponse::Forbidden().content_type("text/html").body(body));
+    }
+
+    // A file sent ahead through /api/upload. It's only claimed once the
+    // post has passed the checks below, and refusals leave it pending:
+    // discard_upload only removes a file sent with the form itself.
+    let attachment_token = form.attachment_token.trim();
+    let mut pending = None;
+    if !attachment_token.is_empty() {
+        if form.upload.is_some() {
+            form.discard_upload(&storage);
+            return refill_form(&req, &tx, &config, &site, &form, StatusCode::BAD_REQUEST, &tr.t("error.file_and_token"));
+        }
+        match find_pending(&tx, attachment_token).map_err(ErrorInternalServerError)? {
+            Some(upload) => pending = Some(upload),
+            None => return refill_form(&req, &tx, &config, &site, &form, StatusCode::BAD_REQUEST, &tr.t("error.token_expired")),
+        }
+    }
+    let upload = form.upload.as_ref().or(pending.as_ref());
+
+    if upload.and_then(|upload| upload.file_hash.as_deref()).is_some_and(|hash| is_blocked(&tx, hash)) {
+        form.discard_upload(&storage);
+        let (status, message) = UploadError::Blocked.form_message(&tr).unwrap();
+        return refill_form(&req, &tx, &config, &site, &form, status, &message);
+    }
+
+    // The same file posted again into one thread is a common kind of spam
+    let duplicate_of = match upload.and_then(|upload| upload.file_hash.as_deref()) {
+        Some(hash) if parent_id != 0 && config.duplicate_images != DuplicateImages::Allow => db::post_with_file_in_thread(&tx, parent_id, hash).map_err(ErrorInternalServerError)?,
+        _ => None,
+    };
+    if duplicate_of.is_some() && config.duplicate_images == DuplicateImages::Block {
+        form.discard_upload(&storage);
+        return refill_form(&req, &tx, &config, &site, &form, StatusCode::CONFLICT, &tr.t("error.duplicate_image"));
+    }
+
+    if pending.is_some() {
+        claim_pending(&tx, attachment_token).map_err(ErrorInternalServerError)?;
+    }
+    // A file sent with the form takes its share of the quota here, under
+    // the write lock; store_upload only checked that it would fit. One
+    // sent ahead was counted when it was stored.
+    if let Some(upload) = upload.filter(|upload| upload.stored_bytes > 0) {
+        if !reserve(&tx, storage.get_ref(), &config, upload.stored_bytes).map_err(ErrorInternalServerError)? {
+            form.discard_upload(&storage);
+            return UploadError::OverQuota.form_response(&site, &tr);
+        }
+    }
+    // Alt text describes the file, so it's dropped when there isn't one
+    let alt_text = Some(form.alt_text.trim()).filter(|alt| upload.is_some() && !alt.is_empty());
+    let inserted = db::insert_post(&tx, &NewPost {
+        post_id: &post_id,
+        parent_id,
+        title: &form.title,
+        message: &form.message,
+        upload,
+        alt_text,
+        ip_hash: &poster_hash,
+        hidden,
+        // Only a thread's OP can ask for it not to bump
+        autosage: form.autosage && parent_id == 0,
+        by_admin: admin::is_admin(&req),
+        board: &board,
+        country: country.as_deref(),
+    });
+    let id = match inserted {
+        // The thread went away since it was checked
+        Err(e) if db::is_foreign_key_violation(&e) => {
+            form.discard_upload(&storage);
+            return Ok(form_error(&site, &tr, StatusCode::NOT_FOUND, &tr.t("error.thread_not_found")));
+        },
+        inserted => inserted.unwrap(),
+    };
+    if flagged_as_spam {
+        log_mod_action(&tx, None, "spam-hide", Some(id), &format!("post {} scored {}", id, spam_score)).map_err(ErrorInternalServerError)?;
+    }
+    if hidden_for_links {
+        log_mod_action(&tx, None, "link-limit-hide", Some(id), &format!("post {} has {} links, more than {}", id, links, config.max_links)).map_err(ErrorInternalServerError)?;
+    }
+    if let Some(original) = duplicate_of {
+        log_mod_action(&tx, None, "duplicate-image", Some(id), &format!("post {} repeats the file of post {} in thread {}", id, original, parent_id)).map_err(ErrorInternalServerError)?;
     }
+
+    tx.commit().map_err(ErrorInternalServerError)?;
+
+    if parent_id != 0 && !hidden {
+        notifier.notify(parent_id);
+    }
+
+    let location = if parent_id == 0 || form.return_to_board { board_url(&board) } else { format!("/post/{}", parent_id) };
+    let mut response = HttpResponse::SeeOther();
+    response.append_header(("Location", location));
+    // Remembered so the post and replies to it are marked "(You)"
+    update_prefs(&req, &mut response, |prefs| prefs.add_post(id));
+    Ok(response.finish())
 }
 
-async fn view_post(conn: web::Data<Mutex<Connection>>, path: web::Path<i32>) -> Result<HttpResponse> {
+// Served at /post/{id} and /{board}/post/{id}
+async fn view_post(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, sanitizer: web::Data<HtmlSanitizer>, site: web::Data<Site>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
     let conn = conn.lock().unwrap();
-    let post_id = path.into_inner();
+    let Ok(post_id) = req.match_info().query("id").parse::<i32>() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
 
-    let mut stmt = conn.prepare("SELECT id, post_id, parent_id, title, message, file_path FROM files WHERE id = ?1 OR parent_id = ?1 ORDER BY id ASC").unwrap();
-    let posts = stmt.query_map(params![post_id], |row| {
-        Ok((
-            row.get::<_, i32>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, i32>(2)?,
-            row.get::<_, String>(3)?,
-            row.get::<_, String>(4)?,
-            row.get::<_, Option<String>>(5)?,
-        ))
-    }).unwrap();
+    // Post numbers are permalinks; a reply's number leads to its thread
+    let parent_id: Option<i32> = conn.query_row("SELECT parent_id FROM files WHERE id = ?1", params![post_id], |row| row.get(0)).ok();
+    if let Some(parent_id) = parent_id.filter(|&parent_id| parent_id != 0) {
+        let mut location = format!("/post/{}", parent_id);
+        if !req.query_string().is_empty() {
+            location.push('?');
+            location.push_str(req.query_string());
+        }
+        location.push_str(&format!("#p{}", post_id));
+        return Ok(HttpResponse::Found().append_header(("Location", location)).finish());
+    }
 
-    let mut posts_html = String::new();
-    let mut is_original_post = true;
-    let mut reply_count = 1;
+    let board = thread_board(&conn, post_id);
+    if req.match_info().get("board").is_some_and(|slug| slug != board) {
+        let tr = translator(&req);
+        let body = render_notice(&site, &tr, &tr.t("error.form_title"), &tr.t("error.thread_not_found"));
+        return Ok(HttpResponse::NotFound().content_type("text/html").body(body));
+    }
 
-    for post in posts {
-        let (_id, _post_id, _parent_id, title, message, file_path) = post.unwrap();
-        posts_html.push_str("<div class=\"post\">");
-        if is_original_post {
-            posts_html.push_str("<div class=\"post-id\">Original Post</div>");
-            is_original_post = false;
-        } else {
-            posts_html.push_str(&format!("<div class=\"post-id\">Reply {}</div>", reply_count));
-            reply_count += 1;
-        }
-        posts_html.push_str(&format!("<div class=\"post-title\">{}</div>", title));
-        if let Some(file_path) = file_path {
-            if file_path.ends_with(".jpg") || file_path.ends_with(".jpeg") || file_path.ends_with(".png") || file_path.ends_with(".gif") || file_path.ends_with(".webp") {
-                posts_html.push_str(&format!(r#"<img src="/static/{}"><br>"#, file_path.trim_start_matches("./static/")));
-            } else if file_path.ends_with(".mp4") || file_path.ends_with(".mp3") || file_path.ends_with(".webm") {
-                posts_html.push_str(&format!(r#"<video controls><source src="/static/{}"></video><br>"#, file_path.trim_start_matches("./static/")));
+    // ?quote=<id> starts the reply off quoting that post
+    let quote = query.get("quote")
+        .and_then(|quote| quote.parse::<u32>().ok())
+        .map(|quote| format!(">>{}\n", quote))
+        .unwrap_or_default();
+    let form = FormState { message: quote, ..FormState::default() };
+    thread_page(&req, &conn, &config, &sanitizer, &site, post_id, &form)
+}
+
+// Thread `post_id` as shown at /post/{id}, with `form` filled into the
+// reply form
+fn thread_page(req: &HttpRequest, conn: &Connection, config: &AppConfig, sanitizer: &HtmlSanitizer, site: &Site, post_id: i32, form: &FormState) -> Result<HttpResponse> {
+    let board = thread_board(conn, post_id);
+    let viewer = ip_hash(conn, req).map_err(ErrorInternalServerError)?;
+    let Some(thread) = db::get_thread(conn, post_id, &viewer).map_err(ErrorInternalServerError)? else {
+        let tr = translator(req);
+        let body = render_notice(site, &tr, &tr.t("error.form_title"), &tr.t("error.thread_not_found"));
+        return Ok(HttpResponse::NotFound().content_type("text/html").body(body));
+    };
+    let autosage = thread.autosage;
+    // The thread's author (matched by IP hash) or a moderator can change autosage
+    let can_toggle_autosage = admin::is_admin(req) || thread.op.poster.as_deref() == Some(viewer.as_str());
+
+    let tr = translator(req);
+    let autosage_form = if can_toggle_autosage {
+        format!(
+            r#"<form class="autosage-form" action="/post/{}/autosage" method="post"><button type="submit">{}</button></form>"#,
+            post_id, tr.t(if autosage { "thread.allow_bumping" } else { "thread.stop_bumping" })
+        )
+    } else {
+        String::new()
+    };
+
+    // Replies since the previous visit get highlighted
+    let prefs = Prefs::of(req);
+    let seen = if config.highlight_new_replies { last_seen(req, &prefs, post_id) } else { None };
+    let ctx = format_ctx(req);
+    let highlighter = highlighter(req);
+    let renderer = PostRenderer { config, sanitizer, highlighter: &highlighter, ctx: &ctx, tr: &tr, prefs: &prefs };
+
+    // Moderators can delete any post, and block any attached file from
+    // being posted again
+    let is_admin = admin::is_admin(req);
+    let mod_forms = |post: &ThreadPost| {
+        if !is_admin {
+            return String::new();
+        }
+        let mut forms = format!(
+            r#"<form class="autosage-form" action="/admin/delete/{}" method="post"><button type="submit">{}</button></form>"#,
+            post.id, tr.t("thread.delete")
+        );
+        if post.attachment.is_some() {
+            forms.push_str(&format!(
+                r#"<form class="autosage-form" action="/admin/block-file/{}" method="post"><button type="submit">{}</button></form>"#,
+                post.id, tr.t("thread.block_file")
+            ));
+        }
+        forms
+    };
+
+    let regen_form = if is_admin {
+        format!(
+            r#"<form class="autosage-form" action="/admin/regen-id/{}" method="post"><button type="submit">{}</button></form>"#,
+            post_id, tr.t("thread.regen_id")
+        )
+    } else {
+        String::new()
+    };
+    let controls = format!("{}{}{}", autosage_form, regen_form, mod_forms(&thread.op));
+    let mut posts_html = render_thread_post(post_id, &thread.op, PostRole::Op { autosage }, &controls, &renderer);
+    // Runs of replies from one poster can be shown as a single block;
+    // numbering is unaffected
+    let same_poster = |a: Option<&ThreadPost>, b: Option<&ThreadPost>| {
+        config.group_same_poster_replies && a.zip(b).is_some_and(|(a, b)| a.poster.is_some() && a.poster == b.poster)
+    };
+    // Posts per poster among those shown, the opening post included
+    let mut post_counts: HashMap<&str, usize> = HashMap::new();
+    if config.show_poster_post_counts {
+        for post in std::iter::once(&thread.op).chain(&thread.replies) {
+            if let Some(poster) = &post.poster {
+                *post_counts.entry(poster).or_default() += 1;
             }
         }
-        posts_html.push_str(&format!("<div class=\"post-message\">{}</div>", message));
-        posts_html.push_str("</div>");
     }
+    let poster_mark = |reply: &ThreadPost| {
+        let poster = reply.poster.as_deref().filter(|_| config.show_poster_post_counts)?;
+        if thread.op.poster.as_deref() == Some(poster) {
+            Some(PosterMark::Op)
+        } else {
+            post_counts.get(poster).map(|&count| PosterMark::Posts(count))
+        }
+    };
+    for (index, reply) in thread.replies.iter().enumerate() {
+        let (previous, next) = (index.checked_sub(1).and_then(|i| thread.replies.get(i)), thread.replies.get(index + 1));
+        if same_poster(Some(reply), next) && !same_poster(previous, Some(reply)) {
+            posts_html.push_str("<div class=\"reply-group\">");
+        }
+        let role = PostRole::Reply { number: index + 1, new: is_new(seen, &reply.created_at), poster: poster_mark(reply) };
+        posts_html.push_str(&render_thread_post(post_id, reply, role, &mod_forms(reply), &renderer));
+        if same_poster(previous, Some(reply)) && !same_poster(Some(reply), next) {
+            posts_html.push_str("</div>");
+        }
+    }
+    let reply_count = thread.replies.len();
+
+    let reply_form = if site.read_only() {
+        read_only_notice(&tr)
+    } else if thread_archived(conn, post_id).unwrap_or(false) {
+        format!("<div class=\"thread-notice\">{}</div>", tr.t("thread.archived"))
+    } else if thread_full(conn, config, post_id).map_err(ErrorInternalServerError)? {
+        format!("<div class=\"thread-notice\">{}</div>", tr.t("thread.full"))
+    } else {
+        render_page("templates/reply_form.html", &HashMap::from([
+            ("PARENT_ID", post_id.to_string()),
+            ("PLACEHOLDER", message_placeholder(config, &tr)),
+            ("FORM_ERROR", form.error_html()),
+            ("TITLE", form.title_html()),
+            ("MESSAGE", form.message_html()),
+            ("AUTOFOCUS", form.autofocus()),
+            ("PASSWORD_FIELD", password_field(config, &tr)),
+            ("FILE_FIELDS", file_fields(config, &tr, false)),
+        ]), &tr)
+    };
+
+    // Jump links for getting around long threads; the reply count leads to the latest reply
+    let (top_nav, bottom_nav) = if reply_count > 0 {
+        (
+            format!(
+                r##"<div class="thread-nav" id="top"><a href="#bottom">{}</a> &middot; <a href="#r{}">{}</a></div>"##,
+                tr.t("thread.bottom"), reply_count, tr.tn("board.replies", reply_count as i64)
+            ),
+            format!(r##"<div class="thread-nav" id="bottom"><a href="#top">{}</a></div>"##, tr.t("thread.top")),
+        )
+    } else {
+        (String::new(), String::new())
+    };
 
-    let context = HashMap::from([
-        ("PARENT_ID", post_id.to_string()),
+    let mut context = HashMap::from([
+        ("REPLY_FORM", reply_form),
         ("POSTS", posts_html),
+        ("TOP_NAV", top_nav),
+        ("BOTTOM_NAV", bottom_nav),
+        ("BOARD_URL", board_url(&board)),
+        ("BACK_LABEL", if board == DEFAULT_BOARD { tr.t("page.home") } else { tr.tf("page.back_to_board", &[("board", &board)]) }),
+        ("ANNOUNCEMENT", site.announcement_banner()),
     ]);
+    context.extend(site.chrome());
 
-    let body = render_template("templates/view_post.html", &context);
+    let body = render_page("templates/view_post.html", &context, &tr);
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+    let mut response = board_response(req, conn, config, |prefs| {
+        if config.highlight_new_replies {
+            prefs.mark_seen(post_id, chrono::Utc::now().timestamp());
+        }
+    });
+    drop_old_cookie(req, &mut response, post_id);
+    Ok(response.body(body))
 }
 
-async fn index(conn: web::Data<Mutex<Connection>>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
-    let conn = conn.lock().unwrap();
-    let page: usize = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1);
-    let offset = (page - 1) * POSTS_PER_PAGE;
+// What rendering posts for one page needs besides the posts themselves
+struct PostRenderer<'a> {
+    config: &'a AppConfig,
+    sanitizer: &'a HtmlSanitizer,
+    highlighter: &'a Highlighter,
+    ctx: &'a FormatCtx,
+    tr: &'a Tr,
+    // The viewer's prefs, for marking their own posts
+    prefs: &'a Prefs,
+}
 
-    let mut stmt = conn.prepare("SELECT id, post_id, title, message, file_path FROM files WHERE parent_id = 0 ORDER BY last_reply_at DESC LIMIT ?1 OFFSET ?2").unwrap();
-    let posts = stmt.query_map(params![POSTS_PER_PAGE as i64, offset as i64], |row| {
-        Ok((
-            row.get::<_, i32>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, String>(2)?,
-            row.get::<_, String>(3)?,
-            row.get::<_, Option<String>>(4)?,
-        ))
-    }).unwrap();
+// Where a post sits in its thread
+enum PostRole {
+    Op { autosage: bool },
+    // `number` is the position among the thread's replies, starting at 1;
+    // `new` marks a reply posted since the visitor's last look
+    Reply { number: usize, new: bool, poster: Option<PosterMark> },
+}
 
-    let mut posts_html = String::new();
+// What a reply shows about its poster with `show_poster_post_counts`
+enum PosterMark {
+    // The thread's author
+    Op,
+    // Anyone else, with their number of posts in the thread
+    Posts(usize),
+}
 
-    for post in posts {
-        let (id, post_id, title, message, file_path) = post.unwrap();
+fn poster_mark_html(mark: Option<PosterMark>, tr: &Tr) -> String {
+    match mark {
+        Some(PosterMark::Op) => format!(r#" <span class="poster-mark" title="{}">{}</span>"#, tr.t("thread.op_mark_title"), tr.t("thread.op_mark")),
+        Some(PosterMark::Posts(count)) => format!(r#" <span class="poster-mark" title="{}">({})</span>"#, tr.tn("thread.poster_posts", count as i64), count),
+        None => String::new(),
+    }
+}
 
-        let reply_count: i32 = conn.query_row(
-            "SELECT COUNT(*) FROM files WHERE parent_id = ?1",
-            params![id],
-            |row| row.get(0),
-        ).unwrap_or(0);
+// Turns ">>N" in a sanitized message, where it reads "&gt;&gt;N", into a
+// link to post N, followed by `own_mark` when `is_own(N)`
+fn link_quotes(message: &str, is_own: impl Fn(i32) -> bool, own_mark: &str) -> String {
+    const QUOTE: &str = "&gt;&gt;";
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(QUOTE) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + QUOTE.len()..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match after[..digits].parse::<i32>() {
+            Ok(id) => {
+                out.push_str(&format!(r#"<a class="quote-ref" href="/post/{}" data-post="{}">&gt;&gt;{}</a>"#, id, id, id));
+                if is_own(id) {
+                    out.push_str(own_mark);
+                }
+            },
+            Err(_) => out.push_str(&rest[start..start + QUOTE.len() + digits]),
+        }
+        rest = &after[digits..];
+    }
+    out.push_str(rest);
+    out
+}
 
-        let truncated_message = if message.len() > 2700 {
-            format!("{}... <a href=\"/post/{}\" class=\"view-full-post\">Click here to open full post</a>", &message[..2700], id)
-        } else {
-            message.clone()
+// A post message as HTML: fenced code blocks highlighted, everything else
+// sanitized with quote links, then emoji shortcodes and video links
+// expanded outside code
+fn render_message(message: &str, by_admin: bool, renderer: &PostRenderer) -> String {
+    let html: String = split_fences(message)
+        .into_iter()
+        .map(|block| match block {
+            Block::Text(text) => link_quotes(&renderer.sanitizer.clean(text, by_admin), |id| renderer.prefs.is_own(id), &own_mark(renderer.tr)),
+            Block::Code { language, code } => renderer.highlighter.code_block(language, code),
+        })
+        .collect();
+    embed_videos(renderer.config, &expand_shortcodes(&html))
+}
+
+// Renders one post of thread `thread_id`. Thread pages and the hover
+// preview fragment both go through here. `controls` is extra markup shown
+// under the header, such as the autosage form.
+fn render_thread_post(thread_id: i32, post: &ThreadPost, role: PostRole, controls: &str, renderer: &PostRenderer) -> String {
+    let PostRenderer { config, ctx, tr, .. } = *renderer;
+    // Every post is reachable as #p<id>; replies also as #r<n>
+    let class = if matches!(role, PostRole::Reply { new: true, .. }) { "post new-reply" } else { "post" };
+    let mut html = format!("<div class=\"{}\" id=\"p{}\">", class, post.id);
+    let anchor_link = format!("<a class=\"anchor-link\" href=\"#p{}\" title=\"{}\">{}</a>", post.id, tr.t("thread.link_title"), tr.t("thread.link"));
+    let mut title_html = break_long_words(&escape_html(&post.title), config.max_word_length);
+    let flag = country_flag(config, post.country.as_deref());
+    let own = if renderer.prefs.is_own(post.id) { own_mark(tr) } else { String::new() };
+    match role {
+        PostRole::Op { autosage } => {
+            html.push_str(&format!("<div class=\"post-id\">{} {}{} {}{}</div>", tr.t("thread.original_post"), post_number_link(thread_id, post.id), own, anchor_link, flag));
+            title_html.push_str(&autosage_icon(autosage, tr));
+        },
+        PostRole::Reply { number, poster, .. } => {
+            // Without JS the link just jumps to the reply form
+            html.push_str(&format!(
+                "<div class=\"post-id\" id=\"r{}\"><a class=\"quote-link\" href=\"#reply-form\" data-quote=\"{}\">{}</a>{} {}{} {}{}</div>",
+                number, number, tr.tf("thread.reply_number", &[("n", &number.to_string())]), poster_mark_html(poster, tr), post_number_link(thread_id, post.id), own, anchor_link, flag
+            ));
+        },
+    }
+    html.push_str(controls);
+    html.push_str(&format!(
+        "<div class=\"post-time\">{} &middot; {}</div>",
+        absolute_timestamp(ctx, &post.created_at), relative_timestamp(ctx, tr, &post.created_at)
+    ));
+    html.push_str(&format!("<div class=\"post-title\">{}</div>", title_html));
+    if let Some(attachment) = &post.attachment {
+        html.push_str(&render_attachment(attachment, config, None, tr));
+    }
+    // Image-only posts have no message box at all
+    if !post.message.trim().is_empty() {
+        let message = render_message(&post.message, post.by_admin, renderer);
+        html.push_str(&format!("<div class=\"post-message\">{}</div>", break_long_words(&message, config.max_word_length)));
+    }
+    html.push_str("</div>");
+    html
+}
+
+fn reply_limit_reached(config: &AppConfig, reply_count: i32) -> bool {
+    config.max_replies_per_thread > 0 && reply_count >= config.max_replies_per_thread as i32
+}
+
+// Whether a thread has hit `max_replies_per_thread`
+fn thread_full(conn: &Connection, config: &AppConfig, thread_id: i32) -> SqlResult<bool> {
+    Ok(reply_limit_reached(config, db::count_replies(conn, thread_id)?))
+}
+
+// Marker shown on threads that never bump
+fn autosage_icon(autosage: bool, tr: &Tr) -> String {
+    if autosage {
+        format!(r#" <span class="autosage-icon" title="{}">&#9875;</span>"#, tr.t("board.no_bump_icon"))
+    } else {
+        String::new()
+    }
+}
+
+// Marks a post made from the viewer's browser
+fn own_mark(tr: &Tr) -> String {
+    format!(r#" <span class="own-mark" title="{}">{}</span>"#, tr.t("thread.own_mark_title"), tr.t("thread.own_mark"))
+}
+
+// "No.<id>" link that opens the thread with a reply quoting the post
+fn post_number_link(thread_id: i32, id: i32) -> String {
+    format!(r#"<a class="post-no" href="/post/{}?quote={}#reply-form">No.{}</a>"#, thread_id, id, id)
+}
+
+// Maps the index `sort` parameter to its canonical name and ORDER BY clause
+fn thread_order(sort: Option<&str>, searching: bool) -> (&'static str, &'static str) {
+    match sort {
+        Some("new") | Some("created") => ("new", "created_at DESC, id DESC"),
+        Some("replies") | Some("reply") => ("replies", "reply_count DESC, last_reply_at DESC"),
+        Some("bump") => (DEFAULT_SORT, "last_reply_at DESC"),
+        _ if searching => (SEARCH_SORT, "rank, last_reply_at DESC"),
+        _ => (DEFAULT_SORT, "last_reply_at DESC"),
+    }
+}
+
+// Which page of threads to list, from the `page`, `sort` and `q` parameters.
+// Without `sort` a listing takes the visitor's saved order; searches
+// without one are by relevance.
+struct ListingQuery<'a> {
+    page: usize,
+    sort: &'static str,
+    order_by: &'static str,
+    search: Option<&'a str>,
+}
+
+impl<'a> ListingQuery<'a> {
+    fn from_query(query: &'a HashMap<String, String>, saved_sort: Option<&str>) -> Self {
+        let search = query.get("q").map(|q| q.trim()).filter(|q| !q.is_empty());
+        let requested = query.get("sort").map(String::as_str).or(saved_sort.filter(|_| search.is_none()));
+        let (sort, order_by) = thread_order(requested, search.is_some());
+        ListingQuery {
+            page: query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1),
+            sort,
+            order_by,
+            search,
+        }
+    }
+}
+
+// Renders the `<div class="post">` entries for one page of `board`'s thread
+// listing, as seen by `viewer`. Empty when the page has no threads.
+// The quick reply forms are left out while posting is off.
+fn render_thread_list(conn: &Connection, board: &str, viewer: &str, listing: &ListingQuery, read_only: bool, renderer: &PostRenderer) -> String {
+    let PostRenderer { config, ctx, tr, .. } = *renderer;
+    let threads = db::list_threads(conn, board, viewer, listing, config.posts_per_page).unwrap();
+
+    // Filled in once; each card only swaps in its own thread id
+    let quick_reply = render_page("templates/quick_reply.html", &HashMap::from([
+        ("PLACEHOLDER", message_placeholder(config, tr)),
+        ("PASSWORD_FIELD", password_field(config, tr)),
+        ("FILE_FIELDS", file_fields(config, tr, false)),
+    ]), tr);
+
+    let mut posts_html = String::new();
+
+    for thread in threads {
+        let ThreadSummary { id, post_id, title, message, created_at, last_reply_at, autosage, reply_count, by_admin, country, attachment } = thread;
+
+        // Cut before sanitizing so any tag left open by the cut gets closed
+        let preview = Some(config.preview_chars).filter(|&max| max > 0).and_then(|max| truncate_words(&message, max));
+        let truncated_message = match preview {
+            Some(preview) => format!("{}... <a href=\"/post/{}\" class=\"view-full-post\">{}</a>", render_message(preview, by_admin, renderer), id, tr.t("board.read_more")),
+            None => render_message(&message, by_admin, renderer),
         };
 
         let post_color = generate_color_from_id(&post_id);
 
         posts_html.push_str("<div class=\"post\">");
-        posts_html.push_str(&format!("<div class=\"post-id-box\" style=\"background-color: {}\">{}</div>", post_color, post_id));
-        posts_html.push_str(&format!("<div class=\"post-title title-green\">{}</div>", title));
-        if let Some(file_path) = file_path {
-            if file_path.ends_with(".jpg") || file_path.ends_with(".jpeg") || file_path.ends_with(".png") || file_path.ends_with(".gif") || file_path.ends_with(".webp") {
-                posts_html.push_str(&format!(r#"<img src="/static/{}"><br>"#, file_path.trim_start_matches("./static/")));
-            } else if file_path.ends_with(".mp4") || file_path.ends_with(".mp3") || file_path.ends_with(".webm") {
-                posts_html.push_str(&format!(r#"<video controls><source src="/static/{}"></video><br>"#, file_path.trim_start_matches("./static/")));
-            }
+        posts_html.push_str(&format!("<div class=\"post-id-box\" data-color=\"{}\">{}</div> {}{}", post_color, post_id, post_number_link(id, id), country_flag(config, country.as_deref())));
+        let badge = if reply_limit_reached(config, reply_count) {
+            format!(" <span class=\"thread-badge\">{}</span>", tr.t("board.full_badge"))
+        } else {
+            String::new()
+        };
+        posts_html.push_str(&format!("<div class=\"post-title title-green\">{}{}{}</div>", break_long_words(&escape_html(&title), config.max_word_length), autosage_icon(autosage, tr), badge));
+        let created = format!("<span title=\"{}\">{}</span>", absolute_timestamp(ctx, &created_at), relative_timestamp(ctx, tr, &created_at));
+        let active = format!("<span title=\"{}\">{}</span>", absolute_timestamp(ctx, &last_reply_at), relative_timestamp(ctx, tr, &last_reply_at));
+        posts_html.push_str(&format!(
+            "<div class=\"post-time\">{} &middot; {}</div>",
+            tr.tf("board.created", &[("time", &created)]), tr.tf("board.active", &[("time", &active)])
+        ));
+        if let Some(attachment) = attachment {
+            posts_html.push_str(&render_attachment(&attachment, config, Some(id), tr));
+        }
+        if message.trim().is_empty() {
+            posts_html.push_str(&format!("<div class=\"post-message no-text\">{}</div>", tr.t("board.no_text")));
+        } else {
+            posts_html.push_str(&format!("<div class=\"post-message\">{}</div>", break_long_words(&truncated_message, config.max_word_length)));
+        }
+        posts_html.push_str(&format!(
+            "<a class=\"reply-button\" href=\"/post/{}\">{} <span class=\"reply-count\">({})</span></a>",
+            id, tr.t("board.reply"), tr.tn("board.replies", reply_count as i64)
+        ));
+        if !read_only && !reply_limit_reached(config, reply_count) {
+            posts_html.push_str(&fill_template(quick_reply.clone(), &HashMap::from([("PARENT_ID", id.to_string())])));
         }
-        posts_html.push_str(&format!("<div class=\"post-message\">{}</div>", truncated_message));
-        posts_html.push_str(&format!("<a class=\"reply-button\" href=\"/post/{}\">Reply ({})</a>", id, reply_count));
         posts_html.push_str("</div>");
     }
+    posts_html
+}
+
+async fn index(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, sanitizer: web::Data<HtmlSanitizer>, site: web::Data<Site>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    // With several boards the front page lists them instead of showing one
+    if req.match_info().get("board").is_none() && listed_boards(&conn).len() > 1 {
+        return Ok(board::landing_page(&conn, &site, &translator(&req)));
+    }
+    board_page(&req, &conn, &config, &sanitizer, &site, &query, &FormState::default())
+}
+
+// The board in the request's path as shown at /{board}/, with `form`
+// filled into the new-thread form
+fn board_page(req: &HttpRequest, conn: &Connection, config: &AppConfig, sanitizer: &HtmlSanitizer, site: &Site, query: &HashMap<String, String>, form: &FormState) -> Result<HttpResponse> {
+    let tr = translator(req);
+    let Some(board) = request_board(conn, req) else {
+        let body = render_notice(site, &tr, &tr.t("error.form_title"), &tr.t("error.board_not_found"));
+        return Ok(HttpResponse::NotFound().content_type("text/html").body(body));
+    };
+    let boards = listed_boards(conn);
 
+    let prefs = Prefs::of(req);
+    let saved = prefs.sort.clone();
+    let listing = ListingQuery::from_query(query, saved.as_deref());
+    let (page, sort, search) = (listing.page, listing.sort, listing.search);
+    let base = board.url();
+
+    let viewer = ip_hash(conn, req).map_err(ErrorInternalServerError)?;
+    let ctx = format_ctx(req);
+    let renderer = PostRenderer { config, sanitizer, highlighter: &highlighter(req), ctx: &ctx, tr: &tr, prefs: &prefs };
+    let mut posts_html = render_thread_list(conn, &board.slug, &viewer, &listing, site.read_only(), &renderer);
+    let matches = search.map(|search| db::count_matches(conn, &board.slug, &viewer, search).unwrap());
+
+    if let (Some(search), Some(matches)) = (search, matches) {
+        let clear = if sort == DEFAULT_SORT || sort == SEARCH_SORT { base.clone() } else { format!("{}?sort={}", base, sort) };
+        let summary = if matches == 0 {
+            tr.tf("board.no_matches", &[("query", &escape_html(search))])
+        } else {
+            tr.tn("board.matches", matches as i64).replace("{query}", &escape_html(search))
+        };
+        posts_html.insert_str(0, &format!(
+            r#"<div class="thread-notice">{} <a href="{}">{}</a></div>"#,
+            summary, clear, tr.t("board.clear_filter")
+        ));
+    }
+
+    // Carries the current sort and search into every link on the page
+    let search_param = search.map(|q| format!("&q={}", url::form_urlencoded::byte_serialize(q.as_bytes()).collect::<String>())).unwrap_or_default();
+    let default_sort = if search.is_some() { SEARCH_SORT } else { DEFAULT_SORT };
+    let sort_param = if sort == default_sort { String::new() } else { format!("&sort={}", sort) };
+    let sort_param = format!("{}{}", sort_param, search_param);
     let next_page = page + 1;
     let prev_page = if page > 1 { page - 1 } else { 1 };
     let mut pagination_html = String::new();
     if page > 1 {
-        pagination_html.push_str(&format!(r#"<a href="/?page={}">Previous</a>"#, prev_page));
+        pagination_html.push_str(&format!(r#"<a href="{}?page={}{}">{}</a>"#, base, prev_page, sort_param, tr.t("page.previous")));
+    }
+    // Search results know where they end; the plain listing always offers a next page
+    if matches.is_none_or(|matches| page * config.posts_per_page < matches) {
+        pagination_html.push_str(&format!(r#"<a href="{}?page={}{}">{}</a>"#, base, next_page, sort_param, tr.t("page.next")));
     }
-    pagination_html.push_str(&format!(r#"<a href="/?page={}">Next</a>"#, next_page));
 
-    let context = HashMap::from([
+    let mut sort_html = tr.t("board.sort_by");
+    let search_sort = search.map(|_| (SEARCH_SORT, "board.sort_relevance"));
+    for (value, label) in search_sort.into_iter().chain([("bump", "board.sort_bump"), ("new", "board.sort_new"), ("replies", "board.sort_replies")]) {
+        let label = tr.t(label);
+        if value == sort {
+            sort_html.push_str(&format!(r#" <span class="active-sort">{}</span>"#, label));
+        } else {
+            sort_html.push_str(&format!(r#" <a href="{}?sort={}{}">{}</a>"#, base, value, search_param, label));
+        }
+    }
+    // A new search keeps an explicitly chosen order; otherwise it's by relevance
+    let sort_field = if sort == DEFAULT_SORT || sort == SEARCH_SORT {
+        String::new()
+    } else {
+        format!(r#"<input type="hidden" name="sort" value="{}">"#, sort)
+    };
+    sort_html.push_str(&format!(
+        r#"<form class="search-form" action="{}" method="get">{}<input type="search" name="q" value="{}" placeholder="{}"><button type="submit">{}</button></form>"#,
+        base, sort_field, escape_html(search.unwrap_or("")), tr.t("board.search_placeholder"), tr.t("board.search")
+    ));
+
+    let thread_form = if site.read_only() {
+        read_only_notice(&tr)
+    } else {
+        render_page("templates/thread_form.html", &HashMap::from([
+            ("BOARD_URL", base),
+            ("PLACEHOLDER", message_placeholder(config, &tr)),
+            ("FORM_ERROR", form.error_html()),
+            ("TITLE", form.title_html()),
+            ("MESSAGE", form.message_html()),
+            ("AUTOSAGE", form.autosage_checked().to_string()),
+            ("AUTOFOCUS", form.autofocus()),
+            ("PASSWORD_FIELD", password_field(config, &tr)),
+            ("FILE_FIELDS", file_fields(config, &tr, config.require_op_image)),
+        ]), &tr)
+    };
+
+    let mut context = HashMap::from([
         ("POSTS", posts_html),
         ("PAGINATION", pagination_html),
+        ("SORT", sort_html),
+        ("RULES", rules_banner(config)),
+        ("THREAD_FORM", thread_form),
+        ("LANGUAGES", tr.language_links()),
+        ("ANNOUNCEMENT", site.announcement_banner()),
+        ("BOARD_HEADING", if boards.len() > 1 {
+            format!(r#"<h1 class="board-heading">/{}/ - {}</h1>"#, board.slug, escape_html(&board.name))
+        } else {
+            String::new()
+        }),
     ]);
+    context.extend(site.chrome());
 
-    let body = render_template("templates/index.html", &context);
+    let body = render_page("templates/index.html", &context, &tr);
+
+    // An order picked from the sort links sticks for later visits
+    let mut response = board_response(req, conn, config, |prefs| {
+        if query.contains_key("sort") && sort != SEARCH_SORT {
+            prefs.sort = Some(sort.to_string());
+        }
+    });
+    Ok(response.body(body))
+}
+
+async fn gallery(req: HttpRequest, conn: web::Data<Mutex<Connection>>, config: web::Data<AppConfig>, site: web::Data<Site>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+    let offset = (page - 1) * config.posts_per_page;
+
+    let mut stmt = conn.prepare(&format!("SELECT id, parent_id, title, file_path, alt_text FROM files WHERE {} AND hidden = 0 ORDER BY id DESC LIMIT ?1 OFFSET ?2", image_filter_sql(&config.file_types))).unwrap();
+    let images = stmt.query_map(params![config.posts_per_page as i64, offset as i64], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    }).unwrap();
+
+    let tr = translator(&req);
+    let mut images_html = String::new();
+    let mut image_count = 0;
+
+    for image in images {
+        let (id, parent_id, title, file_path, alt_text) = image.unwrap();
+        let thread_id = if parent_id == 0 { id } else { parent_id };
+        images_html.push_str(&format!(
+            r#"<a class="gallery-item" href="/post/{}" title="{}"><img src="{}" alt="{}"></a>"#,
+            thread_id, escape_html(&title), upload_url(&config, &file_path), alt_attr(alt_text.as_deref(), &tr)
+        ));
+        image_count += 1;
+    }
+
+    let mut pagination_html = String::new();
+    if page > 1 {
+        pagination_html.push_str(&format!(r#"<a href="/gallery?page={}">{}</a>"#, page - 1, tr.t("page.previous")));
+    }
+    if image_count == config.posts_per_page {
+        pagination_html.push_str(&format!(r#"<a href="/gallery?page={}">{}</a>"#, page + 1, tr.t("page.next")));
+    }
+
+    let mut context = HashMap::from([
+        ("IMAGES", images_html),
+        ("PAGINATION", pagination_html),
+    ]);
+    context.extend(site.chrome());
+
+    let body = render_page("templates/gallery.html", &context, &tr);
 
     Ok(HttpResponse::Ok().content_type("text/html").body(body))
 }
 
 fn initialize_db() -> SqlResult<Connection> {
-    let conn = Connection::open("my_database.db")?;
+    setup_db(Connection::open(DATABASE_PATH)?)
+}
+
+// Creates the tables in `conn` and brings them up to date
+fn setup_db(conn: Connection) -> SqlResult<Connection> {
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.busy_timeout(DB_BUSY_TIMEOUT)?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS files (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -266,33 +1399,1147 @@ fn initialize_db() -> SqlResult<Connection> {
         )",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS posters (
+            token TEXT PRIMARY KEY,
+            first_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    migrate(&conn)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
     Ok(conn)
 }
 
+fn migrate(conn: &Connection) -> SqlResult<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, index + 1))?;
+    }
+    Ok(())
+}
+
+// Everything the handlers and middleware reach through app_data, built
+// once and shared by every worker
+#[derive(Clone)]
+struct Shared {
+    conn: Data<Mutex<Connection>>,
+    config: Data<AppConfig>,
+    api_limiter: Data<ApiRateLimiter>,
+    throttle: Data<PostThrottle>,
+    db_limiter: Data<DbLimiter>,
+    body_limits: Data<BodyLimits>,
+    reply_notifier: Data<ReplyNotifier>,
+    feed_cache: Data<FeedCache>,
+    cookie_key: Data<CookieKey>,
+    locales: Data<Locales>,
+    site: Data<Site>,
+    sanitizer: Data<HtmlSanitizer>,
+    highlighter: Data<Highlighter>,
+    storage: Data<dyn Storage>,
+    geoip: Data<GeoIp>,
+    security_headers: Data<SecurityHeaders>,
+    scheduler: Data<Scheduler>,
+}
+
+impl Shared {
+    // Fails when the GeoIP database, the locale files or the cookie signing
+    // key can't be loaded
+    fn new(conn: Connection, config: AppConfig) -> Result<Self, String> {
+        let geoip = Data::new(GeoIp::open(&config)?);
+        let locales = Data::new(Locales::load()?);
+        let cookie_key = Data::new(CookieKey::load(&conn).map_err(|e| e.to_string())?);
+        sync_boards(&conn, &config).map_err(|e| e.to_string())?;
+        let site = Data::new(Site::load(&config, &conn));
+        let conn = Data::new(Mutex::new(conn));
+        let storage: Data<dyn Storage> = Data::from(open_storage(&config));
+        let config = Data::new(config);
+
+        let state = AppState { conn: conn.clone(), config: config.clone(), storage: storage.clone() };
+        let scheduler = Data::new(Scheduler::new(state, vec![
+            Arc::new(OptimizeJob),
+            Arc::new(VacuumJob),
+            Arc::new(ArchiveJob),
+            Arc::new(OrphanCleanupJob),
+            Arc::new(DimensionsBackfillJob),
+            Arc::new(DiskUsageJob),
+            Arc::new(HashBackfillJob),
+            Arc::new(ProxyCacheJob),
+        ]));
+
+        Ok(Shared {
+            api_limiter: Data::new(ApiRateLimiter::per_minute(config.api_requests_per_minute)),
+            throttle: Data::new(PostThrottle::new(&config)),
+            db_limiter: Data::new(DbLimiter::new(config.max_db_requests)),
+            body_limits: Data::new(BodyLimits::new(&config)),
+            reply_notifier: Data::new(ReplyNotifier::new()),
+            feed_cache: Data::new(FeedCache::new()),
+            sanitizer: Data::new(HtmlSanitizer::new(&config)),
+            highlighter: Data::new(Highlighter::load()),
+            security_headers: Data::new(SecurityHeaders::new(&config)),
+            conn,
+            config,
+            cookie_key,
+            locales,
+            site,
+            storage,
+            geoip,
+            scheduler,
+        })
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(cli::EXIT_FAILURE);
+        },
+    };
+    if let Err(e) = integrity::check_database(DATABASE_PATH, &config) {
+        eprintln!("{}", e);
+        std::process::exit(cli::EXIT_FAILURE);
+    }
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config),
+        command => std::process::exit(cli::run(command, &config)),
+    }
+}
+
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let conn = initialize_db().unwrap();
-    let conn_data = Data::new(Mutex::new(conn));
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(conn_data.clone())
-            .app_data(Data::new(web::JsonConfig::default().limit(MAX_SIZE)))
-            .service(
-                web::resource("/")
-                    .route(web::get().to(index))
-            )
-            .service(
-                web::resource("/upload")
-                    .route(web::post().to(save_file))
-            )
-            .service(
-                web::resource("/post/{id}")
-                    .route(web::get().to(view_post))
-            )
-            .service(fs::Files::new("/static", "./static").show_files_listing())
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+async fn serve(config: AppConfig) -> std::io::Result<()> {
+    let mut conn = initialize_db().unwrap();
+    install_query_stats(&mut conn, &config);
+
+    if let Some(import_path) = &config.import_path {
+        let imported = import::import_if_empty(&mut conn, import_path, &config.upload_dir)
+            .map_err(|e| std::io::Error::other(format!("Import failed: {}", e)))?;
+        if imported > 0 {
+            println!("Imported {} threads from {}", imported, import_path);
+        }
+    }
+
+    let shared = match Shared::new(conn, config) {
+        Ok(shared) => shared,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    jobs::start(shared.scheduler.clone(), shutdown_rx);
+
+    let server = HttpServer::new(move || app(&shared))
+        .bind("0.0.0.0:8080")?
+        .run();
+
+    let result = server.await;
+    let _ = shutdown_tx.send(true);
+    result
+}
+
+fn app(shared: &Shared) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    App::new()
+        .app_data(shared.conn.clone())
+        .app_data(shared.api_limiter.clone())
+        .app_data(shared.throttle.clone())
+        .app_data(shared.db_limiter.clone())
+        .app_data(shared.body_limits.clone())
+        .app_data(shared.reply_notifier.clone())
+        .app_data(shared.feed_cache.clone())
+        .app_data(shared.config.clone())
+        .app_data(shared.cookie_key.clone())
+        .app_data(shared.locales.clone())
+        .app_data(shared.site.clone())
+        .app_data(shared.sanitizer.clone())
+        .app_data(shared.highlighter.clone())
+        .app_data(shared.storage.clone())
+        .app_data(shared.geoip.clone())
+        .app_data(shared.security_headers.clone())
+        .app_data(shared.scheduler.clone())
+        .app_data(Data::new(web::JsonConfig::default().limit(shared.config.max_upload_bytes)))
+        .wrap(from_fn(track_route))
+        .wrap(from_fn(db_limit))
+        // Outside db_limit, so posts being held back don't take a slot
+        .wrap(from_fn(post_throttle))
+        // Outside db_limit, so oversized bodies never take a database slot
+        .wrap(from_fn(body_limit))
+        // Outermost, so refusals get the headers too
+        .wrap(from_fn(security_headers))
+        .service(
+            web::resource("/")
+                .route(web::get().to(index))
+        )
+        .service(
+            web::resource("/fragment/board")
+                .route(web::get().to(fragment::board_fragment))
+        )
+        .service(
+            web::resource("/upload")
+                .route(web::post().to(save_file))
+        )
+        .service(
+            web::resource("/post/{id}")
+                .route(web::get().to(view_post))
+        )
+        .service(
+            web::resource("/post/{id}/autosage")
+                .route(web::post().to(moderation::toggle_autosage))
+        )
+        .service(
+            web::resource("/admin")
+                .route(web::get().to(dashboard::dashboard))
+        )
+        .service(
+            web::resource("/admin/maintenance")
+                .route(web::post().to(maintenance::trigger_maintenance))
+        )
+        .service(
+            web::resource("/admin/vacuum")
+                .route(web::post().to(maintenance::trigger_maintenance))
+        )
+        .service(
+            web::resource("/admin/jobs")
+                .route(web::get().to(jobs::list_jobs))
+        )
+        .service(
+            web::resource("/admin/jobs/{name}/run")
+                .route(web::post().to(jobs::trigger_job))
+        )
+        .service(
+            web::resource("/admin/merge/{src}/{dst}")
+                .route(web::post().to(moderation::merge_threads))
+        )
+        .service(
+            web::resource("/admin/move-reply/{id}/{dst}")
+                .route(web::post().to(moderation::move_reply))
+        )
+        .service(
+            web::resource("/admin/move/{id}")
+                .route(web::post().to(moderation::move_thread))
+        )
+        .service(
+            web::resource("/admin/regen-id/{id}")
+                .route(web::post().to(moderation::regenerate_display_id))
+        )
+        .service(
+            web::resource("/admin/unhide/{id}")
+                .route(web::post().to(moderation::unhide_post))
+        )
+        .service(
+            web::resource("/admin/announcement")
+                .route(web::post().to(site::set_announcement))
+        )
+        .service(
+            web::resource("/admin/audit")
+                .route(web::get().to(moderation::audit_log))
+        )
+        .service(
+            web::resource("/admin/read-only")
+                .route(web::post().to(site::set_read_only))
+        )
+        .service(
+            web::resource("/admin/ban/{id}")
+                .route(web::post().to(bans::ban_poster))
+        )
+        .service(
+            web::resource("/admin/block-file/{id}")
+                .route(web::post().to(blocklist::block_file))
+        )
+        .service(
+            web::resource("/admin/unban/{id}")
+                .route(web::post().to(bans::unban_poster))
+        )
+        .service(
+            web::resource("/admin/delete/{id}")
+                .route(web::post().to(moderation::delete_post))
+        )
+        .service(
+            web::resource("/archive")
+                .route(web::get().to(archive::archive))
+        )
+        .service(
+            web::resource("/file/{name}")
+                .route(web::get().to(download::serve_file))
+        )
+        .service(
+            web::resource("/img-proxy")
+                .route(web::get().to(img_proxy::image_proxy))
+        )
+        .service(
+            web::resource("/lang/{code}")
+                .route(web::get().to(locale::set_language))
+        )
+        .service(
+            web::resource("/tz/{offset}")
+                .route(web::get().to(timezone::set_timezone))
+        )
+        .service(
+            web::resource("/emoji")
+                .route(web::get().to(emoji::emoji_list))
+        )
+        .service(
+            web::resource("/feed.xml")
+                .route(web::get().to(feed::feed))
+        )
+        .service(
+            web::resource("/healthz")
+                .route(web::get().to(site::healthz))
+        )
+        .service(
+            web::resource("/gallery")
+                .route(web::get().to(gallery))
+        )
+        .service(
+            web::resource("/post/{id}/export")
+                .route(web::get().to(export::export_thread))
+        )
+        .service(
+            web::scope("/api")
+                .wrap(from_fn(api_rate_limit))
+                .route("/posts", web::get().to(api::api_posts))
+                .route("/changes", web::get().to(api::api_changes))
+                .route("/upload", web::post().to(api::api_upload))
+                .route("/thread/{id}", web::get().to(api::api_thread))
+                .route("/which-thread/{id}", web::get().to(api::api_which_thread))
+                .route("/reply/{id}/wait", web::get().to(longpoll::wait_for_replies))
+                .route("/fragment/post/{id}", web::get().to(fragment::post_fragment))
+        )
+        .service(fs::Files::new("/static", config::STATIC_DIR))
+        // Board routes come last so fixed paths take precedence
+        .service(
+            web::resource("/{board}/")
+                .route(web::get().to(index))
+        )
+        .service(
+            web::resource("/{board}/upload")
+                .route(web::post().to(save_file))
+        )
+        .service(
+            web::resource("/{board}/post/{id}")
+                .route(web::get().to(view_post))
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    fn get(uri: &str) -> TestRequest {
+        TestRequest::get().uri(uri).peer_addr(PEER.parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn gallery_lists_only_image_posts() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+        {
+            let conn = shared.conn.lock().unwrap();
+            for (title, file_path) in [("with a cat", Some("./static/cat.png")), ("just words", None), ("a clip", Some("./static/clip.webm"))] {
+                conn.execute("INSERT INTO files (post_id, parent_id, title, message, file_path) VALUES ('x', 0, ?1, '', ?2)", params![title, file_path]).unwrap();
+            }
+        }
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/gallery").to_request()).await.to_vec()).unwrap();
+        assert_eq!(page.matches("class=\"gallery-item\"").count(), 1);
+        assert!(page.contains("title=\"with a cat\""));
+        assert!(page.contains("src=\"/file/cat.png\""));
+        assert!(!page.contains("just words"));
+        assert!(!page.contains("a clip"));
+    }
+
+    // A thread made `created` ago and last bumped `bumped` ago (SQLite
+    // modifiers such as "-2 days"), with `replies` replies
+    fn seed_thread(conn: &Connection, title: &str, created: &str, bumped: &str, replies: i32) {
+        conn.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at, reply_count)
+             VALUES (?1, 0, ?1, 'body', datetime('now', ?2), datetime('now', ?3), ?4)",
+            params![title, created, bumped, replies],
+        ).unwrap();
+        let thread_id = conn.last_insert_rowid();
+        for _ in 0..replies {
+            conn.execute("INSERT INTO files (post_id, parent_id, title, message) VALUES ('r', ?1, 're', 'reply')", params![thread_id]).unwrap();
+        }
+    }
+
+    // Whether `titles` appear in `page` in that order
+    fn in_order(page: &str, titles: &[&str]) -> bool {
+        let positions: Vec<usize> = titles.iter().map(|title| page.find(&format!(">{}<", title)).unwrap()).collect();
+        positions.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    #[actix_web::test]
+    async fn index_sorts_by_bump_creation_and_replies() {
+        let shared = shared(AppConfig::default());
+        {
+            let conn = shared.conn.lock().unwrap();
+            seed_thread(&conn, "oldest-but-bumped", "-3 days", "-1 minutes", 1);
+            seed_thread(&conn, "newest-quiet", "-1 days", "-1 days", 0);
+            seed_thread(&conn, "busiest", "-2 days", "-1 hours", 3);
+        }
+        let app = init_service(app(&shared)).await;
+
+        for (uri, order) in [
+            ("/", ["oldest-but-bumped", "busiest", "newest-quiet"]),
+            ("/?sort=bump", ["oldest-but-bumped", "busiest", "newest-quiet"]),
+            ("/?sort=new", ["newest-quiet", "busiest", "oldest-but-bumped"]),
+            ("/?sort=replies", ["busiest", "oldest-but-bumped", "newest-quiet"]),
+        ] {
+            let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(uri).to_request()).await.to_vec()).unwrap();
+            assert!(in_order(&page, &order), "{}: expected {:?}", uri, order);
+        }
+    }
+
+    #[actix_web::test]
+    async fn replies_bump_but_keep_creation_time() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+        let thread = multipart(&[("title", "thread"), ("message", "body"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", thread, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        let times = || -> (String, String) {
+            shared.conn.lock().unwrap().query_row("SELECT created_at, last_reply_at FROM files WHERE parent_id = 0", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        shared.conn.lock().unwrap().execute("UPDATE files SET created_at = datetime('now', '-1 day'), last_reply_at = datetime('now', '-1 day')", []).unwrap();
+        let (created, bumped) = times();
+
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files", [], |row| row.get(0)).unwrap();
+        let reply = multipart(&[("title", "reply"), ("message", "body"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, "127.0.0.1:40000").to_request()).await.status(), 303);
+        let (created_after, bumped_after) = times();
+        assert_eq!(created_after, created);
+        assert!(bumped_after > bumped);
+    }
+
+    #[actix_web::test]
+    async fn rules_banner_and_placeholder_show_on_the_index() {
+        let shared = shared(AppConfig {
+            rules_banner: "Be kind <or else>".to_string(),
+            content_placeholder: "Say \"hi\"".to_string(),
+            ..AppConfig::default()
+        });
+        let app = init_service(app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(r#"<div class="rules-banner">Be kind &lt;or else&gt;</div>"#));
+        assert!(page.contains(r#"placeholder="Say &quot;hi&quot;""#));
+    }
+
+    #[test]
+    fn original_names_lose_paths_and_control_characters() {
+        assert_eq!(sanitize_original_name("C:\\Users\\me\\holiday.png").as_deref(), Some("holiday.png"));
+        assert_eq!(sanitize_original_name("../../etc/pa\u{7}sswd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_original_name("  \u{0}\t ").as_deref(), None);
+        assert_eq!(sanitize_original_name(&"a".repeat(500)).unwrap().chars().count(), MAX_ORIGINAL_NAME_CHARS);
+    }
+
+    #[actix_web::test]
+    async fn original_file_name_is_shown_escaped() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+        let id = {
+            let conn = shared.conn.lock().unwrap();
+            conn.execute("INSERT INTO files (post_id, parent_id, title, message, file_path, original_name, created_at) VALUES ('p', 0, 'trip', 'm', './static/abc.png', '<i>Beach & sun.png', CURRENT_TIMESTAMP)", []).unwrap();
+            conn.last_insert_rowid()
+        };
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", id)).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("&lt;i&gt;Beach &amp; sun.png"));
+        assert!(!page.contains("<i>Beach"));
+    }
+
+    #[actix_web::test]
+    async fn uploads_land_in_the_upload_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+
+        let image = png(1);
+        let body = multipart(&[("title", "stored"), ("message", "file"), ("parent_id", "0")], Some(("cat.png", "image/png", &image)));
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        let file_path: String = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files", [], |row| row.get(0)).unwrap();
+        assert!(file_path.starts_with(&shared.config.upload_dir), "{}", file_path);
+        assert_eq!(std::fs::read(&file_path).unwrap(), image);
+
+        let response = call_service(&app, TestRequest::get().uri(&upload_url(&shared.config, &file_path)).to_request()).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn replies_render_quotable_numbers() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+
+        let op = multipart(&[("title", "quotes"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, "127.0.0.1:40000").to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        for n in 1..=3 {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread_id.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, "127.0.0.1:40000").to_request()).await.status(), 303);
+        }
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", thread_id)).to_request()).await.to_vec()).unwrap();
+        for n in 1..=3 {
+            assert!(page.contains(&format!(r##"<a class="quote-link" href="#reply-form" data-quote="{}">Reply {}</a>"##, n, n)), "reply {}", n);
+        }
+        assert!(!page.contains("data-quote=\"4\""));
+        assert!(page.contains("id=\"reply-form\""));
+    }
+
+    #[actix_web::test]
+    async fn full_threads_refuse_replies() {
+        let shared = shared(AppConfig { max_replies_per_thread: 2, ..AppConfig::default() });
+        let app = init_service(app(&shared)).await;
+
+        let op = multipart(&[("title", "capped"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        let reply = |n: i32| multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread_id.to_string())], None);
+        let thread = format!("/post/{}", thread_id);
+
+        let page = String::from_utf8(call_and_read_body(&app, get(&thread).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("id=\"reply-form\""));
+        for n in 1..=2 {
+            assert_eq!(call_service(&app, form_post("/upload", reply(n), PEER).to_request()).await.status(), 303);
+        }
+
+        let response = call_service(&app, form_post("/upload", reply(3), PEER).to_request()).await;
+        assert_eq!(response.status(), 403);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Thread is full"));
+        let replies: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files WHERE parent_id = ?1", [thread_id], |row| row.get(0)).unwrap();
+        assert_eq!(replies, 2);
+
+        let page = String::from_utf8(call_and_read_body(&app, get(&thread).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("This thread is full and can no longer be replied to."));
+        assert!(!page.contains("id=\"reply-form\""));
+        let index = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+        assert!(index.contains("<span class=\"thread-badge\">Full</span>"));
+    }
+
+    #[actix_web::test]
+    async fn post_numbers_link_to_a_prefilled_quote() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+
+        let op = multipart(&[("title", "numbers"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        let reply = multipart(&[("title", "re"), ("message", "reply"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        let reply_id = thread_id + 1;
+
+        let thread = format!("/post/{}", thread_id);
+        let page = String::from_utf8(call_and_read_body(&app, get(&thread).to_request()).await.to_vec()).unwrap();
+        for id in [thread_id, reply_id] {
+            assert!(page.contains(&format!(r##"<a class="post-no" href="/post/{}?quote={}#reply-form">No.{}</a>"##, thread_id, id, id)), "post {}", id);
+        }
+
+        let page = String::from_utf8(call_and_read_body(&app, get(&format!("{}?quote={}", thread, reply_id)).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(&format!("&gt;&gt;{}\n</textarea>", reply_id)));
+        // Anything but a number is ignored
+        let page = String::from_utf8(call_and_read_body(&app, get(&format!("{}?quote=%3Cb%3E", thread)).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("></textarea>"));
+        assert!(!page.contains("&lt;b&gt;"));
+    }
+
+    #[actix_web::test]
+    async fn every_post_has_a_stable_anchor_and_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+
+        let op = multipart(&[("title", "anchors"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        for n in 1..=2 {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread_id.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        }
+
+        let thread = format!("/post/{}", thread_id);
+        let first = String::from_utf8(call_and_read_body(&app, get(&thread).to_request()).await.to_vec()).unwrap();
+        for id in thread_id..=thread_id + 2 {
+            assert!(first.contains(&format!(r#"id="p{}">"#, id)), "post {}", id);
+            assert!(first.contains(&format!(r##"<a class="anchor-link" href="#p{}" title="Link to this post">link</a>"##, id)), "post {}", id);
+        }
+        assert!(first.contains(r#"id="r1""#) && first.contains(r#"id="r2""#));
+        // Nothing random goes into the anchors, so a second load links the same way
+        let second = String::from_utf8(call_and_read_body(&app, get(&thread).to_request()).await.to_vec()).unwrap();
+        let anchors = |page: &str| page.match_indices(" id=\"").map(|(at, _)| page[at..].split('>').next().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(anchors(&first), anchors(&second));
+    }
+
+    #[actix_web::test]
+    async fn search_filters_threads_and_keeps_the_sort() {
+        let shared = shared(AppConfig::default());
+        {
+            let conn = shared.conn.lock().unwrap();
+            seed_thread(&conn, "rust tips", "-2 days", "-1 hours", 0);
+            seed_thread(&conn, "cooking", "-1 days", "-1 minutes", 0);
+            seed_thread(&conn, "100% cotton", "-3 days", "-2 hours", 0);
+        }
+        let app = init_service(app(&shared)).await;
+        let index = |uri: &str| {
+            let request = get(uri).to_request();
+            let app = &app;
+            async move { String::from_utf8(call_and_read_body(app, request).await.to_vec()).unwrap() }
+        };
+
+        let page = index("/?q=RUST").await;
+        assert!(page.contains("rust tips"));
+        assert!(!page.contains("cooking") && !page.contains("100% cotton"));
+
+        let page = index("/?sort=new&q=rust").await;
+        assert!(page.contains(r#"<a href="/main/?sort=bump&q=rust">"#));
+        assert!(page.contains(r#"<input type="hidden" name="sort" value="new"><input type="search" name="q" value="rust""#));
+
+        let page = index("/?sort=new&q=%3Cb%3Enothing").await;
+        assert!(page.contains(r#"No threads match "&lt;b&gt;nothing". <a href="/main/?sort=new">Clear filter</a>"#));
+    }
+
+    #[actix_web::test]
+    async fn replies_are_counted_on_the_opening_post() {
+        let shared = shared(AppConfig::default());
+        let app = init_service(app(&shared)).await;
+        let op = multipart(&[("title", "counted"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        for n in 0..5 {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread_id.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        }
+
+        let conn = shared.conn.lock().unwrap();
+        let (cached, actual): (i32, i32) = conn.query_row(
+            "SELECT reply_count, (SELECT COUNT(*) FROM files WHERE parent_id = ?1) FROM files WHERE id = ?1",
+            [thread_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!((cached, actual), (5, 5));
+    }
+
+    #[actix_web::test]
+    async fn quick_replies_land_on_their_thread_and_return_to_the_board() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+
+        let mut threads = Vec::new();
+        for title in ["left", "right"] {
+            let op = multipart(&[("title", title), ("message", "op"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+            threads.push(shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get::<_, i32>(0)).unwrap());
+        }
+        let index = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+        for thread in &threads {
+            assert!(index.contains(&format!(r#"<input type="hidden" name="parent_id" value="{}">"#, thread)));
+        }
+        assert_eq!(index.matches(r#"<input type="hidden" name="return_to" value="board">"#).count(), 2);
+
+        // As the form on the "right" card sends it
+        let quick = multipart(&[("parent_id", &threads[1].to_string()), ("return_to", "board"), ("title", "quick"), ("message", "from the board")], None);
+        let response = call_service(&app, form_post("/upload", quick, PEER).to_request()).await;
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/main/");
+        let parent: i32 = shared.conn.lock().unwrap().query_row("SELECT parent_id FROM files WHERE message = 'from the board'", [], |row| row.get(0)).unwrap();
+        assert_eq!(parent, threads[1]);
+        let counts: Vec<i32> = threads.iter()
+            .map(|thread| shared.conn.lock().unwrap().query_row("SELECT reply_count FROM files WHERE id = ?1", [thread], |row| row.get(0)).unwrap())
+            .collect();
+        assert_eq!(counts, [0, 1]);
+    }
+
+    #[actix_web::test]
+    async fn rejected_forms_get_styled_pages_and_empty_files_are_no_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { site_name: "Crate".to_string(), ..test_config(dir.path()) });
+        let app = init_service(app(&shared)).await;
+
+        // Cut off before the closing boundary
+        let mut body = multipart(&[("title", "broken"), ("message", "body")], None);
+        body.truncate(body.len() - 30);
+        let response = call_service(&app, form_post("/upload", body, PEER).to_request()).await;
+        assert_eq!(response.status(), 400);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("<title>Your post could not be submitted - Crate</title>"));
+        assert!(page.contains("The form data could not be read."));
+
+        // A missing field is named on the form it came from
+        let untitled = multipart(&[("message", "kept text"), ("parent_id", "0")], None);
+        let response = call_service(&app, form_post("/upload", untitled, PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Please enter a title."));
+        assert!(page.contains(r#"class="site-name""#));
+
+        // A zero-byte file part, as some browsers send for a cleared input
+        let cleared = multipart(&[("title", "no file"), ("message", "just text"), ("parent_id", "0")], Some(("cat.png", "image/png", b"")));
+        assert_eq!(call_service(&app, form_post("/upload", cleared, PEER).to_request()).await.status(), 303);
+        let file_path: Option<String> = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files WHERE title = 'no file'", [], |row| row.get(0)).unwrap();
+        assert!(file_path.unwrap_or_default().is_empty());
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn search_pages_keep_the_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { posts_per_page: 2, ..test_config(dir.path()) });
+        {
+            let conn = shared.conn.lock().unwrap();
+            for (title, age) in [("fish & chips one", "-1 hours"), ("fish & chips two", "-2 hours"), ("fish & chips three", "-3 hours"), ("just fish", "-4 hours")] {
+                seed_thread(&conn, title, age, age, 0);
+            }
+        }
+        let app = init_service(app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, get("/?q=fish+%26+chips").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("3 threads match \"fish &amp; chips\"."));
+        assert!(page.find(" chips one<").unwrap() < page.find(" chips two<").unwrap());
+        assert!(page.contains(r#"<a href="/main/?page=2&q=fish+%26+chips">Next</a>"#));
+
+        let page = String::from_utf8(call_and_read_body(&app, get("/?page=2&q=fish+%26+chips").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(" chips three<"));
+        assert!(!page.contains("just fish"));
+        assert!(page.contains(r#"<a href="/main/?page=1&q=fish+%26+chips">Previous</a>"#));
+        assert!(!page.contains(">Next</a>"));
+    }
+
+    #[actix_web::test]
+    async fn posts_may_be_just_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+
+        let nothing = multipart(&[("title", "empty"), ("message", "  \n "), ("parent_id", "0")], None);
+        let response = call_service(&app, form_post("/upload", nothing, PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("A comment or file is required."));
+
+        let picture = multipart(&[("title", "just a cat"), ("message", ""), ("parent_id", "0")], Some(("cat.png", "image/png", &png(7))));
+        assert_eq!(call_service(&app, form_post("/upload", picture, PEER).to_request()).await.status(), 303);
+        let id: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'just a cat'", [], |row| row.get(0)).unwrap();
+
+        let board = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+        assert!(board.contains(r#"<div class="post-message no-text">(no text)</div>"#));
+        let thread = String::from_utf8(call_and_read_body(&app, get(&format!("/post/{}", id)).to_request()).await.to_vec()).unwrap();
+        assert!(thread.contains("just a cat"));
+        assert!(!thread.contains(r#"class="post-message"#));
+        assert!(!thread.contains("(no text)"));
+        let feed = String::from_utf8(call_and_read_body(&app, get("/feed.xml").to_request()).await.to_vec()).unwrap();
+        assert!(feed.contains("<description>(no text)</description>"));
+    }
+
+    #[actix_web::test]
+    async fn new_threads_can_be_made_to_need_an_image() {
+        for required in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { require_op_image: required, ..test_config(dir.path()) });
+            let app = init_service(app(&shared)).await;
+
+            let board = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+            assert_eq!(board.contains(r#"<span class="field-note">Required for new threads</span>"#), required);
+
+            let text_only = multipart(&[("title", "words"), ("message", "no picture"), ("parent_id", "0")], None);
+            let response = call_service(&app, form_post("/upload", text_only, PEER).to_request()).await;
+            if required {
+                assert_eq!(response.status(), 422);
+                let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+                assert!(page.contains("New threads need an image or video."));
+            } else {
+                assert_eq!(response.status(), 303);
+            }
+
+            let with_image = multipart(&[("title", "picture"), ("message", "a cat"), ("parent_id", "0")], Some(("cat.png", "image/png", &png(3))));
+            assert_eq!(call_service(&app, form_post("/upload", with_image, PEER).to_request()).await.status(), 303);
+            let thread: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'picture'", [], |row| row.get(0)).unwrap();
+
+            // Replies never need one
+            let reply = multipart(&[("title", "re"), ("message", "nice cat"), ("parent_id", &thread.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303, "require_op_image = {}", required);
+        }
+    }
+
+    #[actix_web::test]
+    async fn threads_with_replies_get_jump_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+        let op = multipart(&[("title", "long thread"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread = "/post/1";
+
+        // Nothing to jump around yet
+        let page = String::from_utf8(call_and_read_body(&app, get(thread).to_request()).await.to_vec()).unwrap();
+        assert!(!page.contains("thread-nav"));
+
+        for n in 1..=3 {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", "1")], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        }
+        let page = String::from_utf8(call_and_read_body(&app, get(thread).to_request()).await.to_vec()).unwrap();
+        let top = page.find(r##"<div class="thread-nav" id="top"><a href="#bottom">Bottom</a> &middot; <a href="#r3">3 replies</a></div>"##).unwrap();
+        let bottom = page.find(r##"<div class="thread-nav" id="bottom"><a href="#top">Top</a></div>"##).unwrap();
+        let last_reply = page.find(r#"id="r3""#).unwrap();
+        assert!(top < page.find(">long thread<").unwrap());
+        assert!(last_reply < bottom);
+    }
+
+    #[actix_web::test]
+    async fn text_only_boards_take_no_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AppConfig { uploads_enabled: false, ..test_config(dir.path()) };
+        // No upload directory is set up for them
+        assert_eq!(config.validate(), Vec::<String>::new());
+        assert!(!dir.path().join("uploads").exists());
+
+        let shared = shared(config);
+        let app = init_service(app(&shared)).await;
+        let board = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+        assert!(!board.contains(r#"type="file""#));
+        assert!(!board.contains("attachment_token"));
+
+        let with_file = multipart(&[("title", "words only"), ("message", "and a file"), ("parent_id", "0")], Some(("cat.png", "image/png", &png(5))));
+        assert_eq!(call_service(&app, form_post("/upload", with_file, PEER).to_request()).await.status(), 303);
+        let file_path: Option<String> = shared.conn.lock().unwrap().query_row("SELECT file_path FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(file_path, None);
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+
+        let thread = String::from_utf8(call_and_read_body(&app, get("/post/1").to_request()).await.to_vec()).unwrap();
+        assert!(!thread.contains(r#"type="file""#));
+
+        let ahead = form_post("/api/upload", multipart(&[], Some(("cat.png", "image/png", &png(5)))), PEER);
+        assert_eq!(call_service(&app, ahead.to_request()).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn title_matches_rank_above_message_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        {
+            let conn = shared.conn.lock().unwrap();
+            for (title, message, bumped) in [
+                ("kitchen talk", "my teapot collection keeps growing", "-1 minutes"),
+                ("teapots", "pictures of the shelf", "-2 days"),
+                ("unrelated", "nothing to see", "-1 minutes"),
+            ] {
+                conn.execute(
+                    "INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at) VALUES (?1, 0, ?1, ?2, datetime('now', ?3), datetime('now', ?3))",
+                    params![title, message, bumped],
+                ).unwrap();
+            }
+        }
+        let app = init_service(app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, get("/main/?q=teapot").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains("2 threads match"));
+        assert!(in_order(&page, &["teapots", "kitchen talk"]));
+        assert!(!page.contains(">unrelated<"));
+        // Any other order can still be picked
+        let page = String::from_utf8(call_and_read_body(&app, get("/main/?q=teapot&sort=bump").to_request()).await.to_vec()).unwrap();
+        assert!(in_order(&page, &["kitchen talk", "teapots"]));
+
+        // Search syntax is taken as plain words
+        for query in ["teapot%22", "teapot+OR", "NEAR(teapot", "*"] {
+            let response = call_service(&app, get(&format!("/main/?q={}", query)).to_request()).await;
+            assert_eq!(response.status(), 200, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn media_links_use_the_configured_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { media_base_url: Some("https://cdn.example/media/".to_string()), ..test_config(dir.path()) });
+        let app = init_service(app(&shared)).await;
+
+        let form = multipart(&[("title", "far away"), ("message", "cat"), ("parent_id", "0")], Some(("cat.png", "image/png", &png(12))));
+        assert_eq!(call_service(&app, form_post("/upload", form, PEER).to_request()).await.status(), 303);
+        let (id, file_path): (i32, String) = shared.conn.lock().unwrap()
+            .query_row("SELECT id, file_path FROM files WHERE title = 'far away'", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        // The database keeps the stored path, not the link
+        assert!(!file_path.contains("cdn.example"));
+        let url = format!("https://cdn.example/media/file/{}", upload_name(&file_path));
+        assert_eq!(upload_url(&shared.config, &file_path), url);
+
+        let board = String::from_utf8(call_and_read_body(&app, get("/").to_request()).await.to_vec()).unwrap();
+        assert!(board.contains(&format!(r#"src="{}""#, url)));
+        assert!(!board.contains(r#"src="/file/"#));
+        let thread = String::from_utf8(call_and_read_body(&app, get(&format!("/post/{}", id)).to_request()).await.to_vec()).unwrap();
+        assert!(thread.contains(&format!(r#"src="{}""#, url)));
+        let api: serde_json::Value = actix_web::test::call_and_read_body_json(&app, get(&format!("/api/thread/{}", id)).to_request()).await;
+        assert_eq!(api["op"]["file_url"], url.as_str());
+
+        // This server still answers for the file itself
+        let response = call_service(&app, get(&format!("/file/{}", upload_name(&file_path))).to_request()).await;
+        assert_eq!(response.status(), 200);
+
+        // Remote images still go through the proxy
+        assert_eq!(upload_url(&shared.config, "https://elsewhere.example/a.png"), img_proxy::proxied_url("https://elsewhere.example/a.png"));
+    }
+
+    #[actix_web::test]
+    async fn runs_of_replies_by_one_poster_are_grouped() {
+        for grouped in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { group_same_poster_replies: grouped, ..test_config(dir.path()) });
+            let app = init_service(app(&shared)).await;
+            let (alice, bob) = ("10.0.0.1:40000", "10.0.0.2:40000");
+
+            let op = multipart(&[("title", "chatty"), ("message", "op"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", op, alice).to_request()).await.status(), 303);
+            let thread: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'chatty'", [], |row| row.get(0)).unwrap();
+            for (n, peer) in [bob, alice, alice, alice, bob, bob].into_iter().enumerate() {
+                let reply = multipart(&[("title", "re"), ("message", &format!("message {}", n + 1)), ("parent_id", &thread.to_string())], None);
+                assert_eq!(call_service(&app, form_post("/upload", reply, peer).to_request()).await.status(), 303);
+            }
+
+            let page = String::from_utf8(call_and_read_body(&app, get(&format!("/post/{}", thread)).to_request()).await.to_vec()).unwrap();
+            for n in 1..=6 {
+                assert_eq!(page.matches(&format!("id=\"r{}\"", n)).count(), 1, "r{}", n);
+            }
+            let groups: Vec<&str> = page.split("<div class=\"reply-group\">").skip(1).collect();
+            if !grouped {
+                assert!(groups.is_empty());
+                continue;
+            }
+            assert_eq!(groups.len(), 2);
+            // Each group holds its run and nothing after it
+            for (group, inside, after) in [(groups[0], 2..=4, 5), (groups[1], 5..=6, 7)] {
+                for n in inside {
+                    assert!(group.contains(&format!("message {}", n)));
+                }
+                assert!(!group.contains(&format!("message {}", after)));
+            }
+            assert!(!page.split("<div class=\"reply-group\">").next().unwrap().contains("message 2"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn simultaneous_replies_are_numbered_densely_up_to_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { max_replies_per_thread: 25, ..test_config(dir.path()) });
+        let app = init_service(app(&shared)).await;
+
+        let op = multipart(&[("title", "busy"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'busy'", [], |row| row.get(0)).unwrap();
+
+        let replies = (0..30).map(|n| {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread.to_string())], None);
+            call_service(&app, form_post("/upload", reply, &format!("10.0.1.{}:40000", n)).to_request())
+        });
+        let statuses: Vec<u16> = futures_util::future::join_all(replies).await.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses.iter().filter(|&&status| status == 303).count(), 25);
+        assert_eq!(statuses.iter().filter(|&&status| status == 403).count(), 5);
+
+        {
+            let conn = shared.conn.lock().unwrap();
+            let (stored, distinct, cached): (i32, i32, i32) = conn.query_row(
+                "SELECT COUNT(*), COUNT(DISTINCT id), (SELECT reply_count FROM files WHERE id = ?1) FROM files WHERE parent_id = ?1",
+                [thread],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).unwrap();
+            assert_eq!((stored, distinct, cached), (25, 25, 25));
+            assert!(thread_full(&conn, &shared.config, thread).unwrap());
+        }
+
+        let page = String::from_utf8(call_and_read_body(&app, get(&format!("/post/{}", thread)).to_request()).await.to_vec()).unwrap();
+        for n in 1..=25 {
+            assert_eq!(page.matches(&format!("id=\"r{}\"", n)).count(), 1, "r{}", n);
+        }
+        assert!(!page.contains("id=\"r26\""));
+        assert!(page.contains("This thread is full and can no longer be replied to."));
+
+        let late = multipart(&[("title", "re"), ("message", "too late"), ("parent_id", &thread.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", late, PEER).to_request()).await.status(), 403);
+    }
+
+    #[test]
+    fn old_databases_gain_the_thread_foreign_key_and_keep_orphans() {
+        // A database from before the foreign key: the base tables and the
+        // migrations up to it, with replies whose threads were deleted
+        let repair = MIGRATIONS.iter().position(|migration| migration.contains("orphan_threads")).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len()).unwrap();
+        let conn = setup_db(conn).unwrap();
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        for migration in &MIGRATIONS[..repair] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", repair).unwrap();
+        for (id, parent_id) in [(1, 0), (2, 1), (3, 50), (4, 50), (5, 60)] {
+            conn.execute(
+                "INSERT INTO files (id, post_id, parent_id, title, message, created_at, last_reply_at) VALUES (?1, ?1, ?2, 'post', 'text', datetime('now', ?3), datetime('now'))",
+                params![id, parent_id, format!("-{} minutes", 10 - id)],
+            ).unwrap();
+        }
+
+        migrate(&conn).unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+
+        let rows: Vec<(i32, i32, bool, bool)> = conn.prepare("SELECT id, parent_id, archived, hidden FROM files ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(rows, [(1, 0, false, false), (2, 1, false, false), (3, 0, true, true), (4, 3, false, false), (5, 0, true, true)]);
+        let repairs: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'repair'", [], |row| row.get(0)).unwrap();
+        assert_eq!(repairs, 2);
+        let violations: i32 = conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0)).unwrap();
+        assert_eq!(violations, 0);
+
+        // Replies go with their thread, and can't be made for a missing one
+        conn.execute("DELETE FROM files WHERE id = 3", []).unwrap();
+        let left: Vec<i32> = conn.prepare("SELECT id FROM files ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(left, [1, 2, 5]);
+        let error = conn.execute("INSERT INTO files (post_id, parent_id, title, message) VALUES ('x', 999, 're', 'text')", []).unwrap_err();
+        assert!(db::is_foreign_key_violation(&error));
+    }
+
+    #[actix_web::test]
+    async fn replies_to_missing_threads_are_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+
+        let reply = multipart(&[("title", "re"), ("message", "anyone?"), ("parent_id", "999")], Some(("cat.png", "image/png", &png(3))));
+        let response = call_service(&app, form_post("/upload", reply, PEER).to_request()).await;
+        assert_eq!(response.status(), 404);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Thread not found."));
+        let posts: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn a_chosen_order_sticks_for_later_visits() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        {
+            let conn = shared.conn.lock().unwrap();
+            seed_thread(&conn, "oldest-but-bumped", "-3 days", "-1 minutes", 1);
+            seed_thread(&conn, "newest-quiet", "-1 days", "-1 days", 0);
+            seed_thread(&conn, "busiest", "-2 days", "-1 hours", 3);
+        }
+        let app = init_service(app(&shared)).await;
+
+        for (sort, order) in [
+            ("replies", ["busiest", "oldest-but-bumped", "newest-quiet"]),
+            ("created", ["newest-quiet", "busiest", "oldest-but-bumped"]),
+            ("bump", ["oldest-but-bumped", "busiest", "newest-quiet"]),
+        ] {
+            let response = call_service(&app, get(&format!("/?sort={}", sort)).to_request()).await;
+            let cookie = response.response().cookies().find(|cookie| cookie.name() == "prefs").unwrap().into_owned();
+            let body = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+            assert!(in_order(&body, &order), "{}: expected {:?}", sort, order);
+
+            // Coming back without ?sort= keeps the order, on the board and
+            // in the infinite-scroll pages
+            let body = String::from_utf8(call_and_read_body(&app, get("/").cookie(cookie.clone()).to_request()).await.to_vec()).unwrap();
+            assert!(in_order(&body, &order), "{} remembered: expected {:?}", sort, order);
+            let fragment = String::from_utf8(call_and_read_body(&app, get("/fragment/board?page=1").cookie(cookie).to_request()).await.to_vec()).unwrap();
+            assert!(in_order(&fragment, &order), "{} fragment: expected {:?}", sort, order);
+        }
+    }
+
+    #[actix_web::test]
+    async fn replies_show_their_posters_post_count() {
+        for shown in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { show_poster_post_counts: shown, ..test_config(dir.path()) });
+            let app = init_service(app(&shared)).await;
+            let (alice, bob, carol) = ("10.0.0.1:40000", "10.0.0.2:40000", "10.0.0.3:40000");
+
+            let op = multipart(&[("title", "counted"), ("message", "op"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", op, alice).to_request()).await.status(), 303);
+            let thread: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = 'counted'", [], |row| row.get(0)).unwrap();
+            for (n, peer) in [bob, carol, bob, alice, bob].into_iter().enumerate() {
+                let reply = multipart(&[("title", "re"), ("message", &format!("message {}", n + 1)), ("parent_id", &thread.to_string())], None);
+                assert_eq!(call_service(&app, form_post("/upload", reply, peer).to_request()).await.status(), 303);
+            }
+
+            let page = String::from_utf8(call_and_read_body(&app, get(&format!("/post/{}", thread)).to_request()).await.to_vec()).unwrap();
+            let (op_post, replies) = page.split_once("id=\"r1\"").unwrap();
+            if !shown {
+                assert!(!page.contains("poster-mark"));
+                continue;
+            }
+            // The opening post itself carries no mark
+            assert!(!op_post.contains("poster-mark"));
+            let replies: Vec<&str> = replies.split("id=\"r").collect();
+            let bob_mark = r#"<span class="poster-mark" title="3 posts by this poster in the thread">(3)</span>"#;
+            let carol_mark = r#"<span class="poster-mark" title="1 post by this poster in the thread">(1)</span>"#;
+            let op_mark = r#"<span class="poster-mark" title="Posted by the thread's author">OP</span>"#;
+            for (reply, mark) in replies.iter().zip([bob_mark, carol_mark, bob_mark, op_mark, bob_mark]) {
+                assert_eq!(reply.matches("poster-mark").count(), 1, "{}", reply);
+                assert!(reply.contains(mark), "expected {} in {}", mark, reply);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn refused_posts_come_back_filled_in_on_their_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(app(&shared)).await;
+        let op = multipart(&[("title", "standing thread"), ("message", "the op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread: i32 = shared.conn.lock().unwrap().query_row("SELECT id FROM files", [], |row| row.get(0)).unwrap();
+
+        let long_title = format!("<i>{}", "x".repeat(30));
+        let typed = "<b>bold</b> & {{POSTS}}";
+        let kept_title = format!(r#"value="&lt;i&gt;{}""#, "x".repeat(30));
+        let kept_message = ">&lt;b&gt;bold&lt;/b&gt; &amp; &#123;&#123;POSTS}}</textarea>";
+        let error = r#"<div class="form-error">The title is too long (30 characters at most)."#;
+
+        // A new thread comes back on the board, the other threads still listed
+        let refused = multipart(&[("title", &long_title), ("message", typed), ("parent_id", "0"), ("autosage", "1")], None);
+        let response = call_service(&app, form_post("/upload", refused, PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains(error));
+        assert!(page.find(error) < page.find("<textarea"));
+        assert!(page.contains(&kept_title));
+        assert!(page.contains(kept_message));
+        assert!(page.contains(r#"value="1" checked>"#));
+        assert!(page.contains(" autofocus>"));
+        assert!(page.contains(">standing thread<"));
+        assert!(!page.contains("choose the file again"));
+
+        // A reply comes back on its thread
+        let refused = multipart(&[("title", &long_title), ("message", typed), ("parent_id", &thread.to_string())], None);
+        let response = call_service(&app, form_post("/upload", refused, PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains(error));
+        assert!(page.contains(&kept_title));
+        assert!(page.contains(kept_message));
+        assert!(page.contains("the op"));
+        assert!(page.contains(&format!(r#"name="parent_id" value="{}">"#, thread)));
+
+        // A file can't be put back, and the poster is told so
+        let refused = multipart(&[("title", &long_title), ("message", typed), ("parent_id", "0")], Some(("cat.png", "image/png", &png(5))));
+        let response = call_service(&app, form_post("/upload", refused, PEER).to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Your text was kept, but files can't be; please choose the file again."));
+        assert!(page.contains(kept_message));
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 1);
+    }
 }
@@ -0,0 +1,296 @@
+use actix_web::{web, HttpResponse, Result};
+use chrono::{Datelike, Duration as ChronoDuration, Timelike, Utc, Weekday};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::admin::Admin;
+use crate::jobs::{AppState, Job};
+
+// VACUUM is skipped on a schedule when anything was written this recently
+const RECENT_WRITE_SECS: i64 = 10;
+const VACUUM_WEEKDAY: Weekday = Weekday::Sun;
+
+#[derive(Serialize)]
+pub struct MaintenanceReport {
+    pub vacuumed: bool,
+    pub duration_ms: u128,
+    pub pages_before: i64,
+    pub pages_after: i64,
+    // Size of the database file on disk
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+fn file_size(db_path: &str) -> u64 {
+    std::fs::metadata(db_path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+// Writes the WAL back into the database file and empties it
+fn checkpoint(conn: &Connection) -> rusqlite::Result<()> {
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+}
+
+fn page_count(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA page_count", [], |row| row.get(0))
+}
+
+// File behind the shared connection, which maintenance opens a connection
+// of its own to; None for an in-memory database
+fn database_path(conn: &Connection) -> Option<String> {
+    conn.path().filter(|path| !path.is_empty()).map(str::to_string)
+}
+
+fn state_database_path(state: &AppState) -> Result<String, String> {
+    database_path(&state.conn.lock().unwrap()).ok_or_else(|| "the database is not a file".to_string())
+}
+
+pub fn recently_written(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM files WHERE last_reply_at > datetime('now', ?1))",
+        [format!("-{} seconds", RECENT_WRITE_SECS)],
+        |row| row.get(0),
+    )
+}
+
+// Runs on its own connection so request handlers keep the shared one; with
+// WAL enabled readers carry on while PRAGMA optimize runs.
+pub fn run_maintenance(db_path: &str, vacuum: bool) -> rusqlite::Result<MaintenanceReport> {
+    let conn = Connection::open(db_path)?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+
+    let started = Instant::now();
+    // Written back first so the file size is the database's own
+    checkpoint(&conn)?;
+    let pages_before = page_count(&conn)?;
+    let bytes_before = file_size(db_path);
+
+    conn.execute_batch("PRAGMA optimize")?;
+
+    if vacuum {
+        // Merges the search index's segments, dropping deleted posts' entries
+        conn.execute_batch("INSERT INTO posts_fts (posts_fts) VALUES ('optimize')")?;
+        conn.execute_batch("VACUUM")?;
+        // In WAL mode the file only shrinks once the log is written back
+        checkpoint(&conn)?;
+    }
+
+    let report = MaintenanceReport {
+        vacuumed: vacuum,
+        duration_ms: started.elapsed().as_millis(),
+        pages_before,
+        pages_after: page_count(&conn)?,
+        bytes_before,
+        bytes_after: file_size(db_path),
+    };
+    println!(
+        "Database maintenance finished in {} ms (vacuum: {}, reclaimed {} pages, {} -> {} bytes)",
+        report.duration_ms,
+        report.vacuumed,
+        report.pages_before - report.pages_after,
+        report.bytes_before,
+        report.bytes_after,
+    );
+    Ok(report)
+}
+
+// Time until `hour` (UTC) next comes round, optionally only on `weekday`
+pub fn until_next_run(hour: u32, weekday: Option<Weekday>) -> Duration {
+    let now = Utc::now();
+    let mut next = now
+        .with_hour(hour).and_then(|t| t.with_minute(0)).and_then(|t| t.with_second(0))
+        .unwrap_or(now);
+    while next <= now || weekday.is_some_and(|day| next.weekday() != day) {
+        next += ChronoDuration::days(1);
+    }
+    (next - now).to_std().unwrap_or(Duration::from_secs(3600))
+}
+
+// PRAGMA optimize, nightly at the configured hour
+pub struct OptimizeJob;
+
+impl Job for OptimizeJob {
+    fn name(&self) -> &'static str {
+        "optimize"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    fn first_delay(&self, state: &AppState) -> Duration {
+        until_next_run(state.config.maintenance_hour, None)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        run_maintenance(&state_database_path(state)?, false).map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+// VACUUM, weekly at the configured hour. Skipped while the board is busy
+// so the exclusive lock doesn't hold up posting.
+pub struct VacuumJob;
+
+impl Job for VacuumJob {
+    fn name(&self) -> &'static str {
+        "vacuum"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(7 * 24 * 60 * 60)
+    }
+
+    fn first_delay(&self, state: &AppState) -> Duration {
+        until_next_run(state.config.maintenance_hour, Some(VACUUM_WEEKDAY))
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        let busy = recently_written(&state.conn.lock().unwrap()).map_err(|e| e.to_string())?;
+        run_maintenance(&state_database_path(state)?, !busy).map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+// Served at /admin/maintenance and /admin/vacuum. Runs on a blocking
+// thread with its own connection; VACUUM waits for the write lock like any
+// writer, so posting stalls only for as long as it runs.
+pub async fn trigger_maintenance(_admin: Admin, conn: web::Data<Mutex<Connection>>) -> Result<HttpResponse> {
+    let Some(path) = database_path(&conn.lock().unwrap()) else {
+        return Ok(HttpResponse::InternalServerError().body("Maintenance failed: the database is not a file"));
+    };
+    let report = web::block(move || run_maintenance(&path, true)).await?;
+
+    match report {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(format!("Maintenance failed: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    use crate::config::AppConfig;
+    use crate::setup_db;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[test]
+    fn vacuum_shrinks_the_file_after_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("board.db");
+        let path = path.to_str().unwrap();
+        let conn = setup_db(Connection::open(path).unwrap()).unwrap();
+        let filler = "x".repeat(4000);
+        for _ in 0..200 {
+            conn.execute("INSERT INTO files (post_id, parent_id, title, message, last_reply_at) VALUES ('p', 0, 't', ?1, datetime('now', '-1 day'))", [&filler]).unwrap();
+        }
+        conn.execute("DELETE FROM files", []).unwrap();
+        assert!(!recently_written(&conn).unwrap());
+
+        let report = run_maintenance(path, true).unwrap();
+        assert!(report.vacuumed);
+        assert!(report.pages_after < report.pages_before, "{} -> {}", report.pages_before, report.pages_after);
+
+        let report = run_maintenance(path, false).unwrap();
+        assert!(!report.vacuumed);
+    }
+
+    #[test]
+    fn fresh_posts_count_as_recent_writes() {
+        let conn = setup_db(Connection::open_in_memory().unwrap()).unwrap();
+        conn.execute("INSERT INTO files (post_id, parent_id, title, message) VALUES ('p', 0, 't', 'm')", []).unwrap();
+        assert!(recently_written(&conn).unwrap());
+    }
+
+    #[test]
+    fn next_run_is_within_a_day_or_a_week() {
+        for hour in [0, 4, 23] {
+            assert!(until_next_run(hour, None) <= Duration::from_secs(24 * 60 * 60));
+            assert!(until_next_run(hour, Some(VACUUM_WEEKDAY)) <= Duration::from_secs(7 * 24 * 60 * 60));
+        }
+        let next = Utc::now() + ChronoDuration::from_std(until_next_run(4, Some(VACUUM_WEEKDAY))).unwrap();
+        assert_eq!(next.weekday(), VACUUM_WEEKDAY);
+    }
+
+    #[actix_web::test]
+    async fn moderators_can_vacuum_and_see_the_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("board.db");
+        let conn = setup_db(Connection::open(&path).unwrap()).unwrap();
+        let filler = "x".repeat(4000);
+        for _ in 0..100 {
+            conn.execute("INSERT INTO files (post_id, parent_id, title, message) VALUES ('p', 0, 't', ?1)", [&filler]).unwrap();
+        }
+        conn.execute("DELETE FROM files", []).unwrap();
+        let config = AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) };
+        let shared = crate::Shared::new(conn, config).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+        let vacuum = || TestRequest::post().uri("/admin/vacuum");
+
+        assert_eq!(call_service(&app, vacuum().to_request()).await.status(), 401);
+
+        let response = call_service(&app, vacuum().insert_header((header::AUTHORIZATION, "Bearer letmein")).to_request()).await;
+        assert_eq!(response.status(), 200);
+        let report: Value = read_body_json(response).await;
+        assert_eq!(report["vacuumed"], true);
+        let (before, after) = (report["bytes_before"].as_u64().unwrap(), report["bytes_after"].as_u64().unwrap());
+        assert!(after < before, "{} -> {}", before, after);
+        assert_eq!(after, std::fs::metadata(&path).unwrap().len());
+        assert!(report["pages_after"].as_i64().unwrap() < report["pages_before"].as_i64().unwrap());
+
+        // The board carries on with its own connection
+        let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn posts_wait_out_another_writer_or_are_asked_to_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("board.db");
+        let shared = crate::Shared::new(setup_db(Connection::open(&path).unwrap()).unwrap(), test_config(dir.path())).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str| form_post("/upload", multipart(&[("title", title), ("message", "m"), ("parent_id", "0")], None), "127.0.0.1:40000").to_request();
+        let count = || shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get::<_, i64>(0)).unwrap();
+
+        // A lock held briefly, as by a VACUUM, is waited out
+        let writer = Connection::open(&path).unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            writer.execute_batch("COMMIT").unwrap();
+        });
+        assert_eq!(call_service(&app, post("waited")).await.status(), 303);
+        release.join().unwrap();
+        assert_eq!(count(), 1);
+
+        // One held past the timeout gets a retryable refusal
+        shared.conn.lock().unwrap().busy_timeout(Duration::from_millis(100)).unwrap();
+        let writer = Connection::open(&path).unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let response = call_service(&app, post("refused")).await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Please try again in a moment."));
+        writer.execute_batch("COMMIT").unwrap();
+        assert_eq!(count(), 1);
+
+        assert_eq!(call_service(&app, post("later")).await.status(), 303);
+        assert_eq!(count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn in_memory_databases_have_nothing_to_vacuum() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let request = TestRequest::post().uri("/admin/maintenance").insert_header((header::AUTHORIZATION, "Bearer letmein"));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(read_body(response).await, "Maintenance failed: the database is not a file");
+    }
+}
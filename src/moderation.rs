@@ -0,0 +1,755 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::admin::{admin, same_site, Admin};
+use crate::bans::ip_hash;
+use crate::board::{board_url, find_board};
+use crate::feed::FeedCache;
+use crate::generate_post_id;
+use crate::quota::{delete_files, files_of};
+use crate::site::site;
+use crate::storage::Storage;
+
+// Records a moderation action in the mod_actions table, and as a line of
+// JSON on stdout for log collectors. `moderator` is None for actions the
+// board takes by itself; `target` is the post or thread acted on.
+pub fn log_mod_action(conn: &Connection, moderator: Option<&str>, action: &str, target: Option<i32>, details: &str) -> rusqlite::Result<()> {
+    let created_at: String = conn.query_row(
+        "INSERT INTO mod_actions (action, target_id, moderator, details, created_at) VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP) RETURNING created_at",
+        params![action, target, moderator, details],
+        |row| row.get(0),
+    )?;
+    println!("{}", json!({
+        "event": "mod_action",
+        "action": action,
+        "target_id": target,
+        "moderator": moderator,
+        "details": details,
+        "created_at": created_at,
+    }));
+    Ok(())
+}
+
+// Entries per page of /admin/audit
+const AUDIT_PAGE_SIZE: i64 = 50;
+
+#[derive(Serialize)]
+struct AuditEntry {
+    id: i64,
+    action: String,
+    target_id: Option<i32>,
+    moderator: Option<String>,
+    details: String,
+    created_at: String,
+}
+
+// The moderation log as JSON, newest first, `page` (from 1) at a time
+pub async fn audit_log(_admin: Admin, conn: web::Data<Mutex<Connection>>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let page: i64 = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+    let conn = conn.lock().unwrap();
+    let total: i64 = conn.query_row("SELECT COUNT(*) FROM mod_actions", [], |row| row.get(0)).map_err(ErrorInternalServerError)?;
+    let mut stmt = conn.prepare(
+        "SELECT id, action, target_id, moderator, details, created_at FROM mod_actions ORDER BY id DESC LIMIT ?1 OFFSET ?2"
+    ).map_err(ErrorInternalServerError)?;
+    let entries: Vec<AuditEntry> = stmt.query_map(params![AUDIT_PAGE_SIZE, (page - 1) * AUDIT_PAGE_SIZE], |row| {
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            action: row.get(1)?,
+            target_id: row.get(2)?,
+            moderator: row.get(3)?,
+            details: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }).and_then(|rows| rows.collect::<rusqlite::Result<_>>()).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "page": page,
+        "pages": (total + AUDIT_PAGE_SIZE - 1) / AUDIT_PAGE_SIZE,
+        "total": total,
+        "actions": entries,
+    })))
+}
+
+fn is_thread(conn: &Connection, id: i32) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT 1 FROM files WHERE id = ?1 AND parent_id = 0", params![id], |_| Ok(())).optional().map(|found| found.is_some())
+}
+
+// Resets a thread's cached reply_count from its actual visible replies
+pub fn recount_replies(conn: &Connection, thread_id: i32) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE files SET reply_count = (SELECT COUNT(*) FROM files AS replies WHERE replies.parent_id = ?1 AND replies.hidden = 0) WHERE id = ?1",
+        params![thread_id],
+    )?;
+    Ok(())
+}
+
+// Folds thread `src` into `dst`: src's OP and replies all become replies of
+// dst. Thread pages order replies by time, so reply numbers stay in posting
+// order, and /post/<src> keeps working since it now resolves to a reply of
+// dst.
+pub async fn merge_threads(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, path: web::Path<(i32, i32)>) -> Result<HttpResponse> {
+    let (src, dst) = path.into_inner();
+    if src == dst {
+        return Ok(HttpResponse::BadRequest().body("Cannot merge a thread into itself."));
+    }
+
+    let conn = conn.lock().unwrap();
+    if !is_thread(&conn, src).map_err(ErrorInternalServerError)? || !is_thread(&conn, dst).map_err(ErrorInternalServerError)? {
+        return Ok(HttpResponse::BadRequest().body("Both ids must be existing threads."));
+    }
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    let moved = tx.execute(
+        "UPDATE files SET parent_id = ?2, archived = 0, board_slug = (SELECT board_slug FROM files WHERE id = ?2) WHERE id = ?1 OR parent_id = ?1",
+        params![src, dst],
+    ).map_err(ErrorInternalServerError)?;
+    tx.execute(
+        "UPDATE files SET last_reply_at = (SELECT MAX(last_reply_at) FROM files WHERE id = ?1 OR parent_id = ?1) WHERE id = ?1",
+        params![dst],
+    ).map_err(ErrorInternalServerError)?;
+    recount_replies(&tx, dst).map_err(ErrorInternalServerError)?;
+    tx.execute("UPDATE files SET reply_count = 0 WHERE id = ?1", params![src]).map_err(ErrorInternalServerError)?;
+    log_mod_action(&tx, Some(&admin.name), "merge", Some(src), &format!("thread {} merged into {} ({} posts)", src, dst, moved)).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    feed_cache.clear();
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}", dst))).finish())
+}
+
+// Moves a single reply into another thread. Reply numbers follow posting
+// time, so the reply takes its place in the destination's ordering and the
+// source thread's later replies close the gap. The destination isn't bumped.
+pub async fn move_reply(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, path: web::Path<(i32, i32)>) -> Result<HttpResponse> {
+    let (reply, dst) = path.into_inner();
+
+    let conn = conn.lock().unwrap();
+    let src: Option<i32> = conn.query_row(
+        "SELECT parent_id FROM files WHERE id = ?1 AND parent_id != 0",
+        params![reply],
+        |row| row.get(0),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some(src) = src else {
+        return Ok(HttpResponse::BadRequest().body("The first id must be an existing reply."));
+    };
+    if !is_thread(&conn, dst).map_err(ErrorInternalServerError)? {
+        return Ok(HttpResponse::BadRequest().body("The destination must be an existing thread."));
+    }
+    if src == dst {
+        return Ok(HttpResponse::BadRequest().body("That reply is already in this thread."));
+    }
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    tx.execute("UPDATE files SET parent_id = ?2, board_slug = (SELECT board_slug FROM files WHERE id = ?2) WHERE id = ?1", params![reply, dst]).map_err(ErrorInternalServerError)?;
+    recount_replies(&tx, src).map_err(ErrorInternalServerError)?;
+    recount_replies(&tx, dst).map_err(ErrorInternalServerError)?;
+    log_mod_action(&tx, Some(&admin.name), "move-reply", Some(reply), &format!("reply {} moved from thread {} to {}", reply, src, dst)).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    feed_cache.clear();
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}#p{}", dst, reply))).finish())
+}
+
+// A random display id no post has yet
+fn unused_post_id(conn: &Connection) -> rusqlite::Result<String> {
+    loop {
+        let candidate = generate_post_id();
+        let taken = conn.query_row("SELECT 1 FROM files WHERE post_id = ?1", params![candidate], |_| Ok(())).optional()?.is_some();
+        if !taken {
+            return Ok(candidate);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MoveForm {
+    board: String,
+    // Leave a thread on the old board pointing to the new place
+    #[serde(default)]
+    tombstone: bool,
+}
+
+// Moves a thread, OP and replies, to another board. Post numbers, reply
+// order and >>N links don't depend on the board, so only board_slug
+// changes. Files stay where they are.
+pub async fn move_thread(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, path: web::Path<i32>, form: web::Form<MoveForm>) -> Result<HttpResponse> {
+    let thread_id = path.into_inner();
+    let conn = conn.lock().unwrap();
+
+    let from: Option<String> = conn.query_row(
+        "SELECT board_slug FROM files WHERE id = ?1 AND parent_id = 0",
+        params![thread_id],
+        |row| row.get(0),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some(from) = from else {
+        return Ok(HttpResponse::NotFound().body("Thread not found."));
+    };
+    let Some(to) = find_board(&conn, form.board.trim()) else {
+        return Ok(HttpResponse::BadRequest().body("No board with that name."));
+    };
+    if to.slug == from {
+        return Ok(HttpResponse::BadRequest().body("The thread is already on that board."));
+    }
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    let moved = tx.execute("UPDATE files SET board_slug = ?2 WHERE id = ?1 OR parent_id = ?1", params![thread_id, to.slug])
+        .map_err(ErrorInternalServerError)?;
+    let mut details = format!("thread {} moved from /{}/ to /{}/ ({} posts)", thread_id, from, to.slug, moved);
+    if form.tombstone {
+        tx.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, by_admin, board_slug, created_at) VALUES (?1, 0, ?2, ?3, 1, ?4, CURRENT_TIMESTAMP)",
+            params![
+                unused_post_id(&tx).map_err(ErrorInternalServerError)?,
+                "Thread moved",
+                format!(r#"Thread moved to <a href="/post/{}">/{}/</a>"#, thread_id, to.slug),
+                from,
+            ],
+        ).map_err(ErrorInternalServerError)?;
+        details.push_str(&format!("; notice left as thread {}", tx.last_insert_rowid()));
+    }
+    log_mod_action(&tx, Some(&admin.name), "move-thread", Some(thread_id), &details).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    feed_cache.clear();
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}", thread_id))).finish())
+}
+
+// Gives a thread a fresh display id, for when the random one happens to
+// spell something unfortunate. Upload names don't use it, so nothing else
+// needs renaming.
+pub async fn regenerate_display_id(admin: Admin, conn: web::Data<Mutex<Connection>>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let thread_id = path.into_inner();
+    let conn = conn.lock().unwrap();
+    let old_id: Option<String> = conn.query_row(
+        "SELECT post_id FROM files WHERE id = ?1 AND parent_id = 0",
+        params![thread_id],
+        |row| row.get(0),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some(old_id) = old_id else {
+        return Ok(HttpResponse::NotFound().body("Thread not found."));
+    };
+
+    let new_id = unused_post_id(&conn).map_err(ErrorInternalServerError)?;
+    conn.execute("UPDATE files SET post_id = ?2 WHERE id = ?1", params![thread_id, new_id]).map_err(ErrorInternalServerError)?;
+    log_mod_action(&conn, Some(&admin.name), "regen-id", Some(thread_id), &format!("thread {}: {} replaced by {}", thread_id, old_id, new_id)).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}", thread_id))).finish())
+}
+
+// Makes a hidden post public, such as one wrongly taken for spam. Posts of
+// a poster still under a shadow ban stay hidden until they are unbanned.
+pub async fn unhide_post(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let post_id = path.into_inner();
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    let parent_id: Option<i32> = tx.query_row(
+        "UPDATE files SET hidden = 0 WHERE id = ?1 AND hidden = 1 RETURNING parent_id",
+        params![post_id],
+        |row| row.get(0),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some(parent_id) = parent_id else {
+        return Ok(HttpResponse::NotFound().body("No hidden post with that number."));
+    };
+    if parent_id != 0 {
+        recount_replies(&tx, parent_id).map_err(ErrorInternalServerError)?;
+    }
+    log_mod_action(&tx, Some(&admin.name), "unhide", Some(post_id), &format!("post {}", post_id)).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    feed_cache.clear();
+
+    let thread_id = if parent_id == 0 { post_id } else { parent_id };
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}#p{}", thread_id, post_id))).finish())
+}
+
+// Deletes a post along with its files. An OP takes its whole thread with
+// it; a visible reply comes off its thread's reply_count in the same
+// transaction.
+pub async fn delete_post(admin: Admin, conn: web::Data<Mutex<Connection>>, feed_cache: web::Data<FeedCache>, storage: web::Data<dyn Storage>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let post_id = path.into_inner();
+
+    let tx = conn.unchecked_transaction().map_err(ErrorInternalServerError)?;
+    let post: Option<(i32, bool, String)> = tx.query_row(
+        "SELECT parent_id, hidden, board_slug FROM files WHERE id = ?1",
+        params![post_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some((parent_id, hidden, board)) = post else {
+        return Ok(HttpResponse::NotFound().body("No post with that number."));
+    };
+
+    let rows = if parent_id == 0 { "id = ?1 OR parent_id = ?1" } else { "id = ?1" };
+    let paths = files_of(&tx, &format!("SELECT file_path, thumbnail_path FROM files WHERE {}", rows), params![post_id])
+        .map_err(ErrorInternalServerError)?;
+    let deleted = tx.execute(&format!("DELETE FROM files WHERE {}", rows), params![post_id]).map_err(ErrorInternalServerError)?;
+    if parent_id != 0 && !hidden {
+        tx.execute("UPDATE files SET reply_count = MAX(reply_count - 1, 0) WHERE id = ?1", params![parent_id])
+            .map_err(ErrorInternalServerError)?;
+    }
+    let details = if parent_id == 0 {
+        format!("thread {} ({} posts)", post_id, deleted)
+    } else {
+        format!("reply {} in thread {}", post_id, parent_id)
+    };
+    log_mod_action(&tx, Some(&admin.name), "delete", Some(post_id), &details).map_err(ErrorInternalServerError)?;
+    tx.commit().map_err(ErrorInternalServerError)?;
+    delete_files(&conn, storage.get_ref(), &paths);
+    feed_cache.clear();
+
+    let location = if parent_id == 0 { board_url(&board) } else { format!("/post/{}", parent_id) };
+    Ok(HttpResponse::SeeOther().append_header(("Location", location)).finish())
+}
+
+// Flips a thread's autosage flag. Allowed for moderators and for the
+// thread's author, recognised by the IP hash they posted from.
+pub async fn toggle_autosage(req: HttpRequest, conn: web::Data<Mutex<Connection>>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let conn = conn.lock().unwrap();
+    let thread_id = path.into_inner();
+
+    let op_hash: Option<Option<String>> = conn.query_row(
+        "SELECT ip_hash FROM files WHERE id = ?1 AND parent_id = 0",
+        params![thread_id],
+        |row| row.get(0),
+    ).optional().map_err(ErrorInternalServerError)?;
+    let Some(op_hash) = op_hash else {
+        return Ok(HttpResponse::NotFound().body("Thread not found."));
+    };
+
+    // The author is only known by IP, which another site's form would carry too
+    if !same_site(&req) {
+        return Ok(HttpResponse::Forbidden().body("This can only be changed from the thread's page."));
+    }
+    let moderator = admin(&req);
+    if moderator.is_none() && site(&req).read_only() {
+        return Ok(HttpResponse::ServiceUnavailable().body("The board is in maintenance; posting is temporarily disabled."));
+    }
+    if moderator.is_none() && op_hash != Some(ip_hash(&conn, &req).map_err(ErrorInternalServerError)?) {
+        return Ok(HttpResponse::Forbidden().body("Only the thread's author or a moderator can change this."));
+    }
+
+    conn.execute("UPDATE files SET autosage = 1 - autosage WHERE id = ?1", params![thread_id]).map_err(ErrorInternalServerError)?;
+    if let Some(moderator) = moderator {
+        log_mod_action(&conn, Some(&moderator.name), "autosage", Some(thread_id), &format!("toggled on thread {}", thread_id)).map_err(ErrorInternalServerError)?;
+    }
+
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/post/{}", thread_id))).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use rusqlite::{params, Connection};
+
+    use crate::config::{AppConfig, BoardConfig};
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    fn config(dir: &std::path::Path) -> AppConfig {
+        AppConfig {
+            admin_password: Some("letmein".to_string()),
+            boards: vec![
+                BoardConfig { slug: "main".to_string(), name: "Main".to_string() },
+                BoardConfig { slug: "tech".to_string(), name: "Tech".to_string() },
+            ],
+            ..test_config(dir)
+        }
+    }
+
+    fn as_admin(request: TestRequest) -> TestRequest {
+        request.peer_addr(PEER.parse().unwrap()).insert_header((header::AUTHORIZATION, "Bearer letmein"))
+    }
+
+    fn last_id(shared: &crate::Shared) -> i32 {
+        shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap()
+    }
+
+    // A post made `minutes` ago, straight into the database
+    fn insert_post(conn: &Connection, parent_id: i32, title: &str, minutes: i32) -> i32 {
+        conn.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, created_at, last_reply_at) VALUES (?1, ?2, ?1, 'body', datetime('now', ?3), datetime('now', ?3))",
+            params![title, parent_id, format!("-{} minutes", minutes)],
+        ).unwrap();
+        conn.last_insert_rowid() as i32
+    }
+
+    #[actix_web::test]
+    async fn merged_threads_number_replies_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let (src, dst, dst_reply) = {
+            let conn = shared.conn.lock().unwrap();
+            let dst = insert_post(&conn, 0, "dst-op", 300);
+            let src = insert_post(&conn, 0, "src-op", 150);
+            let dst_reply = insert_post(&conn, dst, "dst-reply", 120);
+            insert_post(&conn, src, "src-reply", 30);
+            (src, dst, dst_reply)
+        };
+        let app = init_service(crate::app(&shared)).await;
+
+        assert_eq!(call_service(&app, TestRequest::post().uri(&format!("/admin/merge/{}/{}", src, dst)).to_request()).await.status(), 401);
+        for (uri, status) in [
+            (format!("/admin/merge/{}/{}", src, src), 400),
+            (format!("/admin/merge/{}/{}", src, dst_reply), 400),
+            (format!("/admin/merge/{}/{}", src, dst), 303),
+        ] {
+            assert_eq!(call_service(&app, as_admin(TestRequest::post().uri(&uri)).to_request()).await.status(), status, "{}", uri);
+        }
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", dst)).to_request()).await.to_vec()).unwrap();
+        // Each reply number is followed by its post, before the next number
+        for (n, title) in ["src-op", "dst-reply", "src-reply"].iter().enumerate() {
+            let start = page.find(&format!("id=\"r{}\"", n + 1)).unwrap();
+            let end = page.find(&format!("id=\"r{}\"", n + 2)).unwrap_or(page.len());
+            assert!(page[start..end].contains(title), "r{} should be {}", n + 1, title);
+        }
+        assert!(!page.contains("id=\"r4\""));
+
+        let conn = shared.conn.lock().unwrap();
+        let threads: i32 = conn.query_row("SELECT COUNT(*) FROM files WHERE parent_id = 0", [], |row| row.get(0)).unwrap();
+        assert_eq!(threads, 1);
+        let reply_count: i32 = conn.query_row("SELECT reply_count FROM files WHERE id = ?1", [dst], |row| row.get(0)).unwrap();
+        assert_eq!(reply_count, 3);
+        let logged: String = conn.query_row("SELECT details FROM mod_actions WHERE action = 'merge'", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, format!("thread {} merged into {} (2 posts)", src, dst));
+    }
+
+    #[actix_web::test]
+    async fn autosage_threads_sink_until_their_author_or_a_moderator_lifts_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { max_replies_per_thread: 2, ..config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let author = "10.0.0.7:40000";
+
+        let quiet = multipart(&[("title", "slow-thread"), ("message", "op"), ("parent_id", "0"), ("autosage", "on")], None);
+        assert_eq!(call_service(&app, form_post("/upload", quiet, author).to_request()).await.status(), 303);
+        let slow = last_id(&shared);
+        let busy = multipart(&[("title", "busy-thread"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", busy, PEER).to_request()).await.status(), 303);
+        let busy = last_id(&shared);
+        {
+            let conn = shared.conn.lock().unwrap();
+            conn.execute("UPDATE files SET last_reply_at = datetime('now', '-1 days') WHERE id = ?1", [slow]).unwrap();
+            conn.execute("UPDATE files SET last_reply_at = datetime('now', '-2 days') WHERE id = ?1", [busy]).unwrap();
+        }
+        let bumped_at = |thread: i32| -> String { shared.conn.lock().unwrap().query_row("SELECT last_reply_at FROM files WHERE id = ?1", [thread], |row| row.get(0)).unwrap() };
+        let reply = |thread: i32| multipart(&[("title", "re"), ("message", "reply"), ("parent_id", &thread.to_string())], None);
+
+        let before = bumped_at(slow);
+        assert_eq!(call_service(&app, form_post("/upload", reply(slow), PEER).to_request()).await.status(), 303);
+        assert_eq!(bumped_at(slow), before);
+        assert_eq!(call_service(&app, form_post("/upload", reply(busy), PEER).to_request()).await.status(), 303);
+        assert!(bumped_at(busy) > before);
+
+        let index = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/main/").to_request()).await.to_vec()).unwrap();
+        assert!(index.find("busy-thread") < index.find("slow-thread"));
+        assert!(index.contains(r#"slow-thread <span class="autosage-icon" title="This thread does not bump">"#));
+
+        // Only the author and moderators may change it
+        let toggle = format!("/post/{}/autosage", slow);
+        let by = |peer: &str| TestRequest::post().uri(&toggle).peer_addr(peer.parse().unwrap());
+        let autosage = || -> bool { shared.conn.lock().unwrap().query_row("SELECT autosage FROM files WHERE id = ?1", [slow], |row| row.get(0)).unwrap() };
+        assert_eq!(call_service(&app, by(PEER).to_request()).await.status(), 403);
+        assert!(autosage());
+        assert_eq!(call_service(&app, by(author).to_request()).await.status(), 303);
+        assert!(!autosage());
+        assert_eq!(call_service(&app, as_admin(TestRequest::post().uri(&toggle)).to_request()).await.status(), 303);
+        assert!(autosage());
+
+        // Replies that don't bump still count towards the reply cap
+        assert_eq!(call_service(&app, form_post("/upload", reply(slow), PEER).to_request()).await.status(), 303);
+        assert_eq!(bumped_at(slow), before);
+        assert_eq!(call_service(&app, form_post("/upload", reply(slow), PEER).to_request()).await.status(), 403);
+        let index = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/main/").to_request()).await.to_vec()).unwrap();
+        assert!(index.contains(r#"&#9875;</span> <span class="thread-badge">Full</span>"#));
+    }
+
+    #[actix_web::test]
+    async fn a_moved_reply_is_numbered_last_in_its_new_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let (src, dst, moved) = {
+            let conn = shared.conn.lock().unwrap();
+            let src = insert_post(&conn, 0, "src-op", 300);
+            let dst = insert_post(&conn, 0, "dst-op", 290);
+            insert_post(&conn, dst, "dst-first", 200);
+            insert_post(&conn, dst, "dst-second", 100);
+            insert_post(&conn, src, "src-keeps", 60);
+            let moved = insert_post(&conn, src, "off-topic", 10);
+            (src, dst, moved)
+        };
+        let app = init_service(crate::app(&shared)).await;
+
+        for (uri, status) in [
+            (format!("/admin/move-reply/{}/{}", moved, src), 400),
+            (format!("/admin/move-reply/{}/{}", src, dst), 400),
+            (format!("/admin/move-reply/{}/999", moved), 400),
+            (format!("/admin/move-reply/{}/{}", moved, dst), 303),
+        ] {
+            assert_eq!(call_service(&app, as_admin(TestRequest::post().uri(&uri)).to_request()).await.status(), status, "{}", uri);
+        }
+
+        let page = |thread: i32| TestRequest::get().uri(&format!("/post/{}", thread)).to_request();
+        let dst_page = String::from_utf8(call_and_read_body(&app, page(dst)).await.to_vec()).unwrap();
+        let third = dst_page.find("id=\"r3\"").unwrap();
+        assert!(dst_page[third..].contains("off-topic"));
+        assert!(!dst_page.contains("id=\"r4\""));
+        let src_page = String::from_utf8(call_and_read_body(&app, page(src)).await.to_vec()).unwrap();
+        assert!(!src_page.contains("off-topic"));
+        assert!(!src_page.contains("id=\"r2\""));
+
+        let conn = shared.conn.lock().unwrap();
+        let counts: Vec<i32> = [src, dst].iter()
+            .map(|thread| conn.query_row("SELECT reply_count FROM files WHERE id = ?1", [thread], |row| row.get(0)).unwrap())
+            .collect();
+        assert_eq!(counts, [1, 3]);
+    }
+
+    #[actix_web::test]
+    async fn moved_threads_keep_reply_numbers_and_quotes() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "wrong board"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id = last_id(&shared);
+        let mut replies = Vec::new();
+        for n in 1..=3 {
+            let message = match replies.first() {
+                Some(first) => format!(">>{} agreed", first),
+                None => "first".to_string(),
+            };
+            let reply = multipart(&[("title", "re"), ("message", &message), ("parent_id", &thread_id.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303, "reply {}", n);
+            replies.push(last_id(&shared));
+        }
+        let thread = format!("/post/{}", thread_id);
+        let before = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&thread).to_request()).await.to_vec()).unwrap();
+
+        for (board, status) in [("main", 400), ("nowhere", 400), ("tech", 303)] {
+            let request = as_admin(TestRequest::post().uri(&format!("/admin/move/{}", thread_id)))
+                .set_form([("board", board), ("tombstone", "true")]);
+            assert_eq!(call_service(&app, request.to_request()).await.status(), status, "{}", board);
+        }
+
+        let after = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&thread).to_request()).await.to_vec()).unwrap();
+        for (n, reply) in replies.iter().enumerate() {
+            let number = format!(r##"id="r{}"><a class="quote-link" href="#reply-form" data-quote="{}">"##, n + 1, n + 1);
+            assert!(before.contains(&number) && after.contains(&number), "reply {}", n + 1);
+            assert!(after.contains(&format!("id=\"p{}\"", reply)));
+        }
+        let backlink = format!(r#"<a class="quote-ref" href="/post/{}" data-post="{}">&gt;&gt;{}</a>"#, replies[0], replies[0], replies[0]);
+        assert_eq!(after.matches(&backlink).count(), 2);
+
+        let conn = shared.conn.lock().unwrap();
+        let on_tech: i32 = conn.query_row("SELECT COUNT(*) FROM files WHERE board_slug = 'tech'", [], |row| row.get(0)).unwrap();
+        assert_eq!(on_tech, 4);
+        let notice: String = conn.query_row("SELECT message FROM files WHERE board_slug = 'main' AND parent_id = 0", [], |row| row.get(0)).unwrap();
+        assert!(notice.contains(&format!("href=\"{}\"", thread)));
+        let logged: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'move-thread' AND target_id = ?1", [thread_id], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 1);
+    }
+
+    #[actix_web::test]
+    async fn moves_rebuild_the_cached_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        for (title, parent_id) in [("first", "0"), ("re", "1"), ("second", "0")] {
+            let post = multipart(&[("title", title), ("message", "m"), ("parent_id", parent_id)], None);
+            assert_eq!(call_service(&app, form_post("/upload", post, PEER).to_request()).await.status(), 303);
+        }
+        let (first, reply, second) = (1, 2, 3);
+        let feed = || async { String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/feed.xml").to_request()).await.to_vec()).unwrap() };
+        let rename = |title: &str| shared.conn.lock().unwrap().execute("UPDATE files SET title = ?1 WHERE id = ?2", params![title, first]).unwrap();
+
+        assert!(feed().await.contains("<title>first</title>"));
+        // Left alone, the cached feed keeps the old title
+        rename("renamed");
+        assert!(feed().await.contains("<title>first</title>"));
+
+        let request = as_admin(TestRequest::post().uri(&format!("/admin/move-reply/{}/{}", reply, second)));
+        assert_eq!(call_service(&app, request.to_request()).await.status(), 303);
+        assert!(feed().await.contains("<title>renamed</title>"));
+
+        rename("renamed again");
+        let request = as_admin(TestRequest::post().uri(&format!("/admin/move/{}", first))).set_form([("board", "tech")]);
+        assert_eq!(call_service(&app, request.to_request()).await.status(), 303);
+        assert!(feed().await.contains("<title>renamed again</title>"));
+    }
+
+    #[actix_web::test]
+    async fn regenerated_display_ids_are_new_and_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let (thread, reply) = {
+            let conn = shared.conn.lock().unwrap();
+            let thread = insert_post(&conn, 0, "ab12CD", 10);
+            (thread, insert_post(&conn, thread, "ef34GH", 5))
+        };
+        let app = init_service(crate::app(&shared)).await;
+        let display_id = || -> String { shared.conn.lock().unwrap().query_row("SELECT post_id FROM files WHERE id = ?1", [thread], |row| row.get(0)).unwrap() };
+        let regen = |id: i32| TestRequest::post().uri(&format!("/admin/regen-id/{}", id));
+
+        let response = call_service(&app, regen(thread).to_request()).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(display_id(), "ab12CD");
+
+        let mut seen = vec![display_id()];
+        for _ in 0..20 {
+            let response = call_service(&app, as_admin(regen(thread)).to_request()).await;
+            assert_eq!(response.status(), 303);
+            assert_eq!(response.headers().get(header::LOCATION).unwrap(), &format!("/post/{}", thread));
+            let new_id = display_id();
+            assert_eq!(new_id.len(), 6);
+            assert!(!seen.contains(&new_id), "{} came up twice", new_id);
+            seen.push(new_id);
+        }
+
+        // Replies and missing threads have no thread id to change
+        for id in [reply, 999] {
+            assert_eq!(call_service(&app, as_admin(regen(id)).to_request()).await.status(), 404);
+        }
+
+        let conn = shared.conn.lock().unwrap();
+        let (ids, distinct): (i32, i32) = conn.query_row("SELECT COUNT(*), COUNT(DISTINCT post_id) FROM files", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(ids, distinct);
+        let logged: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'regen-id' AND target_id = ?1", [thread], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 20);
+    }
+
+    #[actix_web::test]
+    async fn bans_are_audited_with_who_and_what() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "audited"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id = last_id(&shared);
+        let reply = multipart(&[("title", "re"), ("message", "rude"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        let reply_id = last_id(&shared);
+
+        // Refused actions leave nothing in the log
+        let anonymous = TestRequest::post().uri(&format!("/admin/ban/{}", reply_id)).peer_addr(PEER.parse().unwrap()).set_form([("reason", "spam")]);
+        assert_eq!(call_service(&app, anonymous.to_request()).await.status(), 401);
+        let missing = as_admin(TestRequest::post().uri("/admin/ban/9999")).set_form([("reason", "spam")]);
+        assert_eq!(call_service(&app, missing.to_request()).await.status(), 404);
+        let logged: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM mod_actions", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 0);
+
+        let ban = TestRequest::post()
+            .uri(&format!("/admin/ban/{}", reply_id))
+            .peer_addr(PEER.parse().unwrap())
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", STANDARD.encode("alice:letmein"))))
+            .set_form([("reason", "spam")]);
+        assert_eq!(call_service(&app, ban.to_request()).await.status(), 200);
+        let unban = as_admin(TestRequest::post().uri(&format!("/admin/unban/{}", thread_id)));
+        assert_eq!(call_service(&app, unban.to_request()).await.status(), 200);
+
+        {
+            let conn = shared.conn.lock().unwrap();
+            let rows: Vec<String> = conn
+                .prepare("SELECT action || ' ' || target_id || ' ' || moderator || ': ' || details FROM mod_actions ORDER BY id").unwrap()
+                .query_map([], |row| row.get(0)).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            assert_eq!(rows, [
+                format!("ban {} alice: poster of post {}: spam", reply_id, reply_id),
+                format!("unban {} admin: poster of post {}", thread_id, thread_id),
+            ]);
+            let undated: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE created_at IS NULL OR created_at = ''", [], |row| row.get(0)).unwrap();
+            assert_eq!(undated, 0);
+        }
+
+        // The audit listing shows them newest first, to admins only
+        assert_eq!(call_service(&app, TestRequest::get().uri("/admin/audit").to_request()).await.status(), 401);
+        let body = call_and_read_body(&app, as_admin(TestRequest::get().uri("/admin/audit")).to_request()).await;
+        let audit: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(audit["total"], 2);
+        assert_eq!(audit["pages"], 1);
+        assert_eq!(audit["actions"][0]["target_id"], thread_id);
+        assert_eq!(audit["actions"][1]["target_id"], reply_id);
+        assert_eq!(audit["actions"][1]["moderator"], "alice");
+        let body = call_and_read_body(&app, as_admin(TestRequest::get().uri("/admin/audit?page=2")).to_request()).await;
+        let audit: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(audit["actions"].as_array().unwrap().len(), 0);
+    }
+
+    #[actix_web::test]
+    async fn deletes_are_audited_with_who_and_what() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "audited"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id = last_id(&shared);
+        let reply = multipart(&[("title", "re"), ("message", "doomed"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+        let reply_id = last_id(&shared);
+
+        // Refused deletes leave nothing in the log
+        let anonymous = TestRequest::post().uri(&format!("/admin/delete/{}", reply_id)).peer_addr(PEER.parse().unwrap());
+        assert_eq!(call_service(&app, anonymous.to_request()).await.status(), 401);
+        assert_eq!(call_service(&app, as_admin(TestRequest::post().uri("/admin/delete/9999")).to_request()).await.status(), 404);
+        let logged: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM mod_actions", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 0);
+
+        let delete = TestRequest::post()
+            .uri(&format!("/admin/delete/{}", reply_id))
+            .peer_addr(PEER.parse().unwrap())
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", STANDARD.encode("alice:letmein"))));
+        assert_eq!(call_service(&app, delete.to_request()).await.status(), 303);
+        let delete = as_admin(TestRequest::post().uri(&format!("/admin/delete/{}", thread_id)));
+        assert_eq!(call_service(&app, delete.to_request()).await.status(), 303);
+
+        let conn = shared.conn.lock().unwrap();
+        let rows: Vec<String> = conn
+            .prepare("SELECT action || ' ' || target_id || ' ' || moderator || ': ' || details FROM mod_actions ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(rows, [
+            format!("delete {} alice: reply {} in thread {}", reply_id, reply_id, thread_id),
+            format!("delete {} admin: thread {} (1 posts)", thread_id, thread_id),
+        ]);
+    }
+
+    #[actix_web::test]
+    async fn deleting_replies_keeps_the_reply_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let op = multipart(&[("title", "counted"), ("message", "op"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, PEER).to_request()).await.status(), 303);
+        let thread_id = last_id(&shared);
+        let mut replies = Vec::new();
+        for n in 0..12 {
+            let reply = multipart(&[("title", "re"), ("message", &format!("reply {}", n)), ("parent_id", &thread_id.to_string())], None);
+            assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 303);
+            replies.push(last_id(&shared));
+        }
+        for reply in [replies[0], replies[5], replies[11]] {
+            let delete = as_admin(TestRequest::post().uri(&format!("/admin/delete/{}", reply)));
+            assert_eq!(call_service(&app, delete.to_request()).await.status(), 303);
+        }
+
+        let conn = shared.conn.lock().unwrap();
+        let (cached, actual): (i32, i32) = conn.query_row(
+            "SELECT reply_count, (SELECT COUNT(*) FROM files WHERE parent_id = ?1) FROM files WHERE id = ?1",
+            [thread_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!((cached, actual), (9, 9));
+    }
+}
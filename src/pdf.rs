@@ -0,0 +1,74 @@
+// Reading the page count stops after this many page objects
+const MAX_PAGES: i64 = 100_000;
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b'\x0c' | b'\0')
+}
+
+// Number of pages in a PDF, counted from its /Type /Page objects. PDFs
+// that keep their objects in compressed streams show none, and get None
+// rather than a wrong count.
+pub fn page_count(data: &[u8]) -> Option<i64> {
+    const TYPE: &[u8] = b"/Type";
+    const PAGE: &[u8] = b"/Page";
+    let mut pages = 0;
+    let mut rest = data;
+    while let Some(start) = rest.windows(TYPE.len()).position(|window| window == TYPE) {
+        rest = &rest[start + TYPE.len()..];
+        let value = &rest[rest.iter().take_while(|byte| is_whitespace(**byte)).count()..];
+        // "/Pages" is the page tree, not a page
+        let is_page = value.starts_with(PAGE) && !value.get(PAGE.len()).is_some_and(u8::is_ascii_alphanumeric);
+        if is_page {
+            pages += 1;
+            if pages >= MAX_PAGES {
+                break;
+            }
+        }
+    }
+    (pages > 0).then_some(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const TWO_PAGES: &[u8] = b"%PDF-1.4\n1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj\n2 0 obj << /Type /Pages /Kids [3 0 R 4 0 R] /Count 2 >> endobj\n3 0 obj << /Type /Page >> endobj\n4 0 obj <</Type/Page/Parent 2 0 R>> endobj\n%%EOF\n";
+
+    #[test]
+    fn pages_are_counted_but_not_the_page_tree() {
+        assert_eq!(page_count(TWO_PAGES), Some(2));
+        assert_eq!(page_count(b"%PDF-1.5\n<< /Type /ObjStm /N 4 >> stream ... %%EOF"), None);
+        assert_eq!(page_count(b""), None);
+    }
+
+    #[actix_web::test]
+    async fn pdfs_show_as_a_linked_icon_and_open_inline() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let body = multipart(&[("title", "manual"), ("message", "read it"), ("parent_id", "0")], Some(("Manual.pdf", "application/pdf", TWO_PAGES)));
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        let (file_path, thumbnail, width): (String, Option<String>, Option<i64>) = shared.conn.lock().unwrap()
+            .query_row("SELECT file_path, thumbnail_path, width FROM files", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        // Not treated as an image on the way in
+        assert_eq!((thumbnail, width), (None, None));
+
+        let url = crate::upload_url(&shared.config, &file_path);
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/post/1").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(&format!(r#"<a class="document-icon" href="{}"><img src="/static/pdf-icon.svg" alt="PDF""#, url)));
+        assert!(page.contains(&format!(r#"<a href="{}">Manual.pdf</a> <span class="file-details">(1 KB, 2 pages)</span>"#, url)));
+        assert!(!page.contains(&format!(r#"<img src="{}""#, url)));
+
+        let response = call_service(&app, TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/pdf");
+        assert_eq!(response.headers().get(header::CONTENT_DISPOSITION).unwrap(), "inline");
+        assert_eq!(response.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+    }
+}
@@ -0,0 +1,52 @@
+use sha2::{Digest, Sha256};
+
+use crate::admin::constant_time_eq;
+use crate::config::AppConfig;
+use crate::locale::Tr;
+
+// Extra form field shown when posting needs the shared password
+pub fn password_field(config: &AppConfig, tr: &Tr) -> String {
+    if config.post_password.is_some() {
+        format!(r#"<input type="password" name="password" placeholder="{}" required><br>"#, tr.t("form.password_placeholder"))
+    } else {
+        String::new()
+    }
+}
+
+// Whether `presented` matches the configured posting password. The config
+// only holds its SHA-256, so we compare digests.
+pub fn post_password_ok(config: &AppConfig, presented: &str) -> bool {
+    let Some(expected) = &config.post_password else {
+        return true;
+    };
+    let digest: String = Sha256::digest(presented.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect();
+    constant_time_eq(digest.as_bytes(), expected.to_ascii_lowercase().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[actix_web::test]
+    async fn posting_needs_the_password_but_browsing_does_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash: String = Sha256::digest(b"open sesame").iter().map(|byte| format!("{:02x}", byte)).collect();
+        let shared = shared(AppConfig { post_password: Some(hash.to_ascii_uppercase()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(r#"<input type="password" name="password""#));
+
+        for (password, status) in [(None, 403), (Some("open says me"), 403), (Some("open sesame"), 303)] {
+            let mut fields = vec![("title", "private"), ("message", "members only"), ("parent_id", "0")];
+            fields.extend(password.map(|password| ("password", password)));
+            let response = call_service(&app, form_post("/upload", multipart(&fields, None), "127.0.0.1:40000").to_request()).await;
+            assert_eq!(response.status(), status, "{:?}", password);
+        }
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 1);
+    }
+}
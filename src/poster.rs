@@ -0,0 +1,75 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::session::Prefs;
+
+// Seconds since the poster was first seen, or None for an unknown token
+pub fn poster_age(conn: &Connection, token: &str) -> Option<i64> {
+    conn.query_row(
+        "SELECT CAST(strftime('%s', 'now') - strftime('%s', first_seen) AS INTEGER) FROM posters WHERE token = ?1",
+        params![token],
+        |row| row.get(0),
+    ).optional().unwrap()
+}
+
+// Returns the visitor's poster token, registering a new one in `prefs`
+// when they carry none (or one we've never seen). The caller sets the
+// prefs cookie on its response.
+pub fn ensure_poster(conn: &Connection, prefs: &mut Prefs) -> String {
+    if let Some(token) = &prefs.poster {
+        if poster_age(conn, token).is_some() {
+            return token.clone();
+        }
+    }
+
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    conn.execute("INSERT INTO posters (token) VALUES (?1)", params![token]).unwrap();
+    prefs.poster = Some(token.clone());
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config, test_db};
+
+    fn thread() -> Vec<u8> {
+        multipart(&[("title", "hello"), ("message", "first post"), ("parent_id", "0")], None)
+    }
+
+    #[actix_web::test]
+    async fn first_post_waits_out_the_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { first_post_delay_secs: 60, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, form_post("/upload", thread(), "127.0.0.1:40000").to_request()).await;
+        assert_eq!(response.status(), 429);
+        let wait: u64 = response.headers().get("retry-after").unwrap().to_str().unwrap().parse().unwrap();
+        assert!(wait > 0 && wait <= 60);
+        let prefs = response.response().cookies().find(|cookie| cookie.name() == "prefs").unwrap().into_owned();
+
+        // The same visitor, once the delay has passed
+        shared.conn.lock().unwrap().execute("UPDATE posters SET first_seen = datetime('now', '-61 seconds')", []).unwrap();
+        let response = call_service(&app, form_post("/upload", thread(), "127.0.0.1:40000").cookie(prefs).to_request()).await;
+        assert_eq!(response.status(), 303);
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 1);
+    }
+
+    #[test]
+    fn known_token_is_kept_and_unknown_replaced() {
+        let conn = test_db();
+        let mut prefs = Prefs::default();
+        let token = ensure_poster(&conn, &mut prefs);
+        assert_eq!(prefs.poster.as_deref(), Some(token.as_str()));
+        assert_eq!(ensure_poster(&conn, &mut prefs), token);
+
+        prefs.poster = Some("made-up".to_string());
+        let replaced = ensure_poster(&conn, &mut prefs);
+        assert_ne!(replaced, "made-up");
+        assert!(poster_age(&conn, &replaced).is_some());
+    }
+}
@@ -0,0 +1,148 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::AppConfig;
+
+// Upper bounds in ms of the histogram buckets; one more bucket takes the rest
+pub const BUCKETS_MS: [u64; 6] = [1, 5, 10, 50, 100, 500];
+// Characters of statement text kept as a query's name
+const NAME_LENGTH: usize = 160;
+
+static SLOW_MS: AtomicU64 = AtomicU64::new(0);
+static STATS: Mutex<BTreeMap<String, QueryStats>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    // Route of the request being handled, for the slow query log
+    static ROUTE: String;
+}
+
+// Timings of one query since startup
+#[derive(Clone, Default)]
+pub struct QueryStats {
+    pub calls: u64,
+    pub total: Duration,
+    pub max: Duration,
+    pub buckets: [u64; BUCKETS_MS.len() + 1],
+}
+
+// A query is known by its statement text with the whitespace collapsed.
+// SQLite reports the text as written, placeholders and all, so bound
+// values never show up in names or logs.
+fn query_name(sql: &str) -> String {
+    let name = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    match name.char_indices().nth(NAME_LENGTH) {
+        Some((cut, _)) => format!("{}...", &name[..cut]),
+        None => name,
+    }
+}
+
+// The slow query log line for a query that took `ms`, if it was slow
+fn slow_query_line(name: &str, ms: u64) -> Option<String> {
+    let slow_ms = SLOW_MS.load(Ordering::Relaxed);
+    if slow_ms == 0 || ms < slow_ms {
+        return None;
+    }
+    let route = ROUTE.try_with(String::clone).unwrap_or_else(|_| "a background task".to_string());
+    Some(format!("Slow query ({} ms) during {}: {}", ms, route, name))
+}
+
+// Called by SQLite after every statement. Runs inside SQLite's callback,
+// so it must not panic.
+fn profile(sql: &str, elapsed: Duration) {
+    let name = query_name(sql);
+    let ms = elapsed.as_millis() as u64;
+    if let Some(line) = slow_query_line(&name, ms) {
+        eprintln!("{}", line);
+    }
+
+    let Ok(mut stats) = STATS.lock() else {
+        return;
+    };
+    let entry = stats.entry(name).or_default();
+    entry.calls += 1;
+    entry.total += elapsed;
+    entry.max = entry.max.max(elapsed);
+    let bucket = BUCKETS_MS.iter().position(|&bound| ms < bound).unwrap_or(BUCKETS_MS.len());
+    entry.buckets[bucket] += 1;
+}
+
+// Times every statement on `conn`; ones taking `slow_query_ms` or longer
+// are logged
+pub fn install_query_stats(conn: &mut Connection, config: &AppConfig) {
+    SLOW_MS.store(config.slow_query_ms, Ordering::Relaxed);
+    conn.profile(Some(profile));
+}
+
+// Queries by total time spent in them, most first
+pub fn busiest_queries(limit: usize) -> Vec<(String, QueryStats)> {
+    let mut queries: Vec<(String, QueryStats)> = STATS.lock().unwrap().iter().map(|(name, stats)| (name.clone(), stats.clone())).collect();
+    queries.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.total));
+    queries.truncate(limit);
+    queries
+}
+
+// Makes the route known to the slow query log for the rest of the request
+pub async fn track_route(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let route = format!("{} {}", req.method(), req.match_pattern().unwrap_or_else(|| req.path().to_string()));
+    ROUTE.scope(route, next.call(req)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use rusqlite::params;
+
+    #[test]
+    fn names_are_the_statement_text_tidied() {
+        assert_eq!(query_name("SELECT id\n    FROM files\n    WHERE id = ?1"), "SELECT id FROM files WHERE id = ?1");
+        let long = format!("SELECT {} FROM files", "id, ".repeat(100));
+        let name = query_name(&long);
+        assert_eq!(name.chars().count(), NAME_LENGTH + 3);
+        assert!(name.ends_with("..."));
+    }
+
+    #[test]
+    fn queries_are_timed_without_their_values() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        install_query_stats(&mut conn, &AppConfig::default());
+        conn.execute("CREATE TABLE query_stats_probe (secret TEXT)", []).unwrap();
+        for _ in 0..3 {
+            conn.execute("INSERT INTO query_stats_probe (secret)\n VALUES (?1)", params!["hunter2"]).unwrap();
+        }
+
+        let queries = busiest_queries(usize::MAX);
+        let (_, stats) = queries.iter().find(|(name, _)| name == "INSERT INTO query_stats_probe (secret) VALUES (?1)").unwrap();
+        assert_eq!(stats.calls, 3);
+        assert_eq!(stats.buckets.iter().sum::<u64>(), 3);
+        assert!(stats.max <= stats.total);
+        assert!(queries.iter().all(|(name, _)| !name.contains("hunter2")));
+    }
+
+    #[actix_web::test]
+    async fn slow_queries_are_logged_with_their_route() {
+        SLOW_MS.store(AppConfig::default().slow_query_ms, Ordering::Relaxed);
+        assert_eq!(slow_query_line("SELECT 1", 49), None);
+        assert_eq!(slow_query_line("SELECT 1", 50).unwrap(), "Slow query (50 ms) during a background task: SELECT 1");
+
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(track_route))
+                .route("/post/{id}", web::get().to(|| async { HttpResponse::Ok().body(slow_query_line("SELECT 1", 120).unwrap()) }))
+        ).await;
+        let line = call_and_read_body(&app, TestRequest::get().uri("/post/17").to_request()).await;
+        assert_eq!(line, "Slow query (120 ms) during GET /post/{id}: SELECT 1");
+    }
+}
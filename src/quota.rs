@@ -0,0 +1,321 @@
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::jobs::{AppState, Job};
+use crate::maintenance::until_next_run;
+use crate::moderation::log_mod_action;
+use crate::storage::Storage;
+
+// settings key of the running total
+const USAGE_KEY: &str = "stored_bytes";
+
+fn is_remote(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// Bytes of upload storage held by files the database refers to: posts'
+// files and poster frames, and uploads waiting to be attached. Counted as
+// rows take files on and give them up, and recounted nightly.
+pub fn stored_bytes(conn: &Connection) -> Option<i64> {
+    conn.query_row("SELECT CAST(value AS INTEGER) FROM settings WHERE key = ?1", params![USAGE_KEY], |row| row.get(0))
+        .optional()
+        .unwrap()
+}
+
+pub fn add_stored_bytes(conn: &Connection, delta: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, MAX(?2, 0))
+         ON CONFLICT (key) DO UPDATE SET value = MAX(CAST(value AS INTEGER) + ?2, 0)",
+        params![USAGE_KEY, delta],
+    )?;
+    Ok(())
+}
+
+fn set_stored_bytes(conn: &Connection, total: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![USAGE_KEY, total],
+    )?;
+    Ok(())
+}
+
+// Files of the given rows, thumbnails included
+pub fn files_of(conn: &Connection, sql: &str, params: impl Params) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, Option<String>>(1)?)))?;
+    let mut paths = Vec::new();
+    for row in rows {
+        let (file_path, thumbnail_path) = row?;
+        paths.extend(file_path.into_iter().chain(thumbnail_path));
+    }
+    Ok(paths)
+}
+
+fn still_referenced(conn: &Connection, path: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM files WHERE file_path = ?1 OR thumbnail_path = ?1
+         UNION ALL SELECT 1 FROM pending_uploads WHERE file_path = ?1 OR thumbnail_path = ?1
+         LIMIT 1",
+        params![path],
+        |_| Ok(()),
+    ).is_ok()
+}
+
+// Deletes stored files once no row refers to them any more, and takes them
+// off the running total. Callers remove or update the rows first.
+pub fn delete_files(conn: &Connection, storage: &dyn Storage, paths: &[String]) {
+    for path in paths {
+        if is_remote(path) || still_referenced(conn, path) {
+            continue;
+        }
+        let size = storage.size(path).unwrap_or(0);
+        match storage.delete(path) {
+            Ok(()) => add_stored_bytes(conn, -(size as i64)).unwrap(),
+            Err(e) => eprintln!("Failed to remove upload {}: {}", path, e),
+        }
+    }
+}
+
+// Takes the file off the post of the least recently active archived
+// thread that still has one. Returns false when there is none left.
+fn evict_oldest_archived(conn: &Connection, storage: &dyn Storage) -> bool {
+    let oldest: Option<(i32, String, Option<String>)> = conn.query_row(
+        "SELECT files.id, files.file_path, files.thumbnail_path FROM files
+         JOIN files AS threads ON threads.id = CASE files.parent_id WHEN 0 THEN files.id ELSE files.parent_id END
+         WHERE threads.archived = 1 AND files.file_path IS NOT NULL AND files.file_path NOT LIKE 'http%'
+         ORDER BY threads.last_reply_at ASC, files.id ASC
+         LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).optional().unwrap();
+    let Some((id, file_path, thumbnail_path)) = oldest else {
+        return false;
+    };
+
+    conn.execute(
+        "UPDATE files SET file_path = NULL, original_name = NULL, original_format = NULL, original_size = NULL,
+            thumbnail_path = NULL, width = NULL, height = NULL, alt_text = NULL, file_hash = NULL, page_count = NULL
+         WHERE id = ?1",
+        params![id],
+    ).unwrap();
+    let paths: Vec<String> = std::iter::once(file_path).chain(thumbnail_path).collect();
+    delete_files(conn, storage, &paths);
+    log_mod_action(conn, None, "evict", Some(id), &format!("file of archived post {} removed to stay under the storage quota", id)).unwrap();
+    true
+}
+
+// Whether `needed` more bytes fit under the quota (`storage_quota_bytes`).
+// With `evict` (`evict_archived_files`), files of old archived threads are
+// removed until they do.
+pub fn make_room(conn: &Connection, storage: &dyn Storage, quota: Option<u64>, evict: bool, needed: i64) -> bool {
+    let Some(quota) = quota.map(|quota| quota as i64) else {
+        return true;
+    };
+    if needed > quota {
+        return false;
+    }
+    loop {
+        if stored_bytes(conn).unwrap_or(0) + needed <= quota {
+            return true;
+        }
+        if !evict || !evict_oldest_archived(conn, storage) {
+            return false;
+        }
+    }
+}
+
+// Takes `bytes` of the quota for a file whose row is being inserted,
+// evicting as make_room does. Run it in the transaction that inserts the
+// row, an IMMEDIATE one, so two uploads can't both take the last of the
+// space. Returns false, reserving nothing, when the file doesn't fit.
+pub fn reserve(conn: &Connection, storage: &dyn Storage, config: &AppConfig, bytes: i64) -> rusqlite::Result<bool> {
+    if !make_room(conn, storage, config.storage_quota_bytes, config.evict_archived_files, bytes) {
+        return Ok(false);
+    }
+    add_stored_bytes(conn, bytes)?;
+    Ok(true)
+}
+
+// Recounts the running total from the files the database refers to, so
+// files removed by hand or counts lost to crashes don't pile up.
+pub struct DiskUsageJob;
+
+impl Job for DiskUsageJob {
+    fn name(&self) -> &'static str {
+        "disk-usage"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    // Soon after the first start, when there is no total yet
+    fn first_delay(&self, state: &AppState) -> Duration {
+        if stored_bytes(&state.conn.lock().unwrap()).is_none() {
+            Duration::from_secs(60)
+        } else {
+            until_next_run(state.config.maintenance_hour, None)
+        }
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        // Sizes are read without holding the connection
+        let paths: Vec<String> = {
+            let conn = state.conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "SELECT file_path FROM files WHERE file_path IS NOT NULL
+                 UNION SELECT thumbnail_path FROM files WHERE thumbnail_path IS NOT NULL
+                 UNION SELECT file_path FROM pending_uploads
+                 UNION SELECT thumbnail_path FROM pending_uploads WHERE thumbnail_path IS NOT NULL"
+            ).map_err(|e| e.to_string())?;
+            let paths = stmt.query_map([], |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .filter_map(|row| row.ok())
+                .collect();
+            paths
+        };
+
+        let total: u64 = paths.iter()
+            .filter(|path| !is_remote(path))
+            .filter_map(|path| state.storage.size(path))
+            .sum();
+        let conn = state.conn.lock().unwrap();
+        let counted = stored_bytes(&conn).unwrap_or(0);
+        set_stored_bytes(&conn, total as i64).map_err(|e| e.to_string())?;
+
+        println!("Upload storage holds {} bytes in {} files (running total was {})", total, paths.len(), counted);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    // A post holding `file_path`, in a thread last active `days` ago
+    fn insert_post(conn: &Connection, parent_id: i32, file_path: Option<&str>, days: i32, archived: bool) -> i32 {
+        conn.execute(
+            "INSERT INTO files (post_id, parent_id, title, message, file_path, archived, created_at, last_reply_at)
+             VALUES (lower(hex(randomblob(3))), ?1, 't', 'm', ?2, ?3, datetime('now', ?4), datetime('now', ?4))",
+            params![parent_id, file_path, archived, format!("-{} days", days)],
+        ).unwrap();
+        conn.last_insert_rowid() as i32
+    }
+
+    #[actix_web::test]
+    async fn uploads_are_counted_and_refused_past_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = 1_000_000;
+        let shared = shared(AppConfig {
+            admin_password: Some("letmein".to_string()),
+            storage_quota_bytes: Some(quota),
+            ..test_config(dir.path())
+        });
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str, shade: u8| form_post("/upload", multipart(&[("title", title), ("message", "m"), ("parent_id", "0")], Some(("cat.png", "image/png", &png(shade)))), PEER);
+
+        assert_eq!(call_service(&app, post("counted", 1).to_request()).await.status(), 303);
+        let (id, paths) = {
+            let conn = shared.conn.lock().unwrap();
+            let id: i32 = conn.query_row("SELECT id FROM files WHERE title = 'counted'", [], |row| row.get(0)).unwrap();
+            (id, files_of(&conn, "SELECT file_path, thumbnail_path FROM files WHERE id = ?1", [id]).unwrap())
+        };
+        let sizes: u64 = paths.iter().map(|path| shared.storage.size(path).unwrap()).sum();
+        assert!(sizes > 0);
+        assert_eq!(stored_bytes(&shared.conn.lock().unwrap()), Some(sizes as i64));
+
+        let admin = || TestRequest::get().uri("/admin").insert_header((header::AUTHORIZATION, "Bearer letmein")).to_request();
+        let dashboard = String::from_utf8(call_and_read_body(&app, admin()).await.to_vec()).unwrap();
+        assert!(dashboard.contains("<tr><th>Upload storage used</th><td>1 KB of 976 KB</td></tr>"), "{}", dashboard);
+
+        let delete = TestRequest::post().uri(&format!("/admin/delete/{}", id)).insert_header((header::AUTHORIZATION, "Bearer letmein"));
+        assert_eq!(call_service(&app, delete.to_request()).await.status(), 303);
+        assert_eq!(stored_bytes(&shared.conn.lock().unwrap()), Some(0));
+        assert!(paths.iter().all(|path| shared.storage.size(path).is_none()));
+
+        // Nearly full: files are refused, posts without one still go through
+        set_stored_bytes(&shared.conn.lock().unwrap(), quota as i64 - 10).unwrap();
+        let response = call_service(&app, post("refused", 2).to_request()).await;
+        assert_eq!(response.status(), 507);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("The board has used all the space it has for files."));
+        let response = call_service(&app, form_post("/api/upload", multipart(&[], Some(("cat.png", "image/png", &png(3)))), PEER).to_request()).await;
+        assert_eq!(response.status(), 507);
+        let text = form_post("/upload", multipart(&[("title", "words"), ("message", "only words"), ("parent_id", "0")], None), PEER);
+        assert_eq!(call_service(&app, text.to_request()).await.status(), 303);
+
+        assert_eq!(stored_bytes(&shared.conn.lock().unwrap()), Some(quota as i64 - 10));
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn eviction_takes_the_oldest_archived_files_and_spares_shared_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let storage = shared.storage.get_ref();
+        let shared_file = storage.put("shared.png", &[0; 100], "image/png").unwrap();
+        let old_file = storage.put("old.png", &[0; 100], "image/png").unwrap();
+        let live_file = storage.put("live.png", &[0; 100], "image/png").unwrap();
+
+        let conn = shared.conn.lock().unwrap();
+        // The oldest archived thread shares its file with a live one
+        let oldest = insert_post(&conn, 0, Some(&shared_file), 30, true);
+        let older = insert_post(&conn, 0, None, 20, true);
+        let older_reply = insert_post(&conn, older, Some(&old_file), 20, false);
+        let live = insert_post(&conn, 0, Some(&live_file), 40, false);
+        insert_post(&conn, live, Some(&shared_file), 1, false);
+        set_stored_bytes(&conn, 300).unwrap();
+
+        // Nothing is removed without `evict`, or for a file bigger than the quota
+        assert!(!make_room(&conn, storage, Some(350), false, 100));
+        assert!(!make_room(&conn, storage, Some(350), true, 400));
+        assert_eq!(stored_bytes(&conn), Some(300));
+
+        assert!(make_room(&conn, storage, Some(350), true, 100));
+        let file_of = |id: i32| -> Option<String> { conn.query_row("SELECT file_path FROM files WHERE id = ?1", [id], |row| row.get(0)).unwrap() };
+        assert_eq!(file_of(oldest), None);
+        assert_eq!(file_of(older_reply), None);
+        assert_eq!(file_of(live).as_deref(), Some(live_file.as_str()));
+        assert!(storage.exists("shared.png"));
+        assert!(!storage.exists("old.png"));
+        assert_eq!(stored_bytes(&conn), Some(200));
+        let evicted: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'evict'", [], |row| row.get(0)).unwrap();
+        assert_eq!(evicted, 2);
+
+        // Live threads are never evicted
+        assert!(!make_room(&conn, storage, Some(350), true, 200));
+        assert!(storage.exists("live.png") && storage.exists("shared.png"));
+    }
+
+    #[test]
+    fn the_recount_corrects_the_running_total() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let kept = shared.storage.put("kept.png", &[0; 120], "image/png").unwrap();
+        let pending = shared.storage.put("pending.png", &[0; 30], "image/png").unwrap();
+        {
+            let conn = shared.conn.lock().unwrap();
+            insert_post(&conn, 0, Some(&kept), 1, false);
+            insert_post(&conn, 0, Some("https://elsewhere.example/a.png"), 1, false);
+            // Removed by hand
+            insert_post(&conn, 0, Some(&format!("{}/gone.png", shared.config.upload_dir)), 1, false);
+            conn.execute(
+                "INSERT INTO pending_uploads (token, file_path, original_name, created_at) VALUES ('t', ?1, 'p.png', CURRENT_TIMESTAMP)",
+                [&pending],
+            ).unwrap();
+            set_stored_bytes(&conn, 999_999).unwrap();
+        }
+
+        let state = AppState { conn: shared.conn.clone(), config: shared.config.clone(), storage: shared.storage.clone() };
+        DiskUsageJob.run(&state).unwrap();
+        assert_eq!(stored_bytes(&shared.conn.lock().unwrap()), Some(150));
+    }
+}
@@ -0,0 +1,330 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpResponse};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::admin::{admin, is_admin};
+use crate::client_ip::ClientIp;
+use crate::config::AppConfig;
+use crate::site::site;
+
+const API_WINDOW: Duration = Duration::from_secs(60);
+// Stale buckets are swept once the map grows past this many keys
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+struct Bucket {
+    window_start: Instant,
+    count: u32,
+}
+
+// Fixed-window request counter for the JSON API, separate from anything
+// applied to the HTML board.
+pub struct ApiRateLimiter {
+    limit: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl ApiRateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        ApiRateLimiter {
+            limit,
+            window,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, API_WINDOW)
+    }
+
+    // Counts a request against `key`. Returns the seconds until the bucket
+    // refills when the limit has been exceeded.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_KEYS {
+            let window = self.window;
+            buckets.retain(|_, bucket| now.duration_since(bucket.window_start) < window);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            window_start: now,
+            count: 0,
+        });
+
+        if now.duration_since(bucket.window_start) >= self.window {
+            bucket.window_start = now;
+            bucket.count = 0;
+        }
+
+        if bucket.count >= self.limit {
+            let remaining = self.window - now.duration_since(bucket.window_start);
+            return Err(remaining.as_secs().max(1));
+        }
+
+        bucket.count += 1;
+        Ok(())
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Most posts a client can be behind by; bounds both the delay's growth and
+// how long a flood takes to be forgiven
+const MAX_POST_DEBT: f64 = 16.0;
+
+// Slows down fast posters instead of refusing them. Each client (by IP) has
+// a bucket of `burst` posts that refills by one every `refill`. A post with
+// the bucket empty still goes through, but only after a wait that starts
+// at `base_delay` and doubles for each further post, up to `max_delay`.
+pub struct PostThrottle {
+    burst: f64,
+    refill: Duration,
+    base_delay: Duration,
+    max_delay: Duration,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl PostThrottle {
+    pub fn new(config: &AppConfig) -> Self {
+        PostThrottle {
+            burst: config.post_burst as f64,
+            refill: Duration::from_secs(config.post_refill_secs),
+            base_delay: Duration::from_millis(config.post_slowdown_ms),
+            max_delay: Duration::from_secs(config.post_max_delay_secs),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a post from `key`'s bucket and returns how long to hold it back
+    pub fn delay(&self, key: &str) -> Duration {
+        if self.burst == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_TRACKED_KEYS {
+            // Full buckets are no different from new ones
+            let (burst, refill) = (self.burst, self.refill);
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() / refill.as_secs_f64() < burst);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket { tokens: self.burst, updated: now });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() / self.refill.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst) - 1.0;
+        bucket.tokens = bucket.tokens.max(-MAX_POST_DEBT);
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        // 1 for the first post over, 2 for the next, and so on
+        let over = (-bucket.tokens).ceil() as u32;
+        self.base_delay.saturating_mul(1 << (over - 1).min(16)).min(self.max_delay)
+    }
+}
+
+// Moderators, who authenticate with the admin password (as a bearer
+// token or Basic auth), get a bucket per moderator name; everyone else is
+// keyed by their IP address. Tokens that aren't valid don't get a bucket
+// of their own, or rotating made-up tokens would dodge the limit.
+fn client_key(req: &ServiceRequest) -> String {
+    match admin(req.request()) {
+        Some(admin) => format!("admin:{}", admin.name),
+        None => format!("ip:{}", ClientIp::of(req.request()).text()),
+    }
+}
+
+pub async fn api_rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let limited = req
+        .app_data::<Data<ApiRateLimiter>>()
+        .and_then(|limiter| limiter.check(&client_key(&req)).err());
+
+    if let Some(retry_after) = limited {
+        let response = HttpResponse::TooManyRequests()
+            .append_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(json!({
+                "error": "Too many requests",
+                "retry_after": retry_after,
+            }));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// Holds back posts to the board's upload routes as PostThrottle says.
+// Moderators aren't slowed, and nothing is while posting is off anyway.
+pub async fn post_throttle(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let posting = req.method() == Method::POST
+        && matches!(req.match_pattern().as_deref(), Some("/upload" | "/{board}/upload"));
+    let throttle = req.app_data::<Data<PostThrottle>>().cloned();
+    if let Some(throttle) = throttle.filter(|_| posting && !is_admin(req.request()) && !site(req.request()).read_only()) {
+        let delay = throttle.delay(&ClientIp::of(req.request()).text());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use std::net::SocketAddr;
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_refills_after_the_window() {
+        let limiter = ApiRateLimiter::new(2, Duration::from_millis(100));
+        assert_eq!(limiter.check("ip:127.0.0.1"), Ok(()));
+        assert_eq!(limiter.check("ip:127.0.0.1"), Ok(()));
+        assert_eq!(limiter.check("ip:127.0.0.1"), Err(1));
+        // Other clients have their own bucket
+        assert_eq!(limiter.check("ip:10.0.0.1"), Ok(()));
+
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(limiter.check("ip:127.0.0.1"), Ok(()));
+    }
+
+    #[test]
+    fn zero_limit_never_refuses() {
+        let limiter = ApiRateLimiter::per_minute(0);
+        for _ in 0..100 {
+            assert_eq!(limiter.check("ip:127.0.0.1"), Ok(()));
+        }
+    }
+
+    #[actix_web::test]
+    async fn exhausted_bucket_gets_429_with_retry_after() {
+        let shared = shared(AppConfig { api_requests_per_minute: 2, ..AppConfig::default() });
+        let app = init_service(crate::app(&shared)).await;
+
+        for _ in 0..2 {
+            let response = call_service(&app, TestRequest::get().uri("/api/posts").peer_addr(local()).to_request()).await;
+            assert_eq!(response.status(), 200);
+        }
+        let response = call_service(&app, TestRequest::get().uri("/api/posts").peer_addr(local()).to_request()).await;
+        assert_eq!(response.status(), 429);
+        let retry_after: u64 = response.headers().get(header::RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["retry_after"], retry_after);
+
+        // The board itself isn't counted
+        let response = call_service(&app, TestRequest::get().uri("/").peer_addr(local()).to_request()).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn api_is_served_again_once_the_window_passes() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(ApiRateLimiter::new(1, Duration::from_millis(100))))
+                .service(web::scope("/api").wrap(from_fn(api_rate_limit)).route("/ping", web::get().to(HttpResponse::Ok))),
+        ).await;
+        let ping = || TestRequest::get().uri("/api/ping").peer_addr(local()).to_request();
+
+        assert_eq!(call_service(&app, ping()).await.status(), 200);
+        assert_eq!(call_service(&app, ping()).await.status(), 429);
+        actix_web::rt::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(call_service(&app, ping()).await.status(), 200);
+    }
+
+    fn throttle(burst: u32, refill: Duration, base_ms: u64, max_ms: u64) -> PostThrottle {
+        PostThrottle {
+            burst: burst as f64,
+            refill,
+            base_delay: Duration::from_millis(base_ms),
+            max_delay: Duration::from_millis(max_ms),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn post_delays_grow_with_frequency() {
+        let throttle = throttle(2, Duration::from_secs(60), 100, 1000);
+        let delays: Vec<u128> = (0..8).map(|_| throttle.delay("127.0.0.1").as_millis()).collect();
+        assert_eq!(delays, [0, 0, 100, 200, 400, 800, 1000, 1000]);
+        // Other posters have their own bucket
+        assert_eq!(throttle.delay("10.0.0.1"), Duration::ZERO);
+
+        // Off by default
+        let off = PostThrottle::new(&AppConfig::default());
+        assert!((0..50).all(|_| off.delay("127.0.0.1").is_zero()));
+    }
+
+    #[test]
+    fn posting_slowly_earns_the_burst_back() {
+        let throttle = throttle(1, Duration::from_millis(50), 100, 1000);
+        assert_eq!(throttle.delay("127.0.0.1"), Duration::ZERO);
+        assert_eq!(throttle.delay("127.0.0.1"), Duration::from_millis(100));
+        assert_eq!(throttle.delay("127.0.0.1"), Duration::from_millis(200));
+
+        // Two posts of debt and the burst itself are paid back in three refills
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(throttle.delay("127.0.0.1"), Duration::ZERO);
+        assert_eq!(throttle.delay("127.0.0.1"), Duration::from_millis(100));
+    }
+
+    #[actix_web::test]
+    async fn fast_posts_are_held_back_but_not_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            post_burst: 1,
+            post_slowdown_ms: 200,
+            admin_password: Some("letmein".to_string()),
+            ..test_config(dir.path())
+        });
+        let app = init_service(crate::app(&shared)).await;
+        let post = |n: usize| form_post("/upload", multipart(&[("title", "fast"), ("message", &format!("post {}", n)), ("parent_id", "0")], None), "127.0.0.1:40000");
+
+        let mut waits = Vec::new();
+        for n in 0..3 {
+            let started = Instant::now();
+            assert_eq!(call_service(&app, post(n).to_request()).await.status(), 303);
+            waits.push(started.elapsed());
+        }
+        assert!(waits[0] < Duration::from_millis(200), "{:?}", waits);
+        assert!(waits[1] >= Duration::from_millis(200), "{:?}", waits);
+        assert!(waits[2] >= Duration::from_millis(400), "{:?}", waits);
+
+        // Reading and moderators aren't held back
+        let started = Instant::now();
+        assert_eq!(call_service(&app, TestRequest::get().uri("/").peer_addr(local()).to_request()).await.status(), 200);
+        let admin = post(3).insert_header((header::AUTHORIZATION, "Bearer letmein"));
+        assert_eq!(call_service(&app, admin.to_request()).await.status(), 303);
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+}
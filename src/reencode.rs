@@ -0,0 +1,172 @@
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::{DynamicImage, GenericImageView};
+use std::io::Cursor;
+
+// Formats we know how to re-encode, by upload extension
+pub fn can_reencode(extension: &str) -> bool {
+    matches!(extension, "png" | "jpg" | "jpeg")
+}
+
+fn decode(data: &[u8], extension: &str) -> Result<DynamicImage, String> {
+    match extension {
+        "png" => {
+            let decoder = PngDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            if decoder.is_apng().map_err(|e| e.to_string())? {
+                return Err("animated PNG".to_string());
+            }
+            DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())
+        },
+        "jpg" | "jpeg" => {
+            let decoder = JpegDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())
+        },
+        _ => Err(format!("cannot re-encode .{} files", extension)),
+    }
+}
+
+// Re-encodes a PNG or JPEG upload to WebP. Images with real transparency
+// are encoded losslessly so edges don't smear; everything else goes lossy
+// at `quality`. Animated PNGs are refused, since only the first frame
+// would survive.
+pub fn to_webp(data: &[u8], extension: &str, quality: f32) -> Result<Vec<u8>, String> {
+    let image = decode(data, extension)?;
+    let (width, height) = image.dimensions();
+
+    let (pixels, lossless) = if has_transparency(&image) {
+        (image.into_rgba8().into_raw(), true)
+    } else {
+        (image.into_rgb8().into_raw(), false)
+    };
+    let encoder = if lossless {
+        webp::Encoder::from_rgba(&pixels, width, height)
+    } else {
+        webp::Encoder::from_rgb(&pixels, width, height)
+    };
+    let encoded = encoder.encode_simple(lossless, quality)
+        .map_err(|e| format!("WebP encoding failed: {:?}", e))?;
+    Ok(encoded.to_vec())
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < 255)
+}
+
+// "4.2 MB" or "310 KB"
+pub fn format_size(size: i64) -> String {
+    if size >= 1024 * 1024 {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{} KB", (size / 1024).max(1))
+    }
+}
+
+// "PNG, 4.2 MB" style note for attachments that were re-encoded
+pub fn conversion_note(format: &str, size: i64) -> String {
+    format!("{}, {}", format.to_uppercase(), format_size(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    // A photo-like gradient: large as a PNG, much smaller as lossy WebP
+    fn gradient_png(alpha: u8) -> Vec<u8> {
+        let image = RgbaImage::from_fn(256, 256, |x, y| Rgba([x as u8, y as u8, (x * y % 251) as u8, alpha]));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    fn is_webp(data: &[u8]) -> bool {
+        data.len() > 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP"
+    }
+
+    #[test]
+    fn opaque_images_go_lossy_and_transparent_ones_lossless() {
+        let opaque = to_webp(&gradient_png(255), "png", 80.0).unwrap();
+        assert!(is_webp(&opaque));
+        assert_eq!(&opaque[12..16], b"VP8 ");
+        let decoded = webp::Decoder::new(&opaque).decode().unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 256));
+
+        let transparent = to_webp(&gradient_png(128), "png", 80.0).unwrap();
+        assert_eq!(&transparent[12..16], b"VP8L");
+    }
+
+    #[test]
+    fn only_pngs_and_jpegs_are_reencoded() {
+        assert!(can_reencode("png") && can_reencode("jpg") && can_reencode("jpeg"));
+        assert!(!can_reencode("gif"));
+        assert!(to_webp(b"GIF89a", "gif", 80.0).is_err());
+    }
+
+    #[test]
+    fn sizes_read_in_kb_or_mb() {
+        assert_eq!(conversion_note("png", 100), "PNG, 1 KB");
+        assert_eq!(conversion_note("png", 310 * 1024), "PNG, 310 KB");
+        assert_eq!(conversion_note("png", 4_404_019), "PNG, 4.2 MB");
+    }
+
+    #[actix_web::test]
+    async fn large_pngs_are_stored_as_webp() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { webp_min_png_bytes: 10_000, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let large = gradient_png(255);
+        assert!(large.len() > 10_000);
+        for (name, data) in [("large.png", large.clone()), ("small.png", png(9))] {
+            let body = multipart(&[("title", name), ("message", "screenshot"), ("parent_id", "0")], Some((name, "image/png", &data)));
+            assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        }
+
+        let conn = shared.conn.lock().unwrap();
+        let (path, format, size): (String, Option<String>, Option<i64>) = conn
+            .query_row("SELECT file_path, original_format, original_size FROM files WHERE title = 'large.png'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert!(path.ends_with(".webp"));
+        assert!(is_webp(&std::fs::read(&path).unwrap()));
+        assert_eq!(format.as_deref(), Some("png"));
+        assert_eq!(size, Some(large.len() as i64));
+
+        let (path, format): (String, Option<String>) = conn
+            .query_row("SELECT file_path, original_format FROM files WHERE title = 'small.png'", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert!(path.ends_with(".png"));
+        assert_eq!(format, None);
+    }
+
+    #[actix_web::test]
+    async fn convert_to_webp_stores_pngs_as_webp_but_keeps_gifs() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { convert_to_webp: true, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;".to_vec();
+        for (name, content_type, data) in [("small.png", "image/png", png(40)), ("anim.gif", "image/gif", gif)] {
+            let body = multipart(&[("title", name), ("message", "converted?"), ("parent_id", "0")], Some((name, content_type, &data)));
+            assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        }
+
+        let (png_id, png_path, format): (i32, String, Option<String>) = shared.conn.lock().unwrap()
+            .query_row("SELECT id, file_path, original_format FROM files WHERE title = 'small.png'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        // Even a small PNG is converted when every upload should be
+        assert!(png_path.ends_with(".webp"));
+        assert!(is_webp(&std::fs::read(&png_path).unwrap()));
+        assert_eq!(format.as_deref(), Some("png"));
+        let page = call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", png_id)).to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains(&crate::upload_url(&shared.config, &png_path)));
+
+        let gif_path: String = shared.conn.lock().unwrap()
+            .query_row("SELECT file_path FROM files WHERE title = 'anim.gif'", [], |row| row.get(0))
+            .unwrap();
+        assert!(gif_path.ends_with(".gif"));
+    }
+}
@@ -0,0 +1,246 @@
+use ammonia::Builder;
+use std::collections::HashSet;
+use url::Url;
+
+use crate::config::AppConfig;
+
+// Tags whose contents could run code; refused even if a config lists them
+const NEVER_TAGS: [&str; 6] = ["script", "style", "iframe", "object", "embed", "template"];
+
+// Cleans post messages down to the HTML the config allows: the `post_*`
+// allowlists for ordinary posts and the wider `admin_*` ones for posts made
+// by a moderator. Links, tags and attributes outside the allowlist are
+// stripped; scripts and on* event handlers never get through either way.
+// Links off the site are then marked with the configured rel and target.
+pub struct HtmlSanitizer {
+    post: Builder<'static>,
+    admin: Builder<'static>,
+    site_host: Option<String>,
+    // Added to the opening tag of every external link
+    external_attributes: String,
+}
+
+// The builders borrow their allowlists for as long as they live, which is
+// the whole run, so the strings are leaked rather than kept alongside them
+fn leak(names: &[String], allowed: impl Fn(&str) -> bool) -> HashSet<&'static str> {
+    names.iter()
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty() && allowed(name))
+        .map(|name| &*Box::leak(name.into_boxed_str()))
+        .collect()
+}
+
+fn builder(tags: &[String], attributes: &[String]) -> Builder<'static> {
+    let mut builder = Builder::default();
+    builder
+        .tags(leak(tags, |tag| !NEVER_TAGS.contains(&tag)))
+        // `rel` and `target` are set on external links by the sanitizer itself
+        .generic_attributes(leak(attributes, |attribute| !attribute.starts_with("on") && attribute != "rel" && attribute != "target"))
+        .link_rel(None);
+    builder
+}
+
+// The target and rel attributes, each with a leading space, that links
+// off the site carry
+pub fn external_link_attributes(config: &AppConfig) -> String {
+    let mut attributes = String::new();
+    if config.external_links_new_tab {
+        attributes.push_str(r#" target="_blank""#);
+    }
+    if !config.external_link_rel.trim().is_empty() {
+        attributes.push_str(&format!(r#" rel="{}""#, config.external_link_rel.trim()));
+    }
+    attributes
+}
+
+impl HtmlSanitizer {
+    pub fn new(config: &AppConfig) -> Self {
+        HtmlSanitizer {
+            post: builder(&config.post_html_tags, &config.post_html_attributes),
+            admin: builder(&config.admin_html_tags, &config.admin_html_attributes),
+            site_host: config.site_host.clone(),
+            external_attributes: external_link_attributes(config),
+        }
+    }
+
+    pub fn clean(&self, html: &str, by_admin: bool) -> String {
+        let builder = if by_admin { &self.admin } else { &self.post };
+        self.mark_external_links(&builder.clean(html).to_string())
+    }
+
+    // Relative links, same-page anchors and links to `site_host` are
+    // internal; anything else leaves the site
+    fn is_external(&self, href: &str) -> bool {
+        let href = href.replace("&amp;", "&");
+        // Scheme-relative links name a host of their own
+        let absolute = if href.starts_with("//") { format!("https:{}", href) } else { href };
+        match Url::parse(&absolute) {
+            Ok(url) => !matches!(url.scheme(), "http" | "https")
+                || url.host_str().zip(self.site_host.as_deref()).is_none_or(|(host, site)| !host.eq_ignore_ascii_case(site)),
+            Err(_) => false,
+        }
+    }
+
+    // Adds `external_attributes` to the links of sanitized `html` that point
+    // off the site. The markup comes from the sanitizer, so attribute
+    // values are double-quoted.
+    fn mark_external_links(&self, html: &str) -> String {
+        if self.external_attributes.is_empty() {
+            return html.to_string();
+        }
+        let mut out = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find("<a ") {
+            // The end of the tag, skipping any '>' inside attribute values
+            let mut quoted = false;
+            let end = rest[start..].char_indices()
+                .find(|&(_, c)| {
+                    if c == '"' {
+                        quoted = !quoted;
+                    }
+                    c == '>' && !quoted
+                })
+                .map_or(rest.len(), |(offset, _)| start + offset);
+            let tag = &rest[start..end];
+            let href = tag.split_once(r#" href=""#).and_then(|(_, value)| value.split_once('"')).map(|(href, _)| href);
+            out.push_str(&rest[..end]);
+            if href.is_some_and(|href| self.is_external(href)) {
+                out.push_str(&self.external_attributes);
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+// Elements whose text is shown as written by the later rewrites
+const LITERAL_TAGS: [&str; 3] = ["a", "code", "pre"];
+
+// Copies sanitized `html`, passing each run of text outside links and code
+// through `rewrite`, which appends its result to the output. Markup is
+// copied untouched.
+pub fn rewrite_text(html: &str, mut rewrite: impl FnMut(&str, &mut String)) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut literal_depth = 0usize;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[..end];
+            let name = tag.trim_start_matches(['<', '/'])
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if LITERAL_TAGS.contains(&name.as_str()) {
+                literal_depth = if tag.starts_with("</") { literal_depth.saturating_sub(1) } else { literal_depth + 1 };
+            }
+            out.push_str(tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if literal_depth == 0 {
+                rewrite(&rest[..end], &mut out);
+            } else {
+                out.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const TABLE: &str = "<table><tbody><tr><td>cell</td></tr></tbody></table>";
+
+    #[test]
+    fn each_allowlist_decides_what_survives() {
+        let sanitizer = HtmlSanitizer::new(&AppConfig::default());
+        assert_eq!(sanitizer.clean("<b>bold</b> <marquee>old</marquee>", false), "<b>bold</b> old");
+        assert_eq!(sanitizer.clean(TABLE, false), "cell");
+        assert_eq!(sanitizer.clean(TABLE, true), TABLE);
+        assert_eq!(sanitizer.clean("<blockquote>quoted</blockquote>", true), "<blockquote>quoted</blockquote>");
+        assert_eq!(sanitizer.clean(r#"<p title="hint">x</p>"#, false), "<p>x</p>");
+        assert_eq!(sanitizer.clean(r#"<p title="hint">x</p>"#, true), r#"<p title="hint">x</p>"#);
+    }
+
+    #[test]
+    fn scripts_and_handlers_never_get_through() {
+        let config = AppConfig {
+            admin_html_tags: vec!["script".to_string(), "img".to_string(), "iframe".to_string()],
+            admin_html_attributes: vec!["onerror".to_string(), "src".to_string()],
+            ..AppConfig::default()
+        };
+        let sanitizer = HtmlSanitizer::new(&config);
+        for by_admin in [false, true] {
+            let clean = sanitizer.clean(r#"<script>alert(1)</script><img src="x.png" onerror="alert(2)"><iframe src="//evil"></iframe>"#, by_admin);
+            assert!(!clean.contains("script") && !clean.contains("onerror") && !clean.contains("iframe"), "{}", clean);
+        }
+        assert_eq!(sanitizer.clean(r#"<img src="x.png" onerror="alert(2)">"#, true), r#"<img src="x.png">"#);
+    }
+
+    #[actix_web::test]
+    async fn moderator_posts_keep_their_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { admin_password: Some("letmein".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str| multipart(&[("title", title), ("message", TABLE), ("parent_id", "0")], None);
+
+        assert_eq!(call_service(&app, form_post("/upload", post("anonymous"), "10.0.0.1:4000").to_request()).await.status(), 303);
+        let moderator = form_post("/upload", post("moderator"), "10.0.0.2:4000").insert_header((header::AUTHORIZATION, "Bearer letmein"));
+        assert_eq!(call_service(&app, moderator.to_request()).await.status(), 303);
+
+        for (id, has_table) in [(1, false), (2, true)] {
+            let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", id)).to_request()).await.to_vec()).unwrap();
+            assert_eq!(page.contains("<table><tbody><tr><td>cell</td></tr></tbody></table>"), has_table, "post {}", id);
+        }
+    }
+
+    #[test]
+    fn only_links_off_the_site_are_marked() {
+        let sanitizer = HtmlSanitizer::new(&AppConfig { site_host: Some("board.example".to_string()), ..AppConfig::default() });
+        assert_eq!(
+            sanitizer.clean(r#"<a href="https://elsewhere.example/">out</a>"#, false),
+            r#"<a href="https://elsewhere.example/" target="_blank" rel="noopener nofollow">out</a>"#
+        );
+        assert_eq!(sanitizer.clean(r#"<a href="https://board.example/post/1">in</a>"#, false), r#"<a href="https://board.example/post/1">in</a>"#);
+        assert_eq!(sanitizer.clean(r##"<a href="#p2">here</a>"##, false), r##"<a href="#p2">here</a>"##);
+    }
+
+    #[test]
+    fn link_marking_follows_the_config() {
+        let config = AppConfig { site_host: Some("Board.Example".to_string()), external_links_new_tab: false, external_link_rel: "nofollow".to_string(), ..AppConfig::default() };
+        let sanitizer = HtmlSanitizer::new(&config);
+        assert_eq!(sanitizer.clean(r#"<a href="//elsewhere.example/">out</a>"#, false), r#"<a href="//elsewhere.example/" rel="nofollow">out</a>"#);
+        assert_eq!(sanitizer.clean(r#"<a href="HTTPS://BOARD.EXAMPLE/">in</a>"#, false), r#"<a href="HTTPS://BOARD.EXAMPLE/">in</a>"#);
+        assert_eq!(sanitizer.clean(r#"<a href="/post/1">in</a>"#, false), r#"<a href="/post/1">in</a>"#);
+
+        // Nothing to add, nothing added
+        let plain = HtmlSanitizer::new(&AppConfig { external_links_new_tab: false, external_link_rel: " ".to_string(), ..AppConfig::default() });
+        assert_eq!(plain.clean(r#"<a href="https://elsewhere.example/">out</a>"#, false), r#"<a href="https://elsewhere.example/">out</a>"#);
+    }
+
+    #[actix_web::test]
+    async fn posted_links_are_marked_by_where_they_go() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { site_host: Some("board.example".to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let message = r#"<a href="https://board.example/post/1">inside</a> and <a href="https://elsewhere.example/page" target="_self">outside</a>"#;
+        let form = multipart(&[("title", "links"), ("message", message), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", form, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/post/1").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(r#"<a href="https://board.example/post/1">inside</a>"#));
+        assert!(page.contains(r#"<a href="https://elsewhere.example/page" target="_blank" rel="noopener nofollow">outside</a>"#));
+    }
+}
@@ -0,0 +1,233 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use crate::config::AppConfig;
+
+// clamd takes INSTREAM data in chunks of at most this size
+const CLAMD_CHUNK: usize = 64 * 1024;
+
+// Scans since startup, by outcome
+static CLEAN: AtomicU64 = AtomicU64::new(0);
+static INFECTED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+pub enum Verdict {
+    Clean,
+    // With the scanner's name for what it found
+    Infected(String),
+}
+
+// Labelled scan counts for the dashboard
+pub fn scan_counts() -> [(&'static str, u64); 3] {
+    [
+        ("Uploads scanned clean", CLEAN.load(Ordering::Relaxed)),
+        ("Uploads flagged by the virus scanner", INFECTED.load(Ordering::Relaxed)),
+        ("Virus scans that failed", FAILED.load(Ordering::Relaxed)),
+    ]
+}
+
+// Runs `command` with a temporary copy of the upload as its last argument.
+// Exit status 0 means clean and 1 means infected, as with clamscan and
+// clamdscan; anything else is a failed scan.
+async fn scan_with_command(command: &str, upload_dir: &str, data: &[u8], limit: Duration) -> Result<Verdict, String> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or("scan_command is empty")?;
+    let path = Path::new(upload_dir).join(format!(".scan-{}", uuid::Uuid::new_v4().simple()));
+    tokio::fs::write(&path, data).await.map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+
+    // The scanner is killed if it overruns, and the copy removed either way
+    let output = timeout(limit, Command::new(program)
+        .args(words)
+        .arg(&path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+    ).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    let output = output
+        .map_err(|_| format!("timed out after {} seconds", limit.as_secs()))?
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => {
+            // clamdscan prints "<path>: <signature> FOUND"
+            let found = stdout.lines()
+                .find_map(|line| line.trim().strip_suffix(" FOUND"))
+                .map(|line| line.rsplit_once(": ").map_or(line, |(_, signature)| signature))
+                .unwrap_or("flagged");
+            Ok(Verdict::Infected(found.to_string()))
+        },
+        _ => Err(format!("{} exited with {}: {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+// Streams the upload to clamd with the INSTREAM command
+async fn scan_with_clamd(address: &str, data: &[u8]) -> Result<Verdict, String> {
+    let mut stream = TcpStream::connect(address).await.map_err(|e| format!("could not connect to clamd at {}: {}", address, e))?;
+    let io_error = |e: std::io::Error| format!("clamd at {}: {}", address, e);
+    stream.write_all(b"zINSTREAM\0").await.map_err(io_error)?;
+    for chunk in data.chunks(CLAMD_CHUNK) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await.map_err(io_error)?;
+        stream.write_all(chunk).await.map_err(io_error)?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await.map_err(io_error)?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.map_err(io_error)?;
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches('\0').trim();
+    if reply.ends_with(" OK") {
+        Ok(Verdict::Clean)
+    } else if let Some(found) = reply.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(found.strip_prefix("stream: ").unwrap_or(found).to_string()))
+    } else {
+        Err(format!("clamd at {} answered {:?}", address, reply))
+    }
+}
+
+// Runs the configured scanner over an upload before it is stored. None when
+// no scanner is configured. Every scan is logged and counted.
+pub async fn scan_upload(config: &AppConfig, filename: &str, data: &[u8]) -> Option<Result<Verdict, String>> {
+    let started = Instant::now();
+    let limit = Duration::from_secs(config.scan_timeout_secs);
+    let result = match (&config.scan_command, &config.clamd_address) {
+        (Some(command), _) => scan_with_command(command, &config.upload_dir, data, limit).await,
+        (None, Some(address)) => timeout(limit, scan_with_clamd(address, data)).await
+            .unwrap_or_else(|_| Err(format!("timed out after {} seconds", limit.as_secs()))),
+        (None, None) => return None,
+    };
+
+    let elapsed = started.elapsed().as_millis();
+    match &result {
+        Ok(Verdict::Clean) => {
+            CLEAN.fetch_add(1, Ordering::Relaxed);
+            println!("Virus scan of {}: clean ({} ms)", filename, elapsed);
+        },
+        Ok(Verdict::Infected(found)) => {
+            INFECTED.fetch_add(1, Ordering::Relaxed);
+            println!("Virus scan of {}: {} ({} ms)", filename, found, elapsed);
+        },
+        Err(e) => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Virus scan of {} failed: {} ({} ms)", filename, e, elapsed);
+        },
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body};
+    use tokio::net::TcpListener;
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    // Stands in for clamdscan: flags files containing "EICAR", and fails
+    // on files containing "BROKEN"
+    const SCANNER: &str = r#"
+if grep -q EICAR "$1"; then echo "$1: Eicar-Test-Signature FOUND"; exit 1; fi
+if grep -q BROKEN "$1"; then echo "database missing" >&2; exit 2; fi
+exit 0
+"#;
+
+    fn scanner_config(dir: &Path) -> AppConfig {
+        let script = dir.join("scanner.sh");
+        std::fs::write(&script, SCANNER).unwrap();
+        AppConfig { scan_command: Some(format!("sh {}", script.display())), ..test_config(dir) }
+    }
+
+    #[actix_web::test]
+    async fn a_scan_command_decides_by_its_exit_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = scanner_config(dir.path());
+        std::fs::create_dir_all(&config.upload_dir).unwrap();
+
+        assert!(matches!(scan_upload(&config, "a.png", b"harmless").await, Some(Ok(Verdict::Clean))));
+        match scan_upload(&config, "a.png", b"X5O EICAR test").await {
+            Some(Ok(Verdict::Infected(found))) => assert_eq!(found, "Eicar-Test-Signature"),
+            _ => panic!("expected the file to be flagged"),
+        }
+        let error = scan_upload(&config, "a.png", b"BROKEN").await.unwrap().err().unwrap();
+        assert!(error.contains("database missing"), "{}", error);
+        // The copies handed to the scanner are gone
+        assert_eq!(std::fs::read_dir(&config.upload_dir).unwrap().count(), 0);
+
+        let slow_script = dir.path().join("slow.sh");
+        std::fs::write(&slow_script, "sleep 5").unwrap();
+        let slow = AppConfig { scan_command: Some(format!("sh {}", slow_script.display())), scan_timeout_secs: 1, ..config.clone() };
+        assert!(scan_upload(&slow, "a.png", b"x").await.unwrap().err().unwrap().starts_with("timed out"));
+        assert!(scan_upload(&test_config(dir.path()), "a.png", b"EICAR").await.is_none());
+    }
+
+    // A one-connection clamd that gives `reply` to whatever it is sent
+    async fn fake_clamd(reply: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, b"zINSTREAM\0");
+            // Chunks until the zero-length one
+            loop {
+                let length = socket.read_u32().await.unwrap() as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; length];
+                socket.read_exact(&mut chunk).await.unwrap();
+            }
+            socket.write_all(reply).await.unwrap();
+        });
+        address
+    }
+
+    #[actix_web::test]
+    async fn clamd_answers_are_understood() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |address: String| AppConfig { clamd_address: Some(address), ..test_config(dir.path()) };
+
+        let clean = config(fake_clamd(b"stream: OK\0").await);
+        assert!(matches!(scan_upload(&clean, "a.png", &[7; CLAMD_CHUNK + 10]).await, Some(Ok(Verdict::Clean))));
+        let infected = config(fake_clamd(b"stream: Win.Test.EICAR_HDB-1 FOUND\0").await);
+        match scan_upload(&infected, "a.png", b"x").await {
+            Some(Ok(Verdict::Infected(found))) => assert_eq!(found, "Win.Test.EICAR_HDB-1"),
+            _ => panic!("expected the file to be flagged"),
+        }
+        let confused = config(fake_clamd(b"INSTREAM size limit exceeded. ERROR\0").await);
+        assert!(scan_upload(&confused, "a.png", b"x").await.unwrap().is_err());
+    }
+
+    #[actix_web::test]
+    async fn flagged_uploads_are_refused_and_failed_scans_as_configured() {
+        for reject_on_scan_error in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { reject_on_scan_error, ..scanner_config(dir.path()) });
+            let app = init_service(crate::app(&shared)).await;
+            let post = |contents: &[u8]| {
+                let mut image = png(8);
+                image.extend(contents);
+                form_post("/upload", multipart(&[("title", "t"), ("message", "m"), ("parent_id", "0")], Some(("a.png", "image/png", &image))), "10.0.0.1:4000").to_request()
+            };
+
+            let response = call_service(&app, post(b"EICAR")).await;
+            assert_eq!(response.status(), 422);
+            let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert!(page.contains("The file was flagged by the virus scanner"));
+            assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+
+            let status = call_service(&app, post(b"BROKEN")).await.status();
+            assert_eq!(status, if reject_on_scan_error { 503 } else { 303 });
+            assert_eq!(call_service(&app, post(b"")).await.status(), 303);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+
+use crate::config::AppConfig;
+use crate::embed::player_origins;
+use crate::storage::StorageKind;
+
+// scheme://host[:port] of a URL
+fn origin_of(url: &str) -> &str {
+    let path_start = url.find("://").map_or(0, |scheme_end| scheme_end + 3);
+    url[path_start..].find('/').map_or(url, |slash| &url[..path_start + slash])
+}
+
+// The policy used when `content_security_policy` isn't set: nothing but
+// this site's own scripts and styles, widened just enough for the
+// features that are on (embedded players, uploads on another host). No
+// page may be framed by another site.
+fn default_policy(config: &AppConfig) -> String {
+    let mut media_origins = Vec::new();
+    if let Some(base) = &config.media_base_url {
+        media_origins.push(origin_of(base));
+    }
+    if let (StorageKind::S3, Some(s3)) = (config.storage, &config.s3) {
+        media_origins.push(origin_of(s3.public_url.as_deref().unwrap_or(&s3.endpoint)));
+    }
+    let mut policy = String::from("default-src 'self'; img-src 'self' data:");
+    if !media_origins.is_empty() {
+        let origins = media_origins.join(" ");
+        policy.push_str(&format!(" {}; media-src 'self' {}", origins, origins));
+    }
+    policy.push_str("; style-src 'self'; frame-ancestors 'none'");
+    let players = player_origins(config);
+    if !players.is_empty() {
+        policy.push_str(&format!("; frame-src {}", players.join(" ")));
+    }
+    policy
+}
+
+// Headers added to every response, worked out once at startup
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl SecurityHeaders {
+    // The config has been validated, so the values are all legal headers
+    pub fn new(config: &AppConfig) -> Self {
+        let policy = config.content_security_policy.clone().unwrap_or_else(|| default_policy(config));
+        SecurityHeaders {
+            headers: vec![
+                (CONTENT_SECURITY_POLICY, HeaderValue::from_str(&policy).unwrap()),
+                (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+                // For browsers that predate frame-ancestors
+                (X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+                (REFERRER_POLICY, HeaderValue::from_str(&config.referrer_policy).unwrap()),
+            ],
+        }
+    }
+}
+
+// Handlers that set one of these headers themselves keep their own value
+pub async fn security_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let headers = req.app_data::<Data<SecurityHeaders>>().cloned();
+    let mut response = next.call(req).await?;
+    for (name, value) in headers.iter().flat_map(|headers| &headers.headers) {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use crate::storage::S3Config;
+    use crate::testing::{shared, test_config};
+
+    #[actix_web::test]
+    async fn the_index_carries_a_content_security_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { video_embeds: false, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(response.status(), 200);
+        let headers = response.headers();
+        assert_eq!(headers.get(CONTENT_SECURITY_POLICY).unwrap(), "default-src 'self'; img-src 'self' data:; style-src 'self'; frame-ancestors 'none'");
+        assert_eq!(headers.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(headers.get(REFERRER_POLICY).unwrap(), "same-origin");
+    }
+
+    #[actix_web::test]
+    async fn a_configured_policy_replaces_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = "default-src 'none'; img-src 'self'";
+        let shared = shared(AppConfig { content_security_policy: Some(policy.to_string()), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        for uri in ["/", "/feed.xml", "/no-such-page"] {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.headers().get(CONTENT_SECURITY_POLICY).unwrap(), policy, "{}", uri);
+        }
+
+        let blank = AppConfig { content_security_policy: Some("  ".to_string()), ..test_config(dir.path()) };
+        assert!(blank.validate().iter().any(|problem| problem.starts_with("content_security_policy")));
+    }
+
+    #[actix_web::test]
+    async fn framing_is_refused_and_the_referrer_policy_is_configurable() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { referrer_policy: "no-referrer".to_string(), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        for uri in ["/", "/healthz", "/no-such-page"] {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            let headers = response.headers();
+            assert_eq!(headers.get(X_FRAME_OPTIONS).unwrap(), "DENY", "{}", uri);
+            assert!(headers.get(CONTENT_SECURITY_POLICY).unwrap().to_str().unwrap().contains("frame-ancestors 'none'"), "{}", uri);
+            assert_eq!(headers.get(REFERRER_POLICY).unwrap(), "no-referrer", "{}", uri);
+        }
+
+        for bad in ["", "same-origin\nX-Injected: 1"] {
+            let config = AppConfig { referrer_policy: bad.to_string(), ..test_config(dir.path()) };
+            assert!(config.validate().iter().any(|problem| problem.starts_with("referrer_policy")), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn the_default_policy_widens_for_media_hosts_and_players() {
+        let policy = |config: AppConfig| default_policy(&AppConfig { video_embeds: false, ..config });
+
+        let cdn = policy(AppConfig { media_base_url: Some("https://cdn.example.com/media/".to_string()), ..AppConfig::default() });
+        assert_eq!(cdn, "default-src 'self'; img-src 'self' data: https://cdn.example.com; media-src 'self' https://cdn.example.com; style-src 'self'; frame-ancestors 'none'");
+
+        let s3 = |public_url: Option<&str>| AppConfig {
+            storage: StorageKind::S3,
+            s3: Some(S3Config {
+                endpoint: "http://minio.local:9000".to_string(),
+                bucket: "uploads".to_string(),
+                region: "us-east-1".to_string(),
+                access_key: "key".to_string(),
+                secret_key: "secret".to_string(),
+                public_url: public_url.map(str::to_string),
+            }),
+            ..AppConfig::default()
+        };
+        assert!(policy(s3(None)).contains("img-src 'self' data: http://minio.local:9000; media-src 'self' http://minio.local:9000;"));
+        assert!(policy(s3(Some("https://files.example.org/uploads"))).contains("img-src 'self' data: https://files.example.org; media-src 'self' https://files.example.org;"));
+
+        let players = default_policy(&AppConfig { video_embeds: true, ..AppConfig::default() });
+        assert!(players.ends_with("; frame-src https://www.youtube-nocookie.com https://player.vimeo.com"), "{}", players);
+        let vimeo_only = default_policy(&AppConfig { video_embeds: true, embed_hosts: vec!["vimeo.com".to_string()], ..AppConfig::default() });
+        assert!(vimeo_only.ends_with("; frame-src https://player.vimeo.com"), "{}", vimeo_only);
+        assert!(!policy(AppConfig::default()).contains("frame-src"));
+    }
+
+    #[actix_web::test]
+    async fn a_handler_keeps_its_own_headers() {
+        let headers = Data::new(SecurityHeaders::new(&AppConfig::default()));
+        let app = init_service(
+            App::new()
+                .app_data(headers)
+                .wrap(from_fn(security_headers))
+                .route("/framed", web::get().to(|| async { HttpResponse::Ok().insert_header((X_FRAME_OPTIONS, "SAMEORIGIN")).finish() }))
+                .route("/plain", web::get().to(HttpResponse::Ok)),
+        ).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/framed").to_request()).await;
+        assert_eq!(response.headers().get(X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+        assert_eq!(response.headers().get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        let response = call_service(&app, TestRequest::get().uri("/plain").to_request()).await;
+        assert_eq!(response.headers().get(X_FRAME_OPTIONS).unwrap(), "DENY");
+    }
+}
@@ -0,0 +1,247 @@
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::Cookie;
+use actix_web::dev::Payload;
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponseBuilder};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+
+use crate::signed_cookie::CookieKey;
+
+const PREFS_COOKIE: &str = "prefs";
+
+// The cookies each preference was kept in before they moved into one.
+// Read only while a visitor has no prefs cookie yet.
+const OLD_LANG_COOKIE: &str = "lang";
+const OLD_TZ_COOKIE: &str = "tz";
+const OLD_SORT_COOKIE: &str = "sort";
+const OLD_POSTER_COOKIE: &str = "poster";
+
+// How many of the visitor's own posts, and how many threads' last visits,
+// the cookie remembers. The oldest entries are dropped first, keeping the
+// cookie well under the 4 KB browsers allow.
+const RECENT_POSTS: usize = 50;
+const SEEN_THREADS: usize = 40;
+
+// A visitor's preferences, kept in a single signed cookie. Extracting it
+// never fails: a missing, unreadable or tampered cookie gives the
+// defaults. Values are checked where they are used, not here.
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prefs {
+    // UTC offset in minutes chosen at /tz/
+    pub tz: i32,
+    // Language code chosen at /lang/
+    pub lang: Option<String>,
+    // Board order last picked with ?sort=
+    pub sort: Option<String>,
+    // Token from the posters table, which dates the visitor's first visit
+    pub poster: Option<String>,
+    // Unix time of the last look at each thread, by thread id
+    pub seen: BTreeMap<i32, i64>,
+    // Ids of posts made from this browser, oldest first, marked "(You)"
+    pub recent_posts: Vec<i32>,
+}
+
+impl Prefs {
+    pub fn of(req: &HttpRequest) -> Self {
+        let Some(key) = req.app_data::<Data<CookieKey>>() else {
+            return Prefs::default();
+        };
+        let mut prefs = if req.cookie(PREFS_COOKIE).is_some() {
+            key.verified(req, PREFS_COOKIE)
+                .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+                .and_then(|json| serde_json::from_slice(&json).ok())
+                .unwrap_or_default()
+        } else {
+            Prefs {
+                tz: key.verified(req, OLD_TZ_COOKIE).and_then(|value| value.parse().ok()).unwrap_or(0),
+                lang: req.cookie(OLD_LANG_COOKIE).map(|cookie| cookie.value().to_string()),
+                sort: req.cookie(OLD_SORT_COOKIE).map(|cookie| cookie.value().to_string()),
+                ..Prefs::default()
+            }
+        };
+        // The poster cookie outlived the others, so a visitor can carry it
+        // alongside a prefs cookie
+        if prefs.poster.is_none() {
+            prefs.poster = req.cookie(OLD_POSTER_COOKIE).map(|cookie| cookie.value().to_string());
+        }
+        prefs
+    }
+
+    pub fn is_own(&self, post_id: i32) -> bool {
+        self.recent_posts.contains(&post_id)
+    }
+
+    pub fn add_post(&mut self, post_id: i32) {
+        self.recent_posts.push(post_id);
+        let excess = self.recent_posts.len().saturating_sub(RECENT_POSTS);
+        self.recent_posts.drain(..excess);
+    }
+
+    pub fn mark_seen(&mut self, thread_id: i32, now: i64) {
+        self.seen.insert(thread_id, now);
+        while self.seen.len() > SEEN_THREADS {
+            let oldest = self.seen.iter().min_by_key(|(_, &time)| time).map(|(&thread, _)| thread).unwrap();
+            self.seen.remove(&oldest);
+        }
+    }
+
+    fn cookie(&self, key: &CookieKey) -> Cookie<'static> {
+        let value = URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap());
+        key.sign(Cookie::build(PREFS_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .max_age(Duration::days(365))
+            .finish())
+    }
+}
+
+impl FromRequest for Prefs {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Prefs::of(req)))
+    }
+}
+
+// Applies `change` to the visitor's preferences and sets the updated
+// cookie on `response`, dropping the old single-preference cookies.
+// Nothing is set when `change` leaves everything as it was. A response
+// carries one prefs cookie, so handlers make all their changes in one call.
+pub fn update_prefs(req: &HttpRequest, response: &mut HttpResponseBuilder, change: impl FnOnce(&mut Prefs)) {
+    for cookie in prefs_cookies(req, change) {
+        response.cookie(cookie);
+    }
+}
+
+// The cookies update_prefs sets, for a response that's already built
+pub fn prefs_cookies(req: &HttpRequest, change: impl FnOnce(&mut Prefs)) -> Vec<Cookie<'static>> {
+    let key = req.app_data::<Data<CookieKey>>().expect("the cookie key is registered with the app");
+    let old = Prefs::of(req);
+    let mut prefs = old.clone();
+    change(&mut prefs);
+    let old_cookies: Vec<&str> = [OLD_LANG_COOKIE, OLD_TZ_COOKIE, OLD_SORT_COOKIE, OLD_POSTER_COOKIE]
+        .into_iter()
+        .filter(|&name| req.cookie(name).is_some())
+        .collect();
+    if prefs == old && old_cookies.is_empty() {
+        return Vec::new();
+    }
+    let mut cookies = vec![prefs.cookie(key)];
+    for name in old_cookies {
+        let mut removal = Cookie::build(name, "").path("/").finish();
+        removal.make_removal();
+        cookies.push(removal);
+    }
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    use crate::testing::test_db;
+
+    fn request(key: &Data<CookieKey>, cookies: &[Cookie<'static>]) -> HttpRequest {
+        let mut request = TestRequest::default().app_data(key.clone());
+        for cookie in cookies {
+            request = request.cookie(cookie.clone());
+        }
+        request.to_http_request()
+    }
+
+    #[test]
+    fn prefs_round_trip_through_the_cookie() {
+        let key = Data::new(CookieKey::load(&test_db()).unwrap());
+        assert!(Prefs::of(&request(&key, &[])) == Prefs::default());
+
+        let cookies = prefs_cookies(&request(&key, &[]), |prefs| {
+            prefs.tz = -300;
+            prefs.lang = Some("de".to_string());
+            prefs.add_post(42);
+            prefs.mark_seen(7, 1_700_000_000);
+        });
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].name(), PREFS_COOKIE);
+        assert_eq!(cookies[0].http_only(), Some(true));
+
+        let prefs = Prefs::of(&request(&key, &cookies));
+        assert_eq!(prefs.tz, -300);
+        assert_eq!(prefs.lang.as_deref(), Some("de"));
+        assert!(prefs.is_own(42) && !prefs.is_own(43));
+        assert_eq!(prefs.seen.get(&7), Some(&1_700_000_000));
+
+        // Nothing to set when nothing changes
+        assert!(prefs_cookies(&request(&key, &cookies), |prefs| prefs.tz = -300).is_empty());
+    }
+
+    #[test]
+    fn a_tampered_cookie_gives_the_defaults() {
+        let key = Data::new(CookieKey::load(&test_db()).unwrap());
+        let cookie = prefs_cookies(&request(&key, &[]), |prefs| prefs.add_post(1)).remove(0);
+
+        // Claiming someone else's post as your own, keeping the signature
+        let encode = |prefs: &Prefs| URL_SAFE_NO_PAD.encode(serde_json::to_vec(prefs).unwrap());
+        let signature = cookie.value().strip_suffix(&encode(&Prefs { recent_posts: vec![1], ..Prefs::default() })).unwrap();
+        let forged = encode(&Prefs { recent_posts: vec![1, 99], ..Prefs::default() });
+        let tampered = Cookie::new(PREFS_COOKIE, format!("{}{}", signature, forged));
+        assert!(Prefs::of(&request(&key, &[tampered])) == Prefs::default());
+
+        for junk in ["", "not signed", "%%%"] {
+            assert!(Prefs::of(&request(&key, &[Cookie::new(PREFS_COOKIE, junk)])) == Prefs::default(), "{:?}", junk);
+        }
+        // Signed by another board
+        let other = Data::new(CookieKey::load(&test_db()).unwrap());
+        assert!(Prefs::of(&request(&other, &[cookie])) == Prefs::default());
+    }
+
+    #[test]
+    fn old_single_cookies_move_into_prefs() {
+        let key = Data::new(CookieKey::load(&test_db()).unwrap());
+        let old = [
+            Cookie::new(OLD_LANG_COOKIE, "de"),
+            key.sign(Cookie::new(OLD_TZ_COOKIE, "60")),
+            Cookie::new(OLD_SORT_COOKIE, "replies"),
+            Cookie::new(OLD_POSTER_COOKIE, "token"),
+        ];
+        let prefs = Prefs::of(&request(&key, &old));
+        assert_eq!((prefs.lang.as_deref(), prefs.tz, prefs.sort.as_deref(), prefs.poster.as_deref()), (Some("de"), 60, Some("replies"), Some("token")));
+
+        // The first update writes prefs and removes the old cookies
+        let cookies = prefs_cookies(&request(&key, &old), |_| {});
+        assert_eq!(cookies[0].name(), PREFS_COOKIE);
+        let removed: Vec<&str> = cookies[1..].iter().filter(|cookie| cookie.value().is_empty()).map(Cookie::name).collect();
+        assert_eq!(removed, [OLD_LANG_COOKIE, OLD_TZ_COOKIE, OLD_SORT_COOKIE, OLD_POSTER_COOKIE]);
+        assert!(Prefs::of(&request(&key, &cookies[..1])) == prefs);
+
+        // An unsigned old timezone is ignored
+        let forged = Prefs::of(&request(&key, &[Cookie::new(OLD_TZ_COOKIE, "60")]));
+        assert_eq!(forged.tz, 0);
+    }
+
+    #[test]
+    fn the_cookie_keeps_only_recent_history() {
+        let mut prefs = Prefs::default();
+        for id in 0..RECENT_POSTS as i32 + 10 {
+            prefs.add_post(id);
+        }
+        assert_eq!(prefs.recent_posts.len(), RECENT_POSTS);
+        assert!(!prefs.is_own(9) && prefs.is_own(10));
+
+        for thread in 0..SEEN_THREADS as i32 + 5 {
+            prefs.mark_seen(thread, 1000 + thread as i64);
+        }
+        assert_eq!(prefs.seen.len(), SEEN_THREADS);
+        assert!(!prefs.seen.contains_key(&4) && prefs.seen.contains_key(&5));
+
+        // Even full, it stays well under what browsers allow
+        let key = CookieKey::load(&test_db()).unwrap();
+        assert!(prefs.cookie(&key).to_string().len() < 4096);
+    }
+}
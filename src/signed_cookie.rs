@@ -0,0 +1,82 @@
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use rusqlite::{params, Connection, OptionalExtension};
+
+// Signs cookies whose values we need to trust. The key is generated on
+// first start and kept in settings, so cookies survive restarts.
+pub struct CookieKey(Key);
+
+impl CookieKey {
+    pub fn load(conn: &Connection) -> rusqlite::Result<Self> {
+        let stored: Option<String> = conn.query_row("SELECT value FROM settings WHERE key = 'cookie_key'", [], |row| row.get(0)).optional()?;
+        let key = stored
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .and_then(|bytes| Key::try_from(bytes.as_slice()).ok());
+        if let Some(key) = key {
+            return Ok(CookieKey(key));
+        }
+
+        let key = Key::generate();
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('cookie_key', ?1)",
+            params![STANDARD.encode(key.master())],
+        )?;
+        Ok(CookieKey(key))
+    }
+
+    pub fn sign(&self, cookie: Cookie<'static>) -> Cookie<'static> {
+        let name = cookie.name().to_string();
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&self.0).add(cookie);
+        jar.get(&name).cloned().unwrap()
+    }
+
+    // Value of cookie `name` if it carries a valid signature
+    pub fn verified(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(req.cookie(name)?);
+        jar.signed(&self.0).get(name).map(|cookie| cookie.value().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    use crate::testing::test_db;
+
+    fn request_with(cookie: Cookie<'static>) -> HttpRequest {
+        TestRequest::default().cookie(cookie).to_http_request()
+    }
+
+    #[test]
+    fn signed_values_read_back_and_tampered_ones_dont() {
+        let key = CookieKey::load(&test_db()).unwrap();
+        let signed = key.sign(Cookie::new("tz", "120"));
+        assert_ne!(signed.value(), "120");
+        assert_eq!(key.verified(&request_with(signed.clone()), "tz").as_deref(), Some("120"));
+
+        // The value changed after signing
+        let (signature, _) = signed.value().split_at(signed.value().len() - 3);
+        let tampered = Cookie::new("tz", format!("{}-60", signature));
+        assert_eq!(key.verified(&request_with(tampered), "tz"), None);
+        // Unsigned, or missing
+        assert_eq!(key.verified(&request_with(Cookie::new("tz", "120")), "tz"), None);
+        assert_eq!(key.verified(&TestRequest::default().to_http_request(), "tz"), None);
+
+        // Another board's key doesn't accept it
+        let other = CookieKey::load(&test_db()).unwrap();
+        assert_eq!(other.verified(&request_with(signed), "tz"), None);
+    }
+
+    #[test]
+    fn the_key_survives_a_restart() {
+        let conn = test_db();
+        let signed = CookieKey::load(&conn).unwrap().sign(Cookie::new("tz", "-300"));
+        let reloaded = CookieKey::load(&conn).unwrap();
+        assert_eq!(reloaded.verified(&request_with(signed), "tz").as_deref(), Some("-300"));
+    }
+}
@@ -0,0 +1,248 @@
+use actix_web::web::{self, Data};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Mutex, RwLock};
+
+use crate::admin::Admin;
+use crate::config::AppConfig;
+use crate::escape_html;
+use crate::moderation::log_mod_action;
+
+// Branding shown on every page, plus the moderator announcement. The
+// announcement lives in the settings table and is cached here so pages
+// don't have to read it on every request.
+pub struct Site {
+    name: String,
+    tagline: String,
+    footer: String,
+    announcement: RwLock<String>,
+    // Posting turned off for maintenance; starts from the config and is
+    // switched by moderators until the next restart
+    read_only: RwLock<bool>,
+}
+
+impl Site {
+    pub fn load(config: &AppConfig, conn: &Connection) -> Self {
+        let announcement = conn.query_row("SELECT value FROM settings WHERE key = 'announcement'", [], |row| row.get(0))
+            .optional()
+            .unwrap()
+            .unwrap_or_default();
+        Site {
+            name: config.site_name.clone(),
+            tagline: config.tagline.clone(),
+            footer: config.footer_text.clone(),
+            announcement: RwLock::new(announcement),
+            read_only: RwLock::new(config.read_only),
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        *self.read_only.read().unwrap()
+    }
+
+    // Template values for the page title, header and footer
+    pub fn chrome(&self) -> [(&'static str, String); 3] {
+        let title_suffix = if self.name.is_empty() { String::new() } else { format!(" - {}", escape_html(&self.name)) };
+        let header = if self.name.is_empty() && self.tagline.is_empty() {
+            String::new()
+        } else {
+            format!(
+                r#"<header class="site-header"><a class="site-name" href="/">{}</a><div class="site-tagline">{}</div></header>"#,
+                escape_html(&self.name), escape_html(&self.tagline)
+            )
+        };
+        let footer = if self.footer.is_empty() {
+            String::new()
+        } else {
+            format!(r#"<footer class="site-footer">{}</footer>"#, escape_html(&self.footer))
+        };
+        [("SITE_TITLE", title_suffix), ("SITE_HEADER", header), ("SITE_FOOTER", footer)]
+    }
+
+    // Banner for the board and thread pages; empty when no announcement is set
+    pub fn announcement_banner(&self) -> String {
+        let announcement = self.announcement.read().unwrap();
+        if announcement.trim().is_empty() {
+            String::new()
+        } else {
+            format!(r#"<div class="announcement">{}</div>"#, escape_html(&announcement))
+        }
+    }
+}
+
+pub fn site(req: &HttpRequest) -> Data<Site> {
+    req.app_data::<Data<Site>>().cloned().expect("site is registered with the app")
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementForm {
+    #[serde(default)]
+    text: String,
+}
+
+// Sets the announcement banner; an empty text removes it
+pub async fn set_announcement(admin: Admin, conn: web::Data<Mutex<Connection>>, site: Data<Site>, form: web::Form<AnnouncementForm>) -> Result<HttpResponse> {
+    let text = form.text.trim();
+    let conn = conn.lock().unwrap();
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES ('announcement', ?1) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![text],
+    ).unwrap();
+    log_mod_action(&conn, Some(&admin.name), "announcement", None, text).unwrap();
+    *site.announcement.write().unwrap() = text.to_string();
+
+    let message = if text.is_empty() { "Announcement removed." } else { "Announcement updated." };
+    Ok(HttpResponse::Ok().body(message))
+}
+
+#[derive(Deserialize)]
+pub struct ReadOnlyForm {
+    #[serde(default)]
+    enabled: bool,
+}
+
+// Turns posting off or back on while the board stays readable
+pub async fn set_read_only(admin: Admin, conn: web::Data<Mutex<Connection>>, site: Data<Site>, form: web::Form<ReadOnlyForm>) -> Result<HttpResponse> {
+    *site.read_only.write().unwrap() = form.enabled;
+    log_mod_action(&conn.lock().unwrap(), Some(&admin.name), "read-only", None, if form.enabled { "on" } else { "off" }).unwrap();
+
+    let message = if form.enabled { "Posting disabled; the board is read-only." } else { "Posting enabled again." };
+    Ok(HttpResponse::Ok().body(message))
+}
+
+// For load balancers and monitoring: whether the database answers, and
+// whether posting is currently off
+pub async fn healthz(conn: web::Data<Mutex<Connection>>, site: Data<Site>) -> Result<HttpResponse> {
+    let database_ok = conn.lock().unwrap().query_row("SELECT 1", [], |_| Ok(())).is_ok();
+    let body = json!({
+        "status": if database_ok { "ok" } else { "error" },
+        "read_only": site.read_only(),
+    });
+    Ok(if database_ok { HttpResponse::Ok().json(body) } else { HttpResponse::ServiceUnavailable().json(body) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, png, shared, test_config, test_db};
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    #[actix_web::test]
+    async fn branding_and_the_announcement_show_on_the_board() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            site_name: "Tea & Toast".to_string(),
+            tagline: "All about breakfast".to_string(),
+            footer_text: "Run by volunteers".to_string(),
+            admin_password: Some("letmein".to_string()),
+            ..test_config(dir.path())
+        });
+        let app = init_service(crate::app(&shared)).await;
+        let board = || TestRequest::get().uri("/").to_request();
+        let announce = |text: &str| TestRequest::post()
+            .uri("/admin/announcement")
+            .insert_header((header::AUTHORIZATION, "Bearer letmein"))
+            .set_form([("text", text)])
+            .to_request();
+
+        let page = String::from_utf8(call_and_read_body(&app, board()).await.to_vec()).unwrap();
+        assert!(page.contains(" - Tea &amp; Toast</title>"));
+        assert!(page.contains(r#"<a class="site-name" href="/">Tea &amp; Toast</a><div class="site-tagline">All about breakfast</div>"#));
+        assert!(page.contains(r#"<footer class="site-footer">Run by volunteers</footer>"#));
+        assert!(!page.contains(r#"class="announcement""#));
+
+        // Anyone else can't set it
+        let response = call_service(&app, TestRequest::post().uri("/admin/announcement").set_form([("text", "hi")]).to_request()).await;
+        assert_eq!(response.status(), 401);
+
+        assert_eq!(call_service(&app, announce("Back <soon>")).await.status(), 200);
+        let page = String::from_utf8(call_and_read_body(&app, board()).await.to_vec()).unwrap();
+        assert!(page.contains(r#"<div class="announcement">Back &lt;soon&gt;</div>"#));
+        // Kept for the next start
+        assert_eq!(Site::load(&shared.config, &shared.conn.lock().unwrap()).announcement_banner(), r#"<div class="announcement">Back &lt;soon&gt;</div>"#);
+
+        assert_eq!(call_service(&app, announce("  ")).await.status(), 200);
+        let page = String::from_utf8(call_and_read_body(&app, board()).await.to_vec()).unwrap();
+        assert!(!page.contains(r#"class="announcement""#));
+    }
+
+    #[test]
+    fn unset_branding_adds_nothing() {
+        let site = Site::load(&AppConfig::default(), &test_db());
+        assert!(site.chrome().iter().all(|(_, value)| value.is_empty()));
+        assert_eq!(site.announcement_banner(), "");
+    }
+
+    #[actix_web::test]
+    async fn read_only_mode_stops_posting_but_not_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig {
+            admin_password: Some("letmein".to_string()),
+            ..test_config(dir.path())
+        });
+        let app = init_service(crate::app(&shared)).await;
+        let thread = || form_post("/upload", multipart(&[("title", "op"), ("message", "thread"), ("parent_id", "0")], None), PEER).to_request();
+        let toggle = |enabled: &str| TestRequest::post()
+            .uri("/admin/read-only")
+            .insert_header((header::AUTHORIZATION, "Bearer letmein"))
+            .set_form([("enabled", enabled)])
+            .to_request();
+        let page = |uri: &str| TestRequest::get().uri(uri).to_request();
+        let health = |body: actix_web::web::Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap()["read_only"].clone();
+
+        assert_eq!(call_service(&app, thread()).await.status(), 303);
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(health(call_and_read_body(&app, page("/healthz")).await), false);
+
+        // Only an admin can switch it
+        let response = call_service(&app, TestRequest::post().uri("/admin/read-only").set_form([("enabled", "true")]).to_request()).await;
+        assert_eq!(response.status(), 401);
+        assert!(!shared.site.read_only());
+
+        assert_eq!(call_service(&app, toggle("true")).await.status(), 200);
+        assert_eq!(health(call_and_read_body(&app, page("/healthz")).await), true);
+
+        let response = call_service(&app, thread()).await;
+        assert_eq!(response.status(), 503);
+        let body = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("The board is in maintenance; posting is temporarily disabled. You can keep reading in the meantime."));
+        let reply = multipart(&[("title", ""), ("message", "late"), ("parent_id", &thread_id.to_string())], None);
+        assert_eq!(call_service(&app, form_post("/upload", reply, PEER).to_request()).await.status(), 503);
+        let response = call_service(&app, form_post("/api/upload", multipart(&[("alt", "")], Some(("drop.png", "image/png", &png(1)))), PEER).to_request()).await;
+        assert_eq!(response.status(), 503);
+        let count: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // Pages still load, with the notice in place of the forms
+        for uri in ["/".to_string(), format!("/post/{}", thread_id)] {
+            let response = call_service(&app, page(&uri)).await;
+            assert_eq!(response.status(), 200);
+            let body = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+            assert!(body.contains("The board is in maintenance; posting is temporarily disabled."));
+            assert!(!body.contains("<textarea"));
+        }
+
+        assert_eq!(call_service(&app, toggle("false")).await.status(), 200);
+        assert_eq!(health(call_and_read_body(&app, page("/healthz")).await), false);
+        assert_eq!(call_service(&app, thread()).await.status(), 303);
+
+        let logged: Vec<String> = shared.conn.lock().unwrap()
+            .prepare("SELECT details FROM mod_actions WHERE action = 'read-only' ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(logged, ["on", "off"]);
+    }
+
+    #[test]
+    fn read_only_can_start_switched_on() {
+        let config = AppConfig { read_only: true, ..AppConfig::default() };
+        assert!(Site::load(&config, &test_db()).read_only());
+        assert!(!Site::load(&AppConfig::default(), &test_db()).read_only());
+    }
+}
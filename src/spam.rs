@@ -0,0 +1,201 @@
+use serde::Deserialize;
+
+use crate::config::AppConfig;
+
+// Points a post earns for each link, each run of one character repeated
+// REPEAT_RUN times or more, and each listed spam word it contains
+const LINK_POINTS: u32 = 1;
+const REPEAT_POINTS: u32 = 2;
+const WORD_POINTS: u32 = 3;
+const REPEAT_RUN: usize = 10;
+
+// What happens to a post with more than `max_links` links
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkLimit {
+    Reject,
+    // Posted shadow-hidden, like spam, with a note in the moderation log
+    Hide,
+}
+
+// Links in a post: bare ones, and ones inside Markdown or HTML such as
+// "[text](https://...)" or "<a href=\"https://...\">"
+pub fn link_count(text: &str) -> usize {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '(' | '[' | '<' | '"' | '\'' | '='))
+        .filter(|word| {
+            let word = word.trim_start_matches(|c: char| !c.is_alphanumeric()).to_ascii_lowercase();
+            word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+        })
+        .count()
+}
+
+// Runs such as "!!!!!!!!!!" or "aaaaaaaaaaaa"; whitespace doesn't count
+fn repeated_runs(text: &str) -> usize {
+    let mut runs = 0;
+    let mut previous = None;
+    let mut length = 0;
+    for c in text.chars() {
+        if Some(c) == previous {
+            length += 1;
+        } else {
+            previous = Some(c);
+            length = 1;
+        }
+        if length == REPEAT_RUN && !c.is_whitespace() {
+            runs += 1;
+        }
+    }
+    runs
+}
+
+// How spammy a new post looks. Matching is case-insensitive and each
+// spam word counts once however often it appears.
+pub fn spam_score(config: &AppConfig, title: &str, message: &str) -> u32 {
+    let text = format!("{}\n{}", title, message);
+    let lowercase = text.to_lowercase();
+    let words = config.spam_words.iter()
+        .filter(|word| !word.trim().is_empty() && lowercase.contains(&word.trim().to_lowercase()))
+        .count();
+    link_count(&text) as u32 * LINK_POINTS + repeated_runs(&text) as u32 * REPEAT_POINTS + words as u32 * WORD_POINTS
+}
+
+// Whether a post with `links` links is over the `max_links` limit
+pub fn too_many_links(config: &AppConfig, links: usize) -> bool {
+    config.max_links > 0 && links > config.max_links
+}
+
+// Whether a post scoring `score` is shadow-hidden: kept and shown to its
+// poster, but left out for everyone else
+pub fn is_spam(config: &AppConfig, score: u32) -> bool {
+    config.spam_score_threshold > 0 && score >= config.spam_score_threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use std::net::SocketAddr;
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    const AUTHOR: &str = "10.0.0.1:40000";
+    const READER: &str = "10.0.0.2:40000";
+
+    #[test]
+    fn links_runs_and_spam_words_add_up() {
+        let config = AppConfig { spam_words: vec!["Casino".to_string(), " ".to_string()], ..AppConfig::default() };
+        assert_eq!(spam_score(&config, "hello", "just talking"), 0);
+        assert_eq!(spam_score(&config, "deals", "see https://a.example and [here](http://b.example) or www.c.example"), 3);
+        assert_eq!(spam_score(&config, "WIN!!!!!!!!!!", "aaaaaaaaaaaaaaaaaaaa"), 4);
+        // Spaces don't make a run, and a word counts once
+        assert_eq!(spam_score(&config, "title", &format!("{}casino CASINO casino", " ".repeat(30))), 3);
+
+        assert!(!is_spam(&config, 100));
+        let strict = AppConfig { spam_score_threshold: 4, ..config };
+        assert!(!is_spam(&strict, 3));
+        assert!(is_spam(&strict, 4));
+    }
+
+    #[actix_web::test]
+    async fn spam_is_hidden_from_everyone_but_its_author() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { spam_score_threshold: 5, spam_words: vec!["casino".to_string()], ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let post = |title: &str, message: &str, parent: i32| multipart(&[("title", title), ("message", message), ("parent_id", &parent.to_string())], None);
+        let id_of = |title: &str| -> i32 { shared.conn.lock().unwrap().query_row("SELECT id FROM files WHERE title = ?1", [title], |row| row.get(0)).unwrap() };
+        let page = |uri: String, peer: &str| TestRequest::get().uri(&uri).peer_addr(peer.parse::<SocketAddr>().unwrap()).to_request();
+
+        assert_eq!(call_service(&app, form_post("/upload", post("honest", "a real thread", 0), READER).to_request()).await.status(), 303);
+        let honest = id_of("honest");
+        let spam = post("FREE MONEY", "casino https://a.example https://b.example !!!!!!!!!!!!", 0);
+        assert_eq!(call_service(&app, form_post("/upload", spam, AUTHOR).to_request()).await.status(), 303);
+        let spam_thread = id_of("FREE MONEY");
+        let spam_reply = post("re", "casino https://c.example !!!!!!!!!!!!", honest);
+        assert_eq!(call_service(&app, form_post("/upload", spam_reply, AUTHOR).to_request()).await.status(), 303);
+
+        let as_reader = String::from_utf8(call_and_read_body(&app, page("/".to_string(), READER)).await.to_vec()).unwrap();
+        assert!(as_reader.contains("honest"));
+        assert!(!as_reader.contains("FREE MONEY"));
+        let as_author = String::from_utf8(call_and_read_body(&app, page("/".to_string(), AUTHOR)).await.to_vec()).unwrap();
+        assert!(as_author.contains("FREE MONEY"));
+
+        assert_eq!(call_service(&app, page(format!("/post/{}", spam_thread), READER)).await.status(), 404);
+        assert_eq!(call_service(&app, page(format!("/post/{}", spam_thread), AUTHOR)).await.status(), 200);
+        let thread = |peer: &str| page(format!("/post/{}", honest), peer);
+        let as_reader = String::from_utf8(call_and_read_body(&app, thread(READER)).await.to_vec()).unwrap();
+        assert!(!as_reader.contains("c.example"));
+        let as_author = String::from_utf8(call_and_read_body(&app, thread(AUTHOR)).await.to_vec()).unwrap();
+        assert!(as_author.contains("c.example"));
+
+        let conn = shared.conn.lock().unwrap();
+        let replies: i32 = conn.query_row("SELECT reply_count FROM files WHERE id = ?1", [honest], |row| row.get(0)).unwrap();
+        assert_eq!(replies, 0);
+        let logged: i32 = conn.query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'spam-hide'", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 2);
+    }
+
+    #[actix_web::test]
+    async fn a_hidden_post_that_cant_be_logged_is_not_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { spam_score_threshold: 5, spam_words: vec!["casino".to_string()], ..test_config(dir.path()) });
+        shared.conn.lock().unwrap().execute_batch("DROP TABLE mod_actions").unwrap();
+        let app = init_service(crate::app(&shared)).await;
+
+        let spam = multipart(&[("title", "FREE MONEY"), ("message", "casino https://a.example https://b.example !!!!!!!!!!!!"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", spam, AUTHOR).to_request()).await.status(), 500);
+        let posts: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+    }
+
+    #[test]
+    fn bare_markdown_and_html_links_are_counted() {
+        assert_eq!(link_count("no links here, not even example.com"), 0);
+        assert_eq!(link_count("https://a.example"), 1);
+        assert_eq!(link_count("see www.b.example, or (http://c.example)."), 2);
+        assert_eq!(link_count("[text](https://d.example) and <a href=\"https://e.example\">e</a>"), 2);
+        assert_eq!(link_count("<a href='HTTP://F.EXAMPLE'>f</a> [g](www.g.example)"), 2);
+    }
+
+    #[actix_web::test]
+    async fn posts_over_the_link_limit_are_refused_or_hidden() {
+        let five = "https://a.example www.b.example [c](https://c.example) <a href=\"https://d.example\">d</a> (http://e.example)";
+        let three = "https://a.example www.b.example [c](https://c.example)";
+        for limit in [LinkLimit::Reject, LinkLimit::Hide] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { max_links: 3, link_limit: limit, ..test_config(dir.path()) });
+            let app = init_service(crate::app(&shared)).await;
+            let post = |title: &str, message: &str| form_post("/upload", multipart(&[("title", title), ("message", message), ("parent_id", "0")], None), AUTHOR).to_request();
+            let hidden = |title: &str| shared.conn.lock().unwrap().query_row("SELECT hidden FROM files WHERE title = ?1", [title], |row| row.get::<_, bool>(0)).ok();
+
+            assert_eq!(call_service(&app, post("three", three)).await.status(), 303);
+            assert_eq!(hidden("three"), Some(false));
+
+            let response = call_service(&app, post("five", five)).await;
+            let logged: i32 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM mod_actions WHERE action = 'link-limit-hide'", [], |row| row.get(0)).unwrap();
+            match limit {
+                LinkLimit::Reject => {
+                    assert_eq!(response.status(), 422);
+                    let page = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+                    assert!(page.contains("Posts may contain at most 3 links."));
+                    assert_eq!(hidden("five"), None);
+                    assert_eq!(logged, 0);
+                },
+                LinkLimit::Hide => {
+                    assert_eq!(response.status(), 303);
+                    assert_eq!(hidden("five"), Some(true));
+                    assert_eq!(logged, 1);
+                },
+            }
+            // Title links count too
+            let response = call_service(&app, post("www.x.example", three)).await;
+            assert_eq!(response.status(), if limit == LinkLimit::Reject { 422 } else { 303 });
+        }
+
+        // No limit at all
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { max_links: 0, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+        let body = multipart(&[("title", "five"), ("message", five), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", body, AUTHOR).to_request()).await.status(), 303);
+    }
+}
@@ -0,0 +1,65 @@
+// Shared pieces for the tests: a fresh in-memory database, a config
+// writing under a temporary directory, the app's state built on them, and
+// multipart bodies like the post forms send.
+use actix_web::http::header;
+use actix_web::test::TestRequest;
+use rusqlite::Connection;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::config::AppConfig;
+use crate::{setup_db, Shared};
+
+pub const BOUNDARY: &str = "test-form-boundary";
+
+pub fn test_db() -> Connection {
+    setup_db(Connection::open_in_memory().unwrap()).unwrap()
+}
+
+pub fn test_config(dir: &Path) -> AppConfig {
+    AppConfig {
+        upload_dir: dir.join("uploads").to_string_lossy().into_owned(),
+        ..AppConfig::default()
+    }
+}
+
+pub fn shared(config: AppConfig) -> Shared {
+    std::fs::create_dir_all(&config.upload_dir).unwrap();
+    Shared::new(test_db(), config).unwrap()
+}
+
+// A multipart body with the given text fields, plus `file` as the
+// `file` field: (file name, content type, bytes)
+pub fn multipart(fields: &[(&str, &str)], file: Option<(&str, &str, &[u8])>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value).as_bytes());
+    }
+    if let Some((filename, content_type, bytes)) = file {
+        body.extend(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            BOUNDARY, filename, content_type
+        ).as_bytes());
+        body.extend(bytes);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+// A POST of `body` from multipart() to `uri`, as the client at `peer`
+pub fn form_post(uri: &str, body: Vec<u8>, peer: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(uri)
+        .peer_addr(peer.parse::<SocketAddr>().unwrap())
+        .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+        .set_payload(body)
+}
+
+// A small solid-colour PNG; different shades give different bytes
+pub fn png(shade: u8) -> Vec<u8> {
+    let image = image::RgbImage::from_pixel(8, 8, image::Rgb([shade, 80, 160]));
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+    bytes.into_inner()
+}
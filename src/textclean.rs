@@ -0,0 +1,192 @@
+use unicode_general_category::{get_general_category, GeneralCategory};
+use unicode_normalization::UnicodeNormalization;
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+// Categories of characters that may appear in posts: letters, marks,
+// numbers, punctuation, symbols and ordinary spaces. Everything else
+// (controls, format characters such as zero-width spaces and bidi
+// overrides, line/paragraph separators, private use, unassigned) goes.
+fn allowed_category(c: char) -> bool {
+    use GeneralCategory::*;
+    matches!(
+        get_general_category(c),
+        UppercaseLetter | LowercaseLetter | TitlecaseLetter | ModifierLetter | OtherLetter
+            | NonspacingMark | SpacingMark | EnclosingMark
+            | DecimalNumber | LetterNumber | OtherNumber
+            | ConnectorPunctuation | DashPunctuation | OpenPunctuation | ClosePunctuation
+            | InitialPunctuation | FinalPunctuation | OtherPunctuation
+            | MathSymbol | CurrencySymbol | ModifierSymbol | OtherSymbol
+            | SpaceSeparator
+    )
+}
+
+// Emoji sequences like 👩‍💻 are symbols glued with a zero-width joiner;
+// the joiner is kept there and nowhere else
+fn joins_symbols(prev: Option<char>, next: Option<char>) -> bool {
+    // U+FE0F asks for the emoji presentation of the symbol before it
+    let symbol = |c: Option<char>| c.is_some_and(|c| {
+        c == '\u{FE0F}' || matches!(get_general_category(c), GeneralCategory::OtherSymbol | GeneralCategory::ModifierSymbol)
+    });
+    symbol(prev) && symbol(next)
+}
+
+// Removes characters outside the allowed categories, keeping newlines,
+// carriage returns and tabs
+pub fn strip_invisible(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let keep = matches!(c, '\n' | '\r' | '\t')
+            || allowed_category(c)
+            || (c == ZERO_WIDTH_JOINER && joins_symbols(out.chars().last(), chars.get(i + 1).copied()));
+        if keep {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Blank lines allowed in a row; longer runs are cut down to this many
+const MAX_BLANK_LINES: usize = 2;
+
+// Tidies a message before it is stored: CRLF and lone CRs become LF, ASCII
+// control characters other than newline and tab are dropped, the text is
+// put in Unicode NFC, trailing whitespace is trimmed from each line and
+// runs of blank lines are capped. Running it twice changes nothing.
+pub fn normalize_message(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let text: String = text
+        .chars()
+        .filter(|&c| !c.is_ascii_control() || matches!(c, '\n' | '\t'))
+        .nfc()
+        .collect();
+
+    let mut out = String::with_capacity(text.len());
+    let mut blank_run = 0;
+    for line in text.split('\n') {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank_run += 1;
+            if blank_run > MAX_BLANK_LINES {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    // Every line got a newline; the last one never had it
+    out.pop();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[test]
+    fn invisible_and_control_characters_are_removed() {
+        assert_eq!(strip_invisible("free\u{200B}mo\u{200C}ney\u{FEFF}"), "freemoney");
+        assert_eq!(strip_invisible("evil\u{202E}txt.exe\u{0007}"), "eviltxt.exe");
+        assert_eq!(strip_invisible("line\u{2028}sep\u{E000}"), "linesep");
+        assert_eq!(strip_invisible("tabs\tand\r\nnewlines stay"), "tabs\tand\r\nnewlines stay");
+        assert_eq!(strip_invisible("Ünïcödé, 日本語 and café"), "Ünïcödé, 日本語 and café");
+        // A joiner inside an emoji sequence stays; one between letters goes
+        assert_eq!(strip_invisible("👩\u{200D}💻 a\u{200D}b"), "👩\u{200D}💻 ab");
+    }
+
+    #[actix_web::test]
+    async fn posts_are_stored_without_zero_width_spaces() {
+        for (strip, expected) in [(true, ("spam", "buy now\nplease")), (false, ("sp\u{200B}am", "buy\u{200B} now\u{200D}\nplease"))] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { strip_invisible_chars: strip, ..test_config(dir.path()) });
+            let app = init_service(crate::app(&shared)).await;
+
+            let body = multipart(&[("title", "sp\u{200B}am"), ("message", "buy\u{200B} now\u{200D}\r\nplease"), ("parent_id", "0")], None);
+            assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+            let (title, message): (String, String) = shared.conn.lock().unwrap()
+                .query_row("SELECT title, message FROM files", [], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap();
+            assert_eq!((title.as_str(), message.as_str()), expected, "strip_invisible_chars = {}", strip);
+        }
+    }
+
+    #[test]
+    fn messages_are_normalized() {
+        assert_eq!(normalize_message("one\r\ntwo\rthree"), "one\ntwo\nthree");
+        assert_eq!(normalize_message("bell\u{0007} and\tnul\u{0000}"), "bell and\tnul");
+        assert_eq!(normalize_message("trailing   \nspaces\t\n"), "trailing\nspaces\n");
+        assert_eq!(normalize_message("top\n\n\n\n\n\nbottom"), "top\n\n\nbottom");
+        // e + combining acute becomes the single é
+        assert_eq!(normalize_message("cafe\u{0301}"), "caf\u{00E9}");
+    }
+
+    // Pseudo-random text weighted towards what the normalizer rewrites:
+    // line breaks, blanks, controls and combining marks
+    fn samples() -> impl Iterator<Item = String> {
+        const TRICKY: &[char] = &['\n', '\r', '\t', ' ', '\u{0000}', '\u{001B}', '\u{007F}', '\u{0085}', '\u{00A0}', '\u{0301}', '\u{0323}', 'e', 'A', '\u{1100}', '\u{1161}', '\u{200D}', '\u{3000}'];
+        let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        (0..2000).map(move |_| {
+            let len = next() % 40;
+            (0..len)
+                .map(|_| match next() % 3 {
+                    0 => char::from_u32((next() % 0x11_0000) as u32).unwrap_or('\u{FFFD}'),
+                    _ => TRICKY[(next() % TRICKY.len() as u64) as usize],
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn normalizing_twice_changes_nothing() {
+        for text in samples() {
+            let once = normalize_message(&text);
+            assert_eq!(normalize_message(&once), once, "input {:?}", text);
+            assert!(!once.contains('\r'));
+            assert!(!once.chars().any(|c| c.is_ascii_control() && !matches!(c, '\n' | '\t')));
+            assert!(!once.contains(&"\n".repeat(MAX_BLANK_LINES + 2)));
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic() {
+        let mut byte: u8 = 0;
+        for len in 0..512 {
+            let bytes: Vec<u8> = (0..len).map(|i| {
+                byte = byte.wrapping_mul(31).wrapping_add(i as u8 ^ 0x5A);
+                byte
+            }).collect();
+            let text = String::from_utf8_lossy(&bytes);
+            let normalized = normalize_message(&text);
+            assert_eq!(normalize_message(&normalized), normalized);
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_length_limit_counts_the_normalized_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        let post = |message: &str| form_post("/upload", multipart(&[("title", "long"), ("message", message), ("parent_id", "0")], None), "127.0.0.1:40000").to_request();
+
+        // Over the limit only because of the blank lines that get collapsed
+        let padded = format!("{}{}end", "a".repeat(49_990), "\r\n".repeat(200));
+        assert_eq!(call_service(&app, post(&padded)).await.status(), 303);
+        let stored: String = shared.conn.lock().unwrap().query_row("SELECT message FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored.chars().count(), 49_990 + MAX_BLANK_LINES + 1 + 3);
+
+        assert_eq!(call_service(&app, post(&"a".repeat(50_001))).await.status(), 422);
+    }
+}
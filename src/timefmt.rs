@@ -0,0 +1,108 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+
+use crate::locale::Tr;
+
+// What timestamps are formatted against: the current time and the
+// reader's offset from UTC in minutes
+pub struct FormatCtx {
+    pub now: DateTime<Utc>,
+    pub offset_minutes: i32,
+}
+
+// SQLite CURRENT_TIMESTAMP values are UTC in "YYYY-MM-DD HH:MM:SS" form
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+pub fn relative_time(tr: &Tr, now: DateTime<Utc>, then: DateTime<Utc>) -> String {
+    let secs = (now - then).num_seconds().max(0);
+    match secs {
+        0..=59 => tr.t("time.just_now"),
+        60..=3599 => tr.tn("time.minutes_ago", secs / 60),
+        3600..=86399 => tr.tn("time.hours_ago", secs / 3600),
+        86400..=2591999 => tr.tn("time.days_ago", secs / 86400),
+        2592000..=31535999 => tr.tn("time.months_ago", secs / 2592000),
+        _ => tr.tn("time.years_ago", secs / 31536000),
+    }
+}
+
+// Relative time for a stored timestamp, falling back to the raw value
+pub fn relative_timestamp(ctx: &FormatCtx, tr: &Tr, timestamp: &str) -> String {
+    match parse_timestamp(timestamp) {
+        Some(then) => relative_time(tr, ctx.now, then),
+        None => timestamp.to_string(),
+    }
+}
+
+// "UTC", "UTC+2", "UTC-3:30"
+fn offset_label(offset_minutes: i32) -> String {
+    let sign = if offset_minutes < 0 { '-' } else { '+' };
+    let (hours, minutes) = (offset_minutes.abs() / 60, offset_minutes.abs() % 60);
+    match (hours, minutes) {
+        (0, 0) => "UTC".to_string(),
+        (_, 0) => format!("UTC{}{}", sign, hours),
+        _ => format!("UTC{}{}:{:02}", sign, hours, minutes),
+    }
+}
+
+// Wall-clock time in the reader's offset, labelled: "2024-05-01 14:07 UTC+2"
+pub fn absolute_time(ctx: &FormatCtx, then: DateTime<Utc>) -> String {
+    let offset = FixedOffset::east_opt(ctx.offset_minutes * 60).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    format!("{} {}", then.with_timezone(&offset).format("%Y-%m-%d %H:%M"), offset_label(ctx.offset_minutes))
+}
+
+// Absolute time for a stored timestamp, falling back to the raw value
+pub fn absolute_timestamp(ctx: &FormatCtx, timestamp: &str) -> String {
+    match parse_timestamp(timestamp) {
+        Some(then) => absolute_time(ctx, then),
+        None => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Data;
+
+    use crate::locale::{translator, Locales};
+
+    fn ctx(offset_minutes: i32) -> FormatCtx {
+        FormatCtx { now: parse_timestamp("2024-05-01 15:00:00").unwrap(), offset_minutes }
+    }
+
+    fn english() -> Tr {
+        translator(&TestRequest::default().app_data(Data::new(Locales::load().unwrap())).to_http_request())
+    }
+
+    #[test]
+    fn relative_times_round_down_to_the_largest_unit() {
+        let tr = english();
+        let now = parse_timestamp("2024-05-01 15:00:00").unwrap();
+        let ago = |timestamp| relative_time(&tr, now, parse_timestamp(timestamp).unwrap());
+        assert_eq!(ago("2024-05-01 14:59:30"), "just now");
+        assert_eq!(ago("2024-05-01 14:59:00"), "1 minute ago");
+        assert_eq!(ago("2024-05-01 12:00:00"), "3 hours ago");
+        assert_eq!(ago("2024-04-29 15:00:00"), "2 days ago");
+        assert_eq!(relative_timestamp(&ctx(0), &tr, "not a time"), "not a time");
+    }
+
+    #[test]
+    fn absolute_times_shift_by_the_offset_and_say_so() {
+        assert_eq!(absolute_timestamp(&ctx(0), "2024-05-01 12:07:00"), "2024-05-01 12:07 UTC");
+        assert_eq!(absolute_timestamp(&ctx(120), "2024-05-01 12:07:00"), "2024-05-01 14:07 UTC+2");
+        assert_eq!(absolute_timestamp(&ctx(-210), "2024-05-01 02:07:00"), "2024-04-30 22:37 UTC-3:30");
+        assert_eq!(absolute_timestamp(&ctx(60), "not a time"), "not a time");
+    }
+
+    #[test]
+    fn relative_times_ignore_the_offset() {
+        let tr = english();
+        for offset in [0, 120, -600] {
+            assert_eq!(relative_timestamp(&ctx(offset), &tr, "2024-05-01 12:00:00"), "3 hours ago");
+        }
+        assert_eq!(relative_timestamp(&ctx(120), &tr, "2024-05-01 14:59:30"), "just now");
+    }
+}
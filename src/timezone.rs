@@ -0,0 +1,67 @@
+use actix_web::web::Data;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+
+use crate::locale::translator;
+use crate::render_notice;
+use crate::session::{update_prefs, Prefs};
+use crate::timefmt::FormatCtx;
+use crate::site::Site;
+
+// Real-world offsets run from UTC-12 to UTC+14
+const MAX_OFFSET_MINUTES: i32 = 14 * 60;
+
+fn valid_offset(minutes: i32) -> bool {
+    minutes.abs() <= MAX_OFFSET_MINUTES
+}
+
+// Formatting context for this request: the reader's chosen UTC offset, or
+// plain UTC without one
+pub fn format_ctx(req: &HttpRequest) -> FormatCtx {
+    let offset_minutes = Some(Prefs::of(req).tz).filter(|&minutes| valid_offset(minutes)).unwrap_or(0);
+    FormatCtx { now: Utc::now(), offset_minutes }
+}
+
+// Stores the reader's preferred UTC offset in minutes, e.g. /tz/120 for UTC+2
+pub async fn set_timezone(req: HttpRequest, site: Data<Site>, path: web::Path<i32>) -> Result<HttpResponse> {
+    let minutes = path.into_inner();
+    if !valid_offset(minutes) {
+        let tr = translator(&req);
+        let body = render_notice(&site, &tr, &tr.t("error.invalid_tz_title"), &tr.t("error.invalid_tz"));
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(body));
+    }
+
+    let mut response = HttpResponse::SeeOther();
+    update_prefs(&req, &mut response, |prefs| prefs.tz = minutes);
+    Ok(response.append_header(("Location", "/")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[actix_web::test]
+    async fn the_chosen_offset_is_remembered_and_shown() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+        let op = multipart(&[("title", "clock"), ("message", "tick"), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", op, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        for offset in ["841", "-841", "abc"] {
+            assert_ne!(call_service(&app, TestRequest::get().uri(&format!("/tz/{}", offset)).to_request()).await.status(), 303, "{}", offset);
+        }
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(" UTC\""));
+
+        let response = call_service(&app, TestRequest::get().uri("/tz/-150").to_request()).await;
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/");
+        let cookie = response.response().cookies().find(|cookie| cookie.name() == "prefs").unwrap().into_owned();
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").cookie(cookie).to_request()).await.to_vec()).unwrap();
+        assert!(page.contains(" UTC-2:30\""));
+    }
+}
@@ -0,0 +1,721 @@
+use actix_multipart::{Field, MultipartError};
+use actix_web::error::BlockingError;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::Data;
+use actix_web::{web, HttpResponse};
+use futures_util::stream::StreamExt as _;
+use image::ImageFormat;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::blocklist::file_hash;
+use crate::config::AppConfig;
+use crate::dimensions::image_dimensions;
+use crate::filetypes::{extension_of, find_type, FileKind};
+use crate::jobs::{AppState, Job};
+use crate::locale::Tr;
+use crate::scan::{scan_upload, Verdict};
+use crate::quota::{delete_files, make_room, reserve};
+use crate::storage::Storage;
+use crate::{form_error, generate_upload_name, pdf, reencode, render_notice, sanitize_original_name, upload_name, video};
+use crate::site::Site;
+
+// Pre-uploaded attachments nobody posted are removed after this long
+const PENDING_MAX_AGE_MINUTES: u32 = 60;
+
+// Seconds a client is asked to wait when the upload disk is full
+const STORAGE_RETRY_AFTER: u64 = 300;
+
+// Names tried for a stored upload before giving up
+const MAX_NAME_ATTEMPTS: u32 = 10;
+
+// What happens to a reply carrying a file already posted in its thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateImages {
+    Allow,
+    // Posted anyway, with a note in the moderation log
+    Warn,
+    Block,
+}
+
+// How stored uploads are named
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadNames {
+    // A random UUID
+    Uuid,
+    // SHA-256 of the stored file; a second copy gets a numbered name
+    Hash,
+    // A short random prefix and the uploader's file name
+    Original,
+}
+
+// An upload that has been checked and written to the upload directory
+pub struct StoredUpload {
+    pub file_path: String,
+    pub original_name: Option<String>,
+    pub original_format: Option<String>,
+    pub original_size: Option<i64>,
+    pub thumbnail_path: Option<String>,
+    // Of the image shown for the upload: the file, or a video's poster frame
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // SHA-256 of the file as uploaded, checked against the blocklist
+    pub file_hash: Option<String>,
+    // Of a PDF, when it could be read off the file
+    pub page_count: Option<i64>,
+    // Storage taken by the file and its poster frame, added to the running
+    // total once a row refers to them
+    pub stored_bytes: i64,
+}
+
+pub enum UploadError {
+    Unsupported,
+    // A file part with no contents, as browsers send when no file is chosen
+    Empty,
+    TooLarge,
+    Mismatch,
+    // An image that fails to decode, e.g. truncated mid-upload
+    Corrupt,
+    // On the blocklist; the poster isn't told that
+    Blocked,
+    // Flagged by the virus scanner
+    Infected,
+    // The virus scan failed and `reject_on_scan_error` is set
+    ScanFailed,
+    SaveFailed,
+    // The upload directory's disk or quota is full
+    StorageFull,
+    // Over `storage_quota_bytes`, with no archived files left to evict
+    OverQuota,
+    // The request body isn't valid multipart
+    Malformed(MultipartError),
+    Request(actix_web::Error),
+}
+
+impl From<MultipartError> for UploadError {
+    fn from(e: MultipartError) -> Self {
+        UploadError::Malformed(e)
+    }
+}
+
+impl From<BlockingError> for UploadError {
+    fn from(e: BlockingError) -> Self {
+        UploadError::Request(e.into())
+    }
+}
+
+impl UploadError {
+    // Status and message for a file the poster can fix by choosing another;
+    // None for failures on the server's side or of the form itself
+    pub fn form_message(&self, tr: &Tr) -> Option<(StatusCode, String)> {
+        Some(match self {
+            UploadError::Unsupported => (StatusCode::BAD_REQUEST, tr.t("error.unsupported_file")),
+            UploadError::Empty => (StatusCode::BAD_REQUEST, tr.t("error.empty_file")),
+            UploadError::TooLarge => (StatusCode::BAD_REQUEST, tr.t("error.file_too_large")),
+            UploadError::Mismatch => (StatusCode::BAD_REQUEST, tr.t("error.file_mismatch")),
+            UploadError::Corrupt => (StatusCode::UNPROCESSABLE_ENTITY, tr.t("error.corrupt_image")),
+            UploadError::Blocked => (StatusCode::UNPROCESSABLE_ENTITY, tr.t("error.file_rejected")),
+            UploadError::Infected => (StatusCode::UNPROCESSABLE_ENTITY, tr.t("error.file_infected")),
+            UploadError::ScanFailed => (StatusCode::SERVICE_UNAVAILABLE, tr.t("error.scan_failed")),
+            _ => return None,
+        })
+    }
+
+    // Response for the HTML posting forms
+    pub fn form_response(self, site: &Site, tr: &Tr) -> actix_web::Result<HttpResponse> {
+        if let Some((status, message)) = self.form_message(tr) {
+            return Ok(form_error(site, tr, status, &message));
+        }
+        match self {
+            UploadError::Malformed(e) => {
+                eprintln!("Malformed post form: {}", e);
+                Ok(form_error(site, tr, StatusCode::BAD_REQUEST, &tr.t("error.malformed_form")))
+            },
+            UploadError::SaveFailed => {
+                let body = render_notice(site, tr, &tr.t("error.upload_failed_title"), &tr.t("error.upload_failed"));
+                Ok(HttpResponse::InternalServerError().content_type("text/html").body(body))
+            },
+            UploadError::StorageFull => {
+                let body = render_notice(site, tr, &tr.t("error.storage_full_title"), &tr.t("error.storage_full"));
+                Ok(HttpResponse::ServiceUnavailable()
+                    .insert_header((header::RETRY_AFTER, STORAGE_RETRY_AFTER.to_string()))
+                    .content_type("text/html")
+                    .body(body))
+            },
+            UploadError::OverQuota => {
+                let body = render_notice(site, tr, &tr.t("error.over_quota_title"), &tr.t("error.over_quota"));
+                Ok(HttpResponse::InsufficientStorage().content_type("text/html").body(body))
+            },
+            UploadError::Request(e) => Err(e),
+            _ => unreachable!("handled by form_message"),
+        }
+    }
+
+    // Response for the JSON API
+    pub fn json_response(self) -> actix_web::Result<HttpResponse> {
+        let error = match self {
+            UploadError::Unsupported => "Unsupported file type",
+            UploadError::Empty => "The file is empty",
+            UploadError::TooLarge => "File is too large",
+            UploadError::Mismatch => "File contents do not match its type",
+            UploadError::Corrupt => return Ok(HttpResponse::UnprocessableEntity().json(json!({ "error": "Image is corrupt or truncated" }))),
+            UploadError::Blocked => return Ok(HttpResponse::UnprocessableEntity().json(json!({ "error": "This file can't be posted" }))),
+            UploadError::Infected => return Ok(HttpResponse::UnprocessableEntity().json(json!({ "error": "File was flagged by the virus scanner" }))),
+            UploadError::ScanFailed => return Ok(HttpResponse::ServiceUnavailable().json(json!({ "error": "File could not be scanned for viruses" }))),
+            UploadError::SaveFailed => return Ok(HttpResponse::InternalServerError().json(json!({ "error": "File could not be saved" }))),
+            UploadError::StorageFull => return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, STORAGE_RETRY_AFTER.to_string()))
+                .json(json!({ "error": "Storage temporarily unavailable", "retry_after": STORAGE_RETRY_AFTER }))),
+            UploadError::OverQuota => return Ok(HttpResponse::InsufficientStorage().json(json!({ "error": "Upload storage quota reached" }))),
+            UploadError::Malformed(e) => return Err(e.into()),
+            UploadError::Request(e) => return Err(e),
+        };
+        Ok(HttpResponse::BadRequest().json(json!({ "error": error })))
+    }
+}
+
+// Decodes the whole image. The magic bytes can be fine while the rest is
+// cut short or garbled, and browsers then show a broken image. Formats
+// the image crate can't read are taken on their signature alone.
+fn image_decodes(extension: &str, data: &[u8]) -> bool {
+    let Some(format) = ImageFormat::from_extension(extension).filter(|format| format.reading_enabled()) else {
+        return true;
+    };
+    match image::load_from_memory_with_format(data, format) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Rejecting undecodable .{} upload: {}", extension, e);
+            false
+        },
+    }
+}
+
+// Name for an upload under `scheme`; `attempt` counts names already found taken
+fn upload_file_name(scheme: UploadNames, filename: &str, extension: &str, data: &[u8], attempt: u32) -> String {
+    match scheme {
+        UploadNames::Uuid => format!("{}.{}", uuid::Uuid::new_v4().simple(), extension),
+        UploadNames::Hash if attempt == 0 => format!("{}.{}", file_hash(data), extension),
+        UploadNames::Hash => format!("{}-{}.{}", file_hash(data), attempt, extension),
+        UploadNames::Original => generate_upload_name(&Path::new(filename).with_extension(extension).to_string_lossy()),
+    }
+}
+
+fn is_storage_full(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge)
+}
+
+// Puts an upload in storage and returns its path. Storage never replaces
+// an existing file, so a taken name just moves on to the next one.
+fn write_upload(storage: &dyn Storage, scheme: UploadNames, filename: &str, extension: &str, data: &[u8], content_type: &str) -> std::io::Result<String> {
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let name = upload_file_name(scheme, filename, extension, data, attempt);
+        if storage.exists(&name) {
+            continue;
+        }
+        match storage.put(&name, data, content_type) {
+            Ok(path) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(ErrorKind::AlreadyExists, "no free file name"))
+}
+
+// First frame of a video as a JPEG. ffmpeg works on files, so it is run
+// on a temporary copy, whatever the storage.
+async fn poster_frame(ffmpeg: &str, data: &[u8], extension: &str) -> Option<Vec<u8>> {
+    let base = std::env::temp_dir().join(format!("poster-{}", uuid::Uuid::new_v4().simple()));
+    let video_path = base.with_extension(extension).to_string_lossy().into_owned();
+    let poster_path = base.with_extension("jpg").to_string_lossy().into_owned();
+    if let Err(e) = tokio::fs::write(&video_path, data).await {
+        eprintln!("No poster frame: could not write {}: {}", video_path, e);
+        return None;
+    }
+    let poster = match video::extract_poster_frame(ffmpeg, &video_path, &poster_path).await {
+        Ok(()) => tokio::fs::read(&poster_path).await.ok(),
+        Err(e) => {
+            eprintln!("No poster frame: {}", e);
+            None
+        },
+    };
+    let _ = tokio::fs::remove_file(&video_path).await;
+    let _ = tokio::fs::remove_file(&poster_path).await;
+    poster
+}
+
+// Reads a file field and runs it through the upload pipeline for its entry
+// in `file_types`: size limit, content sniffing, optional WebP
+// re-encoding, grabbing a poster frame for videos and putting it all in
+// storage.
+pub async fn store_upload(field: &mut Field, filename: &str, config: &AppConfig, storage: &Data<dyn Storage>, conn: &Data<Mutex<Connection>>) -> Result<StoredUpload, UploadError> {
+    let Some(file_type) = find_type(&config.file_types, extension_of(filename)) else {
+        return Err(UploadError::Unsupported);
+    };
+    let file_extension = file_type.extension.to_ascii_lowercase();
+    let file_extension = file_extension.as_str();
+    let max_bytes = file_type.max_bytes.map_or(config.max_upload_bytes, |max| max.min(config.max_upload_bytes));
+    let mut stored_extension = file_extension.to_string();
+    let mut content_type = file_type.mime.clone();
+    let mut original_format = None;
+    let mut original_size = None;
+    let mut thumbnail_path = None;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > max_bytes {
+            return Err(UploadError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.is_empty() {
+        return Err(UploadError::Empty);
+    }
+
+    // An upload named .png that actually contains HTML or script is refused
+    if !file_type.matches(&data) {
+        return Err(UploadError::Mismatch);
+    }
+
+    if file_type.kind == FileKind::Image {
+        let (extension, image) = (file_extension.to_string(), data.clone());
+        if !web::block(move || image_decodes(&extension, &image)).await? {
+            return Err(UploadError::Corrupt);
+        }
+    }
+    let hash = file_hash(&data);
+
+    match scan_upload(config, filename, &data).await {
+        None | Some(Ok(Verdict::Clean)) => {},
+        Some(Ok(Verdict::Infected(_))) => return Err(UploadError::Infected),
+        Some(Err(_)) if config.reject_on_scan_error => return Err(UploadError::ScanFailed),
+        Some(Err(_)) => {},
+    }
+
+    // With `convert_to_webp` every JPEG and PNG is converted; otherwise only
+    // large PNGs (usually screenshots), and only when that saves space.
+    // If anything goes wrong we keep the original upload.
+    let convert_all = config.convert_to_webp && reencode::can_reencode(file_extension);
+    let large_png = file_extension == "png" && config.webp_min_png_bytes > 0 && data.len() as u64 > config.webp_min_png_bytes;
+    if convert_all || large_png {
+        let original = data.clone();
+        let quality = config.webp_quality;
+        let format = file_extension.to_string();
+        match web::block(move || reencode::to_webp(&original, &format, quality)).await? {
+            Ok(webp) if convert_all || webp.len() < data.len() => {
+                original_format = Some(if file_extension == "jpeg" { "jpg" } else { file_extension }.to_string());
+                original_size = Some(data.len() as i64);
+                stored_extension = "webp".to_string();
+                content_type = "image/webp".to_string();
+                data = webp;
+            },
+            Ok(_) => {},
+            Err(e) => eprintln!("Keeping {} unconverted: {}", filename, e),
+        }
+    }
+
+    // Each kind has its own metadata: images their size, PDFs their pages
+    // and videos the size of their poster frame
+    let mut poster = None;
+    let (dimensions, page_count) = match file_type.kind {
+        FileKind::Image => (image_dimensions(&data), None),
+        FileKind::Document if file_extension == "pdf" => (None, pdf::page_count(&data)),
+        FileKind::Video => {
+            if let Some(ffmpeg) = &config.ffmpeg_path {
+                poster = poster_frame(ffmpeg, &data, &stored_extension).await;
+            }
+            (poster.as_deref().and_then(image_dimensions), None)
+        },
+        FileKind::Audio | FileKind::Document | FileKind::Download => (None, None),
+    };
+
+    let mut stored_bytes = data.len() as i64;
+    // An early check so a file that can't fit isn't written at all. The
+    // bytes are only taken from the quota when the row is inserted.
+    let needed = stored_bytes + poster.as_ref().map_or(0, |poster| poster.len() as i64);
+    let (db, target, quota, evict) = (conn.clone(), storage.clone(), config.storage_quota_bytes, config.evict_archived_files);
+    if !web::block(move || make_room(&db.lock().unwrap(), &**target, quota, evict, needed)).await? {
+        eprintln!("Refused {}: upload storage quota reached", filename);
+        return Err(UploadError::OverQuota);
+    }
+
+    let (target, scheme, name) = (storage.clone(), config.upload_names, filename.to_string());
+    let file_path = match web::block(move || write_upload(&**target, scheme, &name, &stored_extension, &data, &content_type)).await? {
+        Ok(file_path) => file_path,
+        Err(e) if is_storage_full(&e) => {
+            eprintln!("Upload directory {} is out of space; refused {}: {}", config.upload_dir, filename, e);
+            return Err(UploadError::StorageFull);
+        },
+        Err(e) => {
+            eprintln!("Failed to save upload {}: {}", filename, e);
+            return Err(UploadError::SaveFailed);
+        },
+    };
+
+    if let Some(poster) = poster {
+        let poster_bytes = poster.len() as i64;
+        let (target, poster_name) = (storage.clone(), format!("{}.jpg", upload_name(&file_path)));
+        match web::block(move || target.put(&poster_name, &poster, "image/jpeg")).await? {
+            Ok(poster_path) => {
+                thumbnail_path = Some(poster_path);
+                stored_bytes += poster_bytes;
+            },
+            Err(e) => eprintln!("Failed to save the poster frame for {}: {}", file_path, e),
+        }
+    }
+
+    Ok(StoredUpload {
+        file_path,
+        original_name: sanitize_original_name(filename),
+        original_format,
+        original_size,
+        thumbnail_path,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        file_hash: Some(hash),
+        page_count,
+        stored_bytes,
+    })
+}
+
+impl StoredUpload {
+    // Removes the files of an upload whose post was refused
+    pub fn discard(&self, storage: &dyn Storage) {
+        for path in std::iter::once(&self.file_path).chain(&self.thumbnail_path) {
+            if let Err(e) = storage.delete(path) {
+                eprintln!("Failed to remove upload {}: {}", path, e);
+            }
+        }
+    }
+}
+
+// Records an upload made ahead of its post and returns the one-time token
+// the post form hands back to claim it. Its bytes are reserved in the same
+// transaction; None when they no longer fit under the quota.
+pub fn save_pending(conn: &mut Connection, storage: &dyn Storage, config: &AppConfig, upload: &StoredUpload) -> rusqlite::Result<Option<String>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if !reserve(&tx, storage, config, upload.stored_bytes)? {
+        return Ok(None);
+    }
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    tx.execute(
+        "INSERT INTO pending_uploads (token, file_path, original_name, original_format, original_size, thumbnail_path, width, height, file_hash, page_count, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, CURRENT_TIMESTAMP)",
+        params![token, upload.file_path, upload.original_name, upload.original_format, upload.original_size, upload.thumbnail_path, upload.width, upload.height, upload.file_hash, upload.page_count],
+    )?;
+    tx.commit()?;
+    Ok(Some(token))
+}
+
+// The upload sent ahead under `token`, if it is still pending. Looking it
+// up leaves it in place, so a post refused by its checks doesn't use it up.
+pub fn find_pending(conn: &Connection, token: &str) -> rusqlite::Result<Option<StoredUpload>> {
+    conn.query_row(
+        "SELECT file_path, original_name, original_format, original_size, thumbnail_path, width, height, file_hash, page_count FROM pending_uploads WHERE token = ?1",
+        params![token],
+        |row| Ok(StoredUpload {
+            file_path: row.get(0)?,
+            original_name: row.get(1)?,
+            original_format: row.get(2)?,
+            original_size: row.get(3)?,
+            thumbnail_path: row.get(4)?,
+            width: row.get(5)?,
+            height: row.get(6)?,
+            file_hash: row.get(7)?,
+            page_count: row.get(8)?,
+            // Counted when it was sent ahead
+            stored_bytes: 0,
+        }),
+    ).optional()
+}
+
+// Takes a pending upload for the post being inserted in the same
+// transaction. The row is deleted, so a token only ever works once; if the
+// post is rolled back the upload stays pending, for the orphan cleanup to
+// remove along with its share of the quota.
+pub fn claim_pending(conn: &Connection, token: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM pending_uploads WHERE token = ?1", params![token])?;
+    Ok(())
+}
+
+// Removes pre-uploaded attachments that were never attached to a post,
+// along with their files.
+pub struct OrphanCleanupJob;
+
+impl Job for OrphanCleanupJob {
+    fn name(&self) -> &'static str {
+        "orphan-cleanup"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    fn run(&self, state: &AppState) -> Result<(), String> {
+        let conn = state.conn.lock().unwrap();
+        let cutoff = format!("-{} minutes", PENDING_MAX_AGE_MINUTES);
+        let mut stmt = conn.prepare("SELECT token, file_path, thumbnail_path FROM pending_uploads WHERE created_at < datetime('now', ?1)")
+            .map_err(|e| e.to_string())?;
+        let expired: Vec<(String, String, Option<String>)> = stmt.query_map(params![cutoff], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .filter_map(|row| row.ok())
+            .collect();
+
+        for (token, file_path, thumbnail_path) in &expired {
+            conn.execute("DELETE FROM pending_uploads WHERE token = ?1", params![token]).map_err(|e| e.to_string())?;
+            let paths: Vec<String> = std::iter::once(file_path).chain(thumbnail_path).cloned().collect();
+            delete_files(&conn, &**state.storage, &paths);
+        }
+
+        println!("Removed {} unclaimed uploads", expired.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    use crate::locale::translator;
+
+    use crate::testing::{form_post, multipart, png, shared, test_config};
+
+    const HTML: &[u8] = b"<html><script>alert(document.cookie)</script></html>";
+
+    #[actix_web::test]
+    async fn html_sent_as_png_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let body = multipart(&[("title", "polyglot"), ("message", "look"), ("parent_id", "0")], Some(("cat.png", "image/png", HTML)));
+        let response = call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await;
+        assert_eq!(response.status(), 400);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("File contents do not match its type."));
+
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn truncated_images_are_refused_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        // Signature and header intact, image data cut off
+        let image = png(40);
+        let truncated = &image[..image.len() / 2];
+        let body = multipart(&[("title", "cut short"), ("message", "look"), ("parent_id", "0")], Some(("cut.png", "image/png", truncated)));
+        let response = call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await;
+        assert_eq!(response.status(), 422);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("The image is corrupt or truncated and could not be read."));
+
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+        assert_eq!(std::fs::read_dir(&shared.config.upload_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn only_whole_images_decode() {
+        let image = png(40);
+        assert!(image_decodes("png", &image));
+        assert!(!image_decodes("png", &image[..image.len() - 20]));
+        assert!(!image_decodes("png", b"\x89PNG\r\n\x1a\n"));
+        assert!(!image_decodes("jpg", &image));
+    }
+
+    #[test]
+    fn signatures_decide_not_the_name() {
+        let types = crate::filetypes::default_file_types();
+        let png = find_type(&types, "png").unwrap();
+        assert!(!png.matches(HTML));
+        assert!(png.matches(b"\x89PNG\r\n\x1a\n rest of the file"));
+        // A real PNG doesn't pass as a JPEG either
+        assert!(!find_type(&types, "jpg").unwrap().matches(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn stored_names_never_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let data = png(60);
+        for scheme in [UploadNames::Uuid, UploadNames::Hash, UploadNames::Original] {
+            let mut paths: Vec<String> = (0..MAX_NAME_ATTEMPTS)
+                .map(|_| write_upload(&**shared.storage, scheme, "cat.png", "png", &data, "image/png").unwrap())
+                .collect();
+            paths.sort();
+            paths.dedup();
+            assert_eq!(paths.len(), MAX_NAME_ATTEMPTS as usize, "{:?}", scheme);
+        }
+
+        let hash = file_hash(&data);
+        assert!(Path::new(&shared.config.upload_dir).join(format!("{}.png", hash)).exists());
+        assert!(Path::new(&shared.config.upload_dir).join(format!("{}-1.png", hash)).exists());
+        // Every numbered name is taken now
+        assert_eq!(write_upload(&**shared.storage, UploadNames::Hash, "cat.png", "png", &data, "image/png").unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[actix_web::test]
+    async fn the_same_image_posted_twice_gets_two_uuid_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        for title in ["first", "second"] {
+            let body = multipart(&[("title", title), ("message", "same cat"), ("parent_id", "0")], Some(("cat.png", "image/png", &png(61))));
+            assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+        }
+        let paths: Vec<String> = shared.conn.lock().unwrap()
+            .prepare("SELECT file_path FROM files ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|path| path.unwrap())
+            .collect();
+        assert_ne!(paths[0], paths[1]);
+        for path in &paths {
+            let name = Path::new(path).file_stem().unwrap().to_str().unwrap();
+            assert!(name.len() == 32 && name.chars().all(|c| c.is_ascii_hexdigit()), "{}", name);
+            assert!(!name.contains("cat"));
+        }
+    }
+
+    #[test]
+    fn full_disks_are_told_apart_from_other_write_errors() {
+        for kind in [ErrorKind::StorageFull, ErrorKind::QuotaExceeded, ErrorKind::FileTooLarge] {
+            assert!(is_storage_full(&kind.into()), "{:?}", kind);
+        }
+        assert!(!is_storage_full(&ErrorKind::PermissionDenied.into()));
+    }
+
+    #[actix_web::test]
+    async fn failed_writes_get_a_plain_error_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let tr = translator(&TestRequest::default().app_data(shared.locales.clone()).to_http_request());
+        for (error, status, message) in [
+            (UploadError::StorageFull, 503, "The board can't store new files right now."),
+            (UploadError::SaveFailed, 500, "Your file could not be saved."),
+        ] {
+            let response = error.form_response(&shared.site, &tr).unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers().contains_key(header::RETRY_AFTER), status == 503);
+            let page = String::from_utf8(to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+            assert!(page.contains(message), "{}", page);
+        }
+
+        // Writes into an upload directory that isn't there fail
+        std::fs::remove_dir(&shared.config.upload_dir).unwrap();
+        let app = init_service(crate::app(&shared)).await;
+        let body = multipart(&[("title", "pic"), ("message", "m"), ("parent_id", "0")], Some(("a.png", "image/png", &png(3))));
+        let response = call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await;
+        assert_eq!(response.status(), 500);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(page.contains("Your file could not be saved."));
+        assert!(!page.contains("os error"));
+
+        let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+        assert_eq!(posts, 0);
+    }
+
+    // Storage whose writes fail with `kind`, as a full or broken disk does
+    struct FailingStorage(ErrorKind);
+
+    impl Storage for FailingStorage {
+        fn put(&self, _name: &str, _data: &[u8], _content_type: &str) -> std::io::Result<String> {
+            Err(std::io::Error::new(self.0, "No space left on device (os error 28)"))
+        }
+
+        fn get(&self, _file_path: &str) -> std::io::Result<Vec<u8>> {
+            Err(ErrorKind::NotFound.into())
+        }
+
+        fn delete(&self, _file_path: &str) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn exists(&self, _name: &str) -> bool {
+            false
+        }
+
+        fn size(&self, _file_path: &str) -> Option<u64> {
+            None
+        }
+
+        fn get_url(&self, _name: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_storage_writes_get_a_plain_error_page() {
+        for (kind, status, message) in [
+            (ErrorKind::StorageFull, 503, "The board can't store new files right now."),
+            (ErrorKind::PermissionDenied, 500, "Your file could not be saved."),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut shared = shared(test_config(dir.path()));
+            shared.storage = Data::from(std::sync::Arc::new(FailingStorage(kind)) as std::sync::Arc<dyn Storage>);
+            let app = init_service(crate::app(&shared)).await;
+
+            let body = multipart(&[("title", "pic"), ("message", "m"), ("parent_id", "0")], Some(("a.png", "image/png", &png(3))));
+            let response = call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await;
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers().contains_key(header::RETRY_AFTER), status == 503);
+            let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert!(page.contains(message), "{}", page);
+            assert!(!page.contains("os error") && !page.contains("No space"));
+
+            let posts: i64 = shared.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0)).unwrap();
+            assert_eq!(posts, 0);
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_file_repeated_in_one_thread_is_blocked_or_noted() {
+        for mode in [DuplicateImages::Block, DuplicateImages::Warn, DuplicateImages::Allow] {
+            let dir = tempfile::tempdir().unwrap();
+            let shared = shared(AppConfig { duplicate_images: mode, ..test_config(dir.path()) });
+            let app = init_service(crate::app(&shared)).await;
+            let post = |message: &str, parent_id: &str, shade: u8| {
+                let body = multipart(&[("title", "cats"), ("message", message), ("parent_id", parent_id)], Some(("cat.png", "image/png", &png(shade))));
+                form_post("/upload", body, "127.0.0.1:40000").to_request()
+            };
+            let count = |sql: &str| shared.conn.lock().unwrap().query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+
+            assert_eq!(call_service(&app, post("my cat", "0", 70)).await.status(), 303);
+            assert_eq!(call_service(&app, post("the same cat elsewhere", "0", 70)).await.status(), 303);
+            let thread_id = count("SELECT MIN(id) FROM files").to_string();
+            // A different file is always fine
+            assert_eq!(call_service(&app, post("another cat", &thread_id, 71)).await.status(), 303);
+
+            let stored = || std::fs::read_dir(&shared.config.upload_dir).unwrap().count();
+            let before = stored();
+            let response = call_service(&app, post("that cat again", &thread_id, 70)).await;
+            if mode == DuplicateImages::Block {
+                assert_eq!(response.status(), 409, "{:?}", mode);
+                let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+                assert!(page.contains("This image has already been posted in this thread."));
+                assert_eq!(count("SELECT COUNT(*) FROM files WHERE parent_id != 0"), 1);
+                // Nothing of the refused upload is left behind
+                assert_eq!(stored(), before);
+            } else {
+                assert_eq!(response.status(), 303, "{:?}", mode);
+                assert_eq!(count("SELECT COUNT(*) FROM files WHERE parent_id != 0"), 2);
+            }
+            let noted = if mode == DuplicateImages::Warn { 1 } else { 0 };
+            assert_eq!(count("SELECT COUNT(*) FROM mod_actions WHERE action = 'duplicate-image'"), noted, "{:?}", mode);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+// Longest we let ffmpeg run on a single upload
+const EXTRACT_TIMEOUT: Duration = Duration::from_secs(10);
+// Poster frames are scaled down to at most this width
+const POSTER_WIDTH: u32 = 320;
+
+// Grabs the first frame of `video_path` as a JPEG at `poster_path` using
+// the configured ffmpeg binary. The process is killed if it overruns the
+// timeout, so a hostile file can't hold the request open.
+pub async fn extract_poster_frame(ffmpeg: &str, video_path: &str, poster_path: &str) -> Result<(), String> {
+    let child = Command::new(ffmpeg)
+        .args(["-nostdin", "-v", "error", "-y", "-i", video_path, "-frames:v", "1"])
+        .arg("-vf")
+        .arg(format!("scale='min({},iw)':-2", POSTER_WIDTH))
+        .args(["-f", "image2", "-c:v", "mjpeg", poster_path])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("could not run {}: {}", ffmpeg, e))?;
+
+    let result = match timeout(EXTRACT_TIMEOUT, child.wait_with_output()).await {
+        Err(_) => Err("timed out".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(output)) if !output.status.success() => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Ok(Ok(_)) => Ok(()),
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(poster_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    // A stand-in for ffmpeg: a script running `body` with the output path
+    // in $last
+    fn fake_ffmpeg(dir: &Path, body: &str) -> String {
+        let path = dir.join("ffmpeg");
+        std::fs::write(&path, format!("#!/bin/sh\nfor last; do :; done\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn jpeg(dir: &Path) -> String {
+        let path = dir.join("frame.jpg");
+        image::RgbImage::from_pixel(64, 36, image::Rgb([10, 20, 30])).save(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[actix_web::test]
+    async fn failures_are_reported_and_leave_no_poster() {
+        let dir = tempfile::tempdir().unwrap();
+        let poster = dir.path().join("poster.jpg");
+        let poster_path = poster.to_str().unwrap();
+
+        let failing = fake_ffmpeg(dir.path(), "touch \"$last\"; echo 'moov atom not found' >&2; exit 1");
+        assert_eq!(extract_poster_frame(&failing, "in.mp4", poster_path).await, Err("moov atom not found".to_string()));
+        assert!(!poster.exists());
+
+        let missing = dir.path().join("no-such-ffmpeg");
+        let error = extract_poster_frame(missing.to_str().unwrap(), "in.mp4", poster_path).await.unwrap_err();
+        assert!(error.starts_with("could not run"), "{}", error);
+    }
+
+    #[actix_web::test]
+    async fn video_uploads_get_a_poster_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let frame = jpeg(dir.path());
+        let ffmpeg = fake_ffmpeg(dir.path(), &format!("cp '{}' \"$last\"", frame));
+        let shared = shared(AppConfig { ffmpeg_path: Some(ffmpeg), ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let video = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00mp42isom";
+        let body = multipart(&[("title", "clip"), ("message", "a clip"), ("parent_id", "0")], Some(("clip.mp4", "video/mp4", video)));
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        let (thumbnail, width, height): (String, i64, i64) = shared.conn.lock().unwrap()
+            .query_row("SELECT thumbnail_path, width, height FROM files", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert!(thumbnail.ends_with(".jpg"));
+        assert_eq!(std::fs::read(&thumbnail).unwrap(), std::fs::read(&frame).unwrap());
+        assert_eq!((width, height), (64, 36));
+    }
+}
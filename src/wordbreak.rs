@@ -0,0 +1,136 @@
+// Inserts <wbr> break opportunities into runs of more than `max` characters
+// without whitespace, so a single huge word can't stretch the page. Markup
+// inside tags and character entities is left intact. A `max` of 0 disables
+// it.
+pub fn break_long_words(text: &str, max: usize) -> String {
+    if max == 0 {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut run = 0;
+    let mut in_tag = false;
+    let mut in_entity = false;
+
+    for c in text.chars() {
+        if in_tag {
+            in_tag = c != '>';
+            out.push(c);
+            continue;
+        }
+        if c == '<' {
+            in_tag = true;
+            out.push(c);
+            continue;
+        }
+        if c.is_whitespace() {
+            run = 0;
+            in_entity = false;
+            out.push(c);
+            continue;
+        }
+        // An entity like &amp; counts as the single character it shows
+        if in_entity {
+            in_entity = c != ';';
+            out.push(c);
+            continue;
+        }
+        if run >= max {
+            out.push_str("<wbr>");
+            run = 0;
+        }
+        in_entity = c == '&';
+        out.push(c);
+        run += 1;
+    }
+    out
+}
+
+// The start of `text` cut to at most `max` characters at a word boundary,
+// or None when it already fits. The cut never lands inside a tag or an
+// entity, so rendering the result can't show half of one.
+pub fn truncate_words(text: &str, max: usize) -> Option<&str> {
+    let (limit, _) = text.char_indices().nth(max)?;
+    let mut head = &text[..limit];
+
+    // Step back out of an unfinished tag or entity; the cut then sits just
+    // before it, which is already a clean break
+    if let Some(open) = head.rfind('<').filter(|&open| !head[open..].contains('>')) {
+        head = &head[..open];
+    }
+    if let Some(amp) = head.rfind('&').filter(|&amp| !head[amp..].contains([';', ' ', '\n'])) {
+        head = &head[..amp];
+    }
+    let on_boundary = head.len() < limit || text[limit..].starts_with(char::is_whitespace);
+    if !on_boundary {
+        // A single word longer than `max` is cut where it is
+        if let Some(space) = head.rfind(char::is_whitespace) {
+            head = &head[..space];
+        }
+    }
+    Some(head.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use crate::config::AppConfig;
+    use crate::testing::{form_post, multipart, shared, test_config};
+
+    #[test]
+    fn long_runs_get_break_points_but_markup_does_not() {
+        assert_eq!(break_long_words("abcdefgh ij", 3), "abc<wbr>def<wbr>gh ij");
+        assert_eq!(break_long_words(r#"<a href="/post/123456789">x</a>"#, 3), r#"<a href="/post/123456789">x</a>"#);
+        assert_eq!(break_long_words("&amp;&amp;&amp;&amp;", 3), "&amp;&amp;&amp;<wbr>&amp;");
+        assert_eq!(break_long_words(&"a".repeat(100), 0), "a".repeat(100));
+    }
+
+    #[actix_web::test]
+    async fn a_2000_character_word_is_broken_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(test_config(dir.path()));
+        let app = init_service(crate::app(&shared)).await;
+
+        let word = "x".repeat(2000);
+        let body = multipart(&[("title", "wide"), ("message", &word), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        let thread_id: i32 = shared.conn.lock().unwrap().query_row("SELECT MAX(id) FROM files", [], |row| row.get(0)).unwrap();
+        let page = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri(&format!("/post/{}", thread_id)).to_request()).await.to_vec()).unwrap();
+        let broken = ["x".repeat(80), "<wbr>".to_string()].concat().repeat(24) + &"x".repeat(80);
+        assert!(page.contains(&broken));
+        assert!(!page.contains(&"x".repeat(81)));
+    }
+
+    #[test]
+    fn previews_are_cut_between_words() {
+        assert_eq!(truncate_words("short", 10), None);
+        assert_eq!(truncate_words("one two three four", 10), Some("one two"));
+        assert_eq!(truncate_words("one two three four", 7), Some("one two"));
+        assert_eq!(truncate_words("abcdefghijkl", 5), Some("abcde"));
+        assert_eq!(truncate_words("fish &amp; chips", 8), Some("fish"));
+        assert_eq!(truncate_words("see <b>bold</b> text", 7), Some("see"));
+        assert_eq!(truncate_words("日本語の 文章です", 6), Some("日本語の"));
+    }
+
+    #[actix_web::test]
+    async fn long_openers_are_cut_on_the_board_but_whole_in_the_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = shared(AppConfig { preview_chars: 40, ..test_config(dir.path()) });
+        let app = init_service(crate::app(&shared)).await;
+
+        let message = format!("{}the end", "lorem ipsum ".repeat(10));
+        let body = multipart(&[("title", "long"), ("message", &message), ("parent_id", "0")], None);
+        assert_eq!(call_service(&app, form_post("/upload", body, "127.0.0.1:40000").to_request()).await.status(), 303);
+
+        let board = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await.to_vec()).unwrap();
+        assert!(board.contains(r#"lorem ipsum lorem ipsum lorem ipsum... <a href="/post/1" class="view-full-post">Click here to open full post</a>"#));
+        assert!(!board.contains("the end"));
+
+        let thread = String::from_utf8(call_and_read_body(&app, TestRequest::get().uri("/post/1").to_request()).await.to_vec()).unwrap();
+        assert!(thread.contains("lorem ipsum the end"));
+        assert!(!thread.contains("view-full-post"));
+    }
+}
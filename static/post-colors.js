@@ -0,0 +1,5 @@
+// Post ID boxes carry their colour in data-color. It is applied from here
+// because the content security policy doesn't allow inline styles.
+document.querySelectorAll('.post-id-box[data-color]').forEach(function (box) {
+    box.style.backgroundColor = box.dataset.color;
+});
@@ -0,0 +1,49 @@
+// Hovering a ">>N" link shows that post in a floating box
+(function () {
+    var cache = {};
+    var box = null;
+
+    function hide() {
+        if (box) {
+            box.remove();
+            box = null;
+        }
+    }
+
+    function show(link, html) {
+        hide();
+        box = document.createElement('div');
+        box.className = 'post-preview';
+        box.innerHTML = html;
+        var rect = link.getBoundingClientRect();
+        box.style.left = (rect.left + window.scrollX) + 'px';
+        box.style.top = (rect.bottom + window.scrollY + 4) + 'px';
+        document.body.appendChild(box);
+    }
+
+    document.addEventListener('mouseover', function (event) {
+        var link = event.target.closest('.quote-ref');
+        if (!link) {
+            return;
+        }
+        var id = link.dataset.post;
+        if (cache[id] === undefined) {
+            cache[id] = fetch('/api/fragment/post/' + id).then(function (response) {
+                return response.ok ? response.text() : null;
+            }).catch(function () {
+                return null;
+            });
+        }
+        cache[id].then(function (html) {
+            if (html && link.matches(':hover')) {
+                show(link, html);
+            }
+        });
+    });
+
+    document.addEventListener('mouseout', function (event) {
+        if (event.target.closest('.quote-ref')) {
+            hide();
+        }
+    });
+})();
@@ -0,0 +1,16 @@
+// Clicking a reply number adds ">>N" to the reply box
+document.addEventListener('click', function (event) {
+    var link = event.target.closest('.quote-link');
+    var textarea = document.getElementById('reply-message');
+    if (!link || !textarea) {
+        return;
+    }
+    event.preventDefault();
+
+    if (textarea.value && !textarea.value.endsWith('\n')) {
+        textarea.value += '\n';
+    }
+    textarea.value += '>>' + link.dataset.quote + '\n';
+    textarea.focus();
+    textarea.selectionStart = textarea.selectionEnd = textarea.value.length;
+});
@@ -0,0 +1,83 @@
+// Sends a chosen or dropped file ahead through /api/upload with a progress
+// bar, then posts only the token it gets back. Without JS, or if the
+// upload fails, the file goes with the form as usual.
+function sendAhead(zone, file) {
+    var input = zone.querySelector('input[type=file]');
+    var token = zone.querySelector('input[name=attachment_token]');
+    var progress = zone.querySelector('.upload-progress');
+    var status = zone.querySelector('.upload-status');
+    var preview = zone.querySelector('.upload-preview');
+    if (preview) {
+        preview.remove();
+    }
+    token.value = '';
+    progress.value = 0;
+    progress.hidden = false;
+    status.textContent = zone.dataset.uploading;
+
+    var data = new FormData();
+    data.append('file', file);
+    var request = new XMLHttpRequest();
+    request.open('POST', '/api/upload');
+    request.upload.addEventListener('progress', function (event) {
+        if (event.lengthComputable) {
+            progress.value = Math.round(event.loaded * 100 / event.total);
+        }
+    });
+    request.addEventListener('load', function () {
+        progress.hidden = true;
+        var reply = null;
+        try {
+            reply = JSON.parse(request.responseText);
+        } catch (e) {
+        }
+        if (request.status !== 200 || !reply || !reply.token) {
+            status.textContent = zone.dataset.failed + ' ' + ((reply && reply.error) || request.status);
+            return;
+        }
+        token.value = reply.token;
+        // The file is on the server now, so the form doesn't send it again
+        input.value = '';
+        input.required = false;
+        status.textContent = zone.dataset.attached + ' ' + file.name;
+        if (reply.thumbnail_url) {
+            var image = document.createElement('img');
+            image.className = 'upload-preview';
+            image.src = reply.thumbnail_url;
+            image.alt = '';
+            zone.appendChild(image);
+        }
+    });
+    request.addEventListener('error', function () {
+        progress.hidden = true;
+        status.textContent = zone.dataset.failed + ' ' + file.name;
+    });
+    request.send(data);
+}
+
+document.querySelectorAll('.drop-zone').forEach(function (zone) {
+    var input = zone.querySelector('input[type=file]');
+    var status = zone.querySelector('.upload-status');
+    status.textContent = zone.dataset.hint;
+
+    input.addEventListener('change', function () {
+        if (input.files.length > 0) {
+            sendAhead(zone, input.files[0]);
+        }
+    });
+    zone.addEventListener('dragover', function (event) {
+        event.preventDefault();
+        zone.classList.add('dragging');
+    });
+    zone.addEventListener('dragleave', function () {
+        zone.classList.remove('dragging');
+    });
+    zone.addEventListener('drop', function (event) {
+        event.preventDefault();
+        zone.classList.remove('dragging');
+        // A post carries one file
+        if (event.dataTransfer.files.length > 0) {
+            sendAhead(zone, event.dataTransfer.files[0]);
+        }
+    });
+});